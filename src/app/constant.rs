@@ -133,7 +133,21 @@ def_pub_const!(
     ROUTE_TOKENS_STATUS_SET_PATH = "/tokens/status/set",
     ROUTE_TOKENS_PROXY_SET_PATH = "/tokens/proxy/set",
     ROUTE_TOKENS_TIMEZONE_SET_PATH = "/tokens/timezone/set",
+    ROUTE_TOKENS_WEIGHT_SET_PATH = "/tokens/weight/set",
+    ROUTE_TOKENS_LIMITS_SET_PATH = "/tokens/limits/set",
+    ROUTE_TOKENS_SUSPENDED_GET_PATH = "/tokens/suspended/get",
+    ROUTE_TOKENS_GROUPS_GET_PATH = "/tokens/groups/get",
+    ROUTE_TOKENS_GROUPS_SET_PATH = "/tokens/groups/set",
     ROUTE_TOKENS_MERGE_PATH = "/tokens/merge",
+    ROUTE_KEYS_GET_PATH = "/keys/get",
+    ROUTE_KEYS_ADD_PATH = "/keys/add",
+    ROUTE_KEYS_SET_PATH = "/keys/set",
+    ROUTE_KEYS_DELETE_PATH = "/keys/del",
+    ROUTE_BUDGETS_GET_PATH = "/budgets/get",
+    ROUTE_BUDGETS_SET_PATH = "/budgets/set",
+    ROUTE_BUDGETS_DELETE_PATH = "/budgets/del",
+    ROUTE_REVOKED_KEYS_GET_PATH = "/revoked-keys/get",
+    ROUTE_REVOKED_KEYS_ADD_PATH = "/revoked-keys/add",
     // ROUTE_PROXIES_PATH = "/proxies",
     ROUTE_PROXIES_GET_PATH = "/proxies/get",
     ROUTE_PROXIES_SET_PATH = "/proxies/set",
@@ -160,8 +174,14 @@ def_pub_const!(
     ROUTE_RAW_MODELS_PATH = "/raw/models",
     ROUTE_MODELS_PATH = "/v1/models",
     ROUTE_CHAT_COMPLETIONS_PATH = "/v1/chat/completions",
+    ROUTE_RESPONSES_PATH = "/v1/responses",
     ROUTE_MESSAGES_PATH = "/v1/messages",
     ROUTE_MESSAGES_COUNT_TOKENS_PATH = "/v1/messages/count_tokens",
+    ROUTE_GEMINI_MODELS_PATH = "/v1beta/models/{model_action}",
+    ROUTE_OLLAMA_CHAT_PATH = "/api/chat",
+    ROUTE_OLLAMA_GENERATE_PATH = "/api/generate",
+    ROUTE_OLLAMA_TAGS_PATH = "/api/tags",
+    ROUTE_OLLAMA_SHOW_PATH = "/api/show",
 );
 
 // Status constants
@@ -184,6 +204,7 @@ def_pub_const!(
 def_pub_const!(
     CHATCMPL_PREFIX = "chatcmpl-",
     MSG01_PREFIX = "msg_01",
+    RESP_PREFIX = "resp_",
     // TOOLU01_PREFIX = "toolu_01",
     // OBJECT_TEXT_COMPLETION = "text_completion"
 );
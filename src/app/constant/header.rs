@@ -79,6 +79,7 @@ def_header_name! {
     (PROXY_HOST, "x-co"),
     (CONFIG_HASH, "x-config-hash"),
     (API_KEY, "x-api-key"),
+    (GOOG_API_KEY, "x-goog-api-key"),
     (SESSION_ID, "x-session-id"),
     (GHOST_MODE, "x-ghost-mode"),
     (CONNECT_ACCEPT_ENCODING, "connect-accept-encoding"),
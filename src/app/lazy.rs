@@ -3,8 +3,8 @@ mod path;
 
 use super::{
     constant::{
-        CURSOR_API2_HOST, CURSOR_API4_HOST, CURSOR_GCPP_ASIA_HOST, CURSOR_GCPP_EU_HOST,
-        CURSOR_GCPP_US_HOST, CURSOR_HOST, EMPTY_STRING, HTTPS_PREFIX,
+        AUTHORIZATION_BEARER_PREFIX, CURSOR_API2_HOST, CURSOR_API4_HOST, CURSOR_GCPP_ASIA_HOST,
+        CURSOR_GCPP_EU_HOST, CURSOR_GCPP_US_HOST, CURSOR_HOST, EMPTY_STRING, HTTPS_PREFIX,
     },
     model::{DateTime, GcppHost},
 };
@@ -18,8 +18,8 @@ use crate::common::{
 use alloc::borrow::Cow;
 use manually_init::ManuallyInit;
 pub use path::{
-    CONFIG_FILE_PATH, DATA_DIR, LOGS_FILE_PATH, PROXIES_FILE_PATH, TOKENS_FILE_PATH,
-    init as init_paths,
+    API_KEYS_FILE_PATH, BUDGETS_FILE_PATH, CONFIG_FILE_PATH, DATA_DIR, LOGS_FILE_PATH,
+    PROXIES_FILE_PATH, REVOKED_KEYS_FILE_PATH, TOKENS_FILE_PATH, init as init_paths,
 };
 use std::sync::LazyLock;
 use url::Url;
@@ -60,6 +60,9 @@ pub fn init() {
     SERVICE_TIMEOUT
         .init(parse_from_env("SERVICE_TIMEOUT", DEFAULT_SERVICE_TIMEOUT).min(MAX_SERVICE_TIMEOUT));
     REAL_USAGE.init(parse_from_env("REAL_USAGE", true));
+    TOOL_SESSION_IDLE_TIMEOUT
+        .init(ToDuration::parse_from_env("TOOL_SESSION_IDLE_TIMEOUT"));
+    STICKY_SESSION_TTL.init(ToDuration::parse_from_env("STICKY_SESSION_TTL"));
 }
 
 pub static GENERAL_TIMEZONE: LazyLock<chrono_tz::Tz> = LazyLock::new(|| {
@@ -99,6 +102,21 @@ pub static GENERAL_GCPP_HOST: LazyLock<GcppHost> = LazyLock::new(|| {
     }
 });
 
+/// `Authorization` assumed for Ollama requests without a key, most Ollama clients cannot send one
+pub static OLLAMA_AUTH_KEY: LazyLock<Option<http::header::HeaderValue>> = LazyLock::new(|| {
+    let key = parse_from_env("OLLAMA_AUTH_KEY", EMPTY_STRING);
+    if key.is_empty() {
+        return None;
+    }
+    match http::header::HeaderValue::try_from(format!("{AUTHORIZATION_BEARER_PREFIX}{key}")) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("Invalid OLLAMA_AUTH_KEY, Ollama requests without a key are rejected");
+            None
+        }
+    }
+});
+
 pub static PRI_REVERSE_PROXY_HOST: ManuallyInit<Cow<'static, str>> = ManuallyInit::new();
 pub static PUB_REVERSE_PROXY_HOST: ManuallyInit<Cow<'static, str>> = ManuallyInit::new();
 
@@ -308,6 +326,20 @@ const DEFAULT_SERVICE_TIMEOUT: u16 = 30;
 const MAX_SERVICE_TIMEOUT: u16 = 600;
 pub static SERVICE_TIMEOUT: ManuallyInit<u16> = ManuallyInit::new();
 
+// How long an upstream stream stays open at a tool call, negative closes it right away
+const DEFAULT_TOOL_SESSION_IDLE_TIMEOUT: NonNegativeI16 = NonNegativeI16::new(120).unwrap();
+const MAX_TOOL_SESSION_IDLE_TIMEOUT: NonNegativeI16 = NonNegativeI16::new(600).unwrap();
+pub static TOOL_SESSION_IDLE_TIMEOUT: ManuallyInit<
+    ToDuration<DEFAULT_TOOL_SESSION_IDLE_TIMEOUT, MAX_TOOL_SESSION_IDLE_TIMEOUT>,
+> = ManuallyInit::new();
+
+// How long a conversation stays pinned to its pooled token after its last turn, negative disables it
+const DEFAULT_STICKY_SESSION_TTL: NonNegativeI16 = NonNegativeI16::new(1800).unwrap();
+const MAX_STICKY_SESSION_TTL: NonNegativeI16 = NonNegativeI16::new(21600).unwrap();
+pub static STICKY_SESSION_TTL: ManuallyInit<
+    ToDuration<DEFAULT_STICKY_SESSION_TTL, MAX_STICKY_SESSION_TTL>,
+> = ManuallyInit::new();
+
 #[derive(Debug, Clone, Copy)]
 pub struct ToDuration<const DEFAULT: NonNegativeI16, const MAX: NonNegativeI16>(
     Option<NonNegativeI16>,
@@ -29,6 +29,9 @@ pub fn init(current_dir: PathBuf) {
     LOGS_FILE_PATH.init(DATA_DIR.join("logs.bin"));
     TOKENS_FILE_PATH.init(DATA_DIR.join("tokens.bin"));
     PROXIES_FILE_PATH.init(DATA_DIR.join("proxies.bin"));
+    API_KEYS_FILE_PATH.init(DATA_DIR.join("api_keys.bin"));
+    BUDGETS_FILE_PATH.init(DATA_DIR.join("budgets.bin"));
+    REVOKED_KEYS_FILE_PATH.init(DATA_DIR.join("revoked_keys.bin"));
 }
 
 pub static CURRENT_DIR: ManuallyInit<PathBuf> = ManuallyInit::new();
@@ -42,3 +45,6 @@ pub static LOGS_DIR: ManuallyInit<PathBuf> = ManuallyInit::new();
 pub static LOGS_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
 pub static TOKENS_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
 pub static PROXIES_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
+pub static API_KEYS_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
+pub static BUDGETS_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
+pub static REVOKED_KEYS_FILE_PATH: ManuallyInit<PathBuf> = ManuallyInit::new();
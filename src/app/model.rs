@@ -1,4 +1,7 @@
+mod admin;
 mod alias;
+mod api_key;
+mod budget;
 mod build_key;
 mod checksum;
 mod config;
@@ -15,23 +18,38 @@ mod log;
 pub mod platform;
 mod proxy;
 pub mod proxy_pool;
+mod revoked_key;
+mod selection_strategy;
 mod state;
 pub mod timestamp_header;
 mod token;
 mod tz;
 mod usage_check;
 pub mod version;
+mod versioned_file;
 mod vision_ability;
 
 use super::constant::{
     AUTHORIZATION_BEARER_PREFIX, EMPTY_STRING, STATUS_FAILURE, STATUS_PENDING, STATUS_SUCCESS,
 };
-use crate::common::model::{
-    ApiStatus,
-    userinfo::{Session, StripeProfile, UsageProfile, UserProfile},
+use crate::common::{
+    model::{
+        ApiStatus,
+        userinfo::{MembershipType, Session, StripeProfile, UsageProfile, UserProfile},
+    },
+    utils::RefreshFailure,
 };
+pub use admin::{AdminCredential, AdminRole, Permission};
 pub use alias::Alias;
-use alloc::borrow::Cow;
+use alloc::{borrow::Cow, sync::Arc};
+pub use api_key::{
+    ApiKey, ApiKeyDenial, ApiKeySettings, ApiKeys, ApiKeysAddResponse, ApiKeysDeleteRequest,
+    ApiKeysGetResponse, ApiKeysSetRequest, Endpoint, run_api_key_save,
+};
+pub use budget::{
+    BudgetExceeded, BudgetInfo, BudgetLimits, Budgets, BudgetsDeleteRequest, BudgetsGetResponse,
+    BudgetsSetRequest, Spender, run_budget_save,
+};
 pub use build_key::{
     BuildKeyRequest, BuildKeyResponse, GetConfigVersionRequest, GetConfigVersionResponse,
     UsageCheckModelType,
@@ -53,9 +71,14 @@ pub use proxy::{
 };
 use proxy_pool::get_client_or_general;
 use reqwest::Client;
+pub use revoked_key::{RevokedKeys, RevokedKeysAddRequest, RevokedKeysGetResponse};
 use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+pub use selection_strategy::SelectionStrategy;
 use serde::{Deserialize, Serialize};
-pub use state::{AppState, QueueType, TokenError, TokenHealth, TokenManager, TokenWriter};
+pub use state::{
+    AppState, HealthEvent, InFlight, QueueType, TokenError, TokenHealth, TokenLoad, TokenManager,
+    TokenWriter, run_profile_poll, run_token_refresh,
+};
 pub use token::{
     Duration as TokenDuration, Randomness, RawToken, RawTokenHelper, Subject, Token, TokenKey,
     UserId,
@@ -63,6 +86,7 @@ pub use token::{
 pub use tz::DateTime;
 pub use usage_check::UsageCheck;
 pub use version::Version;
+use versioned_file::{HEADER_LEN, VersionedFile};
 pub use vision_ability::VisionAbility;
 
 type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
@@ -119,6 +143,12 @@ pub struct RequestLog {
     pub model: &'static str,
     pub token_info: LogTokenInfo,
     pub chain: Chain,
+    /// Chains of the individual choices when `n > 1`, `chain` then holds the summed usage
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<Chain>,
+    /// Pooled tokens the request failed on before `token_info` took it over
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<Attempt>,
     pub timing: TimingInfo,
     pub stream: bool,
     pub status: LogStatus,
@@ -130,6 +160,13 @@ impl RequestLog {
     pub fn token_key(&self) -> TokenKey { self.token_info.key }
 }
 
+#[derive(Serialize, Clone)]
+pub struct Attempt {
+    #[serde(serialize_with = "serialize_token_key")]
+    pub key: TokenKey,
+    pub error: ErrorInfo,
+}
+
 #[derive(Serialize, Clone, Archive, RkyvDeserialize, RkyvSerialize)]
 pub struct Chain {
     // #[serde(skip_serializing_if = "Prompt::is_none")]
@@ -158,6 +195,21 @@ pub struct ChainUsage {
     pub cents: f32,
 }
 
+impl core::ops::Add for ChainUsage {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            input: self.input + rhs.input,
+            output: self.output + rhs.output,
+            cache_write: self.cache_write + rhs.cache_write,
+            cache_read: self.cache_read + rhs.cache_read,
+            cents: self.cents + rhs.cents,
+        }
+    }
+}
+
 impl ChainUsage {
     pub fn total(&self) -> i32 { self.input + self.output + self.cache_read + self.cache_write }
 
@@ -168,7 +220,7 @@ impl ChainUsage {
             completion_tokens: self.output,
             total_tokens: self.input + self.output,
             prompt_tokens_details: openai::PromptTokensDetails { cached_tokens: self.cache_read },
-            // completion_tokens_details: openai::CompletionTokensDetails { reasoning_tokens: 0 },
+            completion_tokens_details: openai::CompletionTokensDetails::default(),
         }
     }
 
@@ -536,6 +588,13 @@ pub struct TokenInfo {
     pub stripe: Option<StripeProfile>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub sessions: Vec<Session>,
+    #[serde(default)]
+    pub policy: TokenPolicy,
+    /// Groups the token can be selected for, see `AUTH_TOKEN@group`
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub groups: Vec<String>,
+    #[serde(skip_deserializing)]
+    pub load: Arc<TokenLoad>,
 }
 
 #[derive(Archive, RkyvSerialize, RkyvDeserialize)]
@@ -592,6 +651,8 @@ struct TokenInfoHelper {
     user: Option<UserProfile>,
     stripe: Option<StripeProfile>,
     sessions: Vec<Session>,
+    policy: TokenPolicy,
+    groups: Vec<String>,
 }
 
 impl TokenInfoHelper {
@@ -605,6 +666,8 @@ impl TokenInfoHelper {
             user: token_info.user.clone(),
             stripe: token_info.stripe,
             sessions: token_info.sessions.clone(),
+            policy: token_info.policy,
+            groups: token_info.groups.clone(),
         }
     }
 
@@ -618,12 +681,59 @@ impl TokenInfoHelper {
                 user: self.user,
                 stripe: self.stripe,
                 sessions: self.sessions,
+                policy: self.policy,
+                groups: self.groups,
+                load: Arc::default(),
             },
             self.alias,
         )
     }
 }
 
+/// `tokens.bin`, version 1 added the policy of a token, version 2 its request limits, version 3
+/// the last refresh in its status, version 4 the suspension, version 5 the profile fetch time and
+/// version 6 the groups of a token
+const TOKENS_FILE: VersionedFile = VersionedFile::new(*b"CAPITOKN", 6);
+
+/// `TokenInfoHelper` as written before `tokens.bin` had a version
+#[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+struct TokenInfoHelperV0 {
+    alias: String,
+    bundle: ExtTokenHelper,
+    status: TokenStatusV0,
+    usage: Option<UsageProfile>,
+    user: Option<UserProfile>,
+    stripe: Option<StripeProfile>,
+    sessions: Vec<Session>,
+}
+
+#[derive(Archive, RkyvSerialize, RkyvDeserialize)]
+struct TokenStatusV0 {
+    enabled: bool,
+    health: TokenHealth,
+}
+
+impl From<TokenInfoHelperV0> for TokenInfoHelper {
+    #[inline]
+    fn from(helper: TokenInfoHelperV0) -> Self {
+        Self {
+            alias: helper.alias,
+            bundle: helper.bundle,
+            status: TokenStatus {
+                enabled: helper.status.enabled,
+                health: helper.status.health,
+                ..TokenStatus::default()
+            },
+            usage: helper.usage,
+            user: helper.user,
+            stripe: helper.stripe,
+            sessions: helper.sessions,
+            policy: TokenPolicy::default(),
+            groups: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Archive, RkyvSerialize, RkyvDeserialize)]
 pub struct LogTokenInfo {
     #[serde(serialize_with = "serialize_token_key")]
@@ -649,16 +759,157 @@ pub struct TokenStatus {
     pub enabled: bool,
     #[serde(default)]
     pub health: TokenHealth,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_refresh: Option<RefreshOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspension: Option<Suspension>,
+    /// Seconds since the epoch at which the usage profile was last fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_updated_at: Option<u64>,
 }
 
 impl const Default for TokenStatus {
     #[inline]
-    fn default() -> Self { Self { enabled: true, health: TokenHealth::new() } }
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            health: TokenHealth::new(),
+            last_refresh: None,
+            suspension: None,
+            profile_updated_at: None,
+        }
+    }
+}
+
+/// A token kept out of the queue until a set time, unlike `enabled` it lifts by itself
+#[derive(Clone, Copy, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct Suspension {
+    pub reason: SuspensionReason,
+    /// Seconds since the epoch at which the token comes back
+    pub until: u64,
+}
+
+impl Suspension {
+    #[inline]
+    pub fn is_active(&self) -> bool { self.until > crate::common::utils::now_secs() }
+}
+
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum SuspensionReason {
+    /// The plan usage is used up and on-demand usage is disabled, until the billing cycle ends
+    QuotaExhausted,
+}
+
+/// How the last refresh of a token went, manual or in the background
+#[derive(Clone, Copy, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct RefreshOutcome {
+    /// Seconds since the epoch
+    pub at: u64,
+    pub success: bool,
+}
+
+impl RefreshOutcome {
+    #[inline]
+    pub fn now(success: bool) -> Self { Self { at: crate::common::utils::now_secs(), success } }
+}
+
+/// Settings of a token that steer how it is selected
+#[derive(Clone, Copy, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+#[serde(default)]
+pub struct TokenPolicy {
+    /// Share of the picks under weighted selection, 0 leaves the token out of it
+    pub weight: u32,
+    /// Requests the token serves at once for pooled keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<u32>,
+    /// Times the token is picked from the pool per minute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rpm: Option<u32>,
+}
+
+impl const Default for TokenPolicy {
+    #[inline]
+    fn default() -> Self { Self { weight: 1, max_concurrent: None, max_rpm: None } }
 }
 
 impl TokenInfo {
     #[inline(always)]
     pub fn is_enabled(&self) -> bool { self.status.enabled }
+
+    /// Whether the token belongs to the paid queues: its plan is not free and has usage left in
+    /// the billing cycle, a token whose profiles were never fetched counts as paid
+    pub fn is_paid(&self) -> bool {
+        let membership = self
+            .stripe
+            .map(|stripe| stripe.membership_type)
+            .or(self.usage.as_ref().map(|usage| usage.membership_type));
+        if membership == Some(MembershipType::Free) {
+            return false;
+        }
+        let Some(ref usage) = self.usage else { return true };
+        if usage.is_unlimited
+            || usage.billing_cycle_end.timestamp() <= crate::common::utils::now_secs() as i64
+        {
+            return true;
+        }
+        let individual = usage.individual_usage;
+        individual.plan.is_none_or(|plan| plan.remaining > 0)
+            || individual.on_demand.is_some_and(|on_demand| {
+                on_demand.enabled && on_demand.remaining.is_none_or(|remaining| remaining > 0)
+            })
+    }
+
+    /// Whether the token belongs to `group`
+    #[inline]
+    pub fn in_group(&self, group: &str) -> bool { self.groups.iter().any(|g| g == group) }
+
+    /// Whether a suspension that has not lifted yet keeps the token out of the queue
+    #[inline]
+    pub fn is_suspended(&self) -> bool {
+        self.status.suspension.is_some_and(|suspension| suspension.is_active())
+    }
+
+    /// Stores a fetched usage profile, suspending the token until its billing cycle ends once
+    /// the plan is used up with on-demand usage disabled, and lifting that suspension otherwise
+    ///
+    /// A profile that failed to fetch leaves the suspension and `profile_updated_at` as they are
+    pub fn set_usage(&mut self, usage: Option<UsageProfile>) {
+        if let Some(ref usage) = usage {
+            let now = crate::common::utils::now_secs();
+            let individual = usage.individual_usage;
+            let exhausted = !usage.is_unlimited
+                && individual.plan.is_some_and(|plan| plan.remaining <= 0)
+                && !individual.on_demand.is_some_and(|on_demand| on_demand.enabled);
+            let until = usage.billing_cycle_end.timestamp().max(0) as u64;
+            self.status.suspension = (exhausted && until > now)
+                .then_some(Suspension { reason: SuspensionReason::QuotaExhausted, until });
+            self.status.profile_updated_at = Some(now);
+        }
+        self.usage = usage;
+    }
+
+    /// Notes a refresh of the primary token, a failed one only takes the token out of the queue
+    /// when upstream rejected it or it has expired
+    pub fn record_refresh(&mut self, refreshed: Result<(), RefreshFailure>) {
+        self.status.last_refresh = Some(RefreshOutcome::now(refreshed.is_ok()));
+        let event = match refreshed {
+            Ok(()) => HealthEvent::Success,
+            Err(RefreshFailure::Rejected) => HealthEvent::Unauthorized,
+            Err(RefreshFailure::Failed)
+                if self.bundle.primary_token.raw().duration.end
+                    <= crate::common::utils::now_secs() as i64 =>
+            {
+                HealthEvent::Unauthorized
+            }
+            // Says nothing about the token, which keeps serving until it expires
+            Err(RefreshFailure::Failed) => return,
+        };
+        self.status.health.record(event);
+    }
 }
 
 // pub struct TokenValidityRange {
@@ -685,6 +936,8 @@ impl TokenInfo {
 
 pub struct TokensGetResponse {
     pub tokens: Vec<(usize, Alias, TokenInfo)>,
+    /// Leaves out the token bundles, for credentials without `Manage`
+    pub redacted: bool,
 }
 
 impl Serialize for TokensGetResponse {
@@ -693,12 +946,121 @@ impl Serialize for TokensGetResponse {
         use serde::ser::SerializeStruct as _;
         let mut state = serializer.serialize_struct("TokensGetResponse", 3)?;
         state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("tokens", &ListedTokens(&self.tokens, self.redacted))?;
+        state.serialize_field("tokens_count", &self.tokens.len())?;
+        state.end()
+    }
+}
+
+struct ListedTokens<'a>(&'a [(usize, Alias, TokenInfo)], bool);
+
+/// A listed token along with when its primary token expires and the queues it is in
+#[derive(Serialize)]
+struct ListedToken<'a> {
+    #[serde(flatten)]
+    info: ListedInfo<'a>,
+    expires_at: i64,
+    queue: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ListedInfo<'a> {
+    Full(&'a TokenInfo),
+    Redacted(RedactedTokenInfo<'a>),
+}
+
+/// `TokenInfo` without the tokens, checksum, keys and sessions it holds
+#[derive(Serialize)]
+struct RedactedTokenInfo<'a> {
+    status: &'a TokenStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<&'a UsageProfile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a UserProfile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stripe: Option<&'a StripeProfile>,
+    policy: &'a TokenPolicy,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    groups: &'a [String],
+}
+
+impl<'a> ListedInfo<'a> {
+    fn new(info: &'a TokenInfo, redacted: bool) -> Self {
+        if !redacted {
+            return Self::Full(info);
+        }
+        Self::Redacted(RedactedTokenInfo {
+            status: &info.status,
+            usage: info.usage.as_ref(),
+            user: info.user.as_ref(),
+            stripe: info.stripe.as_ref(),
+            policy: &info.policy,
+            groups: &info.groups,
+        })
+    }
+}
+
+impl Serialize for ListedTokens<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        serializer.collect_seq(self.0.iter().map(|(id, alias, info)| {
+            let expires_at = info.bundle.primary_token.raw().duration.end;
+            let queue = if info.is_paid() { "paid" } else { "free" };
+            (id, alias, ListedToken { info: ListedInfo::new(info, self.1), expires_at, queue })
+        }))
+    }
+}
+
+/// Tokens held out of the queue by a suspension, along with when each comes back
+pub struct TokensSuspendedResponse {
+    pub tokens: Vec<SuspendedToken>,
+}
+
+#[derive(Serialize)]
+pub struct SuspendedToken {
+    pub id: usize,
+    pub alias: Alias,
+    pub reason: SuspensionReason,
+    /// Seconds since the epoch
+    pub resumes_at: u64,
+}
+
+impl Serialize for TokensSuspendedResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("TokensSuspendedResponse", 3)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
         state.serialize_field("tokens", &self.tokens)?;
         state.serialize_field("tokens_count", &self.tokens.len())?;
         state.end()
     }
 }
 
+/// Groups along with the aliases of their members
+pub struct TokensGroupsGetResponse {
+    pub groups: Vec<TokenGroup>,
+}
+
+#[derive(Serialize)]
+pub struct TokenGroup {
+    pub name: String,
+    pub aliases: Vec<Alias>,
+}
+
+impl Serialize for TokensGroupsGetResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("TokensGroupsGetResponse", 3)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("groups", &self.groups)?;
+        state.serialize_field("groups_count", &self.groups.len())?;
+        state.end()
+    }
+}
+
 pub struct TokensAddResponse {
     pub tokens_count: usize,
     pub message: &'static str,
@@ -840,3 +1202,22 @@ pub struct TokensTimezoneSetRequest {
     pub aliases: Vec<String>,
     pub timezone: Option<chrono_tz::Tz>,
 }
+
+#[derive(Deserialize)]
+pub struct TokensWeightSetRequest {
+    pub aliases: Vec<String>,
+    pub weight: u32,
+}
+
+#[derive(Deserialize)]
+pub struct TokensGroupsSetRequest {
+    pub aliases: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokensLimitsSetRequest {
+    pub aliases: Vec<String>,
+    pub max_concurrent: Option<u32>,
+    pub max_rpm: Option<u32>,
+}
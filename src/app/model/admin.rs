@@ -0,0 +1,72 @@
+//! Admin credentials set through `admins` in `config.toml`, each limited to what its role grants
+
+use serde::Deserialize;
+
+/// What an admin endpoint needs from the credential calling it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Listing tokens, keys, logs, proxies and the like
+    Read,
+    /// Refreshing tokens, updating their profiles and setting their status
+    Operate,
+    /// Changing or deleting anything, the config included
+    Manage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// `Read`
+    Viewer,
+    /// `Read` and `Operate`
+    Operator,
+    /// Every permission, what `AUTH_TOKEN` has
+    Owner,
+}
+
+impl AdminRole {
+    #[inline]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Operator => "operator",
+            Self::Owner => "owner",
+        }
+    }
+
+    #[inline]
+    pub const fn grants(self, permission: Permission) -> bool {
+        match self {
+            Self::Viewer => matches!(permission, Permission::Read),
+            Self::Operator => !matches!(permission, Permission::Manage),
+            Self::Owner => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminCredential {
+    /// Who the credential was handed out to
+    pub name: String,
+    pub token: String,
+    pub role: AdminRole,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_grant_their_permissions() {
+        use Permission::*;
+        for (role, granted) in [
+            (AdminRole::Viewer, [true, false, false]),
+            (AdminRole::Operator, [true, true, false]),
+            (AdminRole::Owner, [true, true, true]),
+        ] {
+            for (permission, granted) in [Read, Operate, Manage].into_iter().zip(granted) {
+                assert_eq!(role.grants(permission), granted, "{role:?} {permission:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,520 @@
+//! API keys handed out one per developer, each selecting from the pooled tokens within its own
+//! limits
+
+use super::{ApiStatus, DateTime};
+use crate::{
+    app::lazy::{API_KEYS_FILE_PATH, KEY_PREFIX},
+    common::utils::now_secs,
+};
+use chrono::Datelike as _;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use manually_init::ManuallyInit;
+use memmap2::{Mmap, MmapMut};
+use parking_lot::RwLock;
+use rand::Rng as _;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::fs::OpenOptions;
+
+type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Route family a key can be allowed to call
+#[derive(
+    Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Endpoint {
+    /// `/v1/chat/completions`, `/v1/responses`, Gemini and Ollama
+    Chat,
+    /// `/v1/messages` and its `count_tokens`
+    Messages,
+    /// Cursor Tab, `/cpp/*` and `/file/*`
+    Cpp,
+}
+
+impl Endpoint {
+    pub const ALL: [Self; 3] = [Self::Chat, Self::Messages, Self::Cpp];
+}
+
+#[inline]
+fn default_endpoints() -> Vec<Endpoint> { Endpoint::ALL.to_vec() }
+
+/// What an API key may do, as set through the admin endpoints
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct ApiKeySettings {
+    /// Who the key was handed out to
+    pub owner: String,
+    /// Model ids the key may use, every model when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default = "default_endpoints")]
+    pub endpoints: Vec<Endpoint>,
+    /// Requests the key may make per day, the day turning over at midnight in `TZ`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_requests: Option<u32>,
+    /// Seconds since the epoch from which the key stops working
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default = "crate::common::utils::r#true")]
+    pub enabled: bool,
+}
+
+impl ApiKeySettings {
+    /// Drops repeated models and endpoints
+    pub fn normalize(&mut self) {
+        self.models.sort_unstable();
+        self.models.dedup();
+        let mut endpoints = Vec::with_capacity(self.endpoints.len());
+        for endpoint in Endpoint::ALL {
+            if self.endpoints.contains(&endpoint) {
+                endpoints.push(endpoint);
+            }
+        }
+        self.endpoints = endpoints;
+    }
+}
+
+#[derive(Clone, Serialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct ApiKey {
+    pub key: String,
+    /// Derived from the key on creation and load, what budgets know the key by
+    #[rkyv(with = rkyv::with::Skip)]
+    pub id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: ApiKeySettings,
+    /// Seconds since the epoch
+    pub created_at: u64,
+    /// Day `requests_today` counts, in days since the common era
+    #[serde(skip)]
+    day: i32,
+    pub requests_today: u32,
+}
+
+/// Why an API key turned a request away
+#[derive(Clone, Copy)]
+pub enum ApiKeyDenial {
+    Disabled,
+    Expired,
+    Endpoint,
+    Model,
+    DailyQuota,
+}
+
+#[inline]
+fn today() -> i32 { DateTime::now().num_days_from_ce() }
+
+/// Hex of the first 16 bytes of the SHA-256 of `key`, as long as the id of a dynamic key
+fn key_id(key: &str) -> String { hex::encode(&Sha256::digest(key.as_bytes())[..16]) }
+
+impl ApiKey {
+    /// Cuts the key down to its first 8 and last 4 characters, for credentials that may list the
+    /// keys but not use them
+    pub fn redact(&mut self) {
+        // Keys are ASCII, the prefix and hex
+        if self.key.len() > 16 {
+            self.key.replace_range(8..self.key.len() - 4, "...");
+        } else {
+            self.key = String::from("...");
+        }
+    }
+
+    fn new(name: String, settings: ApiKeySettings) -> Self {
+        let secret: [u8; 24] = rand::rng().random();
+        let key = [&**KEY_PREFIX, hex::encode(secret).as_str()].concat();
+        Self {
+            id: key_id(&key),
+            key,
+            name,
+            settings,
+            created_at: now_secs(),
+            day: today(),
+            requests_today: 0,
+        }
+    }
+
+    fn check(&self) -> Result<(), ApiKeyDenial> {
+        if !self.settings.enabled {
+            return Err(ApiKeyDenial::Disabled);
+        }
+        if self.settings.expires_at.is_some_and(|expires_at| expires_at <= now_secs()) {
+            return Err(ApiKeyDenial::Expired);
+        }
+        Ok(())
+    }
+
+    /// Whether the key allows a request made on `day`, without counting it
+    ///
+    /// Requests without a model, such as Cursor Tab ones, pass the model check
+    fn permit(
+        &self,
+        endpoint: Endpoint,
+        model: Option<&str>,
+        day: i32,
+    ) -> Result<(), ApiKeyDenial> {
+        self.check()?;
+        let settings = &self.settings;
+        if !settings.endpoints.contains(&endpoint) {
+            return Err(ApiKeyDenial::Endpoint);
+        }
+        if !settings.models.is_empty()
+            && endpoint != Endpoint::Cpp
+            && !model.is_some_and(|model| settings.models.iter().any(|m| m == model))
+        {
+            return Err(ApiKeyDenial::Model);
+        }
+        if settings.daily_requests.is_some_and(|limit| self.requests_on(day) >= limit) {
+            return Err(ApiKeyDenial::DailyQuota);
+        }
+        Ok(())
+    }
+
+    /// Counts a request made on `day` against the daily quota, checked again as other requests
+    /// may have used it up since `permit`
+    fn count(&mut self, day: i32) -> Result<(), ApiKeyDenial> {
+        let requests = self.requests_on(day);
+        if self.settings.daily_requests.is_some_and(|limit| requests >= limit) {
+            return Err(ApiKeyDenial::DailyQuota);
+        }
+        self.day = day;
+        self.requests_today = requests + 1;
+        Ok(())
+    }
+
+    /// Requests made on `day`
+    #[inline]
+    fn requests_on(&self, day: i32) -> u32 { if self.day == day { self.requests_today } else { 0 } }
+}
+
+pub struct ApiKeys {
+    /// Keyed by the key itself
+    inner: RwLock<HashMap<String, ApiKey>>,
+    /// Whether a request was counted since the last save
+    unsaved: AtomicBool,
+}
+
+impl ApiKeys {
+    pub fn init(keys: Vec<ApiKey>) {
+        let mut map = HashMap::with_capacity_and_hasher(keys.len(), ahash::RandomState::new());
+        for mut key in keys {
+            key.id = key_id(&key.key);
+            map.insert(key.key.clone(), key);
+        }
+        API_KEYS.init(ApiKeys { inner: RwLock::new(map), unsaved: AtomicBool::new(false) })
+    }
+
+    /// Whether `key` is an API key, whatever its state
+    #[inline]
+    pub fn contains(key: &str) -> bool { API_KEYS.inner.read().contains_key(key) }
+
+    /// Id of the API key `key`
+    pub fn id(key: &str) -> Option<String> {
+        API_KEYS.inner.read().get(key).map(|api_key| api_key.id.clone())
+    }
+
+    /// Id of the API key named `name`
+    pub fn id_by_name(name: &str) -> Option<String> {
+        API_KEYS.inner.read().values().find(|key| key.name == name).map(|key| key.id.clone())
+    }
+
+    /// Whether `key` still works, `None` when it is not an API key
+    pub fn check(key: &str) -> Option<Result<(), ApiKeyDenial>> {
+        API_KEYS.inner.read().get(key).map(ApiKey::check)
+    }
+
+    /// Whether `key` allows a request to `endpoint` for `model`, `None` when it is not an API key
+    ///
+    /// The request is only counted against the daily quota by `count`, once a token serves it
+    #[inline]
+    pub fn permit(
+        key: &str,
+        endpoint: Endpoint,
+        model: Option<&str>,
+    ) -> Option<Result<(), ApiKeyDenial>> {
+        let day = today();
+        API_KEYS.inner.read().get(key).map(|api_key| api_key.permit(endpoint, model, day))
+    }
+
+    /// Counts a request of `key` against its daily quota, denied when the quota ran out or the
+    /// key was deleted since `permit`
+    pub fn count(key: &str) -> Result<(), ApiKeyDenial> {
+        let day = today();
+        let counted = match API_KEYS.inner.write().get_mut(key) {
+            Some(api_key) => api_key.count(day),
+            None => Err(ApiKeyDenial::Disabled),
+        };
+        if counted.is_ok() {
+            API_KEYS.unsaved.store(true, Ordering::Relaxed);
+        }
+        counted
+    }
+
+    /// Every key, ordered by name
+    pub fn list() -> Vec<ApiKey> {
+        let day = today();
+        let mut keys: Vec<ApiKey> = API_KEYS
+            .inner
+            .read()
+            .values()
+            .map(|key| {
+                let mut key = key.clone();
+                key.requests_today = key.requests_on(day);
+                key
+            })
+            .collect();
+        keys.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    /// Creates a key named `name`, `None` when the name is taken
+    pub fn add(name: String, settings: ApiKeySettings) -> Option<ApiKey> {
+        let mut keys = API_KEYS.inner.write();
+        if keys.values().any(|key| key.name == name) {
+            return None;
+        }
+        let key = ApiKey::new(name, settings);
+        keys.insert(key.key.clone(), key.clone());
+        Some(key)
+    }
+
+    /// Replaces the settings of the key named `name`, its usage today is kept
+    pub fn update(name: &str, settings: ApiKeySettings) -> bool {
+        let mut keys = API_KEYS.inner.write();
+        match keys.values_mut().find(|key| key.name == name) {
+            Some(key) => {
+                key.settings = settings;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Deletes the key named `name`
+    pub fn remove(name: &str) -> bool {
+        let mut keys = API_KEYS.inner.write();
+        let len = keys.len();
+        keys.retain(|_, key| key.name != name);
+        keys.len() != len
+    }
+
+    pub async fn save() -> Result<(), Box<dyn core::error::Error + Send + Sync + 'static>> {
+        API_KEYS.unsaved.store(false, Ordering::Relaxed);
+        let keys: Vec<ApiKey> = API_KEYS.inner.read().values().cloned().collect();
+        let bytes = ::rkyv::to_bytes::<::rkyv::rancor::Error>(&keys)?;
+        if bytes.len() > usize::MAX >> 1 {
+            return Err("API key data too large".into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&*API_KEYS_FILE_PATH)
+            .await?;
+        file.set_len(bytes.len() as u64).await?;
+
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap.copy_from_slice(&bytes);
+        mmap.flush()?;
+
+        Ok(())
+    }
+
+    pub async fn load() -> Result<Vec<ApiKey>, Box<dyn core::error::Error + Send + Sync + 'static>>
+    {
+        let file = match OpenOptions::new().read(true).open(&*API_KEYS_FILE_PATH).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if file.metadata().await?.len() > usize::MAX as u64 {
+            return Err("API key file too large".into());
+        }
+
+        let mmap = unsafe { Mmap::map(&file)? };
+        unsafe { ::rkyv::from_bytes_unchecked::<Vec<ApiKey>, ::rkyv::rancor::Error>(&mmap) }
+            .map_err(|_| "Load API keys failed".into())
+    }
+}
+
+static API_KEYS: ManuallyInit<ApiKeys> = ManuallyInit::new();
+
+/// Saves the requests counted against the daily quotas every `SAVE_INTERVAL` if some were
+/// counted since, for as long as the process runs
+pub async fn run_api_key_save() {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        if API_KEYS.unsaved.load(Ordering::Relaxed)
+            && let Err(e) = ApiKeys::save().await
+        {
+            __cold_path!();
+            API_KEYS.unsaved.store(true, Ordering::Relaxed);
+            eprintln!("Failed to save API keys: {e}");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeysSetRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: ApiKeySettings,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeysDeleteRequest {
+    pub names: Vec<String>,
+}
+
+pub struct ApiKeysGetResponse {
+    pub keys: Vec<ApiKey>,
+}
+
+impl Serialize for ApiKeysGetResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("ApiKeysGetResponse", 3)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("keys", &self.keys)?;
+        state.serialize_field("keys_count", &self.keys.len())?;
+        state.end()
+    }
+}
+
+pub struct ApiKeysAddResponse {
+    pub key: ApiKey,
+}
+
+impl Serialize for ApiKeysAddResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("ApiKeysAddResponse", 2)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("key", &self.key)?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(settings: ApiKeySettings) -> ApiKey {
+        let key = ["sk-", &"0123456789abcdef".repeat(3)].concat();
+        ApiKey {
+            id: key_id(&key),
+            key,
+            name: String::from("dev"),
+            settings,
+            created_at: 0,
+            day: 0,
+            requests_today: 0,
+        }
+    }
+
+    fn settings() -> ApiKeySettings {
+        ApiKeySettings {
+            owner: String::from("dev"),
+            models: Vec::new(),
+            endpoints: default_endpoints(),
+            daily_requests: None,
+            expires_at: None,
+            enabled: true,
+        }
+    }
+
+    /// What a request whose key gets a token goes through
+    fn admit(
+        api_key: &mut ApiKey,
+        endpoint: Endpoint,
+        model: Option<&str>,
+        day: i32,
+    ) -> Result<(), ApiKeyDenial> {
+        api_key.permit(endpoint, model, day)?;
+        api_key.count(day)
+    }
+
+    #[test]
+    fn quota_is_used_only_by_counted_requests() {
+        let mut api_key = key(ApiKeySettings { daily_requests: Some(1), ..settings() });
+        // Permitted requests that find no token are never counted
+        assert!(api_key.permit(Endpoint::Chat, None, 1).is_ok());
+        assert!(api_key.permit(Endpoint::Chat, None, 1).is_ok());
+        assert_eq!(api_key.requests_on(1), 0);
+
+        assert!(api_key.count(1).is_ok());
+        assert!(matches!(api_key.count(1), Err(ApiKeyDenial::DailyQuota)));
+        assert!(matches!(api_key.permit(Endpoint::Chat, None, 1), Err(ApiKeyDenial::DailyQuota)));
+        assert_eq!(api_key.requests_on(1), 1);
+    }
+
+    #[test]
+    fn redacted_keys_keep_only_their_ends() {
+        let mut api_key = key(settings());
+        api_key.redact();
+        assert_eq!(api_key.key, "sk-01234...cdef");
+        assert_eq!(api_key.id.len(), 32);
+        assert_eq!(api_key.id, key(settings()).id);
+    }
+
+    #[test]
+    fn only_allowed_models_and_endpoints_are_admitted() {
+        let mut api_key = key(ApiKeySettings {
+            models: vec![String::from("gpt-5")],
+            endpoints: vec![Endpoint::Chat, Endpoint::Cpp],
+            ..settings()
+        });
+        assert!(admit(&mut api_key, Endpoint::Chat, Some("gpt-5"), 1).is_ok());
+        assert!(matches!(
+            admit(&mut api_key, Endpoint::Chat, Some("claude-4-sonnet"), 1),
+            Err(ApiKeyDenial::Model)
+        ));
+        assert!(matches!(admit(&mut api_key, Endpoint::Chat, None, 1), Err(ApiKeyDenial::Model)));
+        assert!(matches!(
+            admit(&mut api_key, Endpoint::Messages, Some("gpt-5"), 1),
+            Err(ApiKeyDenial::Endpoint)
+        ));
+        // Cursor Tab requests carry no model and skip the model check
+        assert!(admit(&mut api_key, Endpoint::Cpp, None, 1).is_ok());
+        assert_eq!(api_key.requests_today, 2);
+    }
+
+    #[test]
+    fn daily_quota_turns_over_with_the_day() {
+        let mut api_key = key(ApiKeySettings { daily_requests: Some(2), ..settings() });
+        assert!(admit(&mut api_key, Endpoint::Chat, None, 10).is_ok());
+        assert!(admit(&mut api_key, Endpoint::Chat, None, 10).is_ok());
+        assert!(matches!(
+            admit(&mut api_key, Endpoint::Chat, None, 10),
+            Err(ApiKeyDenial::DailyQuota)
+        ));
+        assert_eq!(api_key.requests_on(10), 2);
+
+        assert_eq!(api_key.requests_on(11), 0);
+        assert!(admit(&mut api_key, Endpoint::Chat, None, 11).is_ok());
+        assert_eq!(api_key.requests_on(11), 1);
+        assert_eq!(api_key.requests_on(10), 0);
+    }
+
+    #[test]
+    fn expired_and_disabled_keys_are_denied() {
+        let mut api_key = key(ApiKeySettings { expires_at: Some(1), ..settings() });
+        assert!(matches!(admit(&mut api_key, Endpoint::Chat, None, 1), Err(ApiKeyDenial::Expired)));
+        api_key.settings.expires_at = Some(u64::MAX);
+        assert!(api_key.check().is_ok());
+        api_key.settings.enabled = false;
+        assert!(matches!(api_key.check(), Err(ApiKeyDenial::Disabled)));
+        assert_eq!(api_key.requests_today, 0);
+    }
+}
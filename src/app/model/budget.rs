@@ -0,0 +1,355 @@
+//! Spend budgets in cents of the keys that select from the pooled tokens, charged with the usage
+//! of their requests as soon as it is known
+
+use super::{ApiKeys, ApiStatus, DateTime};
+use crate::app::lazy::BUDGETS_FILE_PATH;
+use chrono::Datelike as _;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use manually_init::ManuallyInit;
+use memmap2::{Mmap, MmapMut};
+use parking_lot::RwLock;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+
+type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
+
+/// Wait between two saves of the budgets charged in the meantime
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who a request spends for
+#[derive(
+    Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize,
+)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Spender {
+    /// API key, by `ApiKey::id`
+    ///
+    /// Budgets saved before keys had an id name the key instead, they are moved over to its id
+    /// on load, which keeps the layout of the file
+    ApiKey(String),
+    /// Dynamic key, by the hex of `ConfiguredKey::id`
+    DynamicKey(String),
+    /// The share token
+    Share,
+}
+
+/// Most a spender may spend, in cents, each unlimited when unset
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct BudgetLimits {
+    /// Per day, the day turning over at midnight in `TZ`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<u64>,
+    /// Per calendar month in `TZ`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lifetime: Option<u64>,
+}
+
+/// Cents spent so far
+#[derive(Clone, Copy, Default, Archive, RkyvSerialize, RkyvDeserialize)]
+struct Spent {
+    /// Day `today` counts, in days since the common era
+    day: i32,
+    today: f64,
+    /// Month `this_month` counts, in months since year 0
+    month: i32,
+    this_month: f64,
+    lifetime: f64,
+}
+
+#[derive(Clone, Copy)]
+struct Period {
+    day: i32,
+    month: i32,
+}
+
+impl Period {
+    fn now() -> Self {
+        let now = DateTime::now();
+        Self { day: now.num_days_from_ce(), month: now.year() * 12 + now.month0() as i32 }
+    }
+}
+
+impl Spent {
+    #[inline]
+    fn today(&self, period: Period) -> f64 { if self.day == period.day { self.today } else { 0.0 } }
+
+    #[inline]
+    fn this_month(&self, period: Period) -> f64 {
+        if self.month == period.month { self.this_month } else { 0.0 }
+    }
+}
+
+/// Which budget a spender ran out of
+#[derive(Clone, Copy)]
+pub enum BudgetExceeded {
+    Daily,
+    Monthly,
+    Lifetime,
+}
+
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct Budget {
+    spender: Spender,
+    limits: BudgetLimits,
+    spent: Spent,
+}
+
+impl Budget {
+    fn check(&self, period: Period) -> Result<(), BudgetExceeded> {
+        let reached = |limit: Option<u64>, spent: f64| limit.is_some_and(|l| spent >= l as f64);
+        if reached(self.limits.lifetime, self.spent.lifetime) {
+            return Err(BudgetExceeded::Lifetime);
+        }
+        if reached(self.limits.monthly, self.spent.this_month(period)) {
+            return Err(BudgetExceeded::Monthly);
+        }
+        if reached(self.limits.daily, self.spent.today(period)) {
+            return Err(BudgetExceeded::Daily);
+        }
+        Ok(())
+    }
+
+    fn charge(&mut self, cents: f64, period: Period) {
+        let spent = &mut self.spent;
+        spent.today = spent.today(period) + cents;
+        spent.this_month = spent.this_month(period) + cents;
+        spent.lifetime += cents;
+        spent.day = period.day;
+        spent.month = period.month;
+    }
+}
+
+pub struct Budgets {
+    inner: RwLock<HashMap<Spender, Budget>>,
+    /// Whether something was charged since the last save
+    unsaved: AtomicBool,
+}
+
+impl Budgets {
+    /// Takes the budgets loaded, after the API keys were initialized
+    pub fn init(budgets: Vec<Budget>) {
+        let mut map = HashMap::with_capacity_and_hasher(budgets.len(), ahash::RandomState::new());
+        let mut migrated = false;
+        for mut budget in budgets {
+            if let Spender::ApiKey(ref mut id) = budget.spender
+                && let Some(key_id) = ApiKeys::id_by_name(id)
+            {
+                *id = key_id;
+                migrated = true;
+            }
+            map.insert(budget.spender.clone(), budget);
+        }
+        BUDGETS.init(Budgets { inner: RwLock::new(map), unsaved: AtomicBool::new(migrated) })
+    }
+
+    /// Whether `spender` has a budget, only those are charged
+    #[inline]
+    pub fn contains(spender: &Spender) -> bool { BUDGETS.inner.read().contains_key(spender) }
+
+    #[inline]
+    pub fn is_empty() -> bool { BUDGETS.inner.read().is_empty() }
+
+    /// Whether `spender` has budget left, spenders without one always have
+    pub fn check(spender: &Spender) -> Result<(), BudgetExceeded> {
+        match BUDGETS.inner.read().get(spender) {
+            Some(budget) => budget.check(Period::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds `cents` to what `spender` spent, if it has a budget
+    pub fn charge(spender: &Spender, cents: f32) {
+        if !cents.is_finite() || cents <= 0.0 {
+            return;
+        }
+        if let Some(budget) = BUDGETS.inner.write().get_mut(spender) {
+            budget.charge(cents as f64, Period::now());
+            BUDGETS.unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Every budget along with what was spent in its current periods
+    pub fn list() -> Vec<BudgetInfo> {
+        let period = Period::now();
+        BUDGETS
+            .inner
+            .read()
+            .values()
+            .map(|budget| BudgetInfo {
+                spender: budget.spender.clone(),
+                limits: budget.limits,
+                spent: SpentInfo {
+                    today: budget.spent.today(period),
+                    this_month: budget.spent.this_month(period),
+                    lifetime: budget.spent.lifetime,
+                },
+            })
+            .collect()
+    }
+
+    /// Sets the limits of `spender`, keeping what it spent so far
+    pub fn set(spender: Spender, limits: BudgetLimits) {
+        let mut budgets = BUDGETS.inner.write();
+        match budgets.get_mut(&spender) {
+            Some(budget) => budget.limits = limits,
+            None => {
+                budgets
+                    .insert(spender.clone(), Budget { spender, limits, spent: Spent::default() });
+            }
+        }
+    }
+
+    /// Removes the budget of `spender` along with what it spent
+    #[inline]
+    pub fn remove(spender: &Spender) -> bool { BUDGETS.inner.write().remove(spender).is_some() }
+
+    pub async fn save() -> Result<(), Box<dyn core::error::Error + Send + Sync + 'static>> {
+        BUDGETS.unsaved.store(false, Ordering::Relaxed);
+        let budgets: Vec<Budget> = BUDGETS.inner.read().values().cloned().collect();
+        let bytes = ::rkyv::to_bytes::<::rkyv::rancor::Error>(&budgets)?;
+        if bytes.len() > usize::MAX >> 1 {
+            return Err("Budget data too large".into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&*BUDGETS_FILE_PATH)
+            .await?;
+        file.set_len(bytes.len() as u64).await?;
+
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap.copy_from_slice(&bytes);
+        mmap.flush()?;
+
+        Ok(())
+    }
+
+    pub async fn load() -> Result<Vec<Budget>, Box<dyn core::error::Error + Send + Sync + 'static>>
+    {
+        let file = match OpenOptions::new().read(true).open(&*BUDGETS_FILE_PATH).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if file.metadata().await?.len() > usize::MAX as u64 {
+            return Err("Budget file too large".into());
+        }
+
+        let mmap = unsafe { Mmap::map(&file)? };
+        unsafe { ::rkyv::from_bytes_unchecked::<Vec<Budget>, ::rkyv::rancor::Error>(&mmap) }
+            .map_err(|_| "Load budgets failed".into())
+    }
+}
+
+static BUDGETS: ManuallyInit<Budgets> = ManuallyInit::new();
+
+/// Saves the budgets every `SAVE_INTERVAL` if they were charged since, for as long as the
+/// process runs
+pub async fn run_budget_save() {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        if BUDGETS.unsaved.load(Ordering::Relaxed)
+            && let Err(e) = Budgets::save().await
+        {
+            __cold_path!();
+            BUDGETS.unsaved.store(true, Ordering::Relaxed);
+            eprintln!("Failed to save budgets: {e}");
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BudgetInfo {
+    pub spender: Spender,
+    #[serde(flatten)]
+    pub limits: BudgetLimits,
+    pub spent: SpentInfo,
+}
+
+/// Cents spent in the current periods
+#[derive(Serialize)]
+pub struct SpentInfo {
+    pub today: f64,
+    pub this_month: f64,
+    pub lifetime: f64,
+}
+
+#[derive(Deserialize)]
+pub struct BudgetsSetRequest {
+    pub spender: Spender,
+    #[serde(flatten)]
+    pub limits: BudgetLimits,
+}
+
+#[derive(Deserialize)]
+pub struct BudgetsDeleteRequest {
+    pub spenders: Vec<Spender>,
+}
+
+pub struct BudgetsGetResponse {
+    pub budgets: Vec<BudgetInfo>,
+}
+
+impl Serialize for BudgetsGetResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("BudgetsGetResponse", 3)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("budgets", &self.budgets)?;
+        state.serialize_field("budgets_count", &self.budgets.len())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spend_of_past_periods_is_not_counted() {
+        let mut budget = Budget {
+            spender: Spender::Share,
+            limits: BudgetLimits { daily: Some(100), monthly: Some(150), lifetime: Some(250) },
+            spent: Spent::default(),
+        };
+        let first = Period { day: 10, month: 1 };
+        budget.charge(100.0, first);
+        assert!(matches!(budget.check(first), Err(BudgetExceeded::Daily)));
+
+        let next_day = Period { day: 11, month: 1 };
+        assert!(budget.check(next_day).is_ok());
+        budget.charge(60.0, next_day);
+        assert!(matches!(budget.check(next_day), Err(BudgetExceeded::Monthly)));
+
+        let next_month = Period { day: 40, month: 2 };
+        assert!(budget.check(next_month).is_ok());
+        budget.charge(90.0, next_month);
+        assert!(matches!(budget.check(next_month), Err(BudgetExceeded::Lifetime)));
+    }
+
+    #[test]
+    fn spender_is_denied_once_its_budget_is_spent() {
+        let mut budget = Budget {
+            spender: Spender::ApiKey("0123456789abcdef0123456789abcdef".to_owned()),
+            limits: BudgetLimits { daily: None, monthly: None, lifetime: Some(50) },
+            spent: Spent::default(),
+        };
+        let period = Period { day: 10, month: 1 };
+        budget.charge(49.5, period);
+        assert!(budget.check(period).is_ok());
+        budget.charge(0.5, period);
+        assert!(matches!(budget.check(period), Err(BudgetExceeded::Lifetime)));
+        assert!(budget.check(Period { day: 400, month: 13 }).is_err());
+    }
+}
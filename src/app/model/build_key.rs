@@ -15,6 +15,18 @@ pub struct BuildKeyRequest {
     pub enable_slow_pool: Option<bool>,
     pub include_web_references: Option<bool>,
     pub usage_check_models: Option<UsageCheckModelConfig>,
+    pub group: Option<String>,
+    /// Seconds since the epoch from which the key works
+    pub not_before: Option<u64>,
+    /// Seconds since the epoch from which the key no longer works
+    pub not_after: Option<u64>,
+    pub label: Option<String>,
+    /// Model ids the key may use, `*` standing for any run of characters
+    pub models: Option<Vec<String>>,
+    /// Route families the key may call
+    pub routes: Option<Vec<KeyRoute>>,
+    /// Requests the key may make per minute
+    pub max_rpm: Option<u32>,
 }
 
 pub struct UsageCheckModelConfig {
@@ -53,6 +65,8 @@ impl<'de> Deserialize<'de> for UsageCheckModelConfig {
 
 pub type UsageCheckModelType = crate::core::config::configured_key::usage_check_model::Type;
 
+pub type KeyRoute = crate::core::config::configured_key::Route;
+
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BuildKeyResponse {
@@ -1,4 +1,4 @@
-use super::{FetchMode, UsageCheck, VisionAbility};
+use super::{AdminCredential, AdminRole, FetchMode, SelectionStrategy, UsageCheck, VisionAbility};
 use crate::app::{
     lazy::CONFIG_FILE_PATH,
     model::{Hash, cursor_version::Version, platform::PlatformType},
@@ -21,8 +21,45 @@ pub struct AppConfig {
     pub raw_model_fetch_mode: FetchMode,
     pub emulated_platform: PlatformType,
     pub cursor_client_version: Version,
+    #[serde(default)]
+    pub choice_token_spread_enabled: bool,
+    #[serde(default = "default_failover_attempts")]
+    pub failover_attempts: u8,
+    #[serde(default)]
+    pub token_selection: SelectionStrategy,
+    #[serde(default = "default_token_refresh_window")]
+    pub token_refresh_window: u32,
+    #[serde(default = "default_token_refresh_interval")]
+    pub token_refresh_interval: u32,
+    #[serde(default = "default_profile_poll_interval")]
+    pub profile_poll_interval: u32,
+    #[serde(default = "default_profile_poll_concurrency")]
+    pub profile_poll_concurrency: u32,
+    #[serde(default = "default_profile_poll_jitter")]
+    pub profile_poll_jitter: u32,
+    /// Credentials for the admin endpoints besides `AUTH_TOKEN`
+    #[serde(default)]
+    pub admins: Vec<AdminCredential>,
 }
 
+#[inline]
+const fn default_failover_attempts() -> u8 { 2 }
+
+#[inline]
+const fn default_token_refresh_window() -> u32 { 3 * 24 * 60 * 60 }
+
+#[inline]
+const fn default_token_refresh_interval() -> u32 { 10 * 60 }
+
+#[inline]
+const fn default_profile_poll_interval() -> u32 { 60 * 60 }
+
+#[inline]
+const fn default_profile_poll_concurrency() -> u32 { 4 }
+
+#[inline]
+const fn default_profile_poll_jitter() -> u32 { 30 }
+
 pub struct AppConfigWrapper {
     pub hash: Hash,
     pub inner: AppConfig,
@@ -82,6 +119,9 @@ impl AppConfig {
             super::context_fill_mode::init();
         }
         crate::core::constant::create_models();
+        crate::core::session::Registry::init();
+        crate::core::auth::Affinity::init();
+        crate::core::auth::RateLimits::init();
 
         let (content, config) = if let Ok(s) = std::fs::read_to_string(&*CONFIG_FILE_PATH) {
             match toml::from_str(&s) {
@@ -153,6 +193,14 @@ impl AppConfig {
         web_references_included: bool as is_web_references_included;
         raw_model_fetch_mode: FetchMode;
         emulated_platform: PlatformType;
+        choice_token_spread_enabled: bool as is_choice_token_spread_enabled;
+        failover_attempts: u8;
+        token_selection: SelectionStrategy;
+        token_refresh_window: u32;
+        token_refresh_interval: u32;
+        profile_poll_interval: u32;
+        profile_poll_concurrency: u32;
+        profile_poll_jitter: u32;
     );
 
     #[inline]
@@ -163,6 +211,16 @@ impl AppConfig {
 
     #[inline]
     pub fn is_share() -> bool { APP_CONFIG.load().share_token.is_empty() }
+
+    /// Role of the admin credential `token`, `AUTH_TOKEN` not included
+    pub fn admin_role(token: &str) -> Option<AdminRole> {
+        APP_CONFIG
+            .load()
+            .admins
+            .iter()
+            .find(|admin| !admin.token.is_empty() && admin.token == token)
+            .map(|admin| admin.role)
+    }
 }
 
 fn hash(config: &AppConfig) -> Hash {
@@ -188,6 +246,31 @@ fn hash(config: &AppConfig) -> Hash {
     hasher.update(config.emulated_platform.as_str().as_bytes());
     hasher.update(b"cursor_client_version");
     hasher.update(config.cursor_client_version.to_bytes());
+    hasher.update(b"choice_token_spread_enabled");
+    hasher.update([config.choice_token_spread_enabled as u8]);
+    hasher.update(b"failover_attempts");
+    hasher.update([config.failover_attempts]);
+    hasher.update(b"token_selection");
+    hasher.update(config.token_selection.as_str().as_bytes());
+    hasher.update(b"token_refresh_window");
+    hasher.update(config.token_refresh_window.to_le_bytes());
+    hasher.update(b"token_refresh_interval");
+    hasher.update(config.token_refresh_interval.to_le_bytes());
+    hasher.update(b"profile_poll_interval");
+    hasher.update(config.profile_poll_interval.to_le_bytes());
+    hasher.update(b"profile_poll_concurrency");
+    hasher.update(config.profile_poll_concurrency.to_le_bytes());
+    hasher.update(b"profile_poll_jitter");
+    hasher.update(config.profile_poll_jitter.to_le_bytes());
+    hasher.update(b"admins");
+    hasher.update((config.admins.len() as u64).to_le_bytes());
+    for admin in &config.admins {
+        hasher.update((admin.name.len() as u64).to_le_bytes());
+        hasher.update(admin.name.as_bytes());
+        hasher.update((admin.token.len() as u64).to_le_bytes());
+        hasher.update(admin.token.as_bytes());
+        hasher.update(admin.role.as_str().as_bytes());
+    }
     Hash(hasher.finalize().0)
 }
 
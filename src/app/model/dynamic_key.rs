@@ -70,7 +70,8 @@ pub fn init(secret: [u8; 64]) { INSTANCE.init(ArcSwap::from_pointee(Hmac::new(&A
 
 pub fn update(secret: [u8; 64]) { INSTANCE.store(Arc::new(Hmac::new(&Array(secret)))) }
 
-pub fn get_hash(raw: &RawToken) -> [u8; 32] {
+/// HMAC of a dynamic key over its token, `ConfiguredKey::hash` goes on with the rest of the key
+pub fn token_hmac(raw: &RawToken) -> Hmac<Sha256> {
     let mut hmac = (**INSTANCE.get().load()).clone();
     hmac.update(b"subject");
     hmac.update(raw.subject.provider.as_str().as_bytes());
@@ -84,5 +85,5 @@ pub fn get_hash(raw: &RawToken) -> [u8; 32] {
     hmac.update(&raw.randomness.to_bytes());
     hmac.update(b"type");
     hmac.update(if raw.is_session { TYPE_SESSION } else { TYPE_WEB }.as_bytes());
-    hmac.finalize_fixed().0
+    hmac
 }
@@ -3,6 +3,7 @@ mod limit;
 pub mod manager;
 mod storage;
 
+use super::VersionedFile;
 use crate::{app::model::ExtTokenHelper, core::constant::get_static_id};
 pub use command::{GetLogsParams, LogUpdate};
 use interned::Str;
@@ -10,6 +11,9 @@ pub use manager::{LogManager, create_task};
 
 type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
 
+/// `logs.bin`, version 1 added the choices of a request and version 2 its failover attempts
+const LOGS_FILE: VersionedFile = VersionedFile::new(*b"CAPILOGS", 2);
+
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
 enum ErrorInfoHelper {
     Empty,
@@ -41,12 +45,27 @@ impl From<&super::ErrorInfo> for ErrorInfoHelper {
     }
 }
 #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+struct AttemptHelper {
+    key: super::TokenKey,
+    error: ErrorInfoHelper,
+}
+impl From<AttemptHelper> for super::Attempt {
+    #[inline]
+    fn from(helper: AttemptHelper) -> Self { Self { key: helper.key, error: helper.error.into() } }
+}
+impl From<&super::Attempt> for AttemptHelper {
+    #[inline]
+    fn from(ori: &super::Attempt) -> Self { Self { key: ori.key, error: (&ori.error).into() } }
+}
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
 pub(super) struct RequestLogHelper {
     id: u64,
     timestamp: chrono::NaiveDateTime,
     model: String,
     token_info: super::LogTokenInfo,
     chain: super::Chain,
+    choices: Vec<super::Chain>,
+    attempts: Vec<AttemptHelper>,
     timing: super::TimingInfo,
     stream: bool,
     status: super::LogStatus,
@@ -61,6 +80,8 @@ impl From<RequestLogHelper> for super::RequestLog {
             model: get_static_id(log.model.as_str()),
             token_info: log.token_info,
             chain: log.chain,
+            choices: log.choices,
+            attempts: log.attempts.into_iter().map(Into::into).collect(),
             timing: log.timing,
             stream: log.stream,
             status: log.status,
@@ -77,6 +98,8 @@ impl From<&super::RequestLog> for RequestLogHelper {
             model: log.model.to_string(),
             token_info: log.token_info.clone(),
             chain: log.chain.clone(),
+            choices: log.choices.clone(),
+            attempts: log.attempts.iter().map(Into::into).collect(),
             timing: log.timing,
             stream: log.stream,
             status: log.status,
@@ -84,6 +107,37 @@ impl From<&super::RequestLog> for RequestLogHelper {
         }
     }
 }
+/// `RequestLogHelper` as written before `logs.bin` had a version
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+pub(super) struct RequestLogHelperV0 {
+    id: u64,
+    timestamp: chrono::NaiveDateTime,
+    model: String,
+    token_info: super::LogTokenInfo,
+    chain: super::Chain,
+    timing: super::TimingInfo,
+    stream: bool,
+    status: super::LogStatus,
+    error: ErrorInfoHelper,
+}
+impl From<RequestLogHelperV0> for super::RequestLog {
+    #[inline]
+    fn from(log: RequestLogHelperV0) -> Self {
+        Self {
+            id: log.id,
+            timestamp: log.timestamp.into(),
+            model: get_static_id(log.model.as_str()),
+            token_info: log.token_info,
+            chain: log.chain,
+            choices: Vec::new(),
+            attempts: Vec::new(),
+            timing: log.timing,
+            stream: log.stream,
+            status: log.status,
+            error: log.error.into(),
+        }
+    }
+}
 // #[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
 // pub struct PromptMessageHelper {
 //     role: Role,
@@ -195,6 +249,21 @@ impl From<LogManagerHelper> for manager::LogManager {
         }
     }
 }
+/// `LogManagerHelper` as written before `logs.bin` had a version
+#[derive(rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+pub(super) struct LogManagerHelperV0 {
+    logs: Vec<RequestLogHelperV0>,
+    tokens: HashMap<super::TokenKey, AssociatedTokenHelper>,
+}
+impl From<LogManagerHelperV0> for manager::LogManager {
+    #[inline]
+    fn from(helper: LogManagerHelperV0) -> manager::LogManager {
+        manager::LogManager {
+            logs: helper.logs.into_iter().map(Into::into).collect(),
+            tokens: helper.tokens.into_iter().map(|(k, v)| (k, v.into())).collect(),
+        }
+    }
+}
 impl From<&manager::LogManager> for LogManagerHelper {
     #[inline]
     fn from(mgr: &manager::LogManager) -> Self {
@@ -75,4 +75,9 @@ pub enum LogUpdate {
     Delays(Option<(String, Vec<(u32, f32)>)>, Option<String>),
     Usage(ChainUsage),
     TimingChain(f64, Chain),
+    Choices(Vec<Chain>),
+    /// The request is retried on another pooled token after failing with the error
+    Failover(ExtToken, ErrorInfo),
+    /// The request is served by another token than it was logged with, without having failed
+    Token(ExtToken),
 }
@@ -7,7 +7,9 @@ use crate::{
     app::{
         constant::ERR_LOG_TOKEN_NOT_FOUND,
         lazy::LOGS_FILE_PATH,
-        model::{ExtToken, LogStatus, RequestLog, TokenKey},
+        model::{
+            Attempt, ErrorInfo, ExtToken, HEADER_LEN, LogStatus, LogTokenInfo, RequestLog, TokenKey,
+        },
     },
     common::utils::{format_time_ms, parse_from_env},
 };
@@ -60,13 +62,20 @@ impl LogManager {
             return Err("Log file too large".into());
         }
 
+        use ::rkyv::{from_bytes_unchecked, rancor::Error};
         let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
-        let manager = unsafe {
-            ::rkyv::from_bytes_unchecked::<super::LogManagerHelper, rkyv::rancor::Error>(&mmap)
+        // Files written before the header are migrated, the next save writes the current version
+        let manager = match super::LOGS_FILE.split(&mmap)? {
+            (0, bytes) => {
+                unsafe { from_bytes_unchecked::<super::LogManagerHelperV0, Error>(bytes) }
+                    .map(Into::into)
+            }
+            (_, bytes) => unsafe { from_bytes_unchecked::<super::LogManagerHelper, Error>(bytes) }
+                .map(Into::into),
         }
         .map_err(|_| "Load logs failed")?;
 
-        Ok(manager.into())
+        Ok(manager)
     }
 
     pub async fn save() -> Result<(), Box<dyn core::error::Error + Send + Sync + 'static>> {
@@ -85,13 +94,14 @@ impl LogManager {
             .open(&*LOGS_FILE_PATH)
             .await?;
 
-        if bytes.len() > usize::MAX >> 1 {
+        if bytes.len() > (usize::MAX >> 1) - HEADER_LEN {
             return Err("Log data too large".into());
         }
 
-        file.set_len(bytes.len() as u64).await?;
+        file.set_len((HEADER_LEN + bytes.len()) as u64).await?;
         let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
-        mmap.copy_from_slice(&bytes);
+        mmap[..HEADER_LEN].copy_from_slice(&super::LOGS_FILE.header());
+        mmap[HEADER_LEN..].copy_from_slice(&bytes);
         mmap.flush()?;
 
         Ok(())
@@ -132,6 +142,50 @@ pub fn create_task(log_manager: LogManager) {
     LOG_COMMAND_SENDER.init(tx)
 }
 
+/// Drops one log's reference to the token with `key`
+fn release_token(tokens: &mut AssociatedStorage, key: TokenKey) {
+    use hashbrown::hash_map::Entry;
+    match tokens.entry(key) {
+        Entry::Occupied(mut e) => {
+            let a = e.get_mut();
+            a.ref_count -= 1;
+            if a.ref_count == 0 {
+                e.remove();
+            }
+        }
+        Entry::Vacant(e) => crate::debug!("[LOG] Data inconsistency: {:?}", e.into_key()),
+    };
+}
+
+/// Adds one log's reference to `token`
+fn retain_token(tokens: &mut AssociatedStorage, key: TokenKey, token: ExtToken) {
+    use hashbrown::hash_map::Entry;
+    match tokens.entry(key) {
+        Entry::Occupied(e) => {
+            let a = e.into_mut();
+            a.token = token;
+            a.ref_count += 1;
+        }
+        Entry::Vacant(e) => {
+            e.insert(AssociatedToken { token, ref_count: 1 });
+        }
+    }
+}
+
+/// Hands the log over to the token with `key` the request failed over to, recording the token
+/// it failed on as an attempt, whose key is returned
+fn record_attempt(
+    token_info: &mut LogTokenInfo,
+    attempts: &mut Vec<Attempt>,
+    key: TokenKey,
+    error: ErrorInfo,
+) -> TokenKey {
+    let failed =
+        core::mem::replace(token_info, LogTokenInfo { key, usage: None, user: None, stripe: None });
+    attempts.push(Attempt { key: failed.key, error });
+    failed.key
+}
+
 fn handle_command(mgr: &mut LogManager, cmd: LogCommand) -> bool {
     match cmd {
         LogCommand::GetLogs { params, tx } => {
@@ -293,36 +347,14 @@ fn handle_command(mgr: &mut LogManager, cmd: LogCommand) -> bool {
             )))
         }
         LogCommand::AddLog { log, token } => {
-            use hashbrown::hash_map::Entry;
             let key = log.token_key();
             while mgr.logs.len() >= REQUEST_LOGS_LIMIT.get_limit() {
                 if let Some(log) = mgr.logs.pop_front() {
-                    let key = log.token_key();
-                    match mgr.tokens.entry(key) {
-                        Entry::Occupied(mut e) => {
-                            let a = e.get_mut();
-                            a.ref_count -= 1;
-                            if a.ref_count == 0 {
-                                e.remove();
-                            }
-                        }
-                        Entry::Vacant(e) => {
-                            crate::debug!("[LOG] Data inconsistency: {:?}", e.into_key())
-                        }
-                    };
+                    release_token(&mut mgr.tokens, log.token_key());
                 }
             }
             mgr.logs.push_back(*log);
-            match mgr.tokens.entry(key) {
-                Entry::Occupied(e) => {
-                    let a = e.into_mut();
-                    a.token = token;
-                    a.ref_count += 1;
-                }
-                Entry::Vacant(e) => {
-                    e.insert(AssociatedToken { token, ref_count: 1 });
-                }
-            }
+            retain_token(&mut mgr.tokens, key, token);
         }
         LogCommand::GetNextLogId { tx } => {
             unwrap!(tx.send(mgr.logs.back().map_or(1, |log| log.id + 1)))
@@ -370,6 +402,23 @@ fn handle_command(mgr: &mut LogManager, cmd: LogCommand) -> bool {
                         log.timing.total = format_time_ms(t);
                         log.chain = chain;
                     }
+                    LogUpdate::Choices(choices) => log.choices = choices,
+                    LogUpdate::Failover(token, error) => {
+                        let key = token.primary_token.key();
+                        let failed =
+                            record_attempt(&mut log.token_info, &mut log.attempts, key, error);
+                        release_token(&mut mgr.tokens, failed);
+                        retain_token(&mut mgr.tokens, key, token);
+                    }
+                    LogUpdate::Token(token) => {
+                        let key = token.primary_token.key();
+                        let previous = core::mem::replace(
+                            &mut log.token_info,
+                            LogTokenInfo { key, usage: None, user: None, stripe: None },
+                        );
+                        release_token(&mut mgr.tokens, previous.key);
+                        retain_token(&mut mgr.tokens, key, token);
+                    }
                 }
             }
         }
@@ -435,3 +484,29 @@ pub async fn update_log(id: u64, ops: LogUpdate) {
 }
 
 pub fn is_enabled() -> bool { REQUEST_LOGS_LIMIT.should_log() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::model::{Randomness, UserId};
+    use interned::Str;
+
+    #[test]
+    fn failovers_record_each_failed_token_in_order() {
+        let key =
+            |n| TokenKey { user_id: UserId::from_u128(n), randomness: Randomness::from_u64(0) };
+        let mut token_info = LogTokenInfo { key: key(1), usage: None, user: None, stripe: None };
+        let mut attempts = Vec::new();
+
+        let error = |message| ErrorInfo::Simple(Str::from_static(message));
+        assert_eq!(record_attempt(&mut token_info, &mut attempts, key(2), error("first")), key(1));
+        assert_eq!(record_attempt(&mut token_info, &mut attempts, key(3), error("second")), key(2));
+
+        assert_eq!(token_info.key, key(3));
+        assert_eq!(attempts.len(), 2);
+        for (attempt, (n, message)) in attempts.iter().zip([(1, "first"), (2, "second")]) {
+            assert_eq!(attempt.key, key(n));
+            assert!(matches!(&attempt.error, ErrorInfo::Simple(error) if *error == message));
+        }
+    }
+}
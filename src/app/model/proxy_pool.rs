@@ -262,6 +262,17 @@ impl SingleProxy {
     }
 }
 
+impl SingleProxy {
+    /// The proxy without the credentials of its URL
+    #[inline]
+    pub fn redacted(&self) -> Self {
+        match self {
+            Self::Url(url) => Self::Url(url.without_userinfo()),
+            _ => self.clone(),
+        }
+    }
+}
+
 impl Serialize for SingleProxy {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: serde::Serializer {
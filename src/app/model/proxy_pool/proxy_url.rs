@@ -22,6 +22,23 @@ impl ProxyUrl {
     /// - Once constructed, the internal URL string is immutable
     #[inline]
     pub fn to_proxy(&self) -> Proxy { unsafe { Proxy::all(self.0.as_str()).unwrap_unchecked() } }
+
+    /// The URL without its `user:pass@` credentials, for roles that may list proxies but not
+    /// use them
+    ///
+    /// Dropping the userinfo of a valid URL leaves it valid, so `to_proxy` stays safe
+    pub fn without_userinfo(&self) -> Self {
+        let url = self.0.as_str();
+        let Some(authority_start) = url.find("://").map(|i| i + 3) else { return self.clone() };
+        let authority_len =
+            url[authority_start..].find(['/', '?', '#']).unwrap_or(url.len() - authority_start);
+        match url[authority_start..authority_start + authority_len].rfind('@') {
+            Some(at) => Self(Str::new(
+                &[&url[..authority_start], &url[authority_start + at + 1..]].concat(),
+            )),
+            None => self.clone(),
+        }
+    }
 }
 
 impl From<ProxyUrl> for Proxy {
@@ -65,3 +82,25 @@ impl core::hash::Hash for ProxyUrl {
     #[inline]
     fn hash<H: core::hash::Hasher>(&self, state: &mut H) { self.0.hash(state); }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn userinfo_is_dropped() {
+        let url = |s: &str| s.parse::<ProxyUrl>().unwrap();
+        assert_eq!(
+            &*url("http://user:p@ss@proxy.local:8080/path").without_userinfo(),
+            "http://proxy.local:8080/path"
+        );
+        assert_eq!(
+            &*url("socks5://proxy.local:1080").without_userinfo(),
+            "socks5://proxy.local:1080"
+        );
+        assert_eq!(
+            &*url("http://proxy.local:8080/user@path").without_userinfo(),
+            "http://proxy.local:8080/user@path"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Dynamic keys that no longer work, by `ConfiguredKey::id`
+
+use super::ApiStatus;
+use crate::{app::lazy::REVOKED_KEYS_FILE_PATH, common::utils::now_secs};
+use manually_init::ManuallyInit;
+use memmap2::{Mmap, MmapMut};
+use parking_lot::RwLock;
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+
+type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
+
+#[derive(Clone, Archive, RkyvSerialize, RkyvDeserialize)]
+pub struct RevokedKey {
+    id: [u8; 16],
+    /// Label of the key, when it was revoked by the key itself
+    label: Option<String>,
+    /// Seconds since the epoch
+    revoked_at: u64,
+}
+
+pub struct RevokedKeys {
+    inner: RwLock<HashMap<[u8; 16], RevokedKey>>,
+}
+
+impl RevokedKeys {
+    pub fn init(keys: Vec<RevokedKey>) {
+        let mut map = HashMap::with_capacity_and_hasher(keys.len(), ahash::RandomState::new());
+        for key in keys {
+            map.insert(key.id, key);
+        }
+        REVOKED_KEYS.init(RevokedKeys { inner: RwLock::new(map) })
+    }
+
+    #[inline]
+    pub fn contains(id: &[u8; 16]) -> bool { REVOKED_KEYS.inner.read().contains_key(id) }
+
+    /// Revokes the key with `id`, `false` when it already was
+    pub fn revoke(id: [u8; 16], label: Option<String>) -> bool {
+        let mut keys = REVOKED_KEYS.inner.write();
+        if keys.contains_key(&id) {
+            return false;
+        }
+        keys.insert(id, RevokedKey { id, label, revoked_at: now_secs() });
+        true
+    }
+
+    /// Every revoked key, latest first
+    pub fn list() -> Vec<RevokedKeyInfo> {
+        let mut keys: Vec<RevokedKeyInfo> = REVOKED_KEYS
+            .inner
+            .read()
+            .values()
+            .map(|key| RevokedKeyInfo {
+                id: hex::encode(key.id),
+                label: key.label.clone(),
+                revoked_at: key.revoked_at,
+            })
+            .collect();
+        keys.sort_unstable_by(|a, b| b.revoked_at.cmp(&a.revoked_at));
+        keys
+    }
+
+    pub async fn save() -> Result<(), Box<dyn core::error::Error + Send + Sync + 'static>> {
+        let keys: Vec<RevokedKey> = REVOKED_KEYS.inner.read().values().cloned().collect();
+        let bytes = ::rkyv::to_bytes::<::rkyv::rancor::Error>(&keys)?;
+        if bytes.len() > usize::MAX >> 1 {
+            return Err("Revoked key data too large".into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&*REVOKED_KEYS_FILE_PATH)
+            .await?;
+        file.set_len(bytes.len() as u64).await?;
+
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap.copy_from_slice(&bytes);
+        mmap.flush()?;
+
+        Ok(())
+    }
+
+    pub async fn load()
+    -> Result<Vec<RevokedKey>, Box<dyn core::error::Error + Send + Sync + 'static>> {
+        let file = match OpenOptions::new().read(true).open(&*REVOKED_KEYS_FILE_PATH).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if file.metadata().await?.len() > usize::MAX as u64 {
+            return Err("Revoked key file too large".into());
+        }
+
+        let mmap = unsafe { Mmap::map(&file)? };
+        unsafe { ::rkyv::from_bytes_unchecked::<Vec<RevokedKey>, ::rkyv::rancor::Error>(&mmap) }
+            .map_err(|_| "Load revoked keys failed".into())
+    }
+}
+
+static REVOKED_KEYS: ManuallyInit<RevokedKeys> = ManuallyInit::new();
+
+#[derive(Serialize)]
+pub struct RevokedKeyInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub revoked_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RevokedKeysAddRequest {
+    /// Dynamic keys, or their ids
+    pub keys: Vec<String>,
+}
+
+pub struct RevokedKeysGetResponse {
+    pub keys: Vec<RevokedKeyInfo>,
+}
+
+impl Serialize for RevokedKeysGetResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("RevokedKeysGetResponse", 3)?;
+        state.serialize_field("status", &ApiStatus::Success)?;
+        state.serialize_field("keys", &self.keys)?;
+        state.serialize_field("keys_count", &self.keys.len())?;
+        state.end()
+    }
+}
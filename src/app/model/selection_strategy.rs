@@ -0,0 +1,86 @@
+/// How pooled keys pick a token from their queue
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum SelectionStrategy {
+    /// Hand the tokens out in turn
+    RoundRobin,
+
+    /// Pick the token that has gone unused the longest
+    LeastRecentlyUsed,
+
+    /// Pick the token with the fewest requests in flight
+    LeastInFlight,
+
+    /// Pick at random, in proportion to the weight of each token
+    Weighted,
+
+    /// Pick the token with the most plan usage left in its billing cycle
+    QuotaAware,
+}
+
+impl SelectionStrategy {
+    /// String constant representing round-robin selection
+    const ROUND_ROBIN: &'static str = "round_robin";
+
+    /// String constant representing least-recently-used selection
+    const LEAST_RECENTLY_USED: &'static str = "least_recently_used";
+
+    /// String constant representing least-in-flight selection
+    const LEAST_IN_FLIGHT: &'static str = "least_in_flight";
+
+    /// String constant representing weighted selection
+    const WEIGHTED: &'static str = "weighted";
+
+    /// String constant representing quota-aware selection
+    const QUOTA_AWARE: &'static str = "quota_aware";
+
+    /// Parse selection strategy from string
+    #[inline]
+    pub fn from_str(mut s: String) -> Self {
+        s.make_ascii_lowercase();
+        match s.as_str() {
+            Self::ROUND_ROBIN => Self::RoundRobin,
+            Self::LEAST_RECENTLY_USED => Self::LeastRecentlyUsed,
+            Self::LEAST_IN_FLIGHT => Self::LeastInFlight,
+            Self::WEIGHTED => Self::Weighted,
+            Self::QUOTA_AWARE => Self::QuotaAware,
+            _ => Self::default(),
+        }
+    }
+
+    /// Return string representation of selection strategy
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RoundRobin => Self::ROUND_ROBIN,
+            Self::LeastRecentlyUsed => Self::LEAST_RECENTLY_USED,
+            Self::LeastInFlight => Self::LEAST_IN_FLIGHT,
+            Self::Weighted => Self::WEIGHTED,
+            Self::QuotaAware => Self::QUOTA_AWARE,
+        }
+    }
+}
+
+impl const Default for SelectionStrategy {
+    #[inline(always)]
+    fn default() -> Self { Self::RoundRobin }
+}
+
+impl ::serde::Serialize for SelectionStrategy {
+    /// Serialize selection strategy
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: ::serde::Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> ::serde::Deserialize<'de> for SelectionStrategy {
+    /// Deserialize selection strategy
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: ::serde::Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        Ok(Self::from_str(s))
+    }
+}
@@ -1,11 +1,16 @@
+mod lifecycle;
 mod token;
 
 use super::{
+    ApiKeys, Budgets, RevokedKeys, TokenKey,
     log::{LogManager, create_task},
     proxy_pool::Proxies,
 };
 use core::sync::atomic::{AtomicU64, Ordering};
-pub use token::{QueueType, TokenError, TokenHealth, TokenManager, TokenWriter};
+pub use lifecycle::{run_profile_poll, run_token_refresh};
+pub use token::{
+    HealthEvent, InFlight, QueueType, TokenError, TokenHealth, TokenLoad, TokenManager, TokenWriter,
+};
 use tokio::sync::RwLock;
 
 pub struct AppState {
@@ -17,13 +22,29 @@ pub struct AppState {
 
 impl AppState {
     pub async fn load() -> Result<Self, Box<dyn core::error::Error + Send + Sync + 'static>> {
-        // Load logs, tokens and proxies in parallel
-        let (log_manager_result, token_manager_result, proxies_result) =
-            tokio::join!(LogManager::load(), TokenManager::load(), Proxies::load());
+        // Load logs, tokens, proxies, API keys, budgets and revoked keys in parallel
+        let (
+            log_manager_result,
+            token_manager_result,
+            proxies_result,
+            api_keys_result,
+            budgets_result,
+            revoked_keys_result,
+        ) = tokio::join!(
+            LogManager::load(),
+            TokenManager::load(),
+            Proxies::load(),
+            ApiKeys::load(),
+            Budgets::load(),
+            RevokedKeys::load()
+        );
 
         // Get results, handle errors
         let log_manager = log_manager_result?;
         let token_manager = token_manager_result?;
+        ApiKeys::init(api_keys_result?);
+        Budgets::init(budgets_result?);
+        RevokedKeys::init(revoked_keys_result?);
 
         // Handle proxies
         let proxies = proxies_result.unwrap_or_default();
@@ -72,13 +93,29 @@ impl AppState {
     }
 
     pub async fn save(&self) -> Result<(), Box<dyn core::error::Error + Send + Sync + 'static>> {
-        // Save logs, tokens and proxies in parallel
-        let (log_result, tokens_result, proxies_result) =
-            tokio::join!(LogManager::save(), self.save_tokens(), Proxies::save());
+        // Save logs, tokens, proxies, API keys, budgets and revoked keys in parallel
+        let (
+            log_result,
+            tokens_result,
+            proxies_result,
+            api_keys_result,
+            budgets_result,
+            revoked_keys_result,
+        ) = tokio::join!(
+            LogManager::save(),
+            self.save_tokens(),
+            Proxies::save(),
+            ApiKeys::save(),
+            Budgets::save(),
+            RevokedKeys::save()
+        );
 
         log_result?;
         tokens_result?;
         proxies_result?;
+        api_keys_result?;
+        budgets_result?;
+        revoked_keys_result?;
         Ok(())
     }
 
@@ -86,6 +123,25 @@ impl AppState {
         self.token_manager.read().await.save().await
     }
 
+    /// Applies the outcome of a request to the health of the pooled token with `key`
+    pub async fn record_token_health(&self, key: TokenKey, event: HealthEvent) {
+        if event == HealthEvent::Success {
+            let token_manager = self.token_manager.read().await;
+            let Some(&id) = token_manager.id_map().get(&key) else { return };
+            // Most requests succeed on a healthy token, which needs no write lock
+            let info =
+                unsafe { token_manager.tokens().get_unchecked(id).as_ref().unwrap_unchecked() };
+            if info.status.health.is_clear() {
+                return;
+            }
+        }
+        let mut token_manager = self.token_manager.write().await;
+        if let Some(id) = token_manager.id_map().get(&key).copied() {
+            let info = unsafe { token_manager.tokens_mut().get_unchecked_mut(id) };
+            info.status.health.record(event);
+        }
+    }
+
     /// Update client key in token manager
     pub async fn update_client_key(&self) { self.token_manager.write().await.update_client_key() }
 }
@@ -0,0 +1,182 @@
+//! Background upkeep of the tokens in the manager
+
+use super::AppState;
+use crate::{
+    app::model::{AppConfig, ExtToken, TokenKey},
+    common::{
+        model::userinfo::{Session, StripeProfile, UsageProfile, UserProfile},
+        utils::{get_token_profile, now_secs, refresh_ext_token},
+    },
+};
+use alloc::sync::Arc;
+use core::time::Duration;
+use rand::Rng as _;
+use tokio::task::JoinSet;
+
+/// Shortest wait between two scans, whatever the configuration says
+const MIN_REFRESH_INTERVAL_SECS: u64 = 60;
+
+/// Shortest wait between two profile polls, whatever the configuration says
+const MIN_POLL_INTERVAL_SECS: u64 = 60;
+
+type Profile =
+    (Option<UsageProfile>, Option<StripeProfile>, Option<UserProfile>, Option<Vec<Session>>);
+
+impl AppState {
+    /// Refreshes the enabled tokens that expire within `token_refresh_window`
+    ///
+    /// Tokens are refreshed outside the lock, a token removed or replaced in the meantime keeps
+    /// its new state and the refreshed one is dropped
+    pub async fn refresh_expiring_tokens(&self) {
+        let window = AppConfig::token_refresh_window();
+        if window == 0 {
+            return;
+        }
+        let deadline = (now_secs() + window as u64) as i64;
+
+        let expiring: Vec<(usize, ExtToken)> = self
+            .token_manager_read()
+            .await
+            .tokens()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, info)| {
+                let info = info.as_ref()?;
+                (info.is_enabled() && info.bundle.primary_token.raw().duration.end <= deadline)
+                    .then(|| (id, Clone::clone(&info.bundle)))
+            })
+            .collect();
+        if expiring.is_empty() {
+            return;
+        }
+
+        let mut refreshed = 0usize;
+        for (id, mut bundle) in expiring {
+            let key = bundle.primary_token.key();
+            let refreshed = refresh_ext_token(&mut bundle, true).await;
+
+            let mut token_manager = self.token_manager_write().await;
+            if token_manager.id_map().get(&key) != Some(&id) {
+                continue;
+            }
+            if refreshed.is_ok() {
+                let mut writer = unsafe { token_manager.tokens_mut().into_token_writer(id) };
+                writer.primary_token = bundle.primary_token;
+                writer.secondary_token = bundle.secondary_token;
+            }
+            unsafe { token_manager.tokens_mut().get_unchecked_mut(id) }.record_refresh(refreshed);
+            refreshed += 1;
+        }
+
+        if refreshed > 0
+            && let Err(e) = self.token_manager_read().await.save().await
+        {
+            __cold_path!();
+            eprintln!("Failed to save refreshed tokens: {e}");
+        }
+    }
+
+    /// Fetches the profiles of the enabled tokens, `profile_poll_concurrency` at a time, each
+    /// after a random delay of up to `profile_poll_jitter` seconds
+    ///
+    /// Profiles are fetched outside the lock, a token removed or replaced in the meantime keeps
+    /// its state
+    pub async fn poll_token_profiles(&self) {
+        if AppConfig::profile_poll_interval() == 0 {
+            return;
+        }
+        let concurrency = AppConfig::profile_poll_concurrency().max(1) as usize;
+        let jitter = AppConfig::profile_poll_jitter() as u64;
+
+        let tokens: Vec<(usize, ExtToken)> = self
+            .token_manager_read()
+            .await
+            .tokens()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, info)| {
+                let info = info.as_ref()?;
+                info.is_enabled().then(|| (id, Clone::clone(&info.bundle)))
+            })
+            .collect();
+
+        let mut fetches = JoinSet::new();
+        let mut updated = 0usize;
+        for (id, bundle) in tokens {
+            if fetches.len() >= concurrency
+                && let Some(Ok((id, key, profile))) = fetches.join_next().await
+            {
+                updated += self.apply_profile(id, key, profile).await as usize;
+            }
+            let delay = rand::rng().random_range(0..=jitter);
+            fetches.spawn(async move {
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                let profile =
+                    get_token_profile(bundle.get_client(), bundle.as_unext(), true, true).await;
+                (id, bundle.primary_token.key(), profile)
+            });
+        }
+        while let Some(result) = fetches.join_next().await {
+            if let Ok((id, key, profile)) = result {
+                updated += self.apply_profile(id, key, profile).await as usize;
+            }
+        }
+
+        if updated > 0
+            && let Err(e) = self.token_manager_read().await.save().await
+        {
+            __cold_path!();
+            eprintln!("Failed to save polled token profiles: {e}");
+        }
+    }
+
+    /// Stores a polled profile if the token with `id` still has `key`, naming an unnamed token
+    /// after its user
+    async fn apply_profile(&self, id: usize, key: TokenKey, profile: Profile) -> bool {
+        let (usage, stripe, user, sessions) = profile;
+        let mut token_manager = self.token_manager_write().await;
+        if token_manager.id_map().get(&key) != Some(&id) {
+            return false;
+        }
+
+        // SAFETY: id_map only holds the ids of present tokens, kept in sync with id_to_alias
+        let alias_is_unnamed = unsafe {
+            token_manager.id_to_alias().get_unchecked(id).as_ref().unwrap_unchecked().is_unnamed()
+        };
+        let alias =
+            user.as_ref().filter(|_| alias_is_unnamed).and_then(|user| user.alias().cloned());
+
+        let token_info = unsafe { token_manager.tokens_mut().get_unchecked_mut(id) };
+        token_info.set_usage(usage);
+        token_info.user = user;
+        token_info.stripe = stripe;
+        if let Some(sessions) = sessions {
+            token_info.sessions = sessions;
+        }
+
+        if let Some(alias) = alias {
+            let _ = token_manager.set_alias(id, alias);
+        }
+        true
+    }
+}
+
+/// Fetches the profiles of every enabled token every `profile_poll_interval` seconds, for as
+/// long as the process runs
+pub async fn run_profile_poll(state: Arc<AppState>) {
+    loop {
+        let interval = (AppConfig::profile_poll_interval() as u64).max(MIN_POLL_INTERVAL_SECS);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        state.poll_token_profiles().await;
+    }
+}
+
+/// Scans for expiring tokens every `token_refresh_interval` seconds, for as long as the process
+/// runs
+pub async fn run_token_refresh(state: Arc<AppState>) {
+    loop {
+        let interval = (AppConfig::token_refresh_interval() as u64).max(MIN_REFRESH_INTERVAL_SECS);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        state.refresh_expiring_tokens().await;
+    }
+}
@@ -3,11 +3,14 @@ mod queue;
 use crate::app::{
     constant::{UNNAMED, UNNAMED_PATTERN},
     lazy::TOKENS_FILE_PATH,
-    model::{Alias, ExtToken, TokenInfo, TokenInfoHelper, TokenKey},
+    model::{
+        Alias, ExtToken, HEADER_LEN, TOKENS_FILE, TokenInfo, TokenInfoHelper, TokenInfoHelperV0,
+        TokenKey,
+    },
 };
-use alloc::{borrow::Cow, collections::VecDeque};
+use alloc::{borrow::Cow, collections::VecDeque, sync::Arc};
 use memmap2::{Mmap, MmapMut};
-pub use queue::{QueueType, TokenHealth, TokenQueue};
+pub use queue::{HealthEvent, InFlight, QueueType, TokenHealth, TokenLoad, TokenQueue};
 use tokio::fs::OpenOptions;
 
 type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
@@ -59,7 +62,7 @@ pub struct TokenManager {
     id_to_alias: Vec<Option<Alias>>,
     /// Reusable ID queue (FIFO), prioritize reusing earliest released IDs
     free_ids: VecDeque<usize>,
-    /// Round-robin token selection queue
+    /// Token selection queue
     queue: TokenQueue,
 }
 
@@ -107,6 +110,9 @@ impl TokenManager {
         let key = token_info.bundle.primary_token.key();
         self.id_map.insert(key, id);
         self.queue.push(key, id);
+        for group in &token_info.groups {
+            self.queue.add_group(group);
+        }
 
         // SAFETY: id is either reused_id (from free_ids, must be <len), or new index just pushed
         unsafe { *self.tokens.get_unchecked_mut(id) = Some(token_info) };
@@ -155,9 +161,37 @@ impl TokenManager {
 
         // Add ID to end of free queue, waiting for reuse
         self.free_ids.push_back(id);
+        if !token_info.groups.is_empty() {
+            self.sync_groups();
+        }
         Some(token_info)
     }
 
+    /// Replaces the groups of the token with `id`
+    #[inline(never)]
+    pub fn set_groups(&mut self, id: usize, groups: Vec<String>) -> Result<(), TokenError> {
+        let token_info =
+            self.tokens.get_mut(id).and_then(Option::as_mut).ok_or(TokenError::InvalidId)?;
+        token_info.groups = groups;
+        self.sync_groups();
+        Ok(())
+    }
+
+    /// Rebuilds the group heads of the queue from the groups of the tokens
+    fn sync_groups(&mut self) {
+        self.queue.sync_groups(
+            self.tokens.iter().flatten().flat_map(|token| token.groups.iter().map(String::as_str)),
+        );
+    }
+
+    /// Whether some token belongs to `group`
+    #[inline]
+    pub fn has_group(&self, group: &str) -> bool { self.queue.has_group(group) }
+
+    /// Names of the groups that have a member
+    #[inline]
+    pub fn groups(&self) -> impl Iterator<Item = &str> { self.queue.groups() }
+
     #[inline(never)]
     pub fn set_alias<'a, S: Into<Cow<'a, str>>>(
         &mut self,
@@ -203,8 +237,43 @@ impl TokenManager {
 
     pub fn id_to_alias(&self) -> &Vec<Option<Alias>> { &self.id_to_alias }
 
-    pub fn select(&self, queue_type: QueueType) -> Option<ExtToken> {
-        self.queue.select(queue_type, self)
+    /// Selects a token of `queue_type`, only among the members of `group` when there is one
+    ///
+    /// The request holds a slot on the token for as long as it keeps the returned guard
+    pub fn select(
+        &self,
+        queue_type: QueueType,
+        group: Option<&str>,
+    ) -> Option<(ExtToken, InFlight)> {
+        self.queue.select(queue_type, group, self, &[])
+    }
+
+    /// `select` that passes over the tokens in `skip`
+    pub fn select_except(
+        &self,
+        queue_type: QueueType,
+        group: Option<&str>,
+        skip: &[TokenKey],
+    ) -> Option<(ExtToken, InFlight)> {
+        self.queue.select(queue_type, group, self, skip)
+    }
+
+    /// The token with `key` if `select` could have picked it from `queue_type` right now
+    pub fn select_pinned(
+        &self,
+        queue_type: QueueType,
+        group: Option<&str>,
+        key: &TokenKey,
+    ) -> Option<(ExtToken, InFlight)> {
+        self.queue.select_key(queue_type, group, key, self)
+    }
+
+    /// In-memory load of the token with `key`
+    #[inline]
+    pub fn load(&self, key: &TokenKey) -> Option<&Arc<TokenLoad>> {
+        let &id = self.id_map.get(key)?;
+        // SAFETY: id in id_map is maintained by add/remove, guaranteed <tokens.len() and corresponding Some
+        Some(unsafe { &self.tokens.get_unchecked(id).as_ref().unwrap_unchecked().load })
     }
 
     #[inline(never)]
@@ -256,7 +325,7 @@ impl TokenManager {
         };
 
         let bytes = ::rkyv::to_bytes::<::rkyv::rancor::Error>(&helpers)?;
-        if bytes.len() > usize::MAX >> 1 {
+        if bytes.len() > (usize::MAX >> 1) - HEADER_LEN {
             return Err("Token data too large".into());
         }
 
@@ -267,10 +336,11 @@ impl TokenManager {
             .truncate(true)
             .open(&*TOKENS_FILE_PATH)
             .await?;
-        file.set_len(bytes.len() as u64).await?;
+        file.set_len((HEADER_LEN + bytes.len()) as u64).await?;
 
         let mut mmap = unsafe { MmapMut::map_mut(&file)? };
-        mmap.copy_from_slice(&bytes);
+        mmap[..HEADER_LEN].copy_from_slice(&TOKENS_FILE.header());
+        mmap[HEADER_LEN..].copy_from_slice(&bytes);
         mmap.flush()?;
 
         Ok(())
@@ -290,9 +360,13 @@ impl TokenManager {
             return Err("Token file too large".into());
         }
 
+        use ::rkyv::{from_bytes_unchecked, rancor::Error};
         let mmap = unsafe { Mmap::map(&file)? };
-        let helpers = unsafe {
-            ::rkyv::from_bytes_unchecked::<Vec<TokenInfoHelper>, ::rkyv::rancor::Error>(&mmap)
+        // Files written before the header are migrated, the next save writes the current version
+        let helpers: Vec<TokenInfoHelper> = match TOKENS_FILE.split(&mmap)? {
+            (0, bytes) => unsafe { from_bytes_unchecked::<Vec<TokenInfoHelperV0>, Error>(bytes) }
+                .map(|helpers| helpers.into_iter().map(Into::into).collect()),
+            (_, bytes) => unsafe { from_bytes_unchecked::<Vec<TokenInfoHelper>, Error>(bytes) },
         }
         .map_err(|_| "Load tokens failed")?;
         let mut manager = Self::new(helpers.len());
@@ -2,20 +2,17 @@ use super::{HashMap, TokenManager};
 #[cfg(not(feature = "horizon"))]
 use crate::app::model::{Randomness, UserId};
 use crate::{
-    app::model::{ExtToken, TokenKey},
-    common::utils::now_secs,
+    app::model::{AppConfig, ExtToken, SelectionStrategy, TokenInfo, TokenKey, TokenPolicy},
+    common::utils::{now, now_secs},
 };
-use core::sync::atomic::{AtomicUsize, Ordering};
-
-#[derive(
-    Clone,
-    Copy,
-    serde::Serialize,
-    serde::Deserialize,
-    rkyv::Archive,
-    rkyv::Serialize,
-    rkyv::Deserialize,
-)]
+use alloc::sync::Arc;
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use rand::Rng as _;
+
+#[derive(Clone, Copy, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct TokenHealth {
     pub backoff_until: u64,
     pub consecutive_failures: u32,
@@ -48,6 +45,141 @@ impl TokenHealth {
         self.consecutive_failures += 1;
         self.consecutive_failures
     }
+
+    #[inline]
+    pub const fn is_clear(&self) -> bool {
+        self.backoff_until == 0 && self.consecutive_failures == 0
+    }
+
+    /// Applies the outcome of a request made with the token
+    pub fn record(&mut self, event: HealthEvent) {
+        match event {
+            HealthEvent::Success => self.clear_backoff(),
+            HealthEvent::Unauthorized => {
+                self.inc_failures();
+                self.set_permanent_backoff();
+            }
+            HealthEvent::Throttled => {
+                let shift = (self.inc_failures() - 1).min(BACKOFF_MAX_SHIFT);
+                self.set_backoff((BACKOFF_BASE_SECS << shift).min(BACKOFF_MAX_SECS));
+            }
+        }
+    }
+
+    pub fn state(&self) -> &'static str {
+        match self.backoff_until {
+            u64::MAX => "suspended",
+            _ if self.is_available() => "available",
+            _ => "backoff",
+        }
+    }
+}
+
+impl serde::Serialize for TokenHealth {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("TokenHealth", 3)?;
+        state.serialize_field("state", self.state())?;
+        state.serialize_field("backoff_until", &self.backoff_until)?;
+        state.serialize_field("consecutive_failures", &self.consecutive_failures)?;
+        state.end()
+    }
+}
+
+/// First backoff after a throttled request, doubled on every consecutive one
+const BACKOFF_BASE_SECS: u64 = 30;
+const BACKOFF_MAX_SHIFT: u32 = 7;
+const BACKOFF_MAX_SECS: u64 = 3600;
+
+/// What an upstream request said about the token it was made with
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HealthEvent {
+    Success,
+    /// The token was rejected, it is kept out of the queue until re-enabled
+    Unauthorized,
+    /// Rate limited or out of usage, kept out for an exponential backoff
+    Throttled,
+}
+
+/// Requests a token is serving, kept in memory only and shared by the clones of its info
+#[derive(Default)]
+pub struct TokenLoad {
+    in_flight: AtomicU32,
+    /// Milliseconds since the epoch at which the token was last selected
+    last_used: AtomicU64,
+    /// Minute since the epoch in the high half and the selections made in it in the low half,
+    /// so that both change in one step
+    picks: AtomicU64,
+}
+
+impl TokenLoad {
+    #[inline]
+    pub fn in_flight(&self) -> u32 { self.in_flight.load(Ordering::Relaxed) }
+
+    #[inline]
+    pub fn last_used(&self) -> u64 { self.last_used.load(Ordering::Relaxed) }
+
+    /// Times the token was selected in the current minute
+    #[inline]
+    pub fn rpm(&self) -> u32 { picks_in(self.picks.load(Ordering::Relaxed), now_secs() / 60) }
+
+    /// Whether the token has room for another request under `policy`
+    #[inline]
+    pub fn has_capacity(&self, policy: &TokenPolicy) -> bool {
+        policy.max_concurrent.is_none_or(|max| self.in_flight() < max)
+            && policy.max_rpm.is_none_or(|max| self.rpm() < max)
+    }
+
+    /// Selects the token, counting the request as in flight until the returned guard is dropped
+    /// and as a pick of the current minute, `None` when it is at one of the limits of `policy`
+    ///
+    /// Each check and its count are one atomic step, so concurrent selections never exceed
+    /// either limit
+    pub fn try_acquire(self: &Arc<Self>, policy: &TokenPolicy) -> Option<InFlight> {
+        let max = policy.max_concurrent.unwrap_or(u32::MAX);
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < max).then_some(n + 1))
+            .ok()?;
+        // Given back when the request rate turns the selection down
+        let in_flight = InFlight(self.clone());
+
+        let now = now();
+        let minute = now.as_secs() / 60;
+        let max = policy.max_rpm.unwrap_or(u32::MAX);
+        self.picks
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |picks| {
+                let count = picks_in(picks, minute);
+                (count < max).then_some((minute << 32) | (count + 1) as u64)
+            })
+            .ok()?;
+        self.last_used.store(now.as_millis() as u64, Ordering::Relaxed);
+        Some(in_flight)
+    }
+}
+
+/// Selections counted by `picks` in `minute`
+#[inline]
+const fn picks_in(picks: u64, minute: u64) -> u32 {
+    if picks >> 32 == minute { picks as u32 } else { 0 }
+}
+
+impl serde::Serialize for TokenLoad {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct as _;
+        let mut state = serializer.serialize_struct("TokenLoad", 3)?;
+        state.serialize_field("in_flight", &self.in_flight())?;
+        state.serialize_field("last_used", &self.last_used())?;
+        state.serialize_field("rpm", &self.rpm())?;
+        state.end()
+    }
+}
+
+/// A request counted in the load of its token
+pub struct InFlight(Arc<TokenLoad>);
+
+impl Drop for InFlight {
+    #[inline]
+    fn drop(&mut self) { self.0.in_flight.fetch_sub(1, Ordering::Relaxed); }
 }
 
 #[cfg(not(feature = "horizon"))]
@@ -93,18 +225,29 @@ pub enum QueueType {
 impl QueueType {
     #[inline]
     pub const fn as_index(self) -> usize { self as usize }
+
+    /// Paid queues only hold the tokens of paid plans with usage left, free ones hold them all
+    #[inline]
+    pub const fn is_paid(self) -> bool {
+        matches!(self, QueueType::PrivilegedPaid | QueueType::NormalPaid)
+    }
 }
 
+/// Head pointer of each queue type
+type Heads = [AtomicUsize; 4];
+
 /// Global queue head pointer array, each queue type independently maintains polling position
 /// Uses static global variable instead of storing in TokenQueue to avoid borrow checking on each select
-static QUEUE_HEADS: [AtomicUsize; 4] =
+///
+/// Selections inside a group move the heads of that group instead
+static QUEUE_HEADS: Heads =
     [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
 
-/// Round-robin token selection queue
+/// Token selection queue
 ///
 /// Design points:
 /// - All tokens share the same vec, different queue types distinguish polling position through head pointer
-/// - Each select traverses from current head, skips unavailable tokens, updates head after finding one
+/// - Each select traverses from current head, skips unavailable tokens, updates head after the chosen one
 /// - When removing, need to adjust all heads to ensure pointers don't go out of bounds
 pub struct TokenQueue {
     #[cfg(not(feature = "horizon"))]
@@ -112,6 +255,8 @@ pub struct TokenQueue {
     #[cfg(feature = "horizon")]
     vec: Vec<usize>,
     map: HashMap<TokenKey, usize>, // TokenKey -> vec index, for O(1) lookup and deletion
+    /// Group -> its own head pointers, for every group that has a member
+    groups: HashMap<String, Heads>,
 }
 
 impl TokenQueue {
@@ -119,6 +264,47 @@ impl TokenQueue {
         Self {
             vec: Vec::with_capacity(capacity),
             map: HashMap::with_capacity_and_hasher(capacity, ahash::RandomState::new()),
+            groups: HashMap::default(),
+        }
+    }
+
+    /// Whether some token belongs to `group`
+    #[inline]
+    pub fn has_group(&self, group: &str) -> bool { self.groups.contains_key(group) }
+
+    /// Names of the groups that have a member
+    #[inline]
+    pub fn groups(&self) -> impl Iterator<Item = &str> { self.groups.keys().map(String::as_str) }
+
+    /// Gives `group` heads of its own if it has none yet
+    #[inline]
+    pub fn add_group(&mut self, group: &str) {
+        if !self.groups.contains_key(group) {
+            self.groups.insert(group.to_owned(), new_heads());
+        }
+    }
+
+    /// Keeps heads for exactly the groups in `groups`, existing ones keep their position
+    pub fn sync_groups<'a>(&mut self, groups: impl IntoIterator<Item = &'a str>) {
+        let mut synced = HashMap::default();
+        for group in groups {
+            if synced.contains_key(group) {
+                continue;
+            }
+            let heads = self.groups.remove(group).unwrap_or_else(new_heads);
+            synced.insert(group.to_owned(), heads);
+        }
+        self.groups = synced;
+    }
+
+    /// Moves every head that is after `vec_index` back by one, so it keeps pointing at the same
+    /// token once the one at `vec_index` is removed
+    fn shift_heads(&self, vec_index: usize) {
+        for head in QUEUE_HEADS.iter().chain(self.groups.values().flatten()) {
+            let current = head.load(Ordering::Acquire);
+            if current > vec_index {
+                head.store(current - 1, Ordering::Release);
+            }
         }
     }
 
@@ -151,16 +337,7 @@ impl TokenQueue {
 
         // Adjust head pointers of all queues: if head is after the deleted element, need to move forward by one
         // This ensures that after remove, the pointer still points to the correct relative position
-        // SAFETY: QueueType is repr(usize) enum, value range is 0..4, QUEUE_HEADS length is 4
-        unsafe {
-            for i in 0..4 {
-                let head = QUEUE_HEADS.get_unchecked(i);
-                let current = head.load(Ordering::Acquire);
-                if current > vec_index {
-                    head.store(current - 1, Ordering::Release);
-                }
-            }
-        }
+        self.shift_heads(vec_index);
 
         // Vec::remove will move subsequent elements forward, need to update their indices in map
         let removed = self.vec.remove(vec_index);
@@ -189,16 +366,7 @@ impl TokenQueue {
 
         // Adjust head pointers of all queues: if head is after the deleted element, need to move forward by one
         // This ensures that after remove, the pointer still points to the correct relative position
-        // SAFETY: QueueType is repr(usize) enum, value range is 0..4, QUEUE_HEADS length is 4
-        unsafe {
-            for i in 0..4 {
-                let head = QUEUE_HEADS.get_unchecked(i);
-                let current = head.load(Ordering::Acquire);
-                if current > vec_index {
-                    head.store(current - 1, Ordering::Release);
-                }
-            }
-        }
+        self.shift_heads(vec_index);
 
         // Vec::remove will move subsequent elements forward, need to update their indices in map
         let removed = self.vec.remove(vec_index);
@@ -223,72 +391,252 @@ impl TokenQueue {
         Some(removed)
     }
 
-    /// Round-robin select available token
+    /// Select an available token with the configured strategy, along with the in-flight slot
+    /// taken on it
     ///
     /// Algorithm:
-    /// 1. Poll from current queue's head
-    /// 2. Check if token is enabled and healthy
-    /// 3. After finding one, update head to next position
-    /// 4. Try at most one full round of vec to avoid infinite loop
-    pub fn select(&self, queue_type: QueueType, manager: &TokenManager) -> Option<ExtToken> {
+    /// 1. Walk one full round of vec from the current queue's head
+    /// 2. Keep tokens of the queue (and `group`) that are enabled, healthy, under their limits
+    ///    and not in `skip`
+    /// 3. Round-robin takes the first of them, the other strategies rank them all,
+    ///    ties go to the one nearest the head
+    /// 4. Take a slot on the chosen token, a token that filled up since it was checked is
+    ///    passed over and the choice made again
+    /// 5. Update head to the position after the chosen token
+    pub fn select(
+        &self,
+        queue_type: QueueType,
+        group: Option<&str>,
+        manager: &TokenManager,
+        skip: &[TokenKey],
+    ) -> Option<(ExtToken, InFlight)> {
         if self.vec.is_empty() {
             return None;
         }
 
-        // SAFETY: queue_type.as_index() is 0..4, QUEUE_HEADS length is 4
-        let head = unsafe { QUEUE_HEADS.get_unchecked(queue_type.as_index()) };
-        let start = head.load(Ordering::Relaxed);
+        let heads = match group {
+            Some(group) => self.groups.get(group)?,
+            None => &QUEUE_HEADS,
+        };
+        // SAFETY: queue_type.as_index() is 0..4, heads length is 4
+        let head = unsafe { heads.get_unchecked(queue_type.as_index()) };
         let len = self.vec.len();
 
-        // SAFETY: vec is not empty, len>=1, base is valid
-        let base = self.vec.as_ptr();
+        for _ in 0..len {
+            let start = head.load(Ordering::Relaxed);
+            let (index, token) = self.pick(start, queue_type, group, manager, skip)?;
+            let Some(in_flight) = token.load.try_acquire(&token.policy) else {
+                continue;
+            };
+
+            // Found available token, update head to next position
+            head.store((index + 1) % len, Ordering::Relaxed);
 
-        for i in 0..len {
+            return Some((token.bundle.clone(), in_flight));
+        }
+        None
+    }
+
+    /// Index and info of the token the configured strategy picks, walking from `start`
+    fn pick<'m>(
+        &self,
+        start: usize,
+        queue_type: QueueType,
+        group: Option<&str>,
+        manager: &'m TokenManager,
+        skip: &[TokenKey],
+    ) -> Option<(usize, &'m TokenInfo)> {
+        let len = self.vec.len();
+        let mut candidates = (0..len).filter_map(|i| {
             let index = (start + i) % len;
-            // SAFETY: index < len
-            let mgr_key = unsafe { *base.add(index) };
-            #[cfg(not(feature = "horizon"))]
-            let token = {
-                let token_key = mgr_key.token_key();
-
-                // First try to use hint (mgr_key.index) for fast lookup
-                // If token's key has changed, hint is invalid, need to lookup through id_map
-                let token_id = if let Some(token) = manager.get_by_id(mgr_key.index)
-                    && token.bundle.primary_token.key() == token_key
-                {
-                    mgr_key.index
-                } else {
-                    *manager.id_map().get(&token_key)?
-                };
-
-                let token = manager.get_by_id(token_id)?;
-
-                if !token.is_enabled() || !token.status.health.is_available() {
-                    continue;
+            let token = self.available(index, manager, queue_type, group)?;
+            if !skip.is_empty() && skip.contains(&token.bundle.primary_token.key()) {
+                return None;
+            }
+            Some((index, token))
+        });
+
+        Some(match AppConfig::token_selection() {
+            SelectionStrategy::RoundRobin => candidates.next()?,
+            SelectionStrategy::LeastRecentlyUsed => {
+                candidates.min_by_key(|(_, token)| token.load.last_used())?
+            }
+            SelectionStrategy::LeastInFlight => {
+                candidates.min_by_key(|(_, token)| token.load.in_flight())?
+            }
+            SelectionStrategy::Weighted => {
+                let mut rng = rand::rng();
+                let mut total = 0u64;
+                let mut picked = None;
+                for candidate in candidates {
+                    let weight = candidate.1.policy.weight as u64;
+                    if weight == 0 {
+                        continue;
+                    }
+                    total += weight;
+                    // Replacing the pick with chance weight/total leaves each token picked in
+                    // proportion to its weight
+                    if rng.random_range(0..total) < weight {
+                        picked = Some(candidate);
+                    }
                 }
+                picked?
+            }
+            SelectionStrategy::QuotaAware => {
+                let now = now_secs() as i64;
+                candidates.min_by_key(|(_, token)| Reverse(quota_remaining(token, now)))?
+            }
+        })
+    }
 
-                // Found available token, update head to next position
-                head.store((index + 1) % len, Ordering::Relaxed);
+    /// The token with `key` when it can take a request from `queue_type` (and `group`), counted
+    /// as selected like one of the queue and with a slot taken on it
+    pub fn select_key(
+        &self,
+        queue_type: QueueType,
+        group: Option<&str>,
+        key: &TokenKey,
+        manager: &TokenManager,
+    ) -> Option<(ExtToken, InFlight)> {
+        let token = self.available(*self.map.get(key)?, manager, queue_type, group)?;
+        let in_flight = token.load.try_acquire(&token.policy)?;
+        Some((token.bundle.clone(), in_flight))
+    }
 
-                token
+    /// The token at `index`, `None` when it is disabled, backing off, at one of its limits or not
+    /// a member of `queue_type` or `group`
+    #[inline]
+    fn available<'m>(
+        &self,
+        index: usize,
+        manager: &'m TokenManager,
+        queue_type: QueueType,
+        group: Option<&str>,
+    ) -> Option<&'m TokenInfo> {
+        // SAFETY: callers pass index < vec.len()
+        let mgr_key = unsafe { *self.vec.get_unchecked(index) };
+        #[cfg(not(feature = "horizon"))]
+        let token = {
+            let token_key = mgr_key.token_key();
+
+            // First try to use hint (mgr_key.index) for fast lookup
+            // If token's key has changed, hint is invalid, need to lookup through id_map
+            let token_id = if let Some(token) = manager.get_by_id(mgr_key.index)
+                && token.bundle.primary_token.key() == token_key
+            {
+                mgr_key.index
+            } else {
+                *manager.id_map().get(&token_key)?
             };
-            #[cfg(feature = "horizon")]
-            let token = {
-                let token =
-                    unsafe { manager.tokens.get_unchecked(mgr_key).as_ref().unwrap_unchecked() };
 
-                if !token.is_enabled() || !token.status.health.is_available() {
-                    continue;
-                }
+            manager.get_by_id(token_id)?
+        };
+        #[cfg(feature = "horizon")]
+        let token = unsafe { manager.tokens.get_unchecked(mgr_key).as_ref().unwrap_unchecked() };
+
+        (token.is_enabled()
+            && token.status.health.is_available()
+            && !token.is_suspended()
+            && token.load.has_capacity(&token.policy)
+            && (!queue_type.is_paid() || token.is_paid())
+            && group.is_none_or(|group| token.in_group(group)))
+        .then_some(token)
+    }
+}
 
-                // Found available token, update head to next position
-                head.store((index + 1) % len, Ordering::Relaxed);
+#[inline]
+const fn new_heads() -> Heads {
+    [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]
+}
 
-                token
-            };
-            return Some(token.bundle.clone());
+/// Plan usage left to `token` in its billing cycle, unknown usage ranks lowest and a cycle
+/// that has ended counts as renewed
+fn quota_remaining(token: &TokenInfo, now: i64) -> i64 {
+    let Some(ref usage) = token.usage else { return i64::MIN };
+    if usage.is_unlimited {
+        return i64::MAX;
+    }
+    let Some(plan) = usage.individual_usage.plan else { return i64::MIN };
+    if usage.billing_cycle_end.timestamp() <= now {
+        plan.limit as i64
+    } else {
+        plan.remaining as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled_backoff_doubles_up_to_max() {
+        let mut health = TokenHealth::new();
+        health.record(HealthEvent::Throttled);
+        let first = health.backoff_until - now_secs();
+        health.record(HealthEvent::Throttled);
+        let second = health.backoff_until - now_secs();
+        // One second may pass between setting and reading it
+        assert!(first.abs_diff(BACKOFF_BASE_SECS) <= 1);
+        assert!(second.abs_diff(BACKOFF_BASE_SECS * 2) <= 1);
+
+        for _ in 0..16 {
+            health.record(HealthEvent::Throttled);
         }
+        assert!(health.backoff_until - now_secs() <= BACKOFF_MAX_SECS);
+        assert_eq!(health.state(), "backoff");
 
-        None
+        health.record(HealthEvent::Success);
+        assert!(health.is_clear());
+
+        health.record(HealthEvent::Unauthorized);
+        assert_eq!(health.state(), "suspended");
+    }
+
+    #[test]
+    fn limits_free_up_when_requests_end() {
+        let load = Arc::new(TokenLoad::default());
+        let policy = TokenPolicy { weight: 1, max_concurrent: Some(1), max_rpm: Some(2) };
+        assert!(load.has_capacity(&policy));
+
+        let in_flight = __unwrap!(load.try_acquire(&policy));
+        assert!(!load.has_capacity(&policy));
+        assert!(load.try_acquire(&policy).is_none());
+        drop(in_flight);
+        assert!(load.has_capacity(&policy));
+
+        // A selection turned down for concurrency is not counted as a pick
+        drop(__unwrap!(load.try_acquire(&policy)));
+        assert_eq!(load.rpm(), 2);
+        assert!(!load.has_capacity(&policy));
+        assert!(load.try_acquire(&policy).is_none());
+        assert_eq!(load.in_flight(), 0);
+    }
+
+    #[test]
+    fn concurrent_acquires_stay_within_max() {
+        let load = Arc::new(TokenLoad::default());
+        let policy = TokenPolicy { weight: 1, max_concurrent: Some(3), max_rpm: None };
+        let acquired: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..16).map(|_| s.spawn(|| load.try_acquire(&policy))).collect();
+            handles.into_iter().filter_map(|handle| __unwrap!(handle.join())).collect()
+        });
+        assert_eq!(acquired.len(), 3);
+        assert_eq!(load.in_flight(), 3);
+        drop(acquired);
+        assert_eq!(load.in_flight(), 0);
+    }
+
+    #[test]
+    fn concurrent_picks_stay_within_max_rpm() {
+        let load = Arc::new(TokenLoad::default());
+        let policy = TokenPolicy { weight: 1, max_concurrent: None, max_rpm: Some(5) };
+        let picked: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..16).map(|_| s.spawn(|| load.try_acquire(&policy))).collect();
+            handles.into_iter().filter_map(|handle| __unwrap!(handle.join())).collect()
+        });
+        assert_eq!(picked.len(), 5);
+        assert_eq!(load.rpm(), 5);
+        // Selections turned down for the rate give their slot back
+        assert_eq!(load.in_flight(), 5);
     }
 }
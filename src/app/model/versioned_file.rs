@@ -0,0 +1,55 @@
+//! Header in front of the rkyv data of the files whose layout changes between versions
+
+/// Bytes the header takes, kept a multiple of the alignment of the archived data that follows
+pub const HEADER_LEN: usize = 16;
+
+/// Data file along with the layout version it is written in
+pub struct VersionedFile {
+    magic: [u8; 8],
+    /// Version written, the only one read back besides files from before the header
+    pub version: u32,
+}
+
+impl VersionedFile {
+    #[inline]
+    pub const fn new(magic: [u8; 8], version: u32) -> Self { Self { magic, version } }
+
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..8].copy_from_slice(&self.magic);
+        header[8..12].copy_from_slice(&self.version.to_le_bytes());
+        header
+    }
+
+    /// Version of the data in `bytes` along with the data, a file written before the header was
+    /// introduced being version 0. Files of any other version are rejected instead of misread
+    pub fn split<'a>(&self, bytes: &'a [u8]) -> Result<(u32, &'a [u8]), &'static str> {
+        if bytes.len() < HEADER_LEN || bytes[..8] != self.magic {
+            return Ok((0, bytes));
+        }
+        let version = u32::from_le_bytes(__unwrap!(bytes[8..12].try_into()));
+        if version != self.version {
+            return Err("Data file was written by a different version");
+        }
+        Ok((version, &bytes[HEADER_LEN..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_without_a_header_are_version_0() {
+        let file = VersionedFile::new(*b"TESTFILE", 2);
+        let mut bytes = file.header().to_vec();
+        bytes.extend_from_slice(b"data");
+        assert_eq!(file.split(&bytes), Ok((2, &b"data"[..])));
+        assert_eq!(file.split(b"legacy data"), Ok((0, &b"legacy data"[..])));
+
+        let older = VersionedFile::new(*b"TESTFILE", 1);
+        assert!(file.split(&older.header()).is_err());
+        let newer = VersionedFile::new(*b"TESTFILE", 3);
+        assert!(file.split(&newer.header()).is_err());
+    }
+}
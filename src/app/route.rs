@@ -1,39 +1,51 @@
 use super::{
     constant::{
+        ROUTE_BUDGETS_DELETE_PATH, ROUTE_BUDGETS_GET_PATH, ROUTE_BUDGETS_SET_PATH,
         ROUTE_BUILD_KEY_PATH, ROUTE_CHAT_COMPLETIONS_PATH, ROUTE_CONFIG_EXAMPLE_PATH,
         ROUTE_CONFIG_GET_PATH, ROUTE_CONFIG_RELOAD_PATH, ROUTE_CONFIG_SET_PATH,
         ROUTE_CONFIG_VERSION_GET_PATH, ROUTE_CPP_CONFIG_PATH, ROUTE_CPP_MODELS_PATH,
         ROUTE_CPP_STREAM_PATH, ROUTE_ENV_EXAMPLE_PATH, ROUTE_FILE_SYNC_PATH,
-        ROUTE_FILE_UPLOAD_PATH, ROUTE_GEN_CHECKSUM_PATH, ROUTE_GEN_HASH_PATH, ROUTE_GEN_UUID_PATH,
-        ROUTE_GET_CHECKSUM_HEADER_PATH, ROUTE_HEALTH_PATH, ROUTE_LICENSE_PATH, ROUTE_LOGS_GET_PATH,
-        ROUTE_LOGS_TOKENS_GET_PATH, ROUTE_MESSAGES_COUNT_TOKENS_PATH, ROUTE_MESSAGES_PATH,
-        ROUTE_MODELS_PATH, ROUTE_NTP_SYNC_ONCE_PATH, ROUTE_PROXIES_ADD_PATH,
+        ROUTE_FILE_UPLOAD_PATH, ROUTE_GEMINI_MODELS_PATH, ROUTE_GEN_CHECKSUM_PATH,
+        ROUTE_GEN_HASH_PATH, ROUTE_GEN_UUID_PATH, ROUTE_GET_CHECKSUM_HEADER_PATH,
+        ROUTE_HEALTH_PATH, ROUTE_KEYS_ADD_PATH, ROUTE_KEYS_DELETE_PATH, ROUTE_KEYS_GET_PATH,
+        ROUTE_KEYS_SET_PATH, ROUTE_LICENSE_PATH, ROUTE_LOGS_GET_PATH, ROUTE_LOGS_TOKENS_GET_PATH,
+        ROUTE_MESSAGES_COUNT_TOKENS_PATH, ROUTE_MESSAGES_PATH, ROUTE_MODELS_PATH,
+        ROUTE_NTP_SYNC_ONCE_PATH, ROUTE_OLLAMA_CHAT_PATH, ROUTE_OLLAMA_GENERATE_PATH,
+        ROUTE_OLLAMA_SHOW_PATH, ROUTE_OLLAMA_TAGS_PATH, ROUTE_PROXIES_ADD_PATH,
         ROUTE_PROXIES_DELETE_PATH, ROUTE_PROXIES_GET_PATH, ROUTE_PROXIES_SET_GENERAL_PATH,
-        ROUTE_PROXIES_SET_PATH, ROUTE_RAW_MODELS_PATH, ROUTE_README_PATH,
-        ROUTE_TOKEN_PROFILE_GET_PATH, ROUTE_TOKENS_ADD_PATH, ROUTE_TOKENS_ALIAS_SET_PATH,
+        ROUTE_PROXIES_SET_PATH, ROUTE_RAW_MODELS_PATH, ROUTE_README_PATH, ROUTE_RESPONSES_PATH,
+        ROUTE_REVOKED_KEYS_ADD_PATH, ROUTE_REVOKED_KEYS_GET_PATH, ROUTE_TOKEN_PROFILE_GET_PATH,
+        ROUTE_TOKENS_ADD_PATH, ROUTE_TOKENS_ALIAS_SET_PATH,
         ROUTE_TOKENS_CONFIG_VERSION_UPDATE_PATH, ROUTE_TOKENS_DELETE_PATH, ROUTE_TOKENS_GET_PATH,
+        ROUTE_TOKENS_GROUPS_GET_PATH, ROUTE_TOKENS_GROUPS_SET_PATH, ROUTE_TOKENS_LIMITS_SET_PATH,
         ROUTE_TOKENS_MERGE_PATH, ROUTE_TOKENS_PROFILE_UPDATE_PATH, ROUTE_TOKENS_PROXY_SET_PATH,
         ROUTE_TOKENS_REFRESH_PATH, ROUTE_TOKENS_SET_PATH, ROUTE_TOKENS_STATUS_SET_PATH,
-        ROUTE_TOKENS_TIMEZONE_SET_PATH,
+        ROUTE_TOKENS_SUSPENDED_GET_PATH, ROUTE_TOKENS_TIMEZONE_SET_PATH,
+        ROUTE_TOKENS_WEIGHT_SET_PATH,
     },
-    model::AppState,
+    model::{AppState, Permission},
 };
 use crate::{
     common::utils::parse_from_env,
     core::{
         auth::{
-            admin_auth_middleware, cpp_auth_middleware, v1_auth_middleware, v1_auth2_middleware,
+            admin_auth_middleware, cpp_auth_middleware, messages_auth_middleware,
+            ollama_auth_middleware, v1_auth_middleware, v1_auth2_middleware,
         },
         route::{
-            handle_add_proxy, handle_add_tokens, handle_build_key, handle_config_example,
+            handle_add_key, handle_add_proxy, handle_add_tokens, handle_build_key,
+            handle_config_example, handle_delete_budgets, handle_delete_keys,
             handle_delete_proxies, handle_delete_tokens, handle_env_example, handle_gen_checksum,
-            handle_gen_hash, handle_gen_uuid, handle_get_checksum_header, handle_get_config,
-            handle_get_config_version, handle_get_logs, handle_get_logs_tokens, handle_get_proxies,
-            handle_get_token_profile, handle_get_tokens, handle_health, handle_license,
-            handle_merge_tokens, handle_ntp_sync_once, handle_readme, handle_refresh_tokens,
-            handle_reload_config, handle_set_config, handle_set_general_proxy, handle_set_proxies,
-            handle_set_tokens, handle_set_tokens_alias, handle_set_tokens_proxy,
-            handle_set_tokens_status, handle_set_tokens_timezone,
+            handle_gen_hash, handle_gen_uuid, handle_get_budgets, handle_get_checksum_header,
+            handle_get_config, handle_get_config_version, handle_get_keys, handle_get_logs,
+            handle_get_logs_tokens, handle_get_proxies, handle_get_revoked_keys,
+            handle_get_suspended_tokens, handle_get_token_profile, handle_get_tokens,
+            handle_get_tokens_groups, handle_health, handle_license, handle_merge_tokens,
+            handle_ntp_sync_once, handle_readme, handle_refresh_tokens, handle_reload_config,
+            handle_revoke_keys, handle_set_budget, handle_set_config, handle_set_general_proxy,
+            handle_set_key, handle_set_proxies, handle_set_tokens, handle_set_tokens_alias,
+            handle_set_tokens_groups, handle_set_tokens_limits, handle_set_tokens_proxy,
+            handle_set_tokens_status, handle_set_tokens_timezone, handle_set_tokens_weight,
             handle_update_tokens_config_version, handle_update_tokens_profile,
         },
         service::{
@@ -41,15 +53,20 @@ use crate::{
                 handle_cpp_config, handle_cpp_models, handle_stream_cpp, handle_sync_file,
                 handle_upload_file,
             },
+            gemini::handle_generate_content,
             handle_chat_completions, handle_messages, handle_messages_count_tokens, handle_models,
             handle_raw_models,
+            ollama::{
+                handle_ollama_chat, handle_ollama_generate, handle_ollama_show, handle_ollama_tags,
+            },
+            responses::handle_responses,
         },
     },
 };
 use alloc::sync::Arc;
 use axum::{
     Router, middleware,
-    routing::{get, post},
+    routing::{MethodRouter, get, post},
 };
 use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer};
 
@@ -62,58 +79,162 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         }
     };
 
+    let admin = |path: &'static str, route: MethodRouter<Arc<AppState>>| {
+        route.route_layer(middleware::from_fn_with_state(
+            admin_permission(path),
+            admin_auth_middleware,
+        ))
+    };
+
     let mut backend = Router::new()
         .without_v07_checks()
         // .route(exchange_map.resolve(ROUTE_ROOT_PATH), get(handle_root))
         .route(exchange_map.resolve(ROUTE_HEALTH_PATH), get(handle_health))
         // .route(exchange_map.resolve(ROUTE_TOKENS_PATH), get(handle_tokens_page))
         // .route(exchange_map.resolve(ROUTE_PROXIES_PATH), get(handle_proxies_page))
-        .merge(
-            Router::new()
-                .without_v07_checks()
-                .route(exchange_map.resolve(ROUTE_CONFIG_GET_PATH), post(handle_get_config))
-                .route(exchange_map.resolve(ROUTE_CONFIG_SET_PATH), post(handle_set_config))
-                .route(exchange_map.resolve(ROUTE_CONFIG_RELOAD_PATH), get(handle_reload_config))
-                .route(exchange_map.resolve(ROUTE_TOKENS_GET_PATH), post(handle_get_tokens))
-                .route(exchange_map.resolve(ROUTE_TOKENS_SET_PATH), post(handle_set_tokens))
-                .route(exchange_map.resolve(ROUTE_TOKENS_ADD_PATH), post(handle_add_tokens))
-                .route(exchange_map.resolve(ROUTE_TOKENS_DELETE_PATH), post(handle_delete_tokens))
-                .route(exchange_map.resolve(ROUTE_TOKENS_MERGE_PATH), post(handle_merge_tokens))
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_ALIAS_SET_PATH),
-                    post(handle_set_tokens_alias),
-                )
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_PROFILE_UPDATE_PATH),
-                    post(handle_update_tokens_profile),
-                )
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_CONFIG_VERSION_UPDATE_PATH),
-                    post(handle_update_tokens_config_version),
-                )
-                .route(exchange_map.resolve(ROUTE_TOKENS_REFRESH_PATH), post(handle_refresh_tokens))
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_STATUS_SET_PATH),
-                    post(handle_set_tokens_status),
-                )
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_PROXY_SET_PATH),
-                    post(handle_set_tokens_proxy),
-                )
-                .route(
-                    exchange_map.resolve(ROUTE_TOKENS_TIMEZONE_SET_PATH),
-                    post(handle_set_tokens_timezone),
-                )
-                .route(exchange_map.resolve(ROUTE_PROXIES_GET_PATH), post(handle_get_proxies))
-                .route(exchange_map.resolve(ROUTE_PROXIES_SET_PATH), post(handle_set_proxies))
-                .route(exchange_map.resolve(ROUTE_PROXIES_ADD_PATH), post(handle_add_proxy))
-                .route(exchange_map.resolve(ROUTE_PROXIES_DELETE_PATH), post(handle_delete_proxies))
-                .route(
-                    exchange_map.resolve(ROUTE_PROXIES_SET_GENERAL_PATH),
-                    post(handle_set_general_proxy),
-                )
-                .route(exchange_map.resolve(ROUTE_NTP_SYNC_ONCE_PATH), get(handle_ntp_sync_once))
-                .route_layer(middleware::from_fn(admin_auth_middleware)),
+        // Admin endpoints, each checked against the permission `admin_permission` gives it
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_GET_PATH),
+            admin(ROUTE_TOKENS_GET_PATH, post(handle_get_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_SUSPENDED_GET_PATH),
+            admin(ROUTE_TOKENS_SUSPENDED_GET_PATH, post(handle_get_suspended_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_GROUPS_GET_PATH),
+            admin(ROUTE_TOKENS_GROUPS_GET_PATH, post(handle_get_tokens_groups)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_KEYS_GET_PATH),
+            admin(ROUTE_KEYS_GET_PATH, post(handle_get_keys)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_BUDGETS_GET_PATH),
+            admin(ROUTE_BUDGETS_GET_PATH, post(handle_get_budgets)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_REVOKED_KEYS_GET_PATH),
+            admin(ROUTE_REVOKED_KEYS_GET_PATH, post(handle_get_revoked_keys)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_PROXIES_GET_PATH),
+            admin(ROUTE_PROXIES_GET_PATH, post(handle_get_proxies)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_PROFILE_UPDATE_PATH),
+            admin(ROUTE_TOKENS_PROFILE_UPDATE_PATH, post(handle_update_tokens_profile)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_CONFIG_VERSION_UPDATE_PATH),
+            admin(
+                ROUTE_TOKENS_CONFIG_VERSION_UPDATE_PATH,
+                post(handle_update_tokens_config_version),
+            ),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_REFRESH_PATH),
+            admin(ROUTE_TOKENS_REFRESH_PATH, post(handle_refresh_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_STATUS_SET_PATH),
+            admin(ROUTE_TOKENS_STATUS_SET_PATH, post(handle_set_tokens_status)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_NTP_SYNC_ONCE_PATH),
+            admin(ROUTE_NTP_SYNC_ONCE_PATH, get(handle_ntp_sync_once)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_CONFIG_GET_PATH),
+            admin(ROUTE_CONFIG_GET_PATH, post(handle_get_config)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_CONFIG_SET_PATH),
+            admin(ROUTE_CONFIG_SET_PATH, post(handle_set_config)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_CONFIG_RELOAD_PATH),
+            admin(ROUTE_CONFIG_RELOAD_PATH, get(handle_reload_config)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_GROUPS_SET_PATH),
+            admin(ROUTE_TOKENS_GROUPS_SET_PATH, post(handle_set_tokens_groups)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_SET_PATH),
+            admin(ROUTE_TOKENS_SET_PATH, post(handle_set_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_ADD_PATH),
+            admin(ROUTE_TOKENS_ADD_PATH, post(handle_add_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_DELETE_PATH),
+            admin(ROUTE_TOKENS_DELETE_PATH, post(handle_delete_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_MERGE_PATH),
+            admin(ROUTE_TOKENS_MERGE_PATH, post(handle_merge_tokens)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_ALIAS_SET_PATH),
+            admin(ROUTE_TOKENS_ALIAS_SET_PATH, post(handle_set_tokens_alias)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_PROXY_SET_PATH),
+            admin(ROUTE_TOKENS_PROXY_SET_PATH, post(handle_set_tokens_proxy)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_TIMEZONE_SET_PATH),
+            admin(ROUTE_TOKENS_TIMEZONE_SET_PATH, post(handle_set_tokens_timezone)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_WEIGHT_SET_PATH),
+            admin(ROUTE_TOKENS_WEIGHT_SET_PATH, post(handle_set_tokens_weight)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_TOKENS_LIMITS_SET_PATH),
+            admin(ROUTE_TOKENS_LIMITS_SET_PATH, post(handle_set_tokens_limits)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_KEYS_ADD_PATH),
+            admin(ROUTE_KEYS_ADD_PATH, post(handle_add_key)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_KEYS_SET_PATH),
+            admin(ROUTE_KEYS_SET_PATH, post(handle_set_key)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_KEYS_DELETE_PATH),
+            admin(ROUTE_KEYS_DELETE_PATH, post(handle_delete_keys)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_BUDGETS_SET_PATH),
+            admin(ROUTE_BUDGETS_SET_PATH, post(handle_set_budget)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_BUDGETS_DELETE_PATH),
+            admin(ROUTE_BUDGETS_DELETE_PATH, post(handle_delete_budgets)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_REVOKED_KEYS_ADD_PATH),
+            admin(ROUTE_REVOKED_KEYS_ADD_PATH, post(handle_revoke_keys)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_PROXIES_SET_PATH),
+            admin(ROUTE_PROXIES_SET_PATH, post(handle_set_proxies)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_PROXIES_ADD_PATH),
+            admin(ROUTE_PROXIES_ADD_PATH, post(handle_add_proxy)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_PROXIES_DELETE_PATH),
+            admin(ROUTE_PROXIES_DELETE_PATH, post(handle_delete_proxies)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_PROXIES_SET_GENERAL_PATH),
+            admin(ROUTE_PROXIES_SET_GENERAL_PATH, post(handle_set_general_proxy)),
         )
         .merge(
             Router::new()
@@ -129,20 +250,46 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         .route(exchange_map.resolve(ROUTE_MODELS_PATH), get(handle_models))
         .route(
             exchange_map.resolve(ROUTE_MESSAGES_PATH),
-            post(handle_messages)
-                .route_layer(middleware::from_fn_with_state(state.clone(), v1_auth_middleware)),
+            post(handle_messages).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                messages_auth_middleware,
+            )),
         )
         .route(
             exchange_map.resolve(ROUTE_CHAT_COMPLETIONS_PATH),
             post(handle_chat_completions)
                 .route_layer(middleware::from_fn_with_state(state.clone(), v1_auth_middleware)),
         )
+        .route(
+            exchange_map.resolve(ROUTE_RESPONSES_PATH),
+            post(handle_responses)
+                .route_layer(middleware::from_fn_with_state(state.clone(), v1_auth_middleware)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_GEMINI_MODELS_PATH),
+            post(handle_generate_content)
+                .route_layer(middleware::from_fn_with_state(state.clone(), v1_auth_middleware)),
+        )
+        .route(exchange_map.resolve(ROUTE_OLLAMA_TAGS_PATH), get(handle_ollama_tags))
+        .route(exchange_map.resolve(ROUTE_OLLAMA_SHOW_PATH), post(handle_ollama_show))
+        .route(
+            exchange_map.resolve(ROUTE_OLLAMA_CHAT_PATH),
+            post(handle_ollama_chat)
+                .route_layer(middleware::from_fn_with_state(state.clone(), ollama_auth_middleware)),
+        )
+        .route(
+            exchange_map.resolve(ROUTE_OLLAMA_GENERATE_PATH),
+            post(handle_ollama_generate)
+                .route_layer(middleware::from_fn_with_state(state.clone(), ollama_auth_middleware)),
+        )
         .route(
             exchange_map.resolve(ROUTE_MESSAGES_COUNT_TOKENS_PATH),
             post(handle_messages_count_tokens)
                 .route_layer(middleware::from_fn_with_state(state.clone(), v1_auth2_middleware)),
         )
         // .route(exchange_map.resolve(ROUTE_LOGS_PATH), get(handle_logs))
+        // Not behind `admin`, a token or dynamic key may read the logs of its own token here;
+        // the handlers check the admin credentials for everything else
         .route(exchange_map.resolve(ROUTE_LOGS_GET_PATH), post(handle_get_logs))
         .route(exchange_map.resolve(ROUTE_LOGS_TOKENS_GET_PATH), post(handle_get_logs_tokens))
         .route(exchange_map.resolve(ROUTE_ENV_EXAMPLE_PATH), get(handle_env_example))
@@ -180,3 +327,51 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         .layer(CorsLayer::permissive())
         .with_state(state)
 }
+
+/// Permission an admin endpoint needs, anything not listed needing `Manage`
+fn admin_permission(path: &str) -> Permission {
+    match path {
+        ROUTE_TOKENS_GET_PATH
+        | ROUTE_TOKENS_SUSPENDED_GET_PATH
+        | ROUTE_TOKENS_GROUPS_GET_PATH
+        | ROUTE_KEYS_GET_PATH
+        | ROUTE_BUDGETS_GET_PATH
+        | ROUTE_REVOKED_KEYS_GET_PATH
+        | ROUTE_PROXIES_GET_PATH => Permission::Read,
+        ROUTE_TOKENS_PROFILE_UPDATE_PATH
+        | ROUTE_TOKENS_CONFIG_VERSION_UPDATE_PATH
+        | ROUTE_TOKENS_REFRESH_PATH
+        | ROUTE_TOKENS_STATUS_SET_PATH
+        | ROUTE_NTP_SYNC_ONCE_PATH => Permission::Operate,
+        _ => Permission::Manage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::model::AdminRole;
+
+    #[test]
+    fn admin_endpoints_are_gated_by_permission() {
+        assert_eq!(admin_permission(ROUTE_TOKENS_GET_PATH), Permission::Read);
+        assert_eq!(admin_permission(ROUTE_KEYS_GET_PATH), Permission::Read);
+        assert_eq!(admin_permission(ROUTE_TOKENS_REFRESH_PATH), Permission::Operate);
+        assert_eq!(admin_permission(ROUTE_TOKENS_STATUS_SET_PATH), Permission::Operate);
+        for path in [
+            ROUTE_CONFIG_GET_PATH,
+            ROUTE_CONFIG_SET_PATH,
+            ROUTE_TOKENS_ADD_PATH,
+            ROUTE_KEYS_ADD_PATH,
+            ROUTE_BUDGETS_SET_PATH,
+            ROUTE_REVOKED_KEYS_ADD_PATH,
+        ] {
+            assert_eq!(admin_permission(path), Permission::Manage, "{path}");
+        }
+
+        // A viewer gets to list but not to change anything
+        assert!(AdminRole::Viewer.grants(admin_permission(ROUTE_TOKENS_GET_PATH)));
+        assert!(!AdminRole::Viewer.grants(admin_permission(ROUTE_TOKENS_REFRESH_PATH)));
+        assert!(!AdminRole::Operator.grants(admin_permission(ROUTE_TOKENS_ADD_PATH)));
+    }
+}
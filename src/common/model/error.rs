@@ -1,7 +1,7 @@
 use super::GenericError;
 use crate::core::{
     error::ErrorExt,
-    model::{anthropic, openai},
+    model::{anthropic, gemini, ollama, openai},
 };
 use alloc::borrow::Cow;
 use http::StatusCode;
@@ -11,6 +11,10 @@ pub enum ChatError {
     EmptyMessages(StatusCode),
     RequestFailed(StatusCode, Cow<'static, str>),
     ProcessingFailed(Cow<'static, str>),
+    InvalidToolChoice(Cow<'static, str>),
+    ToolCallMissing,
+    InvalidJsonOutput(String),
+    InvalidChoiceCount(u32),
 }
 
 impl ChatError {
@@ -21,6 +25,10 @@ impl ChatError {
             Self::EmptyMessages(_) => "empty_messages",
             Self::RequestFailed(_, _) => "request_failed",
             Self::ProcessingFailed(_) => "processing_failed",
+            Self::InvalidToolChoice(_) => "invalid_tool_choice",
+            Self::ToolCallMissing => "tool_call_missing",
+            Self::InvalidJsonOutput(_) => "invalid_json_output",
+            Self::InvalidChoiceCount(_) => "invalid_choice_count",
         }
     }
 }
@@ -33,6 +41,16 @@ impl core::fmt::Display for ChatError {
             Self::EmptyMessages(_) => write!(f, "Message array cannot be empty"),
             Self::RequestFailed(_, err) => write!(f, "Request failed: {err}"),
             Self::ProcessingFailed(err) => write!(f, "Processing failed: {err}"),
+            Self::InvalidToolChoice(err) => write!(f, "Invalid tool_choice: {err}"),
+            Self::ToolCallMissing => {
+                write!(f, "Model did not call a tool although tool_choice requires one")
+            }
+            Self::InvalidJsonOutput(err) => {
+                write!(f, "Model output does not match the requested format: {err}")
+            }
+            Self::InvalidChoiceCount(n) => {
+                write!(f, "n must be between 1 and {}, got {n}", openai::MAX_CHOICES)
+            }
         }
     }
 }
@@ -45,6 +63,10 @@ impl ChatError {
             Self::EmptyMessages(sc) => sc,
             Self::RequestFailed(sc, _) => sc,
             Self::ProcessingFailed(_) => StatusCode::BAD_GATEWAY,
+            Self::InvalidToolChoice(_) => StatusCode::BAD_REQUEST,
+            Self::ToolCallMissing => StatusCode::BAD_GATEWAY,
+            Self::InvalidJsonOutput(_) => StatusCode::BAD_GATEWAY,
+            Self::InvalidChoiceCount(_) => StatusCode::BAD_REQUEST,
         }
     }
 
@@ -75,6 +97,16 @@ impl ChatError {
         }
         .wrapped()
     }
+
+    #[inline]
+    fn to_gemini(&self) -> gemini::GeminiError {
+        gemini::GeminiError { code: self.status_code(), message: Cow::Owned(self.to_string()) }
+    }
+
+    #[inline]
+    fn to_ollama(&self) -> ollama::OllamaError {
+        ollama::OllamaError { message: Cow::Owned(self.to_string()) }
+    }
 }
 
 impl ErrorExt for ChatError {
@@ -90,4 +122,12 @@ impl ErrorExt for ChatError {
     fn into_anthropic_tuple(self) -> (http::StatusCode, axum::Json<anthropic::AnthropicError>) {
         (self.status_code(), axum::Json(self.to_anthropic()))
     }
+    #[inline]
+    fn into_gemini_tuple(self) -> (http::StatusCode, axum::Json<gemini::GeminiError>) {
+        (self.status_code(), axum::Json(self.to_gemini()))
+    }
+    #[inline]
+    fn into_ollama_tuple(self) -> (http::StatusCode, axum::Json<ollama::OllamaError>) {
+        (self.status_code(), axum::Json(self.to_ollama()))
+    }
 }
@@ -361,11 +361,23 @@ pub async fn get_available_models(
     AvailableModelsResponse::decode(response.as_ref()).ok()
 }
 
+#[inline]
 pub async fn get_token_usage(
     ext_token: ExtToken,
     use_pri: bool,
     time: DateTime,
     model_id: &'static str,
+) -> Option<ChainUsage> {
+    get_token_usages(ext_token, use_pri, time, model_id, 1).await
+}
+
+/// Sums the usage of the latest `count` requests made with the token since `time`
+pub async fn get_token_usages(
+    ext_token: ExtToken,
+    use_pri: bool,
+    time: DateTime,
+    model_id: &'static str,
+    count: usize,
 ) -> Option<ChainUsage> {
     const POLL_MAX_ATTEMPTS: usize = 5;
     const POLL_INTERVAL: Duration = Duration::from_secs(1);
@@ -400,15 +412,19 @@ pub async fn get_token_usage(
         tokio::time::sleep(POLL_INTERVAL).await;
         let res = get_filtered_usage_events(request).await?;
 
-        if let Some(first) = res.usage_events_display.first()
-            && let Some(usage) = first.token_usage
+        if let Some(events) = res.usage_events_display.get(..count)
+            && let Some(usage) = events
+                .iter()
+                .map(|event| event.token_usage.map(ChainUsage::from))
+                .reduce(|a, b| a.zip(b).map(|(a, b)| a + b))
+                .flatten()
         {
             token_usage = Some(usage);
             break;
         };
     }
 
-    token_usage.map(Into::into)
+    token_usage
 }
 
 // pub fn validate_token_and_checksum(auth_token: &str) -> Option<(String, Checksum)> {
@@ -495,6 +511,10 @@ pub async fn get_token_usage(
 #[inline(always)]
 pub fn format_time_ms(seconds: f64) -> f64 { (seconds * 1000.0).round() / 1000.0 }
 
+/// Rough token count for text the upstream usage does not break down, such as thinking
+#[inline]
+pub fn approx_token_count(text: &str) -> i32 { text.len().div_ceil(4) as i32 }
+
 /// Convert JWT token to TokenInfo
 #[inline]
 pub fn token_to_tokeninfo(
@@ -519,10 +539,10 @@ pub fn token_to_tokeninfo(
     }
 }
 
-/// Convert TokenInfo to JWT token
+/// Convert TokenInfo to JWT token, `raw` being its verified token
 #[inline]
-pub fn tokeninfo_to_token(tuple: (configured_key::TokenInfo, [u8; 32])) -> Option<ExtToken> {
-    let (info, hash) = tuple;
+pub fn tokeninfo_to_token(tuple: (configured_key::TokenInfo, RawToken)) -> Option<ExtToken> {
+    let (info, raw) = tuple;
     let checksum = Checksum::from_bytes(info.checksum);
     let client_key = Hash::from_bytes(info.client_key);
     let config_version = info.config_version.and_then(|v| uuid::Uuid::from_slice(&v).ok());
@@ -530,7 +550,7 @@ pub fn tokeninfo_to_token(tuple: (configured_key::TokenInfo, [u8; 32])) -> Optio
     let timezone = info.timezone.and_then(|s| core::str::FromStr::from_str(&s).ok());
     let gcpp_host = info.gcpp_host.and_then(GcppHost::from_u8);
     Some(ExtToken {
-        primary_token: Token::new(info.token.validate(hash)?, None),
+        primary_token: Token::new(raw, None),
         secondary_token: None,
         checksum,
         client_key,
@@ -585,9 +605,38 @@ fn generate_pkce_pair() -> ([u8; 43], [u8; 43]) {
     }
 }
 
-pub async fn get_new_token(mut writer: TokenWriter<'_>, use_pri: bool) -> bool {
+/// Why a token could not be refreshed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RefreshFailure {
+    /// Upstream turned the token down as unauthorized
+    Rejected,
+    /// The request failed or got an answer that says nothing about the token
+    Failed,
+}
+
+impl RefreshFailure {
+    #[inline]
+    fn of(status: http::StatusCode) -> Self {
+        match status {
+            http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN => Self::Rejected,
+            _ => Self::Failed,
+        }
+    }
+}
+
+pub async fn get_new_token(
+    mut writer: TokenWriter<'_>,
+    use_pri: bool,
+) -> Result<(), RefreshFailure> {
+    refresh_ext_token(&mut **writer, use_pri).await
+}
+
+/// Replaces the primary token of `ext_token` with a fresh one
+pub async fn refresh_ext_token(
+    ext_token: &mut ExtToken,
+    use_pri: bool,
+) -> Result<(), RefreshFailure> {
     // Initiate refresh request
-    let ext_token = &mut **writer;
     let is_session = ext_token.primary_token.is_session();
 
     match if is_session {
@@ -595,30 +644,25 @@ pub async fn get_new_token(mut writer: TokenWriter<'_>, use_pri: bool) -> bool {
     } else {
         upgrade_token(ext_token, use_pri).await
     } {
-        Some(new_token) => {
+        Ok(new_token) => {
             if !is_session && ext_token.secondary_token.is_none() {
                 let old_token = core::mem::replace(&mut ext_token.primary_token, new_token);
                 ext_token.secondary_token = Some(old_token);
             } else {
                 ext_token.primary_token = new_token;
             }
-            true
+            Ok(())
         }
-        None => {
-            if is_session
-                && ext_token.secondary_token.is_some()
-                && let Some(new_token) = upgrade_token(ext_token, use_pri).await
-            {
-                ext_token.primary_token = new_token;
-                true
-            } else {
-                false
-            }
+        // The outcome of the upgrade stands for both when the session falls back to it
+        Err(_) if is_session && ext_token.secondary_token.is_some() => {
+            ext_token.primary_token = upgrade_token(ext_token, use_pri).await?;
+            Ok(())
         }
+        Err(e) => Err(e),
     }
 }
 
-async fn upgrade_token(ext_token: &ExtToken, use_pri: bool) -> Option<Token> {
+async fn upgrade_token(ext_token: &ExtToken, use_pri: bool) -> Result<Token, RefreshFailure> {
     const POLL_MAX_ATTEMPTS: usize = 5;
     const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
@@ -648,12 +692,12 @@ async fn upgrade_token(ext_token: &ExtToken, use_pri: bool) -> Option<Token> {
     )
     .send()
     .await
-    .ok()?;
+    .map_err(|_| RefreshFailure::Failed)?;
 
     let status = upgrade_response.status();
     crate::debug!("<upgrade_token1> {}", status);
     if !status.is_success() {
-        return None;
+        return Err(RefreshFailure::of(status));
     }
 
     let mut url = token_poll_url(use_pri).clone();
@@ -665,26 +709,28 @@ async fn upgrade_token(ext_token: &ExtToken, use_pri: bool) -> Option<Token> {
         RequestBuilderClone,
         POLL_MAX_ATTEMPTS,
     ) {
-        let poll_response = request.send().await.ok()?;
+        let poll_response = request.send().await.map_err(|_| RefreshFailure::Failed)?;
 
         let status = poll_response.status();
         crate::debug!("<upgrade_token2> {}", status);
         match status {
             http::StatusCode::OK => {
-                let token = poll_response.json::<PollResponse>().await.ok()?.access_token;
-                return Some(token);
+                let response = poll_response.json::<PollResponse>().await;
+                return response
+                    .map(|response| response.access_token)
+                    .map_err(|_| RefreshFailure::Failed);
             }
             http::StatusCode::NOT_FOUND => {
                 tokio::time::sleep(POLL_INTERVAL).await;
             }
-            _ => return None,
+            status => return Err(RefreshFailure::of(status)),
         }
     }
 
-    None
+    Err(RefreshFailure::Failed)
 }
 
-async fn refresh_token(ext_token: &ExtToken, use_pri: bool) -> Option<Token> {
+async fn refresh_token(ext_token: &ExtToken, use_pri: bool) -> Result<Token, RefreshFailure> {
     const CLIENT_ID: &str = "KbZUR41cY7W6zRSdpSUJ7I7mLYBKOCmB";
 
     struct RefreshTokenRequest<'a> {
@@ -713,19 +759,22 @@ async fn refresh_token(ext_token: &ExtToken, use_pri: bool) -> Option<Token> {
 
     let refresh_request = RefreshTokenRequest { refresh_token: ext_token.primary_token.as_str() };
 
-    let body = serde_json::to_vec(&refresh_request).ok()?;
+    let body = serde_json::to_vec(&refresh_request).map_err(|_| RefreshFailure::Failed)?;
 
     let response =
         super::client::build_token_refresh_request(&ext_token.get_client(), use_pri, body)
             .send()
             .await
-            .ok()?;
+            .map_err(|_| RefreshFailure::Failed)?;
 
-    crate::debug!("<refresh_token> {}", response.status());
-
-    let token = response.json::<RefreshTokenResponse>().await.ok()?.access_token;
+    let status = response.status();
+    crate::debug!("<refresh_token> {}", status);
+    if !status.is_success() {
+        return Err(RefreshFailure::of(status));
+    }
 
-    Some(token)
+    let response = response.json::<RefreshTokenResponse>().await;
+    response.map(|response| response.access_token).map_err(|_| RefreshFailure::Failed)
 }
 
 pub async fn get_server_config(ext_token: ExtToken, use_pri: bool) -> Option<uuid::Uuid> {
@@ -17,8 +17,8 @@ use crate::{
             ExtModel, IndexMap, Role,
             anthropic::{
                 ContentBlockParam, DocumentSource, ImageSource, ImageSourceBase64, MediaType,
-                MessageContent, MessageParam, SystemContent, Tool, ToolResultContent,
-                ToolResultContentBlock,
+                MessageContent, MessageParam, SystemContent, TextBlockParam, Tool,
+                ToolResultContent, ToolResultContentBlock,
             },
         },
     },
@@ -458,3 +458,24 @@ pub async fn encode_tool_result(
     let message = Anthropic::encode_tool_result(tool_result, tool_use_id, tool_name).await?;
     encode_message_framed(&message).map_err(Into::into)
 }
+
+/// Appends `text` to the system prompt, starting from the default instructions if there is none
+pub fn append_instructions(
+    system: &mut Option<SystemContent>,
+    text: String,
+    now: chrono::DateTime<chrono_tz::Tz>,
+) {
+    match system {
+        Some(SystemContent::String(instructions)) => {
+            instructions.push_str(NEWLINE);
+            instructions.push_str(&text);
+        }
+        Some(SystemContent::Array(contents)) => contents.push(TextBlockParam { text }),
+        None => {
+            let mut instructions = DEFAULT_INSTRUCTIONS.get().get(now);
+            instructions.push_str(NEWLINE);
+            instructions.push_str(&text);
+            *system = Some(SystemContent::String(instructions));
+        }
+    }
+}
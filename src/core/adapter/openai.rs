@@ -398,3 +398,19 @@ pub async fn encode_tool_result(
     let message = Openai::encode_tool_result(content, tool_call_id, tool_name).await?;
     encode_message_framed(&message).map_err(Into::into)
 }
+
+/// Appends `text` to the system instructions, keeping the default ones if there are none
+pub fn append_instructions(
+    params: &mut Vec<ChatCompletionMessageParam>,
+    text: String,
+    now: chrono::DateTime<chrono_tz::Tz>,
+) {
+    if !params.iter().any(|param| matches!(param, ChatCompletionMessageParam::System { .. })) {
+        params.push(ChatCompletionMessageParam::System {
+            content: ChatCompletionContentText::String(DEFAULT_INSTRUCTIONS.get().get(now)),
+        });
+    }
+    params.push(ChatCompletionMessageParam::System {
+        content: ChatCompletionContentText::String(text),
+    });
+}
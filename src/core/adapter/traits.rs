@@ -11,7 +11,7 @@ use super::{
     },
     AGENT_MODE_NAME, ASK_MODE_NAME, AdapterError, BaseUuid, Messages, WEB_SEARCH_MODE,
     is_animated_gif, process_http_image,
-    utils::{ToolId, ToolResultBuilder},
+    utils::{ToolId, ToolName, ToolResultBuilder},
 };
 use crate::app::model::{AppConfig, VisionAbility, create_explicit_context};
 use byte_str::ByteStr;
@@ -201,14 +201,7 @@ pub(super) trait Adapter: Sized + 'static {
                         ),
                         // tools_requiring_accepted_return: supported_tools,
                         should_disable_tools: Some(is_chat),
-                        thinking_level: Some(
-                            if model.is_thinking {
-                                stream_unified_chat_request::ThinkingLevel::High
-                            } else {
-                                stream_unified_chat_request::ThinkingLevel::Unspecified
-                            }
-                            .into(),
-                        ),
+                        thinking_level: Some(model.thinking_level().into()),
                         uses_rules: Some(false),
                         // mode_uses_auto_apply: Some(false),
                         unified_mode_name: Some(ByteStr::from_static(if is_chat {
@@ -225,6 +218,7 @@ pub(super) trait Adapter: Sized + 'static {
 
         Ok(message)
     }
+    /// `tool_name` is the name the client was given for the call
     async fn encode_tool_result(
         tool_result: Self::ToolResult,
         tool_call_id: ByteStr,
@@ -232,6 +226,7 @@ pub(super) trait Adapter: Sized + 'static {
     ) -> Result<StreamUnifiedChatRequestWithTools, AdapterError> {
         let result = tool_result.result().await?;
         let tool_id = ToolId::parse(tool_call_id);
+        let ToolName { name: tool_name, .. } = ToolName::parse(tool_name);
         Ok(StreamUnifiedChatRequestWithTools {
             request: Some(stream_unified_chat_request_with_tools::Request::ClientSideToolV2Result(
                 Box::new(ClientSideToolV2Result {
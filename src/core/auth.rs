@@ -1,11 +1,16 @@
+mod affinity;
 mod error;
 mod middleware;
 mod model;
+mod rate_limit;
 mod utils;
 
+pub use affinity::Affinity;
 pub use error::AuthError;
 pub use middleware::{
-    admin_auth_middleware, cpp_auth_middleware, v1_auth_middleware, v1_auth2_middleware,
+    admin_auth_middleware, cpp_auth_middleware, messages_auth_middleware, ollama_auth_middleware,
+    v1_auth_middleware, v1_auth2_middleware,
 };
-pub use model::{TokenBundle, TokenBundleResult};
-pub use utils::auth;
+pub use model::{TokenBundle, TokenBundleResult, TokenPool};
+pub use rate_limit::RateLimits;
+pub use utils::{admin_role, auth};
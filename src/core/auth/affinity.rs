@@ -0,0 +1,167 @@
+//! Conversations pinned to the pooled token of their last turn, so follow-ups reuse its cache
+
+use crate::app::{constant::header::SESSION_ID, lazy::STICKY_SESSION_TTL, model::TokenKey};
+use alloc::borrow::Cow;
+use core::hash::Hasher as _;
+use manually_init::ManuallyInit;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::{hash::DefaultHasher, time::Instant};
+
+type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
+
+/// Identifies a conversation across its turns
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(u64);
+
+#[derive(Deserialize)]
+struct Conversation<'a> {
+    #[serde(borrow, default)]
+    user: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    metadata: Option<Metadata<'a>>,
+    #[serde(borrow, default)]
+    system: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    messages: Vec<Message<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Metadata<'a> {
+    #[serde(borrow, default)]
+    user_id: Option<Cow<'a, str>>,
+}
+
+#[derive(Deserialize)]
+struct Message<'a> {
+    #[serde(borrow)]
+    role: Cow<'a, str>,
+    #[serde(borrow, default)]
+    content: Option<&'a RawValue>,
+}
+
+impl Fingerprint {
+    /// The `X-Session-Id` header of a request
+    pub fn from_headers(headers: &http::HeaderMap) -> Option<Self> {
+        let id = headers.get(SESSION_ID)?;
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u8(0);
+        hasher.write(id.as_bytes());
+        Some(Self(hasher.finish()))
+    }
+
+    /// The `user` (`metadata.user_id` for `/v1/messages`) of a request body, otherwise its
+    /// system prompt along with the first user message
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let conversation: Conversation = serde_json::from_slice(body).ok()?;
+        let mut hasher = DefaultHasher::new();
+
+        if let Some(user) = conversation
+            .user
+            .or_else(|| conversation.metadata.and_then(|metadata| metadata.user_id))
+            .filter(|user| !user.is_empty())
+        {
+            hasher.write_u8(1);
+            hasher.write(user.as_bytes());
+            return Some(Self(hasher.finish()));
+        }
+
+        let system = conversation.system.or_else(|| {
+            conversation
+                .messages
+                .iter()
+                .find(|message| message.role == "system" || message.role == "developer")
+                .and_then(|message| message.content)
+        });
+        let first_user =
+            conversation.messages.iter().find(|message| message.role == "user")?.content?;
+        hasher.write_u8(2);
+        if let Some(system) = system {
+            hasher.write(system.get().as_bytes());
+        }
+        hasher.write_u8(0);
+        hasher.write(first_user.get().as_bytes());
+        Some(Self(hasher.finish()))
+    }
+}
+
+struct Pinned {
+    key: TokenKey,
+    last_seen: Instant,
+}
+
+struct Pins {
+    map: HashMap<Fingerprint, Pinned>,
+    /// Size at which expired pins are swept out next
+    sweep_at: usize,
+}
+
+pub struct Affinity {
+    inner: Mutex<Pins>,
+}
+
+const MIN_SWEEP_AT: usize = 256;
+
+impl Affinity {
+    pub fn init() {
+        AFFINITY.init(Affinity {
+            inner: Mutex::new(Pins {
+                map: HashMap::with_capacity_and_hasher(16, ahash::RandomState::new()),
+                sweep_at: MIN_SWEEP_AT,
+            }),
+        })
+    }
+
+    /// Whether conversations are pinned to tokens at all
+    #[inline]
+    pub fn is_enabled() -> bool { STICKY_SESSION_TTL.to_duration().is_some() }
+
+    /// Token `fingerprint` is pinned to, the pin lives on for another TTL
+    pub fn get(fingerprint: Fingerprint) -> Option<TokenKey> {
+        let ttl = STICKY_SESSION_TTL.to_duration()?;
+        let mut pins = AFFINITY.inner.lock();
+        let pinned = pins.map.get_mut(&fingerprint)?;
+        let now = Instant::now();
+        if now.duration_since(pinned.last_seen) > ttl {
+            pins.map.remove(&fingerprint);
+            return None;
+        }
+        pinned.last_seen = now;
+        Some(pinned.key)
+    }
+
+    /// Pins `fingerprint` to the token with `key`, replacing its previous token
+    pub fn pin(fingerprint: Fingerprint, key: TokenKey) {
+        let Some(ttl) = STICKY_SESSION_TTL.to_duration() else { return };
+        let now = Instant::now();
+        let mut pins = AFFINITY.inner.lock();
+        pins.map.insert(fingerprint, Pinned { key, last_seen: now });
+
+        if pins.map.len() >= pins.sweep_at {
+            pins.map.retain(|_, pinned| now.duration_since(pinned.last_seen) <= ttl);
+            pins.sweep_at = (pins.map.len() * 2).max(MIN_SWEEP_AT);
+        }
+    }
+}
+
+static AFFINITY: ManuallyInit<Affinity> = ManuallyInit::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_turns_share_the_fingerprint_of_the_first() {
+        let first = br#"{"messages":[{"role":"system","content":"Be brief"},{"role":"user","content":"Hi"}]}"#;
+        let later = br#"{"messages":[{"role":"system","content":"Be brief"},{"role":"user","content":"Hi"},{"role":"assistant","content":"Hello"},{"role":"user","content":"Bye"}]}"#;
+        let other = br#"{"messages":[{"role":"system","content":"Be long"},{"role":"user","content":"Hi"}]}"#;
+        assert!(Fingerprint::from_body(first) == Fingerprint::from_body(later));
+        assert!(Fingerprint::from_body(first) != Fingerprint::from_body(other));
+
+        let anthropic = br#"{"system":"Be brief","metadata":{"user_id":"u1"},"messages":[]}"#;
+        let openai = br#"{"user":"u1","messages":[{"role":"user","content":"Hey"}]}"#;
+        assert!(Fingerprint::from_body(anthropic).is_some());
+        assert!(Fingerprint::from_body(anthropic) == Fingerprint::from_body(openai));
+    }
+}
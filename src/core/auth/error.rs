@@ -4,8 +4,9 @@ use http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 
+use crate::app::model::{ApiKeyDenial, BudgetExceeded, Permission};
 use crate::core::error::ErrorExt;
-use crate::core::model::{anthropic, openai};
+use crate::core::model::{anthropic, gemini, ollama, openai};
 use crate::common::model::{ApiStatus, GenericError};
 
 /// Authentication and authorization errors
@@ -19,6 +20,42 @@ pub enum AuthError {
 
     /// Token alias not found (admin tokens only)
     AliasNotFound,
+
+    /// No token belongs to the group
+    GroupNotFound,
+
+    /// API key turned off by an admin
+    KeyDisabled,
+
+    /// API key past its expiry
+    KeyExpired,
+
+    /// Key not allowed on this route family
+    EndpointNotAllowed,
+
+    /// Key not allowed to use the model
+    ModelNotAllowed,
+
+    /// API key used up its requests for the day
+    DailyQuotaExceeded,
+
+    /// Key spent its budget for the day
+    DailyBudgetExceeded,
+
+    /// Key spent its budget for the month
+    MonthlyBudgetExceeded,
+
+    /// Key spent its lifetime budget
+    BudgetExhausted,
+
+    /// Dynamic key revoked by an admin
+    KeyRevoked,
+
+    /// Dynamic key made more requests this minute than it allows
+    RateLimited,
+
+    /// Admin credential whose role lacks the permission
+    PermissionDenied(Permission),
 }
 
 impl AuthError {
@@ -29,6 +66,18 @@ impl AuthError {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::NoAvailableTokens => StatusCode::SERVICE_UNAVAILABLE,
             Self::AliasNotFound => StatusCode::NOT_FOUND,
+            Self::GroupNotFound => StatusCode::NOT_FOUND,
+            Self::KeyDisabled => StatusCode::FORBIDDEN,
+            Self::KeyExpired => StatusCode::UNAUTHORIZED,
+            Self::EndpointNotAllowed => StatusCode::FORBIDDEN,
+            Self::ModelNotAllowed => StatusCode::FORBIDDEN,
+            Self::DailyQuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            Self::DailyBudgetExceeded => StatusCode::TOO_MANY_REQUESTS,
+            Self::MonthlyBudgetExceeded => StatusCode::TOO_MANY_REQUESTS,
+            Self::BudgetExhausted => StatusCode::PAYMENT_REQUIRED,
+            Self::KeyRevoked => StatusCode::UNAUTHORIZED,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
         }
     }
 
@@ -39,6 +88,18 @@ impl AuthError {
             Self::Unauthorized => "unauthorized",
             Self::NoAvailableTokens => "no_available_tokens",
             Self::AliasNotFound => "alias_not_found",
+            Self::GroupNotFound => "group_not_found",
+            Self::KeyDisabled => "key_disabled",
+            Self::KeyExpired => "key_expired",
+            Self::EndpointNotAllowed => "endpoint_not_allowed",
+            Self::ModelNotAllowed => "model_not_allowed",
+            Self::DailyQuotaExceeded => "daily_quota_exceeded",
+            Self::DailyBudgetExceeded => "daily_budget_exceeded",
+            Self::MonthlyBudgetExceeded => "monthly_budget_exceeded",
+            Self::BudgetExhausted => "budget_exhausted",
+            Self::KeyRevoked => "key_revoked",
+            Self::RateLimited => "rate_limit_exceeded",
+            Self::PermissionDenied(_) => "permission_denied",
         }
     }
 
@@ -49,6 +110,46 @@ impl AuthError {
             Self::Unauthorized => "Invalid authorization token",
             Self::NoAvailableTokens => "No available tokens in queue",
             Self::AliasNotFound => "Token alias not found",
+            Self::GroupNotFound => "Token group not found",
+            Self::KeyDisabled => "API key is disabled",
+            Self::KeyExpired => "API key has expired",
+            Self::EndpointNotAllowed => "Key is not allowed on this endpoint",
+            Self::ModelNotAllowed => "Key is not allowed to use this model",
+            Self::DailyQuotaExceeded => "API key has used up its requests for today",
+            Self::DailyBudgetExceeded => "Key has spent its budget for today",
+            Self::MonthlyBudgetExceeded => "Key has spent its budget for this month",
+            Self::BudgetExhausted => "Key has spent its budget",
+            Self::KeyRevoked => "Key has been revoked",
+            Self::RateLimited => "Key has made too many requests this minute",
+            Self::PermissionDenied(Permission::Read) => "Credential lacks the read permission",
+            Self::PermissionDenied(Permission::Operate) => {
+                "Credential lacks the operate permission"
+            }
+            Self::PermissionDenied(Permission::Manage) => "Credential lacks the manage permission",
+        }
+    }
+}
+
+impl From<ApiKeyDenial> for AuthError {
+    #[inline]
+    fn from(denial: ApiKeyDenial) -> Self {
+        match denial {
+            ApiKeyDenial::Disabled => Self::KeyDisabled,
+            ApiKeyDenial::Expired => Self::KeyExpired,
+            ApiKeyDenial::Endpoint => Self::EndpointNotAllowed,
+            ApiKeyDenial::Model => Self::ModelNotAllowed,
+            ApiKeyDenial::DailyQuota => Self::DailyQuotaExceeded,
+        }
+    }
+}
+
+impl From<BudgetExceeded> for AuthError {
+    #[inline]
+    fn from(exceeded: BudgetExceeded) -> Self {
+        match exceeded {
+            BudgetExceeded::Daily => Self::DailyBudgetExceeded,
+            BudgetExceeded::Monthly => Self::MonthlyBudgetExceeded,
+            BudgetExceeded::Lifetime => Self::BudgetExhausted,
         }
     }
 }
@@ -84,6 +185,18 @@ impl AuthError {
         }
         .wrapped()
     }
+
+    /// Converts to Gemini error format
+    #[inline]
+    pub fn into_gemini(self) -> gemini::GeminiError {
+        gemini::GeminiError { code: self.status_code(), message: Cow::Borrowed(self.message()) }
+    }
+
+    /// Converts to Ollama error format
+    #[inline]
+    pub fn into_ollama(self) -> ollama::OllamaError {
+        ollama::OllamaError { message: Cow::Borrowed(self.message()) }
+    }
 }
 
 impl ErrorExt for AuthError {
@@ -104,6 +217,18 @@ impl ErrorExt for AuthError {
     fn into_anthropic_tuple(self) -> (StatusCode, Json<anthropic::AnthropicError>) {
         (self.status_code(), Json(self.into_anthropic()))
     }
+
+    /// Converts to Gemini error format
+    #[inline]
+    fn into_gemini_tuple(self) -> (StatusCode, Json<gemini::GeminiError>) {
+        (self.status_code(), Json(self.into_gemini()))
+    }
+
+    /// Converts to Ollama error format
+    #[inline]
+    fn into_ollama_tuple(self) -> (StatusCode, Json<ollama::OllamaError>) {
+        (self.status_code(), Json(self.into_ollama()))
+    }
 }
 
 impl IntoResponse for AuthError {
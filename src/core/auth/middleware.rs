@@ -1,55 +1,124 @@
 use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
 use axum::body::Body;
 use axum::extract::State;
 use axum::middleware::Next;
 use axum::response::{IntoResponse as _, Response};
-use http::Request;
 use http::header::AUTHORIZATION;
+use http::{Request, StatusCode};
 
-use super::utils::{get_environment_info, get_token_bundle};
+use super::affinity::{Affinity, Fingerprint};
+use super::model::Access;
+use super::utils::{
+    admin_role, get_environment_info, get_token_bundle, is_pooled, request_model, requires_paid,
+    spender, token_pool,
+};
 use super::{AuthError, auth};
 use crate::app::constant::AUTHORIZATION_BEARER_PREFIX;
-use crate::app::lazy::AUTH_TOKEN;
-use crate::app::model::{AppState, DateTime, QueueType};
-use crate::core::config::KeyConfigBuilder;
+use crate::app::lazy::OLLAMA_AUTH_KEY;
+use crate::app::model::{ApiKeys, AppState, Budgets, DateTime, InFlight, Permission, QueueType};
+use crate::core::config::{KeyConfigBuilder, configured_key::Route};
+use crate::core::model::ExtModel;
+use crate::core::session::Owner;
 
-// Admin authentication middleware function
-pub async fn admin_auth_middleware(request: Request<Body>, next: Next) -> Response {
-    if let Some(token) = request
+/// Admin authentication middleware, for routes that need `permission`
+pub async fn admin_auth_middleware(
+    State(permission): State<Permission>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(role) = request
         .headers()
         .get(AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
         .and_then(|h| h.strip_prefix(AUTHORIZATION_BEARER_PREFIX))
-        && token == *AUTH_TOKEN
-    {
-        return next.run(request).await;
+        .and_then(admin_role)
+    else {
+        return AuthError::Unauthorized.into_response();
     };
 
-    AuthError::Unauthorized.into_response()
+    if !role.grants(permission) {
+        return AuthError::PermissionDenied(permission).into_response();
+    }
+    // Read by the endpoints that hold back secrets from roles without `Manage`
+    request.extensions_mut().insert(role);
+    next.run(request).await
 }
 
 pub async fn v1_auth_middleware(
+    state: State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    v1_auth(state, request, next, Route::Chat).await
+}
+
+/// `v1_auth_middleware` for `/v1/messages`, which API keys are allowed on separately
+pub async fn messages_auth_middleware(
+    state: State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    v1_auth(state, request, next, Route::Messages).await
+}
+
+async fn v1_auth(
     State(state): State<Arc<AppState>>,
     mut request: Request<Body>,
     next: Next,
+    route: Route,
 ) -> Response {
     let Some(auth_token) = auth(request.headers()) else {
         return AuthError::Unauthorized.into_response();
     };
 
     let mut current_config = KeyConfigBuilder::new();
+    let mut in_flight = None;
+    let pooled = if is_pooled(auth_token) {
+        match read_pooled_request(&mut request, true).await {
+            Ok(pooled) => pooled,
+            Err(response) => return response,
+        }
+    } else {
+        PooledRequest::default()
+    };
+    // Reading the body needed the request back, the key is still there
+    let auth_token = __unwrap!(auth(request.headers()));
 
-    match get_token_bundle(
-        &state,
-        auth_token,
-        QueueType::PrivilegedPaid,
-        QueueType::NormalPaid,
-        Some(&mut current_config),
-    )
-    .await
-    {
+    // Models that are not free only go to the paid queues
+    let (privileged_queue, normal_queue) = if pooled.model.as_ref().is_some_and(requires_paid) {
+        (QueueType::PrivilegedPaid, QueueType::NormalPaid)
+    } else {
+        (QueueType::PrivilegedFree, QueueType::NormalFree)
+    };
+    let pool = token_pool(auth_token, privileged_queue, normal_queue);
+    let spender = spender(auth_token);
+    let owner = Owner::of(auth_token);
+
+    // A key out of budget gets no token
+    let result = match spender.as_ref().map(Budgets::check) {
+        Some(Err(exceeded)) => Err(AuthError::from(exceeded)),
+        _ => {
+            get_token_bundle(
+                &state,
+                auth_token,
+                privileged_queue,
+                normal_queue,
+                Some(&mut current_config),
+                pooled.access(route),
+                &mut in_flight,
+            )
+            .await
+        }
+    };
+
+    match result {
         v if v.is_ok() => {
+            if let Some(pool) = pool {
+                request.extensions_mut().insert(pool);
+            }
             let request_time = DateTime::now();
             let environment_info = get_environment_info(request.headers(), request_time);
 
@@ -57,6 +126,10 @@ pub async fn v1_auth_middleware(
             request.extensions_mut().insert(current_config.with_global());
             request.extensions_mut().insert(request_time);
             request.extensions_mut().insert(environment_info);
+            request.extensions_mut().insert(owner);
+            if let Some(spender) = spender {
+                request.extensions_mut().insert(spender);
+            }
         }
         e => {
             request.extensions_mut().insert(e);
@@ -74,7 +147,22 @@ pub async fn v1_auth_middleware(
 
     // let request = Request::from_parts(parts, body);
 
-    next.run(request).await
+    hold_in_flight(next.run(request).await, in_flight)
+}
+
+/// `v1_auth_middleware` that falls back to `OLLAMA_AUTH_KEY` when the request has no key
+pub async fn ollama_auth_middleware(
+    state: State<Arc<AppState>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if auth(request.headers()).is_none()
+        && let Some(ref value) = *OLLAMA_AUTH_KEY
+    {
+        request.headers_mut().insert(AUTHORIZATION, value.clone());
+    }
+
+    v1_auth_middleware(state, request, next).await
 }
 
 pub async fn v1_auth2_middleware(
@@ -87,6 +175,19 @@ pub async fn v1_auth2_middleware(
     };
 
     let mut current_config = KeyConfigBuilder::new();
+    let mut in_flight = None;
+    let pool = token_pool(auth_token, QueueType::PrivilegedFree, QueueType::NormalFree);
+    let read_model = ApiKeys::contains(auth_token);
+    let pooled = if pool.is_some() {
+        match read_pooled_request(&mut request, read_model).await {
+            Ok(pooled) => pooled,
+            Err(response) => return response,
+        }
+    } else {
+        PooledRequest::default()
+    };
+    // Reading the body needed the request back, the key is still there
+    let auth_token = __unwrap!(auth(request.headers()));
 
     match get_token_bundle(
         &state,
@@ -94,10 +195,15 @@ pub async fn v1_auth2_middleware(
         QueueType::PrivilegedFree,
         QueueType::NormalFree,
         Some(&mut current_config),
+        pooled.access(Route::CountTokens),
+        &mut in_flight,
     )
     .await
     {
         v if v.is_ok() => {
+            if let Some(pool) = pool {
+                request.extensions_mut().insert(pool);
+            }
             let request_time = DateTime::now();
             let environment_info = get_environment_info(request.headers(), request_time);
 
@@ -121,7 +227,7 @@ pub async fn v1_auth2_middleware(
 
     // let request = Request::from_parts(parts, body);
 
-    next.run(request).await
+    hold_in_flight(next.run(request).await, in_flight)
 }
 
 pub async fn cpp_auth_middleware(
@@ -133,12 +239,21 @@ pub async fn cpp_auth_middleware(
         return AuthError::Unauthorized.into_response();
     };
 
+    // Cursor Tab requests report no usage upstream, so there is nothing to charge them with,
+    // but a key out of budget gets no token for them either
+    if let Some(Err(exceeded)) = spender(auth_token).as_ref().map(Budgets::check) {
+        return AuthError::from(exceeded).into_response();
+    }
+
+    let mut in_flight = None;
     let v = match get_token_bundle(
         &state,
         auth_token,
         QueueType::PrivilegedFree,
         QueueType::NormalFree,
         None,
+        Access { route: Route::Cpp, model: None, fingerprint: None },
+        &mut in_flight,
     )
     .await
     {
@@ -148,5 +263,87 @@ pub async fn cpp_auth_middleware(
 
     request.extensions_mut().insert(v);
 
-    next.run(request).await
+    hold_in_flight(next.run(request).await, in_flight)
+}
+
+/// Largest body read before picking a token, the default limit of the `Json` extractor
+const POOLED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// What picking a token for a pooled request needs from it
+#[derive(Default)]
+struct PooledRequest {
+    /// Conversation the request continues
+    fingerprint: Option<Fingerprint>,
+    /// Model it asks for, when read
+    model: Option<ExtModel>,
+}
+
+impl PooledRequest {
+    #[inline]
+    fn access(&self, route: Route) -> Access {
+        Access {
+            route,
+            model: self.model.map(|model| model.id),
+            fingerprint: self.fingerprint,
+        }
+    }
+}
+
+/// Reads a pooled request for picking its token, the body is read only when the model is
+/// needed or the request has no `X-Session-Id`, and put back for the handler
+async fn read_pooled_request(
+    request: &mut Request<Body>,
+    read_model: bool,
+) -> Result<PooledRequest, Response> {
+    let sticky = Affinity::is_enabled();
+    let mut fingerprint = if sticky { Fingerprint::from_headers(request.headers()) } else { None };
+    if !read_model && (!sticky || fingerprint.is_some()) {
+        return Ok(PooledRequest { fingerprint, model: None });
+    }
+
+    let body = core::mem::take(request.body_mut());
+    let Ok(bytes) = axum::body::to_bytes(body, POOLED_BODY_LIMIT).await else {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    };
+    if sticky && fingerprint.is_none() {
+        fingerprint = Fingerprint::from_body(&bytes);
+    }
+    let model = if read_model { request_model(request.uri().path(), &bytes) } else { None };
+    *request.body_mut() = Body::from(bytes);
+    Ok(PooledRequest { fingerprint, model })
+}
+
+/// Response body that keeps its request counted in the load of the token until it is dropped,
+/// which for a stream is when it ends or the client goes away
+struct InFlightBody {
+    inner: Body,
+    _in_flight: InFlight,
+}
+
+impl http_body::Body for InFlightBody {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    #[inline]
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool { self.inner.is_end_stream() }
+
+    #[inline]
+    fn size_hint(&self) -> http_body::SizeHint { self.inner.size_hint() }
+}
+
+fn hold_in_flight(response: Response, in_flight: Option<InFlight>) -> Response {
+    match in_flight {
+        Some(in_flight) => {
+            response.map(|inner| Body::new(InFlightBody { inner, _in_flight: in_flight }))
+        }
+        None => response,
+    }
 }
@@ -1,5 +1,32 @@
-use crate::app::model::ExtToken;
-use super::error::AuthError;
+use super::{affinity::Fingerprint, error::AuthError};
+use crate::{
+    app::model::{ExtToken, QueueType},
+    core::config::configured_key::Route,
+};
+use alloc::sync::Arc;
 
 pub type TokenBundle = (ExtToken, bool);
 pub type TokenBundleResult = Result<TokenBundle, AuthError>;
+
+/// Queue the token was selected from, only present for pooled keys
+#[derive(Clone)]
+pub struct TokenPool {
+    pub queue: QueueType,
+    /// Group the key selects inside of
+    pub group: Option<Arc<str>>,
+}
+
+impl TokenPool {
+    #[inline]
+    pub fn group(&self) -> Option<&str> { self.group.as_deref() }
+}
+
+/// What a request needs from its key
+#[derive(Clone, Copy)]
+pub struct Access {
+    pub route: Route,
+    /// Id of the requested model, only read for API keys
+    pub model: Option<&'static str>,
+    /// Conversation the request continues
+    pub fingerprint: Option<Fingerprint>,
+}
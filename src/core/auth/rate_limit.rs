@@ -0,0 +1,83 @@
+//! Requests per minute of the dynamic keys that carry a `max_rpm`, counted in fixed windows
+
+use manually_init::ManuallyInit;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+struct Window {
+    started: Instant,
+    requests: u32,
+}
+
+impl Window {
+    /// Counts a request made at `now` if fewer than `max` were made in its window
+    fn admit(&mut self, now: Instant, max: u32) -> bool {
+        if now.duration_since(self.started) >= WINDOW {
+            *self = Window { started: now, requests: 0 };
+        }
+        if self.requests >= max {
+            return false;
+        }
+        self.requests += 1;
+        true
+    }
+}
+
+struct Windows {
+    map: HashMap<[u8; 16], Window>,
+    /// Size at which ended windows are swept out next
+    sweep_at: usize,
+}
+
+pub struct RateLimits {
+    inner: Mutex<Windows>,
+}
+
+const MIN_SWEEP_AT: usize = 256;
+
+impl RateLimits {
+    pub fn init() {
+        RATE_LIMITS.init(RateLimits {
+            inner: Mutex::new(Windows {
+                map: HashMap::with_capacity_and_hasher(16, ahash::RandomState::new()),
+                sweep_at: MIN_SWEEP_AT,
+            }),
+        })
+    }
+
+    /// Counts a request of the key with `id` if it made fewer than `max` this minute
+    pub fn admit(id: [u8; 16], max: u32) -> bool {
+        let now = Instant::now();
+        let mut windows = RATE_LIMITS.inner.lock();
+        let admitted =
+            windows.map.entry(id).or_insert(Window { started: now, requests: 0 }).admit(now, max);
+
+        if windows.map.len() >= windows.sweep_at {
+            windows.map.retain(|_, window| now.duration_since(window.started) < WINDOW);
+            windows.sweep_at = (windows.map.len() * 2).max(MIN_SWEEP_AT);
+        }
+        admitted
+    }
+}
+
+static RATE_LIMITS: ManuallyInit<RateLimits> = ManuallyInit::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_resets_after_a_minute() {
+        let start = Instant::now();
+        let mut window = Window { started: start, requests: 0 };
+        assert!(window.admit(start, 2));
+        assert!(window.admit(start + Duration::from_secs(30), 2));
+        assert!(!window.admit(start + Duration::from_secs(59), 2));
+        assert!(window.admit(start + WINDOW, 2));
+    }
+}
@@ -1,21 +1,33 @@
-use super::{error::AuthError, model::TokenBundleResult};
+use super::{
+    affinity::{Affinity, Fingerprint},
+    error::AuthError,
+    model::{Access, TokenBundleResult, TokenPool},
+    rate_limit::RateLimits,
+};
 use crate::{
     app::{
         constant::{
             AUTHORIZATION_BEARER_PREFIX,
-            header::{API_KEY, STAINLESS_ARCH, STAINLESS_OS},
+            header::{API_KEY, GOOG_API_KEY, STAINLESS_ARCH, STAINLESS_OS},
         },
         lazy::AUTH_TOKEN,
-        model::{AppConfig, AppState, DateTime, QueueType, TokenKey, log_manager},
+        model::{
+            AdminRole, ApiKeys, AppConfig, AppState, Budgets, DateTime, ExtToken, InFlight,
+            QueueType, Spender, TokenKey, TokenManager, log_manager,
+        },
     },
     common::utils::tokeninfo_to_token,
     core::{
         aiserver::v1::EnvironmentInfo,
-        config::{KeyConfigBuilder, parse_dynamic_token},
+        config::{ConfiguredKey, KeyConfigBuilder, configured_key::Route, parse_dynamic_token},
+        constant::FREE_MODELS,
+        model::ExtModel,
     },
 };
+use alloc::{borrow::Cow, sync::Arc};
 use byte_str::ByteStr;
 use http::header::AUTHORIZATION;
+use serde::Deserialize;
 
 #[inline]
 pub fn auth(headers: &http::HeaderMap) -> Option<&str> {
@@ -24,6 +36,11 @@ pub fn auth(headers: &http::HeaderMap) -> Option<&str> {
     {
         return Some(s);
     }
+    if let Some(val) = headers.get(GOOG_API_KEY)
+        && let Ok(s) = val.to_str()
+    {
+        return Some(s);
+    }
     if let Some(val) = headers.get(AUTHORIZATION)
         && let Ok(s) = val.to_str()
     {
@@ -32,6 +49,12 @@ pub fn auth(headers: &http::HeaderMap) -> Option<&str> {
     None
 }
 
+/// Role of `token` on the admin endpoints, `AUTH_TOKEN` being the owner
+#[inline]
+pub fn admin_role(token: &str) -> Option<AdminRole> {
+    if token == *AUTH_TOKEN { Some(AdminRole::Owner) } else { AppConfig::admin_role(token) }
+}
+
 #[inline]
 pub(super) fn get_environment_info(
     headers: &http::HeaderMap,
@@ -66,22 +89,106 @@ pub(super) fn get_environment_info(
     }
 }
 
+/// Queue (and group) that `get_token_bundle` selects from for `auth_token`, `None` when the
+/// key is bound to a single token
+///
+/// The group of a dynamic key is taken as is, `get_token_bundle` is the one that checks it
+pub(super) fn token_pool(
+    auth_token: &str,
+    privileged_queue: QueueType,
+    normal_queue: QueueType,
+) -> Option<TokenPool> {
+    if let Some(part) = auth_token.strip_prefix(&**AUTH_TOKEN) {
+        let group = if part.is_empty() { None } else { Some(Arc::from(part.strip_prefix('@')?)) };
+        Some(TokenPool { queue: privileged_queue, group })
+    } else if (AppConfig::is_share() && AppConfig::share_token_eq(auth_token))
+        || ApiKeys::contains(auth_token)
+    {
+        Some(TokenPool { queue: normal_queue, group: None })
+    } else if AppConfig::is_dynamic_key_enabled()
+        && let Some(group) = parse_dynamic_token(auth_token).and_then(|key| key.group)
+    {
+        Some(TokenPool { queue: normal_queue, group: Some(Arc::from(group)) })
+    } else {
+        None
+    }
+}
+
+/// Whether `get_token_bundle` selects the token of `auth_token` from a queue
+#[inline]
+pub(super) fn is_pooled(auth_token: &str) -> bool {
+    token_pool(auth_token, QueueType::PrivilegedFree, QueueType::NormalFree).is_some()
+}
+
+/// Who the requests of `auth_token` spend for, `None` unless it has a budget
+///
+/// The id of a dynamic key is not verified here, a forged one fails to authenticate anyway
+pub(super) fn spender(auth_token: &str) -> Option<Spender> {
+    if Budgets::is_empty() {
+        return None;
+    }
+    let spender = if AppConfig::is_share() && AppConfig::share_token_eq(auth_token) {
+        Spender::Share
+    } else if let Some(id) = ApiKeys::id(auth_token) {
+        Spender::ApiKey(id)
+    } else if AppConfig::is_dynamic_key_enabled()
+        && let Some(id) = parse_dynamic_token(auth_token).and_then(|key| key.id())
+    {
+        Spender::DynamicKey(hex::encode(id))
+    } else {
+        return None;
+    };
+    Budgets::contains(&spender).then_some(spender)
+}
+
+/// Model of a request, read from the body or, for Gemini, from the path
+pub(super) fn request_model(path: &str, body: &[u8]) -> Option<ExtModel> {
+    #[derive(Deserialize)]
+    struct Model<'a> {
+        #[serde(borrow, default)]
+        model: Option<Cow<'a, str>>,
+    }
+
+    let from_body = serde_json::from_slice::<Model>(body).ok().and_then(|request| request.model);
+    let model = match from_body {
+        Some(ref model) => &**model,
+        // `/v1beta/models/{model}:{action}`
+        None => {
+            let (_, model_action) = path.rsplit_once('/')?;
+            model_action.split_once(':').map_or(model_action, |(model, _)| model)
+        }
+    };
+    ExtModel::from_str(model)
+}
+
+/// Whether `model` is one that only paid tokens serve
+#[inline]
+pub(super) fn requires_paid(model: &ExtModel) -> bool { !FREE_MODELS.contains(&model.id) }
+
 /// Unified token retrieval function
 ///
 /// Extract and verify authentication token from HTTP headers, return corresponding ExtToken
+///
+/// A token selected from a queue comes with its in-flight slot, put in `in_flight`
 pub(super) async fn get_token_bundle(
     state: &AppState,
     auth_token: &str,
     privileged_queue: QueueType,
     normal_queue: QueueType,
     key_config: Option<&mut KeyConfigBuilder>,
+    access: Access,
+    in_flight: &mut Option<InFlight>,
 ) -> TokenBundleResult {
+    let fingerprint = access.fingerprint;
+
     // Admin Token
     if let Some(part) = auth_token.strip_prefix(&**AUTH_TOKEN) {
         let token_manager = state.token_manager.read().await;
 
         let bundle = if part.is_empty() {
-            token_manager.select(privileged_queue).ok_or(AuthError::NoAvailableTokens)?
+            select_pooled(&token_manager, privileged_queue, None, fingerprint, in_flight)?
+        } else if let Some(group) = part.strip_prefix('@') {
+            select_pooled(&token_manager, privileged_queue, Some(group), fingerprint, in_flight)?
         } else if let Some(alias) = part.strip_prefix('-') {
             if !token_manager.alias_map().contains_key(alias) {
                 return Err(AuthError::AliasNotFound);
@@ -99,7 +206,21 @@ pub(super) async fn get_token_bundle(
     // Shared Token
     if AppConfig::is_share() && AppConfig::share_token_eq(auth_token) {
         let token_manager = state.token_manager.read().await;
-        let bundle = token_manager.select(normal_queue).ok_or(AuthError::NoAvailableTokens)?;
+        let bundle = select_pooled(&token_manager, normal_queue, None, fingerprint, in_flight)?;
+        return Ok((bundle, true));
+    } else
+    // API key
+    if let Some(permitted) =
+        ApiKeys::permit(auth_token, access.route.endpoint(), access.model)
+    {
+        permitted?;
+        let token_manager = state.token_manager.read().await;
+        let bundle = select_pooled(&token_manager, normal_queue, None, fingerprint, in_flight)?;
+        // Counted once a token serves it, requests without one do not use up the quota
+        if let Err(denial) = ApiKeys::count(auth_token) {
+            *in_flight = None;
+            return Err(denial.into());
+        }
         return Ok((bundle, true));
     } else
     // Regular user Token
@@ -111,11 +232,32 @@ pub(super) async fn get_token_bundle(
     // Dynamic key
     if AppConfig::is_dynamic_key_enabled() {
         if let Some(mut parsed_config) = parse_dynamic_token(auth_token) {
+            if parsed_config.is_revoked() {
+                return Err(AuthError::KeyRevoked);
+            }
+            let Some(raw) = parsed_config.verify() else {
+                return Err(AuthError::Unauthorized);
+            };
+            admit_scoped(&parsed_config, access.route)?;
             if let Some(config) = key_config {
                 parsed_config.move_to_config_builder(config);
             }
 
-            if let Some(ext_token) = parsed_config.into_tuple().and_then(tokeninfo_to_token) {
+            if let Some(group) = parsed_config.group.as_deref() {
+                let token_manager = state.token_manager.read().await;
+                let bundle = select_pooled(
+                    &token_manager,
+                    normal_queue,
+                    Some(group),
+                    fingerprint,
+                    in_flight,
+                )?;
+                return Ok((bundle, true));
+            }
+
+            if let Some(ext_token) =
+                parsed_config.token_info.and_then(|info| tokeninfo_to_token((info, raw)))
+            {
                 return Ok((ext_token, false));
             }
         }
@@ -123,3 +265,84 @@ pub(super) async fn get_token_bundle(
 
     Err(AuthError::Unauthorized)
 }
+
+/// Checks a verified dynamic key against the route families and rate it was built with, the
+/// models it may use are checked once the handler has resolved the model
+fn admit_scoped(key: &ConfiguredKey, route: Route) -> Result<(), AuthError> {
+    if let Some(ref routes) = key.routes
+        && !routes.contains(&route)
+    {
+        return Err(AuthError::EndpointNotAllowed);
+    }
+    if let Some(max_rpm) = key.max_rpm {
+        // Requests are counted by the id, a verified key always has one through its secret, a
+        // key without one is turned away rather than left unlimited
+        let Some(id) = key.id() else { return Err(AuthError::Unauthorized) };
+        if !RateLimits::admit(id, max_rpm) {
+            return Err(AuthError::RateLimited);
+        }
+    }
+    Ok(())
+}
+
+/// Token of `queue` (inside `group`) for a request, a conversation keeps the token of its last
+/// turn for as long as that one can take requests
+fn select_pooled(
+    token_manager: &TokenManager,
+    queue: QueueType,
+    group: Option<&str>,
+    fingerprint: Option<Fingerprint>,
+    in_flight: &mut Option<InFlight>,
+) -> Result<ExtToken, AuthError> {
+    if let Some(group) = group
+        && !token_manager.has_group(group)
+    {
+        return Err(AuthError::GroupNotFound);
+    }
+    let pinned = fingerprint
+        .and_then(Affinity::get)
+        .and_then(|key| token_manager.select_pinned(queue, group, &key));
+    let (bundle, slot) = match pinned {
+        Some(selected) => selected,
+        None => {
+            let selected =
+                token_manager.select(queue, group).ok_or(AuthError::NoAvailableTokens)?;
+            if let Some(fingerprint) = fingerprint {
+                Affinity::pin(fingerprint, selected.0.primary_token.key());
+            }
+            selected
+        }
+    };
+    *in_flight = Some(slot);
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_rpm_needs_a_key_id() {
+        let key = ConfiguredKey {
+            token_info: None,
+            secret: None,
+            disable_vision: None,
+            enable_slow_pool: None,
+            include_web_references: None,
+            usage_check_models: None,
+            group: None,
+            key_id: None,
+            not_before: None,
+            not_after: None,
+            label: None,
+            models: None,
+            routes: Some(vec![Route::Chat]),
+            max_rpm: Some(10),
+        };
+        assert!(matches!(admit_scoped(&key, Route::Chat), Err(AuthError::Unauthorized)));
+        assert!(matches!(admit_scoped(&key, Route::Messages), Err(AuthError::EndpointNotAllowed)));
+
+        let unlimited = ConfiguredKey { max_rpm: None, ..key };
+        assert!(admit_scoped(&unlimited, Route::Chat).is_ok());
+    }
+}
@@ -2,10 +2,15 @@ use crate::{
     AppConfig,
     app::{
         lazy::KEY_PREFIX,
-        model::{Randomness, RawToken, Subject, TokenDuration, UserId, dynamic_key},
+        model::{
+            Endpoint, Randomness, RawToken, RevokedKeys, Subject, TokenDuration, UserId,
+            dynamic_key,
+        },
     },
-    common::utils::from_base64,
+    common::utils::{from_base64, now_secs},
 };
+use hmac::Mac as _;
+use sha2::digest::FixedOutput as _;
 
 // include!(concat!(env!("OUT_DIR"), "/key.rs"));
 include!("config/key.rs");
@@ -24,10 +29,98 @@ impl ConfiguredKey {
         if self.include_web_references.is_some() {
             config.include_web_references = self.include_web_references.take();
         }
+        if self.models.is_some() {
+            config.models = self.models.take();
+        }
+    }
+
+    /// Token of the key, `None` unless the key verifies
+    pub fn verify(&self) -> Option<RawToken> {
+        let raw = self.token_info.as_ref()?.token.to_raw()?;
+        (self.hash(&raw) == self.secret?).then_some(raw)
+    }
+
+    /// Token of a key bound to a single token, `None` unless the key verifies
+    pub fn into_verified(self) -> Option<(configured_key::TokenInfo, RawToken)> {
+        if self.group.is_some() {
+            return None;
+        }
+        let raw = self.verify()?;
+        Some((self.token_info?, raw))
+    }
+
+    /// Group the key selects pooled tokens from, `None` unless it was built for that group
+    pub fn verified_group(&self) -> Option<&str> {
+        let group = self.group.as_deref()?;
+        self.verify()?;
+        Some(group)
+    }
+
+    /// Secret of the key, which covers its token and every field that limits it so none of them
+    /// can be changed or dropped
+    ///
+    /// Fields are named and sized, except for the group that goes last, so keys built before a
+    /// field existed still verify. Numbers are little-endian, so a key verifies on any host
+    pub fn hash(&self, raw: &RawToken) -> [u8; 32] {
+        let mut hmac = dynamic_key::token_hmac(raw);
+        if let Some(ref key_id) = self.key_id {
+            hmac.update(b"key_id");
+            hmac.update(key_id);
+        }
+        if let Some(not_before) = self.not_before {
+            hmac.update(b"not_before");
+            hmac.update(&not_before.to_le_bytes());
+        }
+        if let Some(not_after) = self.not_after {
+            hmac.update(b"not_after");
+            hmac.update(&not_after.to_le_bytes());
+        }
+        if let Some(ref label) = self.label {
+            hmac.update(b"label");
+            hmac.update(&(label.len() as u64).to_le_bytes());
+            hmac.update(label.as_bytes());
+        }
+        if let Some(ref models) = self.models {
+            hmac.update(b"models");
+            hmac.update(&(models.len() as u64).to_le_bytes());
+            for model in models {
+                hmac.update(&(model.len() as u64).to_le_bytes());
+                hmac.update(model.as_bytes());
+            }
+        }
+        if let Some(ref routes) = self.routes {
+            hmac.update(b"routes");
+            hmac.update(&(routes.len() as u64).to_le_bytes());
+            for &route in routes {
+                hmac.update(&[route as u8]);
+            }
+        }
+        if let Some(max_rpm) = self.max_rpm {
+            hmac.update(b"max_rpm");
+            hmac.update(&max_rpm.to_le_bytes());
+        }
+        if let Some(ref group) = self.group {
+            hmac.update(b"group");
+            hmac.update(group.as_bytes());
+        }
+        hmac.finalize_fixed().0
     }
 
-    pub fn into_tuple(self) -> Option<(configured_key::TokenInfo, [u8; 32])> {
-        self.token_info.zip(self.secret)
+    /// Id the key is revoked and budgeted by, its `key_id` or, for a key built without one, the
+    /// start of its secret, which the keys built for the same token and group share
+    pub fn id(&self) -> Option<[u8; 16]> {
+        self.key_id.or_else(|| self.secret.and_then(|secret| secret[..16].try_into().ok()))
+    }
+
+    #[inline]
+    pub fn is_revoked(&self) -> bool { self.id().is_some_and(|id| RevokedKeys::contains(&id)) }
+
+    /// Whether now is within the time window of the key
+    #[inline]
+    pub fn is_current(&self) -> bool {
+        let now = now_secs();
+        self.not_before.is_none_or(|not_before| not_before <= now)
+            && self.not_after.is_none_or(|not_after| now < not_after)
     }
 }
 
@@ -46,8 +139,8 @@ impl configured_key::token_info::Token {
     }
 
     #[inline]
-    pub fn validate(self, hash: [u8; 32]) -> Option<RawToken> {
-        let raw = RawToken {
+    fn to_raw(&self) -> Option<RawToken> {
+        Some(RawToken {
             subject: Subject {
                 provider: self.provider.parse().ok()?,
                 id: UserId::from_bytes(self.sub_id),
@@ -56,15 +149,33 @@ impl configured_key::token_info::Token {
             signature: self.signature,
             duration: TokenDuration { start: self.start, end: self.end },
             is_session: self.is_session,
-        };
-        if dynamic_key::get_hash(&raw) != hash {
-            return None;
+        })
+    }
+}
+
+impl configured_key::Route {
+    /// Family API keys are allowed on for the route, `/v1/messages/count_tokens` going along
+    /// with `/v1/messages`
+    #[inline]
+    pub const fn endpoint(self) -> Endpoint {
+        match self {
+            Self::Chat => Endpoint::Chat,
+            Self::Messages | Self::CountTokens => Endpoint::Messages,
+            Self::Cpp => Endpoint::Cpp,
         }
-        Some(raw)
     }
 }
 
+/// Dynamic key `auth_token`, `None` outside of its time window
+///
+/// Nothing is verified against the secret here
+#[inline]
 pub fn parse_dynamic_token(auth_token: &str) -> Option<ConfiguredKey> {
+    decode_dynamic_token(auth_token).filter(ConfiguredKey::is_current)
+}
+
+/// Dynamic key `auth_token`, whether it still works or not
+pub fn decode_dynamic_token(auth_token: &str) -> Option<ConfiguredKey> {
     auth_token.strip_prefix(&**KEY_PREFIX).and_then(from_base64).and_then(|decoded_bytes| {
         let mut decoder = ::minicbor::Decoder::new(&decoded_bytes);
         decoder.decode().ok()
@@ -77,6 +188,32 @@ pub struct KeyConfig {
     pub disable_vision: bool,
     pub enable_slow_pool: bool,
     pub include_web_references: bool,
+    /// Model ids the key may use, every model when `None`
+    pub models: Option<Vec<String>>,
+}
+
+impl KeyConfig {
+    /// Whether the key may use the model with `id`
+    #[inline]
+    pub fn allows_model(&self, id: &str) -> bool {
+        self.models.as_ref().is_none_or(|models| models.iter().any(|m| matches_model(m, id)))
+    }
+}
+
+/// Whether `id` matches `pattern`, in which `*` stands for any run of characters
+fn matches_model(pattern: &str, id: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else { return pattern == id };
+    let (middle, last) = rest.rsplit_once('*').unwrap_or(("", rest));
+    let Some(mut id) = id.strip_prefix(first).and_then(|id| id.strip_suffix(last)) else {
+        return false;
+    };
+    for part in middle.split('*') {
+        match id.find(part) {
+            Some(i) => id = &id[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
 }
 
 #[derive(Clone)]
@@ -85,6 +222,7 @@ pub struct KeyConfigBuilder {
     pub disable_vision: Option<bool>,
     pub enable_slow_pool: Option<bool>,
     pub include_web_references: Option<bool>,
+    pub models: Option<Vec<String>>,
 }
 
 impl KeyConfigBuilder {
@@ -94,18 +232,42 @@ impl KeyConfigBuilder {
             disable_vision: None,
             enable_slow_pool: None,
             include_web_references: None,
+            models: None,
         }
     }
 
     pub fn with_global(self) -> KeyConfig {
-        let Self { usage_check_models, disable_vision, enable_slow_pool, include_web_references } =
-            self;
+        let Self {
+            usage_check_models,
+            disable_vision,
+            enable_slow_pool,
+            include_web_references,
+            models,
+        } = self;
         KeyConfig {
             usage_check_models,
             disable_vision: disable_vision
                 .unwrap_or_else(|| AppConfig::vision_ability().is_none()),
             enable_slow_pool: enable_slow_pool.unwrap_or_else(AppConfig::is_slow_pool_enabled),
             include_web_references: include_web_references.unwrap_or_else(AppConfig::is_web_references_included),
+            models,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::matches_model;
+
+    #[test]
+    fn model_patterns() {
+        assert!(matches_model("gpt-4o", "gpt-4o"));
+        assert!(!matches_model("gpt-4o", "gpt-4o-mini"));
+        assert!(matches_model("gpt-4o*", "gpt-4o-mini"));
+        assert!(matches_model("*-mini", "gpt-4o-mini"));
+        assert!(matches_model("claude-*-sonnet*", "claude-4.5-sonnet-thinking"));
+        assert!(!matches_model("claude-*-sonnet*", "claude-4.5-opus"));
+        assert!(!matches_model("ab*ba", "aba"));
+        assert!(matches_model("*", "default"));
+    }
+}
@@ -21,6 +21,30 @@ pub struct ConfiguredKey {
     /// Usage check model rules
     #[n(5)]
     pub usage_check_models: Option<configured_key::UsageCheckModel>,
+    /// Group of pooled tokens to select from instead of the token
+    #[n(6)]
+    pub group: Option<String>,
+    /// Random id the key is revoked by (\[u8; 16\])
+    #[n(7)]
+    pub key_id: Option<[u8; 16]>,
+    /// Time from which the key works (Unix timestamp)
+    #[n(8)]
+    pub not_before: Option<u64>,
+    /// Time from which the key no longer works (Unix timestamp)
+    #[n(9)]
+    pub not_after: Option<u64>,
+    /// Note on who or what the key is for
+    #[n(10)]
+    pub label: Option<String>,
+    /// Model ids the key may use, `*` standing for any run of characters
+    #[n(11)]
+    pub models: Option<Vec<String>>,
+    /// Route families the key may call
+    #[n(12)]
+    pub routes: Option<Vec<configured_key::Route>>,
+    /// Requests the key may make per minute
+    #[n(13)]
+    pub max_rpm: Option<u32>,
 }
 
 pub mod configured_key {
@@ -83,6 +107,25 @@ pub mod configured_key {
         }
     }
 
+    /// Route family
+    #[derive(::serde::Deserialize, Clone, Copy, PartialEq, Encode, Decode, CborLen)]
+    #[serde(rename_all = "snake_case")]
+    #[cbor(index_only)]
+    pub enum Route {
+        /// `/v1/chat/completions`, `/v1/responses`, Gemini and Ollama
+        #[n(0)]
+        Chat = 0,
+        /// `/v1/messages`
+        #[n(1)]
+        Messages = 1,
+        /// `/v1/messages/count_tokens`
+        #[n(2)]
+        CountTokens = 2,
+        /// Cursor Tab, `/cpp/*` and `/file/*`
+        #[n(3)]
+        Cpp = 3,
+    }
+
     /// Usage check model rules
     #[derive(Clone, PartialEq, Encode, Decode, CborLen)]
     pub struct UsageCheckModel {
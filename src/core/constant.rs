@@ -271,6 +271,10 @@ impl Models {
     #[inline]
     pub fn last_update_elapsed() -> Duration { Self::get().last_update.elapsed() }
 
+    /// Models currently known, in listing order
+    #[inline]
+    pub fn models(&self) -> &[Model] { &self.models }
+
     // Clone all models
     // pub fn cloned() -> Vec<Model> {
     //     Self::get().models.as_ref().clone()
@@ -4,7 +4,9 @@ mod cursor;
 
 use crate::{
     common::model::GenericError,
-    core::model::{anthropic::AnthropicError, openai::OpenAiError},
+    core::model::{
+        anthropic::AnthropicError, gemini::GeminiError, ollama::OllamaError, openai::OpenAiError,
+    },
 };
 use axum::Json;
 pub use canonical::CanonicalError;
@@ -251,4 +253,6 @@ pub trait ErrorExt: Sized {
     fn into_generic_tuple(self) -> (StatusCode, Json<GenericError>);
     fn into_openai_tuple(self) -> (StatusCode, Json<OpenAiError>);
     fn into_anthropic_tuple(self) -> (StatusCode, Json<AnthropicError>);
+    fn into_gemini_tuple(self) -> (StatusCode, Json<GeminiError>);
+    fn into_ollama_tuple(self) -> (StatusCode, Json<OllamaError>);
 }
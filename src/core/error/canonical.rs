@@ -1,6 +1,9 @@
 // use crate::common::model::{ApiStatus, GenericError};
 use crate::{
-    app::{constant::UNKNOWN, model::ErrorInfo},
+    app::{
+        constant::UNKNOWN,
+        model::{ErrorInfo, HealthEvent},
+    },
     core::{
         aiserver::v1::{CustomErrorDetails, ErrorDetails},
         model::{anthropic, openai},
@@ -44,6 +47,29 @@ impl CanonicalError {
         self.code = Some(code);
         self
     }
+
+    /// What the error says about the token the request was made with, if anything
+    pub fn health_event(&self) -> Option<HealthEvent> {
+        match self.r#type {
+            "bad_api_key"
+            | "bad_user_api_key"
+            | "not_logged_in"
+            | "invalid_auth_id"
+            | "auth_token_not_found"
+            | "auth_token_expired"
+            | "unauthorized" => Some(HealthEvent::Unauthorized),
+            "free_user_rate_limit_exceeded"
+            | "pro_user_rate_limit_exceeded"
+            | "generic_rate_limit_exceeded"
+            | "api_key_rate_limit"
+            | "rate_limited"
+            | "rate_limited_changeable"
+            | "free_user_usage_limit"
+            | "pro_user_usage_limit"
+            | "resource_exhausted" => Some(HealthEvent::Throttled),
+            _ => None,
+        }
+    }
 }
 
 impl ::core::iter::Sum for CanonicalError {
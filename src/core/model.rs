@@ -1,10 +1,16 @@
 pub mod anthropic;
+pub mod gemini;
+mod json_output;
+pub mod ollama;
 pub mod openai;
 mod resolver;
+pub mod responses;
 
 // use crate::app::constant::TOOLU01_PREFIX;
 use super::constant::Models;
-use crate::common::model::raw_json::RawJson;
+use crate::common::model::{error::ChatError, raw_json::RawJson};
+use alloc::borrow::Cow;
+pub(crate) use json_output::JsonOutput;
 pub(crate) use resolver::{ExtModel, init_resolver};
 use serde::{Serialize, ser::SerializeStruct as _};
 
@@ -31,6 +37,51 @@ pub enum Role {
     Assistant,
 }
 
+/// `tool_choice` of any API format, normalized
+pub enum ToolChoice {
+    Auto,
+    None,
+    Required,
+    Function(String),
+}
+
+impl ToolChoice {
+    /// Narrows `tools` down to what the choice allows,
+    /// returns whether the model is required to call a tool
+    pub fn apply<T>(
+        self,
+        tools: &mut Vec<T>,
+        name: impl Fn(&T) -> &str,
+    ) -> Result<bool, ChatError> {
+        match self {
+            Self::Auto => Ok(false),
+            Self::None => {
+                tools.clear();
+                Ok(false)
+            }
+            Self::Required => {
+                if tools.is_empty() {
+                    Err(ChatError::InvalidToolChoice(Cow::Borrowed(
+                        "tool_choice requires a tool call but no tools were provided",
+                    )))
+                } else {
+                    Ok(true)
+                }
+            }
+            Self::Function(function) => {
+                tools.retain(|tool| name(tool) == function);
+                if tools.is_empty() {
+                    Err(ChatError::InvalidToolChoice(Cow::Owned(format!(
+                        "tool_choice names '{function}' which is not in tools"
+                    ))))
+                } else {
+                    Ok(true)
+                }
+            }
+        }
+    }
+}
+
 // Model definition
 #[derive(Debug, Clone, Copy)]
 pub struct Model {
@@ -1,7 +1,8 @@
-use super::{IndexMap, Role};
+use super::{IndexMap, JsonOutput, Role};
 use crate::{
     app::constant::{ERROR, TYPE},
     common::utils::const_string::const_string,
+    core::aiserver::v1::stream_unified_chat_request::ThinkingLevel,
 };
 use alloc::borrow::Cow;
 use byte_str::ByteStr;
@@ -16,18 +17,23 @@ use serde::{
 pub struct MessageCreateParams {
     pub model: String,
     pub messages: Vec<MessageParam>,
-    #[allow(dead_code)]
-    pub max_tokens: usize,
+    pub max_tokens: u32,
     // #[serde(default)]
     // pub mcp_servers: Vec<McpServer>,
     #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
     pub stream: bool,
     #[serde(default)]
     pub system: Option<SystemContent>,
-    // #[serde(default)]
-    // pub thinking: Option<ThinkingConfig>,
+    #[serde(default)]
+    pub thinking: Option<ThinkingConfig>,
     #[serde(default)]
     pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(default)]
+    pub output_format: Option<OutputFormat>,
 }
 
 impl MessageCreateParams {
@@ -37,7 +43,7 @@ impl MessageCreateParams {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct MessageParam {
     #[serde(deserialize_with = "deserialize_anthropic_role")]
     pub role: Role,
@@ -56,14 +62,14 @@ where D: ::serde::Deserializer<'de> {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum MessageContent {
     String(String),
     Array(Vec<ContentBlockParam>),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ContentBlockParam {
     Text {
@@ -96,28 +102,28 @@ pub enum ContentBlockParam {
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum ToolResultContent {
     String(String),
     Array(Vec<ToolResultContentBlock>),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ToolResultContentBlock {
     Text { text: String },
     Image { source: ImageSource },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ImageSource {
     Base64(ImageSourceBase64),
     Url { url: String },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct ImageSourceBase64 {
     pub data: String,
     pub media_type: MediaType,
@@ -175,7 +181,7 @@ impl<'de> Deserialize<'de> for MediaType {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum DocumentSource {
     Base64 {
@@ -215,14 +221,14 @@ const_string!(ApplicationPdf = "application/pdf");
 //     pub enabled: Option<bool>,
 // }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum SystemContent {
     String(String),
     Array(Vec<TextBlockParam>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TextBlockParam {
     pub text: String,
 }
@@ -282,15 +288,28 @@ impl<'de> Deserialize<'de> for TextBlockParam {
     }
 }
 
-// #[derive(Debug, Deserialize)]
-// #[serde(tag = "type", rename_all = "lowercase")]
-// pub enum ThinkingConfig {
-//     Enabled {
-//         #[allow(dead_code)]
-//         budget_tokens: i64,
-//     },
-//     Disabled,
-// }
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ThinkingConfig {
+    Enabled { budget_tokens: i64 },
+    Disabled,
+}
+
+impl ThinkingConfig {
+    /// Budgets at or above this are treated as a request for deep thinking
+    const HIGH_BUDGET_TOKENS: i64 = 16384;
+
+    #[inline]
+    pub const fn thinking_level(self) -> ThinkingLevel {
+        match self {
+            Self::Enabled { budget_tokens } if budget_tokens >= Self::HIGH_BUDGET_TOKENS => {
+                ThinkingLevel::High
+            }
+            Self::Enabled { .. } => ThinkingLevel::Medium,
+            Self::Disabled => ThinkingLevel::Unspecified,
+        }
+    }
+}
 
 // #[derive(Debug, Serialize, Deserialize, Clone)]
 // #[serde(rename_all = "camelCase")]
@@ -327,6 +346,63 @@ pub struct Tool {
     pub description: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToolChoice {
+    Auto {
+        #[serde(default)]
+        disable_parallel_tool_use: bool,
+    },
+    Any {
+        #[serde(default)]
+        disable_parallel_tool_use: bool,
+    },
+    Tool {
+        name: String,
+        #[serde(default)]
+        disable_parallel_tool_use: bool,
+    },
+    None,
+}
+
+impl ToolChoice {
+    #[inline]
+    pub fn disable_parallel_tool_use(&self) -> bool {
+        match *self {
+            Self::Auto { disable_parallel_tool_use }
+            | Self::Any { disable_parallel_tool_use }
+            | Self::Tool { disable_parallel_tool_use, .. } => disable_parallel_tool_use,
+            Self::None => false,
+        }
+    }
+}
+
+impl From<ToolChoice> for super::ToolChoice {
+    #[inline]
+    fn from(choice: ToolChoice) -> Self {
+        match choice {
+            ToolChoice::Auto { .. } => Self::Auto,
+            ToolChoice::Any { .. } => Self::Required,
+            ToolChoice::Tool { name, .. } => Self::Function(name),
+            ToolChoice::None => Self::None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputFormat {
+    JsonSchema { schema: serde_json::Value },
+}
+
+impl From<OutputFormat> for JsonOutput {
+    #[inline]
+    fn from(format: OutputFormat) -> Self {
+        let OutputFormat::JsonSchema { schema } = format;
+        Self::Schema { name: None, schema }
+    }
+}
+
 #[derive(Serialize, Default)]
 pub struct Usage {
     pub input_tokens: i32,
@@ -357,6 +433,7 @@ pub struct Message<'a> {
     pub id: &'a str,
     pub model: &'static str,
     pub stop_reason: Option<StopReason>,
+    pub stop_sequence: Option<String>,
 }
 
 impl Serialize for Message<'_> {
@@ -369,7 +446,7 @@ impl Serialize for Message<'_> {
         state.serialize_field("content", &self.content)?;
         state.serialize_field("model", self.model)?;
         state.serialize_field("stop_reason", &self.stop_reason)?;
-        state.serialize_field("stop_sequence", &None::<bool>)?;
+        state.serialize_field("stop_sequence", &self.stop_sequence)?;
         state.serialize_field("usage", &self.usage)?;
         state.end()
     }
@@ -377,6 +454,7 @@ impl Serialize for Message<'_> {
 
 pub struct MessageDelta {
     pub stop_reason: StopReason,
+    pub stop_sequence: Option<String>,
 }
 
 impl Serialize for MessageDelta {
@@ -384,7 +462,7 @@ impl Serialize for MessageDelta {
     where S: Serializer {
         let mut state = serializer.serialize_struct("MessageDelta", 2)?;
         state.serialize_field("stop_reason", &self.stop_reason)?;
-        state.serialize_field("stop_sequence", &None::<bool>)?;
+        state.serialize_field("stop_sequence", &self.stop_sequence)?;
         state.end()
     }
 }
@@ -393,8 +471,8 @@ impl Serialize for MessageDelta {
 #[serde(rename_all = "snake_case")]
 pub enum StopReason {
     EndTurn = 0,
-    // MaxTokens = 1,
-    // StopSequence = 2,
+    MaxTokens = 1,
+    StopSequence = 2,
     ToolUse = 3,
     // PauseTurn = 4,
     // Refusal = 5,
@@ -0,0 +1,747 @@
+use super::{
+    IndexMap, JsonOutput, Role,
+    openai::{
+        self, ChatCompletionContent, ChatCompletionContentPart, ChatCompletionContentText,
+        ChatCompletionCreateParams, ChatCompletionMessageParam, ChatCompletionMessageToolCall,
+        ChatCompletionStreamOptions, ChatCompletionTool, FunctionDefinition, ImageUrl,
+        ReasoningEffort, StopSequences, chat_completion_message_tool_call,
+    },
+};
+use crate::{app::constant::ERROR, common::utils::const_string::const_string};
+use alloc::borrow::Cow;
+use byte_str::ByteStr;
+use http::StatusCode;
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeStruct as _};
+
+crate::define_typed_constants! {
+    &'static str => {
+        /// Action of `POST /v1beta/models/{model}:generateContent`
+        GENERATE_CONTENT = "generateContent",
+        /// Action of `POST /v1beta/models/{model}:streamGenerateContent`
+        STREAM_GENERATE_CONTENT = "streamGenerateContent",
+        /// `responseMimeType` requesting JSON output
+        APPLICATION_JSON = "application/json",
+        /// `alt` of a streaming request answered with server-sent events
+        ALT_SSE = "sse",
+        /// MIME type prefix of images, the only files passed on
+        MIME_IMAGE_PREFIX = "image/",
+        /// Keys of a conventional function response
+        KEY_OUTPUT = "output",
+        KEY_ERROR = "error",
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GenerateContentQuery {
+    #[serde(default)]
+    pub alt: Option<String>,
+}
+
+impl GenerateContentQuery {
+    /// Whether a streaming response uses server-sent events instead of a JSON array
+    #[inline]
+    pub fn is_sse(&self) -> bool { self.alt.as_deref() == Some(ALT_SSE) }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest {
+    #[serde(default)]
+    pub contents: Vec<Content>,
+    #[serde(default, alias = "system_instruction")]
+    pub system_instruction: Option<Content>,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default, alias = "tool_config")]
+    pub tool_config: Option<ToolConfig>,
+    #[serde(default, alias = "generation_config")]
+    pub generation_config: GenerationConfig,
+}
+
+impl GenerateContentRequest {
+    /// MIME type of the first file of a user turn that is not an image, empty when the file has
+    /// none, `None` when every file is one
+    pub fn non_image_file(&self) -> Option<&str> {
+        self.contents
+            .iter()
+            .filter(|content| content.role != Role::Assistant)
+            .flat_map(|content| &content.parts)
+            .find_map(|part| {
+                let mime_type = match part {
+                    Part::InlineData { inline_data } => inline_data.mime_type.as_str(),
+                    Part::FileData { file_data } => file_data.mime_type.as_deref().unwrap_or(""),
+                    _ => return None,
+                };
+                (!mime_type.starts_with(MIME_IMAGE_PREFIX)).then_some(mime_type)
+            })
+    }
+
+    /// The chat completion answering the request for `model`
+    pub fn into_chat_completion(self, model: String, stream: bool) -> ChatCompletionCreateParams {
+        let Self { contents, system_instruction, tools, tool_config, mut generation_config } = self;
+
+        let mut tools: Vec<FunctionDeclaration> =
+            tools.into_iter().flat_map(|tool| tool.function_declarations).collect();
+        let tool_choice = tool_config
+            .and_then(|config| config.function_calling_config)
+            .map(|config| config.into_tool_choice(&mut tools).into());
+        let response_format = generation_config.json_output().map(Into::into);
+        let GenerationConfig { stop_sequences, max_output_tokens, thinking_config, .. } =
+            generation_config;
+
+        ChatCompletionCreateParams {
+            model,
+            messages: into_messages(contents, system_instruction),
+            reasoning_effort: thinking_config.map(ThinkingConfig::reasoning_effort),
+            stream,
+            // Every response carries `usageMetadata`
+            stream_options: ChatCompletionStreamOptions { include_usage: true },
+            tools: tools.into_iter().map(Into::into).collect(),
+            tool_choice,
+            parallel_tool_calls: None,
+            response_format,
+            stop: (!stop_sequences.is_empty()).then_some(StopSequences::Array(stop_sequences)),
+            max_tokens: None,
+            max_completion_tokens: max_output_tokens,
+            n: None,
+        }
+    }
+}
+
+/// Conversation of `contents` as chat messages, each function call followed by its response
+fn into_messages(
+    contents: Vec<Content>,
+    system_instruction: Option<Content>,
+) -> Vec<ChatCompletionMessageParam> {
+    let mut messages = Vec::with_capacity(contents.len() + 1);
+
+    if let Some(system) = system_instruction {
+        let text = system
+            .parts
+            .into_iter()
+            .filter_map(|part| match part {
+                Part::Text { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !text.is_empty() {
+            messages.push(ChatCompletionMessageParam::System {
+                content: ChatCompletionContentText::String(text),
+            });
+        }
+    }
+
+    let mut contents = contents.into_iter().peekable();
+    while let Some(content) = contents.next() {
+        if content.role != Role::Assistant {
+            let parts: Vec<_> =
+                content.parts.into_iter().filter_map(Part::into_content_part).collect();
+            if !parts.is_empty() {
+                messages.push(ChatCompletionMessageParam::User {
+                    content: ChatCompletionContent::Array(parts),
+                });
+            }
+            continue;
+        }
+
+        let mut text_parts = Vec::new();
+        let mut function_calls = Vec::new();
+        for part in content.parts {
+            match part {
+                Part::Text { text, thought: false } => text_parts.push(text),
+                Part::FunctionCall { function_call } => function_calls.push(function_call),
+                // Thoughts are not sent back
+                _ => {}
+            }
+        }
+        messages.push(ChatCompletionMessageParam::Assistant {
+            content: ChatCompletionContentText::String(text_parts.join("\n")),
+            tool_calls: None,
+        });
+
+        // Take the responses to this turn's calls out of the next user turn, orphans are dropped
+        if function_calls.is_empty() {
+            continue;
+        }
+        let Some(following) = contents.peek_mut().filter(|content| content.role == Role::User)
+        else {
+            continue;
+        };
+        for call in function_calls {
+            let Some(i) = following.parts.iter().position(|part| {
+                let Part::FunctionResponse { function_response } = part else {
+                    return false;
+                };
+                match (&call.id, &function_response.id) {
+                    (Some(a), Some(b)) => a[..] == b[..],
+                    _ => call.name[..] == function_response.name[..],
+                }
+            }) else {
+                continue;
+            };
+            let Part::FunctionResponse { function_response } = following.parts.remove(i) else {
+                __unreachable!()
+            };
+
+            // Gemini ids are optional, one is made up when neither side has it
+            let id = call
+                .id
+                .or(function_response.id)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string().into());
+            let tool_call = Box::new(ChatCompletionMessageToolCall::Function {
+                id: id.clone(),
+                function: chat_completion_message_tool_call::Function {
+                    arguments: __unwrap!(serde_json::to_string(&call.args)),
+                    name: call.name,
+                },
+            });
+
+            // The first call goes along with the text of the turn
+            if let Some(ChatCompletionMessageParam::Assistant {
+                tool_calls: tool_calls @ None,
+                ..
+            }) = messages.last_mut()
+            {
+                *tool_calls = Some(tool_call);
+            } else {
+                messages.push(ChatCompletionMessageParam::Assistant {
+                    content: ChatCompletionContentText::String(String::new()),
+                    tool_calls: Some(tool_call),
+                });
+            }
+            messages.push(ChatCompletionMessageParam::Tool {
+                content: ChatCompletionContentText::String(response_text(
+                    function_response.response,
+                )),
+                tool_call_id: id,
+            });
+        }
+        if following.parts.is_empty() {
+            contents.next();
+        }
+    }
+
+    messages
+}
+
+/// `response` of a `functionResponse` as tool output, `{"output": ...}` and `{"error": ...}`
+/// are unwrapped by convention
+fn response_text(response: serde_json::Value) -> String {
+    let value = match response {
+        serde_json::Value::Object(map)
+            if map.len() == 1 && (map.contains_key(KEY_OUTPUT) || map.contains_key(KEY_ERROR)) =>
+        {
+            __unwrap!(map.into_iter().next()).1
+        }
+        value => value,
+    };
+    match value {
+        serde_json::Value::String(text) => text,
+        value => value.to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Content {
+    #[serde(default = "default_role", deserialize_with = "deserialize_gemini_role")]
+    pub role: Role,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+#[inline]
+const fn default_role() -> Role { Role::User }
+
+fn deserialize_gemini_role<'de, D>(deserializer: D) -> Result<Role, D::Error>
+where D: ::serde::Deserializer<'de> {
+    let s = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+    match s.as_str() {
+        "user" | "function" => Ok(Role::User),
+        "model" => Ok(Role::Assistant),
+        other => Err(serde::de::Error::custom(format_args!(
+            "Invalid Gemini role '{other}': only 'user' and 'model' are supported"
+        ))),
+    }
+}
+
+/// One of the data fields of a `Part`, other kinds (code execution etc.) are rejected
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Part {
+    Text {
+        text: String,
+        #[serde(default)]
+        thought: bool,
+    },
+    InlineData {
+        #[serde(rename = "inlineData", alias = "inline_data")]
+        inline_data: Blob,
+    },
+    FileData {
+        #[serde(rename = "fileData", alias = "file_data")]
+        file_data: FileData,
+    },
+    FunctionCall {
+        #[serde(rename = "functionCall", alias = "function_call")]
+        function_call: FunctionCall,
+    },
+    FunctionResponse {
+        #[serde(rename = "functionResponse", alias = "function_response")]
+        function_response: FunctionResponse,
+    },
+}
+
+impl Part {
+    /// Part of a user message, function responses are taken along with their calls instead
+    ///
+    /// Files are taken as images, `GenerateContentRequest::non_image_file` turns away the rest
+    fn into_content_part(self) -> Option<ChatCompletionContentPart> {
+        match self {
+            Self::Text { text, .. } => Some(ChatCompletionContentPart::Text { text }),
+            Self::InlineData { inline_data: Blob { mime_type, data } } => {
+                Some(ChatCompletionContentPart::ImageUrl {
+                    image_url: ImageUrl { url: format!("data:{mime_type};base64,{data}") },
+                })
+            }
+            Self::FileData { file_data } => Some(ChatCompletionContentPart::ImageUrl {
+                image_url: ImageUrl { url: file_data.file_uri },
+            }),
+            Self::FunctionCall { .. } | Self::FunctionResponse { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Blob {
+    #[serde(alias = "mime_type")]
+    pub mime_type: String,
+    pub data: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileData {
+    #[serde(default, alias = "mime_type")]
+    pub mime_type: Option<String>,
+    #[serde(alias = "file_uri")]
+    pub file_uri: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FunctionCall {
+    #[serde(default)]
+    pub id: Option<ByteStr>,
+    pub name: ByteStr,
+    #[serde(default)]
+    pub args: IndexMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FunctionResponse {
+    #[serde(default)]
+    pub id: Option<ByteStr>,
+    pub name: ByteStr,
+    #[serde(default)]
+    pub response: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Tool {
+    #[serde(default, alias = "function_declarations")]
+    pub function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionDeclaration {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// OpenAPI subset, type names are upper case
+    #[serde(default)]
+    pub parameters: Option<IndexMap<String, serde_json::Value>>,
+    /// Plain JSON Schema, takes precedence over `parameters`
+    #[serde(default, alias = "parameters_json_schema")]
+    pub parameters_json_schema: Option<IndexMap<String, serde_json::Value>>,
+}
+
+impl From<FunctionDeclaration> for ChatCompletionTool {
+    fn from(declaration: FunctionDeclaration) -> Self {
+        let parameters = match (declaration.parameters_json_schema, declaration.parameters) {
+            (Some(schema), _) => schema,
+            (None, Some(mut schema)) => {
+                normalize_schema(schema.iter_mut());
+                schema
+            }
+            (None, None) => IndexMap::default(),
+        };
+        Self::Function {
+            function: FunctionDefinition {
+                name: declaration.name,
+                description: declaration.description,
+                parameters,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolConfig {
+    #[serde(default, alias = "function_calling_config")]
+    pub function_calling_config: Option<FunctionCallingConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionCallingConfig {
+    #[serde(default)]
+    pub mode: FunctionCallingMode,
+    #[serde(default, alias = "allowed_function_names")]
+    pub allowed_function_names: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FunctionCallingMode {
+    #[default]
+    #[serde(alias = "MODE_UNSPECIFIED")]
+    Auto,
+    Any,
+    None,
+    Validated,
+}
+
+impl FunctionCallingConfig {
+    /// Narrows `tools` down to `allowedFunctionNames`, then normalizes the mode
+    pub fn into_tool_choice(self, tools: &mut Vec<FunctionDeclaration>) -> super::ToolChoice {
+        let Self { mode, mut allowed_function_names } = self;
+        if !allowed_function_names.is_empty() {
+            tools.retain(|tool| allowed_function_names.contains(&tool.name));
+        }
+        match mode {
+            FunctionCallingMode::Auto | FunctionCallingMode::Validated => super::ToolChoice::Auto,
+            FunctionCallingMode::None => super::ToolChoice::None,
+            FunctionCallingMode::Any if allowed_function_names.len() == 1 => {
+                super::ToolChoice::Function(__unwrap!(allowed_function_names.pop()))
+            }
+            FunctionCallingMode::Any => super::ToolChoice::Required,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(default, alias = "stop_sequences")]
+    pub stop_sequences: Vec<String>,
+    #[serde(default, alias = "max_output_tokens")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default, alias = "response_mime_type")]
+    pub response_mime_type: Option<String>,
+    #[serde(default, alias = "response_schema")]
+    pub response_schema: Option<serde_json::Value>,
+    #[serde(default, alias = "response_json_schema")]
+    pub response_json_schema: Option<serde_json::Value>,
+    #[serde(default, alias = "thinking_config")]
+    pub thinking_config: Option<ThinkingConfig>,
+}
+
+impl GenerationConfig {
+    /// Structured output requested through `responseMimeType`
+    pub fn json_output(&mut self) -> Option<JsonOutput> {
+        if self.response_mime_type.as_deref() != Some(APPLICATION_JSON) {
+            return None;
+        }
+        Some(match (self.response_json_schema.take(), self.response_schema.take()) {
+            (Some(schema), _) => JsonOutput::Schema { name: None, schema },
+            (None, Some(mut schema)) => {
+                if let serde_json::Value::Object(ref mut map) = schema {
+                    normalize_schema(map.iter_mut());
+                }
+                JsonOutput::Schema { name: None, schema }
+            }
+            (None, None) => JsonOutput::Object,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ThinkingConfig {
+    #[serde(default, alias = "thinking_budget")]
+    pub thinking_budget: Option<i64>,
+    #[serde(default, alias = "thinking_level")]
+    pub thinking_level: Option<GeminiThinkingLevel>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GeminiThinkingLevel {
+    #[serde(alias = "LOW")]
+    Low,
+    #[serde(alias = "HIGH")]
+    High,
+}
+
+impl ThinkingConfig {
+    /// Budgets at or above this are treated as a request for deep thinking
+    const HIGH_BUDGET_TOKENS: i64 = 16384;
+
+    #[inline]
+    pub const fn reasoning_effort(self) -> ReasoningEffort {
+        match (self.thinking_level, self.thinking_budget) {
+            (Some(GeminiThinkingLevel::High), _) => ReasoningEffort::High,
+            (Some(GeminiThinkingLevel::Low), _) | (None, None) => ReasoningEffort::Medium,
+            (None, Some(0)) => ReasoningEffort::None,
+            (None, Some(budget)) if budget >= Self::HIGH_BUDGET_TOKENS => ReasoningEffort::High,
+            // -1 is dynamic thinking
+            (None, Some(_)) => ReasoningEffort::Medium,
+        }
+    }
+}
+
+/// Lower-cases the OpenAPI `type` names so the schema reads as plain JSON Schema
+pub fn normalize_schema<'a>(schema: impl Iterator<Item = (&'a String, &'a mut serde_json::Value)>) {
+    for (key, value) in schema {
+        match value {
+            serde_json::Value::String(s) if key == "type" => s.make_ascii_lowercase(),
+            serde_json::Value::Object(map) => normalize_schema(map.iter_mut()),
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    if let serde_json::Value::Object(map) = value {
+                        normalize_schema(map.iter_mut())
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse<'a> {
+    pub candidates: [Candidate<'a>; 1],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_metadata: Option<UsageMetadata>,
+    pub model_version: &'a str,
+    pub response_id: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate<'a> {
+    pub content: CandidateContent<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    pub index: u32,
+}
+
+#[derive(Serialize)]
+pub struct CandidateContent<'a> {
+    pub role: Model,
+    pub parts: &'a [ResponsePart],
+}
+
+const_string!(Model = "model");
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ResponsePart {
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "core::ops::Not::not")]
+        thought: bool,
+    },
+    FunctionCall {
+        #[serde(rename = "functionCall")]
+        function_call: ResponseFunctionCall,
+    },
+}
+
+#[derive(Serialize)]
+pub struct ResponseFunctionCall {
+    pub id: ByteStr,
+    pub name: ByteStr,
+    pub args: IndexMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FinishReason {
+    Stop,
+    MaxTokens,
+    // Safety,
+    // MalformedFunctionCall,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    pub prompt_token_count: i32,
+    pub candidates_token_count: i32,
+    pub total_token_count: i32,
+    #[serde(skip_serializing_if = "i32_is_zero")]
+    pub cached_content_token_count: i32,
+    #[serde(skip_serializing_if = "i32_is_zero")]
+    pub thoughts_token_count: i32,
+}
+
+impl UsageMetadata {
+    /// Reports `thoughts_tokens` of the output as spent on thinking
+    #[inline]
+    fn with_thoughts_tokens(mut self, thoughts_tokens: i32) -> Self {
+        let thoughts_tokens = thoughts_tokens.min(self.candidates_token_count);
+        self.thoughts_token_count = thoughts_tokens;
+        self.candidates_token_count -= thoughts_tokens;
+        self
+    }
+}
+
+impl From<openai::Usage> for UsageMetadata {
+    #[inline]
+    fn from(usage: openai::Usage) -> Self {
+        let cached_content_token_count = usage.prompt_tokens_details.cached_tokens;
+        let prompt_token_count = usage.prompt_tokens + cached_content_token_count;
+        Self {
+            prompt_token_count,
+            candidates_token_count: usage.completion_tokens,
+            total_token_count: prompt_token_count + usage.completion_tokens,
+            cached_content_token_count,
+            thoughts_token_count: 0,
+        }
+        .with_thoughts_tokens(usage.completion_tokens_details.reasoning_tokens)
+    }
+}
+
+#[inline]
+fn i32_is_zero(i: &i32) -> bool { *i == 0 }
+
+pub struct GeminiError {
+    pub code: StatusCode,
+    pub message: Cow<'static, str>,
+}
+
+impl GeminiError {
+    /// `google.rpc.Code` name of the HTTP status
+    fn status(&self) -> &'static str {
+        match self.code {
+            StatusCode::BAD_REQUEST => "INVALID_ARGUMENT",
+            StatusCode::UNAUTHORIZED => "UNAUTHENTICATED",
+            StatusCode::PAYMENT_REQUIRED | StatusCode::FORBIDDEN => "PERMISSION_DENIED",
+            StatusCode::NOT_FOUND => "NOT_FOUND",
+            StatusCode::TOO_MANY_REQUESTS => "RESOURCE_EXHAUSTED",
+            StatusCode::NOT_IMPLEMENTED => "UNIMPLEMENTED",
+            StatusCode::SERVICE_UNAVAILABLE => "UNAVAILABLE",
+            StatusCode::GATEWAY_TIMEOUT => "DEADLINE_EXCEEDED",
+            code if code.is_client_error() => "FAILED_PRECONDITION",
+            _ => "INTERNAL",
+        }
+    }
+}
+
+impl Serialize for GeminiError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        struct Inner<'a>(&'a GeminiError);
+
+        impl Serialize for Inner<'_> {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer {
+                let mut state = serializer.serialize_struct("Status", 3)?;
+                state.serialize_field("code", &self.0.code.as_u16())?;
+                state.serialize_field("message", &self.0.message)?;
+                state.serialize_field("status", self.0.status())?;
+                state.end()
+            }
+        }
+
+        let mut state = serializer.serialize_struct("GeminiError", 1)?;
+        state.serialize_field(ERROR, &Inner(self))?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_responses_follow_their_calls() {
+        let request: GenerateContentRequest = serde_json::from_str(
+            r#"{
+                "systemInstruction": {"parts": [{"text": "Be brief"}]},
+                "contents": [
+                    {"role": "user", "parts": [{"text": "Weather and time in Paris?"}]},
+                    {"role": "model", "parts": [
+                        {"text": "Checking", "thought": true},
+                        {"functionCall": {"name": "weather", "args": {"city": "Paris"}}},
+                        {"functionCall": {"id": "c2", "name": "time", "args": {}}}
+                    ]},
+                    {"role": "user", "parts": [
+                        {"functionResponse": {"id": "c2", "name": "time",
+                            "response": {"output": "noon"}}},
+                        {"functionResponse": {"name": "weather",
+                            "response": {"output": {"sky": "clear"}}}}
+                    ]}
+                ],
+                "generationConfig": {
+                    "stopSequences": ["END"],
+                    "maxOutputTokens": 64,
+                    "thinkingConfig": {"thinkingBudget": 0}
+                }
+            }"#,
+        )
+        .unwrap();
+        let params = request.into_chat_completion("gemini-test".to_owned(), false);
+
+        use ChatCompletionMessageParam as M;
+        let [
+            M::System { content: system },
+            M::User { .. },
+            M::Assistant { tool_calls: Some(weather), .. },
+            M::Tool { content: weather_output, tool_call_id: weather_id },
+            M::Assistant { tool_calls: Some(time), .. },
+            M::Tool { content: time_output, tool_call_id: time_id },
+        ] = &params.messages[..]
+        else {
+            panic!("unexpected messages")
+        };
+        assert_eq!(system.clone().text(), "Be brief");
+
+        let ChatCompletionMessageToolCall::Function { id, function } = &**weather;
+        assert_eq!(id[..], weather_id[..]);
+        assert_eq!(function.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(weather_output.clone().text(), r#"{"sky":"clear"}"#);
+
+        let ChatCompletionMessageToolCall::Function { id, function } = &**time;
+        assert_eq!((&id[..], &time_id[..]), ("c2", "c2"));
+        assert_eq!(&function.name[..], "time");
+        assert_eq!(time_output.clone().text(), "noon");
+
+        assert!(matches!(params.reasoning_effort, Some(ReasoningEffort::None)));
+        assert!(matches!(params.stop, Some(StopSequences::Array(ref stop)) if stop == &["END"]));
+        assert_eq!(params.max_completion_tokens, Some(64));
+    }
+
+    #[test]
+    fn only_image_files_are_accepted() {
+        let request = |parts: &str| -> GenerateContentRequest {
+            serde_json::from_str(&format!(r#"{{"contents": [{{"parts": {parts}}}]}}"#)).unwrap()
+        };
+        let images = request(
+            r#"[{"inlineData": {"mimeType": "image/png", "data": "AA=="}},
+                {"fileData": {"mimeType": "image/jpeg", "fileUri": "https://example.com/a.jpg"}}]"#,
+        );
+        assert_eq!(images.non_image_file(), None);
+
+        let pdf = request(r#"[{"inlineData": {"mimeType": "application/pdf", "data": "AA=="}}]"#);
+        assert_eq!(pdf.non_image_file(), Some("application/pdf"));
+        let untyped = request(r#"[{"fileData": {"fileUri": "https://example.com/a"}}]"#);
+        assert_eq!(untyped.non_image_file(), Some(""));
+    }
+}
@@ -0,0 +1,395 @@
+//! Structured output (`response_format` / `output_format`) support
+//!
+//! The upstream has no native structured output, so the format is injected as
+//! instructions and the final text is checked here. Schema validation covers the
+//! subset of JSON Schema used by structured outputs, unknown keywords are ignored.
+
+use core::fmt::{Display, Write as _};
+use serde_json::{Map, Value};
+
+/// Nesting limit for schema evaluation, guards against `$ref` cycles
+const MAX_DEPTH: usize = 128;
+
+/// Structured output requested by the client, normalized
+pub enum JsonOutput {
+    /// Any JSON object
+    Object,
+    /// JSON matching the schema
+    Schema { name: Option<String>, schema: Value },
+}
+
+impl JsonOutput {
+    /// Instructions appended to the system prompt
+    pub fn instructions(&self) -> String {
+        match self {
+            Self::Object => String::from(
+                "Respond with a single valid JSON object only. Do not wrap it in a code block \
+                 and do not add any text before or after it.",
+            ),
+            Self::Schema { name, schema } => {
+                let mut s = String::from(
+                    "Respond with a single valid JSON value only, conforming to the following \
+                     JSON Schema",
+                );
+                if let Some(name) = name {
+                    let _ = write!(s, " (\"{name}\")");
+                }
+                let _ = write!(
+                    s,
+                    ". Do not wrap it in a code block and do not add any text before or after \
+                     it.\n\n{schema}"
+                );
+                s
+            }
+        }
+    }
+
+    /// Follow-up user message for a retry after `error`
+    pub fn correction(error: &str) -> String {
+        format!(
+            "Your previous reply was rejected: {error}. Reply again with the corrected JSON \
+             only, without any other text."
+        )
+    }
+
+    /// Extracts the JSON from `text`, dropping code fences and surrounding prose,
+    /// and checks it against the format
+    pub fn extract<'a>(&self, text: &'a str) -> Result<&'a str, String> {
+        let text = strip_code_fence(text.trim());
+        let mut has_invalid = false;
+        let mut format_error = None;
+        let mut pos = 0;
+
+        while let Some(offset) = text[pos..].find(['{', '[']) {
+            let start = pos + offset;
+            let mut values =
+                serde_json::Deserializer::from_str(&text[start..]).into_iter::<Value>();
+            let Some(Ok(value)) = values.next() else {
+                has_invalid = true;
+                pos = start + 1;
+                continue;
+            };
+            let end = start + values.byte_offset();
+
+            let result = match self {
+                Self::Object if value.is_object() => Ok(()),
+                Self::Object => Err(String::from("expected a JSON object")),
+                Self::Schema { schema, .. } => validate(schema, &value),
+            };
+            match result {
+                Ok(()) => return Ok(&text[start..end]),
+                Err(e) if format_error.is_none() => format_error = Some(e),
+                Err(_) => {}
+            }
+            // Values nested in a rejected one are not candidates
+            pos = end;
+        }
+
+        Err(format_error.unwrap_or_else(|| {
+            String::from(if has_invalid { "invalid JSON" } else { "no JSON found in the response" })
+        }))
+    }
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    let Some(start) = text.find("```") else {
+        return text;
+    };
+    let rest = &text[start + 3..];
+    // Skip the info string, e.g. ```json
+    let rest = rest.find('\n').map_or(rest, |i| &rest[i + 1..]);
+    match rest.find("```") {
+        Some(end) => rest[..end].trim(),
+        None => rest.trim(),
+    }
+}
+
+/// Validates `value` against `schema`
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    Validator { root: schema }.check(schema, value, &mut String::new(), 0)
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Validator<'a> {
+    fn check(
+        &self,
+        schema: &'a Value,
+        value: &Value,
+        path: &mut String,
+        depth: usize,
+    ) -> Result<(), String> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => return Err(error(path, "no value is allowed")),
+            _ => return Ok(()),
+        };
+        if depth > MAX_DEPTH {
+            return Err(error(path, "schema nesting is too deep"));
+        }
+
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            let target = reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or_else(|| error(path, format_args!("unresolvable $ref '{reference}'")))?;
+            self.check(target, value, path, depth + 1)?;
+        }
+
+        if let Some(types) = schema.get("type")
+            && !matches_type(types, value)
+        {
+            return Err(error(path, format_args!("expected {}", TypeName(types))));
+        }
+        if let Some(Value::Array(options)) = schema.get("enum")
+            && !options.contains(value)
+        {
+            return Err(error(path, "not one of the allowed values"));
+        }
+        if let Some(expected) = schema.get("const")
+            && expected != value
+        {
+            return Err(error(path, format_args!("expected {expected}")));
+        }
+
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for schema in schemas {
+                self.check(schema, value, path, depth + 1)?;
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("anyOf")
+            && !schemas.iter().any(|schema| self.check(schema, value, path, depth + 1).is_ok())
+        {
+            return Err(error(path, "does not match any schema in anyOf"));
+        }
+        if let Some(Value::Array(schemas)) = schema.get("oneOf")
+            && schemas
+                .iter()
+                .filter(|schema| self.check(schema, value, path, depth + 1).is_ok())
+                .count()
+                != 1
+        {
+            return Err(error(path, "does not match exactly one schema in oneOf"));
+        }
+
+        match value {
+            Value::Object(object) => self.check_object(schema, object, path, depth),
+            Value::Array(items) => self.check_array(schema, items, path, depth),
+            Value::String(s) => {
+                let len = s.chars().count();
+                if let Some(min) = number(schema, "minLength")
+                    && (len as f64) < min
+                {
+                    return Err(error(path, format_args!("shorter than {min} characters")));
+                }
+                if let Some(max) = number(schema, "maxLength")
+                    && (len as f64) > max
+                {
+                    return Err(error(path, format_args!("longer than {max} characters")));
+                }
+                Ok(())
+            }
+            Value::Number(n) => {
+                let Some(n) = n.as_f64() else { return Ok(()) };
+                if let Some(min) = number(schema, "minimum")
+                    && n < min
+                {
+                    return Err(error(path, format_args!("less than {min}")));
+                }
+                if let Some(max) = number(schema, "maximum")
+                    && n > max
+                {
+                    return Err(error(path, format_args!("greater than {max}")));
+                }
+                if let Some(min) = number(schema, "exclusiveMinimum")
+                    && n <= min
+                {
+                    return Err(error(path, format_args!("not greater than {min}")));
+                }
+                if let Some(max) = number(schema, "exclusiveMaximum")
+                    && n >= max
+                {
+                    return Err(error(path, format_args!("not less than {max}")));
+                }
+                Ok(())
+            }
+            Value::Null | Value::Bool(_) => Ok(()),
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &mut String,
+        depth: usize,
+    ) -> Result<(), String> {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    return Err(error(path, format_args!("missing required property '{key}'")));
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema.get("additionalProperties");
+        for (key, value) in object {
+            let subschema = match properties.and_then(|properties| properties.get(key)) {
+                Some(subschema) => subschema,
+                None => match additional {
+                    Some(Value::Bool(false)) => {
+                        return Err(error(path, format_args!("unexpected property '{key}'")));
+                    }
+                    Some(subschema) => subschema,
+                    None => continue,
+                },
+            };
+            let len = path.len();
+            path.push('/');
+            path.push_str(key);
+            let result = self.check(subschema, value, path, depth + 1);
+            path.truncate(len);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn check_array(
+        &self,
+        schema: &'a Map<String, Value>,
+        items: &[Value],
+        path: &mut String,
+        depth: usize,
+    ) -> Result<(), String> {
+        if let Some(min) = number(schema, "minItems")
+            && (items.len() as f64) < min
+        {
+            return Err(error(path, format_args!("fewer than {min} items")));
+        }
+        if let Some(max) = number(schema, "maxItems")
+            && (items.len() as f64) > max
+        {
+            return Err(error(path, format_args!("more than {max} items")));
+        }
+
+        if let Some(subschema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                let len = path.len();
+                let _ = write!(path, "/{i}");
+                let result = self.check(subschema, item, path, depth + 1);
+                path.truncate(len);
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn number(schema: &Map<String, Value>, keyword: &str) -> Option<f64> {
+    schema.get(keyword).and_then(Value::as_f64)
+}
+
+fn matches_type(types: &Value, value: &Value) -> bool {
+    match types {
+        Value::String(ty) => matches_type_name(ty, value),
+        Value::Array(types) => {
+            types.iter().filter_map(Value::as_str).any(|ty| matches_type_name(ty, value))
+        }
+        _ => true,
+    }
+}
+
+fn matches_type_name(ty: &str, value: &Value) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => match value {
+            Value::Number(n) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|n| n.fract() == 0.0)
+            }
+            _ => false,
+        },
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+struct TypeName<'a>(&'a Value);
+
+impl Display for TypeName<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Value::String(ty) => f.write_str(ty),
+            Value::Array(types) => {
+                for (i, ty) in types.iter().filter_map(Value::as_str).enumerate() {
+                    if i != 0 {
+                        f.write_str(" or ")?;
+                    }
+                    f.write_str(ty)?;
+                }
+                Ok(())
+            }
+            other => Display::fmt(other, f),
+        }
+    }
+}
+
+fn error(path: &str, message: impl Display) -> String {
+    if path.is_empty() { format!("{message}") } else { format!("{message} at {path}") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> JsonOutput {
+        JsonOutput::Schema {
+            name: None,
+            schema: json!({
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}}
+                },
+                "required": ["name"],
+                "additionalProperties": false,
+                "$defs": {"tag": {"type": "string", "enum": ["a", "b"]}}
+            }),
+        }
+    }
+
+    #[test]
+    fn test_extract_strips_prose_and_fences() {
+        let format = JsonOutput::Object;
+        assert_eq!(format.extract("Sure! {\"a\": 1} Hope it helps."), Ok("{\"a\": 1}"));
+        assert_eq!(format.extract("```json\n{\"a\": [1, 2]}\n```"), Ok("{\"a\": [1, 2]}"));
+        assert!(format.extract("[1, 2]").is_err());
+        assert!(format.extract("no json here").is_err());
+    }
+
+    #[test]
+    fn test_extract_validates_schema() {
+        let format = schema();
+        assert!(format.extract(r#"{"name": "x", "tags": ["a", "b"]}"#).is_ok());
+        assert_eq!(
+            format.extract(r#"{"tags": []}"#),
+            Err(String::from("missing required property 'name'"))
+        );
+        assert_eq!(
+            format.extract(r#"{"name": "x", "tags": ["c"]}"#),
+            Err(String::from("not one of the allowed values at /tags/0"))
+        );
+        assert_eq!(format.extract(r#"{"name": 1}"#), Err(String::from("expected string at /name")));
+        assert_eq!(
+            format.extract(r#"{"name": "x", "extra": true}"#),
+            Err(String::from("unexpected property 'extra'"))
+        );
+    }
+}
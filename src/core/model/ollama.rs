@@ -0,0 +1,623 @@
+use super::{
+    IndexMap, JsonOutput, Model,
+    openai::{
+        self, ChatCompletionContent, ChatCompletionContentPart, ChatCompletionContentText,
+        ChatCompletionCreateParams, ChatCompletionMessageParam, ChatCompletionMessageToolCall,
+        ChatCompletionStreamOptions, ChatCompletionTool, ImageUrl, ReasoningEffort, StopSequences,
+        chat_completion_message_tool_call,
+    },
+};
+use crate::app::constant::ERROR;
+use alloc::borrow::Cow;
+use byte_str::ByteStr;
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeStruct as _};
+
+crate::define_typed_constants! {
+    &'static str => {
+        /// Tag Ollama clients append to model names
+        LATEST_TAG = ":latest",
+        /// `format` requesting any JSON object
+        FORMAT_JSON = "json",
+        /// Content type of a streaming response
+        APPLICATION_NDJSON = "application/x-ndjson",
+        /// Prefix some clients put in front of the base64 data anyway
+        DATA_URL_PREFIX = "data:",
+    }
+}
+
+/// Strips the `:latest` tag, the only tag the models are listed with
+#[inline]
+pub fn model_name(name: &str) -> &str { name.strip_suffix(LATEST_TAG).unwrap_or(name) }
+
+#[inline]
+const fn default_stream() -> bool { true }
+
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub tools: Vec<ChatCompletionTool>,
+    #[serde(default)]
+    pub format: Option<serde_json::Value>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub think: Option<Think>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub format: Option<serde_json::Value>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub think: Option<Think>,
+}
+
+impl GenerateRequest {
+    /// The single turn conversation `/api/generate` stands for
+    pub fn into_chat(self) -> ChatRequest {
+        let mut messages = Vec::with_capacity(2);
+        if let Some(system) = self.system
+            && !system.is_empty()
+        {
+            messages.push(Message {
+                role: MessageRole::System,
+                content: system,
+                images: vec![],
+                thinking: None,
+                tool_calls: vec![],
+                tool_name: None,
+                tool_call_id: None,
+            });
+        }
+        messages.push(Message {
+            role: MessageRole::User,
+            content: self.prompt,
+            images: self.images,
+            thinking: None,
+            tool_calls: vec![],
+            tool_name: None,
+            tool_call_id: None,
+        });
+        ChatRequest {
+            model: self.model,
+            messages,
+            tools: vec![],
+            format: self.format,
+            options: self.options,
+            stream: self.stream,
+            think: self.think,
+        }
+    }
+}
+
+impl ChatRequest {
+    /// `"json"` for any object or a JSON schema
+    fn json_output(&mut self) -> Option<JsonOutput> {
+        match self.format.take()? {
+            serde_json::Value::String(format) if format == FORMAT_JSON => Some(JsonOutput::Object),
+            schema @ serde_json::Value::Object(_) => {
+                Some(JsonOutput::Schema { name: None, schema })
+            }
+            _ => None,
+        }
+    }
+
+    /// The chat completion answering the request
+    pub fn into_chat_completion(mut self) -> ChatCompletionCreateParams {
+        let response_format = self.json_output().map(Into::into);
+        let Self { model, messages, tools, options, stream, think, .. } = self;
+        let max_completion_tokens = options.max_tokens();
+
+        ChatCompletionCreateParams {
+            model: model_name(&model).to_owned(),
+            messages: into_messages(messages),
+            reasoning_effort: think.map(Think::reasoning_effort),
+            stream,
+            // The final response always carries the counts
+            stream_options: ChatCompletionStreamOptions { include_usage: true },
+            tools,
+            tool_choice: None,
+            parallel_tool_calls: None,
+            response_format,
+            stop: (!options.stop.is_empty()).then_some(StopSequences::Array(options.stop)),
+            max_tokens: None,
+            max_completion_tokens,
+            n: None,
+        }
+    }
+}
+
+/// Chat messages of the conversation, each tool call followed by its result
+fn into_messages(messages: Vec<Message>) -> Vec<ChatCompletionMessageParam> {
+    let mut params = Vec::with_capacity(messages.len());
+    let mut messages = messages.into_iter().peekable();
+
+    while let Some(message) = messages.next() {
+        match message.role {
+            MessageRole::System => params.push(ChatCompletionMessageParam::System {
+                content: ChatCompletionContentText::String(message.content),
+            }),
+            MessageRole::User => {
+                let content = if message.images.is_empty() {
+                    ChatCompletionContent::String(message.content)
+                } else {
+                    let mut parts = Vec::with_capacity(message.images.len() + 1);
+                    parts.push(ChatCompletionContentPart::Text { text: message.content });
+                    parts.extend(message.images.into_iter().map(|image| {
+                        ChatCompletionContentPart::ImageUrl { image_url: image_url(image) }
+                    }));
+                    ChatCompletionContent::Array(parts)
+                };
+                params.push(ChatCompletionMessageParam::User { content });
+            }
+            MessageRole::Assistant => {
+                params.push(ChatCompletionMessageParam::Assistant {
+                    content: ChatCompletionContentText::String(message.content),
+                    tool_calls: None,
+                });
+
+                // The `tool` messages right after answer this turn's calls, orphans are dropped
+                let mut results = Vec::new();
+                while let Some(result) =
+                    messages.next_if(|message| message.role == MessageRole::Tool)
+                {
+                    results.push(result);
+                }
+                for call in message.tool_calls {
+                    let Some(i) =
+                        results.iter().position(|result| match (&call.id, &result.tool_call_id) {
+                            (Some(a), Some(b)) => a[..] == b[..],
+                            _ => result
+                                .tool_name
+                                .as_ref()
+                                .is_none_or(|name| name[..] == call.function.name[..]),
+                        })
+                    else {
+                        continue;
+                    };
+                    let result = results.remove(i);
+
+                    // Older clients send no ids, one is made up
+                    let id = call
+                        .id
+                        .or(result.tool_call_id)
+                        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string().into());
+                    let tool_call = Box::new(ChatCompletionMessageToolCall::Function {
+                        id: id.clone(),
+                        function: chat_completion_message_tool_call::Function {
+                            arguments: __unwrap!(serde_json::to_string(&call.function.arguments)),
+                            name: call.function.name,
+                        },
+                    });
+
+                    // The first call goes along with the text of the turn
+                    if let Some(ChatCompletionMessageParam::Assistant {
+                        tool_calls: tool_calls @ None,
+                        ..
+                    }) = params.last_mut()
+                    {
+                        *tool_calls = Some(tool_call);
+                    } else {
+                        params.push(ChatCompletionMessageParam::Assistant {
+                            content: ChatCompletionContentText::String(String::new()),
+                            tool_calls: Some(tool_call),
+                        });
+                    }
+                    params.push(ChatCompletionMessageParam::Tool {
+                        content: ChatCompletionContentText::String(result.content),
+                        tool_call_id: id,
+                    });
+                }
+            }
+            // Tool results without a preceding call
+            MessageRole::Tool => {}
+        }
+    }
+
+    params
+}
+
+/// Data URL of a base64 encoded image, the format is guessed from its first bytes
+fn image_url(image: String) -> ImageUrl {
+    if image.starts_with(DATA_URL_PREFIX) {
+        return ImageUrl { url: image };
+    }
+    // 16 characters decode to 12 bytes, enough for the signature of each supported format
+    let head = image
+        .get(..16)
+        .and_then(|head| base64_simd::STANDARD.decode_to_vec(head).ok())
+        .unwrap_or_default();
+    let format = match image::guess_format(&head) {
+        Ok(image::ImageFormat::Png) => "png",
+        Ok(image::ImageFormat::Jpeg) => "jpeg",
+        Ok(image::ImageFormat::Gif) => "gif",
+        Ok(image::ImageFormat::WebP) => "webp",
+        // Rejected as unsupported further on
+        _ => "unknown",
+    };
+    ImageUrl { url: format!("data:image/{format};base64,{image}") }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Message {
+    pub role: MessageRole,
+    #[serde(default)]
+    pub content: String,
+    /// Base64 encoded images, without a data URL prefix
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub thinking: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    /// Name of the function a `tool` message answers
+    #[serde(default)]
+    pub tool_name: Option<ByteStr>,
+    #[serde(default)]
+    pub tool_call_id: Option<ByteStr>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ByteStr>,
+    pub function: ToolCallFunction,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToolCallFunction {
+    pub name: ByteStr,
+    #[serde(default)]
+    pub arguments: IndexMap<String, serde_json::Value>,
+}
+
+/// Subset of the model options that has an upstream counterpart
+#[derive(Deserialize, Default)]
+pub struct Options {
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Negative values mean no limit
+    #[serde(default)]
+    pub num_predict: Option<i64>,
+}
+
+impl Options {
+    #[inline]
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.num_predict.and_then(|n| u32::try_from(n).ok()).filter(|&n| n != 0)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum Think {
+    Enabled(bool),
+    Level(ThinkLevel),
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ThinkLevel {
+    Low,
+    Medium,
+    High,
+}
+
+impl Think {
+    #[inline]
+    pub const fn reasoning_effort(self) -> ReasoningEffort {
+        match self {
+            Self::Enabled(false) => ReasoningEffort::None,
+            Self::Level(ThinkLevel::Low) => ReasoningEffort::Low,
+            Self::Level(ThinkLevel::Medium) => ReasoningEffort::Medium,
+            Self::Enabled(true) | Self::Level(ThinkLevel::High) => ReasoningEffort::High,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShowRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+}
+
+#[derive(Serialize)]
+pub struct ChatResponse<'a> {
+    pub model: &'a str,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub message: ResponseMessage<'a>,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<DoneReason>,
+    #[serde(flatten)]
+    pub metrics: Option<Metrics>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseMessage<'a> {
+    pub role: &'static str,
+    pub content: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub tool_calls: &'a [ToolCall],
+}
+
+#[derive(Serialize)]
+pub struct GenerateResponse<'a> {
+    pub model: &'a str,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<&'a str>,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<DoneReason>,
+    #[serde(flatten)]
+    pub metrics: Option<Metrics>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DoneReason {
+    Stop,
+    Length,
+    /// Empty `/api/generate` prompt, which only loads the model
+    Load,
+}
+
+/// Statistics of the final response, durations are in nanoseconds
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Metrics {
+    pub total_duration: u64,
+    pub load_duration: u64,
+    pub prompt_eval_count: i32,
+    pub prompt_eval_duration: u64,
+    pub eval_count: i32,
+    pub eval_duration: u64,
+}
+
+impl From<openai::Usage> for Metrics {
+    /// Token counts only, durations are filled in by the caller
+    #[inline]
+    fn from(usage: openai::Usage) -> Self {
+        Self {
+            prompt_eval_count: usage.prompt_tokens + usage.prompt_tokens_details.cached_tokens,
+            eval_count: usage.completion_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+const MODIFIED_AT: &str = "2024-01-31T00:00:00Z";
+
+/// `capabilities` of a model, every model takes tools
+struct Capabilities<'a>(&'a Model);
+
+impl Serialize for Capabilities<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        use serde::ser::SerializeSeq as _;
+        let model = self.0;
+        let mut seq = serializer
+            .serialize_seq(Some(2 + model.is_thinking as usize + model.is_image as usize))?;
+        seq.serialize_element("completion")?;
+        seq.serialize_element("tools")?;
+        if model.is_thinking {
+            seq.serialize_element("thinking")?;
+        }
+        if model.is_image {
+            seq.serialize_element("vision")?;
+        }
+        seq.end()
+    }
+}
+
+struct Details<'a>(&'a Model);
+
+impl Serialize for Details<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let mut state = serializer.serialize_struct("ModelDetails", 6)?;
+        state.serialize_field("parent_model", "")?;
+        state.serialize_field("format", "")?;
+        state.serialize_field("family", self.0.owned_by)?;
+        state.serialize_field("families", &[self.0.owned_by])?;
+        state.serialize_field("parameter_size", "")?;
+        state.serialize_field("quantization_level", "")?;
+        state.end()
+    }
+}
+
+struct Tag<'a>(&'a Model);
+
+impl Serialize for Tag<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let model = self.0;
+        let id = model.id();
+        let mut state = serializer.serialize_struct("ListModelResponse", 7)?;
+        state.serialize_field("name", id)?;
+        state.serialize_field("model", id)?;
+        state.serialize_field("modified_at", MODIFIED_AT)?;
+        state.serialize_field("size", &0u64)?;
+        state.serialize_field("digest", "")?;
+        state.serialize_field("details", &Details(model))?;
+        state.serialize_field("capabilities", &Capabilities(model))?;
+        state.end()
+    }
+}
+
+/// Response of `/api/tags`
+pub struct TagsResponse<'a>(pub &'a [Model]);
+
+impl Serialize for TagsResponse<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        struct Tags<'a>(&'a [Model]);
+
+        impl Serialize for Tags<'_> {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer {
+                serializer.collect_seq(self.0.iter().map(Tag))
+            }
+        }
+
+        let mut state = serializer.serialize_struct("ListResponse", 1)?;
+        state.serialize_field("models", &Tags(self.0))?;
+        state.end()
+    }
+}
+
+/// Response of `/api/show`
+pub struct ShowResponse(pub Model);
+
+impl Serialize for ShowResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let model = &self.0;
+        let mut state = serializer.serialize_struct("ShowResponse", 7)?;
+        state.serialize_field("modelfile", "")?;
+        state.serialize_field("parameters", "")?;
+        state.serialize_field("template", "")?;
+        state.serialize_field("details", &Details(model))?;
+        state.serialize_field("model_info", &serde_json::Map::new())?;
+        state.serialize_field("capabilities", &Capabilities(model))?;
+        state.serialize_field("modified_at", MODIFIED_AT)?;
+        state.end()
+    }
+}
+
+/// Ollama errors are a bare message, the status code carries the rest
+pub struct OllamaError {
+    pub message: Cow<'static, str>,
+}
+
+impl Serialize for OllamaError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let mut state = serializer.serialize_struct("OllamaError", 1)?;
+        state.serialize_field(ERROR, &self.message)?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ChatCompletionMessageParam as M;
+
+    #[test]
+    fn chat_tool_results_follow_their_calls() {
+        let request: ChatRequest = serde_json::from_str(
+            r#"{
+                "model": "gpt-4o:latest",
+                "messages": [
+                    {"role": "system", "content": "Be brief"},
+                    {"role": "user", "content": "What is this?",
+                        "images": ["iVBORw0KGgoAAAANSUhEUgAAAAEAAAAB"]},
+                    {"role": "assistant", "content": "",
+                        "tool_calls": [{"function": {"name": "lookup", "arguments": {"q": "png"}}}]},
+                    {"role": "tool", "content": "an image format", "tool_name": "lookup"}
+                ],
+                "options": {"stop": ["END"], "num_predict": -1},
+                "format": "json",
+                "think": false,
+                "stream": false
+            }"#,
+        )
+        .unwrap();
+        let params = request.into_chat_completion();
+        assert_eq!(params.model, "gpt-4o");
+        assert!(!params.stream);
+
+        let [
+            M::System { .. },
+            M::User { content: ChatCompletionContent::Array(parts) },
+            M::Assistant { tool_calls: Some(call), .. },
+            M::Tool { content, tool_call_id },
+        ] = &params.messages[..]
+        else {
+            panic!("unexpected messages")
+        };
+        let [
+            ChatCompletionContentPart::Text { text },
+            ChatCompletionContentPart::ImageUrl { image_url },
+        ] = &parts[..]
+        else {
+            panic!("unexpected parts")
+        };
+        assert_eq!(text, "What is this?");
+        assert!(image_url.url.starts_with("data:image/png;base64,iVBOR"));
+
+        let ChatCompletionMessageToolCall::Function { id, function } = &**call;
+        assert_eq!(id[..], tool_call_id[..]);
+        assert_eq!(function.arguments, r#"{"q":"png"}"#);
+        assert_eq!(content.clone().text(), "an image format");
+
+        assert!(matches!(params.stop, Some(StopSequences::Array(ref stop)) if stop == &["END"]));
+        assert_eq!(params.max_completion_tokens, None);
+        assert!(matches!(params.response_format, Some(openai::ResponseFormat::JsonObject)));
+        assert!(matches!(params.reasoning_effort, Some(ReasoningEffort::None)));
+    }
+
+    #[test]
+    fn generate_is_a_single_turn() {
+        let request: GenerateRequest = serde_json::from_str(
+            r#"{
+                "model": "gpt-4o",
+                "prompt": "Hi",
+                "system": "Be brief",
+                "images": ["data:image/jpeg;base64,/9j/4AAQ"],
+                "options": {"num_predict": 32}
+            }"#,
+        )
+        .unwrap();
+        let params = request.into_chat().into_chat_completion();
+        assert!(params.stream);
+        assert_eq!(params.max_completion_tokens, Some(32));
+
+        let [
+            M::System { content: system },
+            M::User { content: ChatCompletionContent::Array(parts) },
+        ] = &params.messages[..]
+        else {
+            panic!("unexpected messages")
+        };
+        assert_eq!(system.clone().text(), "Be brief");
+        let [
+            ChatCompletionContentPart::Text { text },
+            ChatCompletionContentPart::ImageUrl { image_url },
+        ] = &parts[..]
+        else {
+            panic!("unexpected parts")
+        };
+        assert_eq!(text, "Hi");
+        assert_eq!(image_url.url, "data:image/jpeg;base64,/9j/4AAQ");
+    }
+}
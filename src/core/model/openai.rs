@@ -1,27 +1,45 @@
-use super::{IndexMap, Role};
+use super::{IndexMap, JsonOutput, Role, ToolChoice};
 use crate::{
     app::constant::{ERROR, TYPE},
     common::{
         model::tri::Tri,
         utils::{const_string::const_string, option_as_array},
     },
+    core::aiserver::v1::stream_unified_chat_request::ThinkingLevel,
 };
 use alloc::borrow::Cow;
 use byte_str::ByteStr;
 use serde::{Deserialize, Serialize, Serializer, ser::SerializeStruct as _};
 
+/// Upper bound of `n`, each choice is a separate upstream request
+pub const MAX_CHOICES: u32 = 8;
+
 #[derive(Deserialize)]
 pub struct ChatCompletionCreateParams {
     pub model: String,
     pub messages: Vec<ChatCompletionMessageParam>,
-    // #[serde(default)]
-    // pub reasoning_effort: ReasoningEffort,
+    #[serde(default)]
+    pub reasoning_effort: Option<ReasoningEffort>,
     #[serde(default)]
     pub stream: bool,
     #[serde(default)]
     pub stream_options: ChatCompletionStreamOptions,
     #[serde(default)]
     pub tools: Vec<ChatCompletionTool>,
+    #[serde(default)]
+    pub tool_choice: Option<ChatCompletionToolChoiceOption>,
+    #[serde(default)]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    pub n: Option<u32>,
 }
 
 impl ChatCompletionCreateParams {
@@ -34,7 +52,7 @@ impl ChatCompletionCreateParams {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "role")]
 pub enum ChatCompletionMessageParam {
     #[serde(rename = "system", alias = "developer")]
@@ -62,21 +80,21 @@ pub enum ChatCompletionMessageParam {
 //     }
 // }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum ChatCompletionContent {
     String(String),
     Array(Vec<ChatCompletionContentPart>),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ChatCompletionContentPart {
     Text { text: String },
     ImageUrl { image_url: ImageUrl },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum ChatCompletionContentText {
     String(String),
@@ -96,7 +114,7 @@ impl ChatCompletionContentText {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ChatCompletionContentPartText {
     Text { text: String },
@@ -109,7 +127,7 @@ impl ChatCompletionContentPartText {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ChatCompletionMessageToolCall {
     Function { id: ByteStr, function: chat_completion_message_tool_call::Function },
@@ -117,34 +135,177 @@ pub enum ChatCompletionMessageToolCall {
 
 pub mod chat_completion_message_tool_call {
     use super::{ByteStr, Deserialize, Serialize};
-    #[derive(Serialize, Deserialize)]
+    #[derive(Serialize, Deserialize, Clone)]
     pub struct Function {
         pub arguments: String,
         pub name: ByteStr,
     }
 }
 
-// #[derive(Deserialize, Default)]
-// #[serde(rename_all = "lowercase")]
-// pub enum ReasoningEffort {
-//     Minimal,
-//     Low,
-//     #[default]
-//     Medium,
-//     High,
-// }
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    None,
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    #[inline]
+    pub const fn thinking_level(self) -> ThinkingLevel {
+        match self {
+            Self::None | Self::Minimal | Self::Low => ThinkingLevel::Unspecified,
+            Self::Medium => ThinkingLevel::Medium,
+            Self::High => ThinkingLevel::High,
+        }
+    }
+}
 
 #[derive(Deserialize, Default)]
 pub struct ChatCompletionStreamOptions {
     pub include_usage: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ChatCompletionTool {
     Function { function: FunctionDefinition },
 }
 
+impl ChatCompletionTool {
+    #[inline]
+    pub fn name(&self) -> &str {
+        let Self::Function { function } = self;
+        &function.name
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ChatCompletionToolChoiceOption {
+    Mode(ToolChoiceMode),
+    Named(ChatCompletionNamedToolChoice),
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionNamedToolChoice {
+    Function { function: NamedFunction },
+}
+
+#[derive(Deserialize)]
+pub struct NamedFunction {
+    pub name: String,
+}
+
+impl From<ToolChoiceMode> for ToolChoice {
+    #[inline]
+    fn from(mode: ToolChoiceMode) -> Self {
+        match mode {
+            ToolChoiceMode::None => Self::None,
+            ToolChoiceMode::Auto => Self::Auto,
+            ToolChoiceMode::Required => Self::Required,
+        }
+    }
+}
+
+impl From<ChatCompletionToolChoiceOption> for ToolChoice {
+    #[inline]
+    fn from(option: ChatCompletionToolChoiceOption) -> Self {
+        match option {
+            ChatCompletionToolChoiceOption::Mode(mode) => mode.into(),
+            ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice::Function {
+                function,
+            }) => Self::Function(function.name),
+        }
+    }
+}
+
+impl From<ToolChoice> for ChatCompletionToolChoiceOption {
+    #[inline]
+    fn from(choice: ToolChoice) -> Self {
+        match choice {
+            ToolChoice::Auto => Self::Mode(ToolChoiceMode::Auto),
+            ToolChoice::None => Self::Mode(ToolChoiceMode::None),
+            ToolChoice::Required => Self::Mode(ToolChoiceMode::Required),
+            ToolChoice::Function(name) => Self::Named(ChatCompletionNamedToolChoice::Function {
+                function: NamedFunction { name },
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl StopSequences {
+    #[inline]
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::String(s) => vec![s],
+            Self::Array(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Deserialize)]
+pub struct JsonSchemaFormat {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub schema: Option<serde_json::Value>,
+}
+
+impl ResponseFormat {
+    #[inline]
+    pub fn into_json_output(self) -> Option<JsonOutput> {
+        match self {
+            Self::Text => None,
+            Self::JsonObject => Some(JsonOutput::Object),
+            Self::JsonSchema { json_schema: JsonSchemaFormat { schema: None, .. } } => {
+                Some(JsonOutput::Object)
+            }
+            Self::JsonSchema { json_schema: JsonSchemaFormat { name, schema: Some(schema) } } => {
+                Some(JsonOutput::Schema { name, schema })
+            }
+        }
+    }
+}
+
+impl From<JsonOutput> for ResponseFormat {
+    #[inline]
+    fn from(json_output: JsonOutput) -> Self {
+        match json_output {
+            JsonOutput::Object => Self::JsonObject,
+            JsonOutput::Schema { name, schema } => {
+                Self::JsonSchema { json_schema: JsonSchemaFormat { name, schema: Some(schema) } }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct FunctionDefinition {
     pub name: String,
@@ -153,7 +314,7 @@ pub struct FunctionDefinition {
     pub parameters: IndexMap<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ImageUrl {
     pub url: String,
 }
@@ -162,6 +323,8 @@ pub struct ImageUrl {
 pub struct ChatCompletionMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
     pub role: Assistant,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub tool_calls: Vec<ChatCompletionMessageToolCall>,
@@ -170,8 +333,7 @@ pub struct ChatCompletionMessage {
 #[derive(Serialize)]
 pub struct ChatCompletion<'a> {
     pub id: &'a str,
-    #[serde(serialize_with = "option_as_array::serialize")]
-    pub choices: Option<chat_completion::Choice>,
+    pub choices: Vec<chat_completion::Choice>,
     pub created: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<&'static str>,
@@ -185,7 +347,7 @@ pub mod chat_completion {
     #[derive(Serialize)]
     pub struct Choice {
         pub finish_reason: FinishReason,
-        pub index: i32,
+        pub index: u32,
         pub logprobs: (),
         pub message: ChatCompletionMessage,
     }
@@ -207,16 +369,11 @@ pub mod chat_completion_chunk {
     use super::{FinishReason, Serialize};
     #[derive(Serialize)]
     pub struct Choice {
-        #[serde(serialize_with = "serialize_zero")]
-        pub index: (),
+        pub index: u32,
         pub delta: Option<choice::Delta>,
         pub logprobs: (),
         pub finish_reason: Option<FinishReason>,
     }
-    fn serialize_zero<S>(_: &(), serializer: S) -> Result<S::Ok, S::Error>
-    where S: serde::Serializer {
-        serializer.serialize_u32(0)
-    }
     pub mod choice {
         use super::{
             super::{Role, option_as_array},
@@ -227,6 +384,8 @@ pub mod chat_completion_chunk {
             #[serde(skip_serializing_if = "Option::is_none")]
             pub content: Option<alloc::borrow::Cow<'static, str>>,
             #[serde(skip_serializing_if = "Option::is_none")]
+            pub reasoning_content: Option<alloc::borrow::Cow<'static, str>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
             pub role: Option<Role>,
             #[serde(with = "option_as_array", skip_serializing_if = "Option::is_none")]
             pub tool_calls: Option<Box<delta::ToolCall>>,
@@ -254,17 +413,18 @@ pub mod chat_completion_chunk {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     Stop,
-    // Length,
+    Length,
     ToolCalls,
     // ContentFilter,
     // FunctionCall,
 }
 
-#[derive(Default)]
+#[derive(Deserialize, Default)]
+#[serde(default)]
 pub struct PromptTokensDetails {
     pub cached_tokens: i32,
     // pub audio_tokens: i32,
@@ -285,34 +445,49 @@ impl Serialize for PromptTokensDetails {
     }
 }
 
-// #[derive(Default)]
-// pub struct CompletionTokensDetails {
-//   pub reasoning_tokens: i32,
-//   // pub audio_tokens: i32,
-//   // pub accepted_prediction_tokens: i32,
-//   // pub rejected_prediction_tokens: i32,
-// }
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CompletionTokensDetails {
+    pub reasoning_tokens: i32,
+    // pub audio_tokens: i32,
+    // pub accepted_prediction_tokens: i32,
+    // pub rejected_prediction_tokens: i32,
+}
 
-// impl Serialize for CompletionTokensDetails {
-//   #[inline]
-//   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-//   where
-//     S: Serializer,
-//   {
-//     let mut state = serializer.serialize_struct("completion_tokens_details", 1)?;
-//     state.serialize_field("reasoning_tokens", &self.reasoning_tokens)?;
-//     state.end()
-//   }
-// }
+impl CompletionTokensDetails {
+    #[inline]
+    fn is_zero(&self) -> bool { self.reasoning_tokens == 0 }
+}
 
-#[derive(Serialize, Default)]
+impl Serialize for CompletionTokensDetails {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let mut state = serializer.serialize_struct("completion_tokens_details", 1)?;
+        state.serialize_field("reasoning_tokens", &self.reasoning_tokens)?;
+        state.end()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct Usage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
     pub total_tokens: i32,
     #[serde(default, skip_serializing_if = "PromptTokensDetails::is_zero")]
     pub prompt_tokens_details: PromptTokensDetails,
-    // pub completion_tokens_details: CompletionTokensDetails,
+    #[serde(default, skip_serializing_if = "CompletionTokensDetails::is_zero")]
+    pub completion_tokens_details: CompletionTokensDetails,
+}
+
+impl Usage {
+    /// Reports `reasoning_tokens` of `completion_tokens` as spent on thinking
+    #[inline]
+    pub fn with_reasoning_tokens(mut self, reasoning_tokens: i32) -> Self {
+        self.completion_tokens_details.reasoning_tokens =
+            reasoning_tokens.min(self.completion_tokens);
+        self
+    }
 }
 
 const_string!(Assistant = "assistant");
@@ -339,6 +514,11 @@ pub type OpenAiErrorInner = private::ErrorDetail;
 #[repr(transparent)]
 pub struct OpenAiError(private::ErrorDetail);
 
+impl OpenAiError {
+    #[inline(always)]
+    pub fn into_inner(self) -> OpenAiErrorInner { self.0 }
+}
+
 impl Serialize for OpenAiError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
@@ -1,7 +1,7 @@
 use super::Models;
 use crate::{
     app::model::{AppConfig, UsageCheck},
-    core::constant::get_static_id,
+    core::{aiserver::v1::stream_unified_chat_request::ThinkingLevel, constant::get_static_id},
 };
 use byte_str::ByteStr;
 
@@ -21,6 +21,8 @@ pub struct ExtModel {
     pub is_thinking: bool,
     pub web: bool,
     pub max: bool,
+    /// Thinking level requested by the client, overrides the model default
+    pub thinking_level: Option<ThinkingLevel>,
 }
 
 impl ExtModel {
@@ -40,6 +42,7 @@ impl ExtModel {
                 is_thinking: raw.is_thinking,
                 web,
                 max: !raw.is_non_max,
+                thinking_level: None,
             });
         }
 
@@ -58,6 +61,7 @@ impl ExtModel {
                 is_thinking: raw.is_thinking,
                 web,
                 max,
+                thinking_level: None,
             });
         }
 
@@ -78,12 +82,29 @@ impl ExtModel {
                     || id.starts_with("grok-4"),
                 web,
                 max,
+                thinking_level: None,
             });
         }
 
         None
     }
 
+    #[inline]
+    pub const fn with_thinking_level(mut self, thinking_level: Option<ThinkingLevel>) -> Self {
+        self.thinking_level = thinking_level;
+        self
+    }
+
+    /// Thinking level sent upstream, thinking models default to high
+    #[inline]
+    pub const fn thinking_level(&self) -> ThinkingLevel {
+        match self.thinking_level {
+            Some(thinking_level) => thinking_level,
+            None if self.is_thinking => ThinkingLevel::High,
+            None => ThinkingLevel::Unspecified,
+        }
+    }
+
     pub fn is_usage_check(&self, usage_check: Option<UsageCheck>) -> bool {
         usage_check.unwrap_or(AppConfig::model_usage_checks()).check(&self.id)
     }
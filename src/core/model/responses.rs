@@ -0,0 +1,532 @@
+use super::{
+    IndexMap,
+    openai::{
+        self, ChatCompletionContent, ChatCompletionContentPart, ChatCompletionContentText,
+        ChatCompletionCreateParams, ChatCompletionMessageParam, ChatCompletionMessageToolCall,
+        ChatCompletionNamedToolChoice, ChatCompletionStreamOptions, ChatCompletionTool,
+        ChatCompletionToolChoiceOption, FunctionDefinition, ImageUrl, NamedFunction,
+        ReasoningEffort, ToolChoiceMode, chat_completion_message_tool_call,
+    },
+};
+use crate::common::utils::const_string::const_string;
+use alloc::borrow::Cow;
+use byte_str::ByteStr;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct ResponseCreateParams {
+    pub model: String,
+    pub input: ResponseInput,
+    #[serde(default)]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub reasoning: Option<Reasoning>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoiceOption>,
+    #[serde(default)]
+    pub parallel_tool_calls: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct Reasoning {
+    #[serde(default)]
+    pub effort: Option<ReasoningEffort>,
+}
+
+impl ResponseCreateParams {
+    /// The chat completion the request is answered with, the input is flattened into messages
+    pub fn into_chat_completion(self) -> ChatCompletionCreateParams {
+        let mut params = Vec::new();
+
+        if let Some(instructions) = self.instructions
+            && !instructions.is_empty()
+        {
+            params.push(ChatCompletionMessageParam::System {
+                content: ChatCompletionContentText::String(instructions),
+            });
+        }
+
+        match self.input {
+            ResponseInput::String(text) => params.push(ChatCompletionMessageParam::User {
+                content: ChatCompletionContent::String(text),
+            }),
+            ResponseInput::Array(items) => {
+                // Outputs are matched to their calls, the adapter expects each tool result
+                // to immediately follow the assistant message that issued it
+                let (mut outputs, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| {
+                    matches!(item, InputItem::Item(Item::FunctionCallOutput { .. }))
+                });
+
+                for item in items {
+                    match item {
+                        InputItem::Message(message) | InputItem::Item(Item::Message(message)) => {
+                            params.push(message.into_param())
+                        }
+                        InputItem::Item(Item::FunctionCall { call_id, name, arguments }) => {
+                            let Some(pos) = outputs.iter().position(|output| {
+                                matches!(
+                                    output,
+                                    InputItem::Item(Item::FunctionCallOutput { call_id: id, .. })
+                                        if id[..] == call_id[..]
+                                )
+                            }) else {
+                                continue;
+                            };
+                            let InputItem::Item(Item::FunctionCallOutput { output, .. }) =
+                                outputs.swap_remove(pos)
+                            else {
+                                __unreachable!()
+                            };
+
+                            let tool_call = Box::new(ChatCompletionMessageToolCall::Function {
+                                id: call_id.clone(),
+                                function: chat_completion_message_tool_call::Function {
+                                    arguments,
+                                    name,
+                                },
+                            });
+
+                            // Attach to the preceding assistant text when it has no call yet
+                            if let Some(ChatCompletionMessageParam::Assistant {
+                                tool_calls: tool_calls @ None,
+                                ..
+                            }) = params.last_mut()
+                            {
+                                *tool_calls = Some(tool_call);
+                            } else {
+                                params.push(ChatCompletionMessageParam::Assistant {
+                                    content: ChatCompletionContentText::String(String::new()),
+                                    tool_calls: Some(tool_call),
+                                });
+                            }
+                            params.push(ChatCompletionMessageParam::Tool {
+                                content: ChatCompletionContentText::String(output.text()),
+                                tool_call_id: call_id,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let tools = self
+            .tools
+            .into_iter()
+            .filter_map(|tool| match tool {
+                Tool::Function { name, description, parameters } => {
+                    Some(ChatCompletionTool::Function {
+                        function: FunctionDefinition { name, description, parameters },
+                    })
+                }
+                Tool::Unsupported => None,
+            })
+            .collect();
+
+        ChatCompletionCreateParams {
+            model: self.model,
+            messages: params,
+            reasoning_effort: self.reasoning.and_then(|reasoning| reasoning.effort),
+            stream: self.stream,
+            stream_options: ChatCompletionStreamOptions::default(),
+            tools,
+            tool_choice: self.tool_choice.map(Into::into),
+            parallel_tool_calls: self.parallel_tool_calls,
+            response_format: None,
+            stop: None,
+            max_tokens: None,
+            max_completion_tokens: None,
+            n: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ResponseInput {
+    String(String),
+    Array(Vec<InputItem>),
+}
+
+/// Input messages may omit `type`, so plain messages are tried last.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum InputItem {
+    Item(Item),
+    Message(InputMessage),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Item {
+    Message(InputMessage),
+    FunctionCall {
+        call_id: ByteStr,
+        name: ByteStr,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: ByteStr,
+        output: InputContent,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+pub struct InputMessage {
+    pub role: InputRole,
+    pub content: InputContent,
+}
+
+impl InputMessage {
+    fn into_param(self) -> ChatCompletionMessageParam {
+        match self.role {
+            InputRole::System | InputRole::Developer => ChatCompletionMessageParam::System {
+                content: ChatCompletionContentText::String(self.content.text()),
+            },
+            InputRole::Assistant => ChatCompletionMessageParam::Assistant {
+                content: ChatCompletionContentText::String(self.content.text()),
+                tool_calls: None,
+            },
+            InputRole::User => ChatCompletionMessageParam::User {
+                content: match self.content {
+                    InputContent::String(text) => ChatCompletionContent::String(text),
+                    InputContent::Array(parts) => ChatCompletionContent::Array(
+                        parts
+                            .into_iter()
+                            .filter_map(|part| match part {
+                                InputContentPart::InputText { text }
+                                | InputContentPart::OutputText { text } => {
+                                    Some(ChatCompletionContentPart::Text { text })
+                                }
+                                InputContentPart::InputImage { image_url: Some(url) } => {
+                                    Some(ChatCompletionContentPart::ImageUrl {
+                                        image_url: ImageUrl { url },
+                                    })
+                                }
+                                _ => None,
+                            })
+                            .collect(),
+                    ),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputRole {
+    User,
+    Assistant,
+    System,
+    Developer,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum InputContent {
+    String(String),
+    Array(Vec<InputContentPart>),
+}
+
+impl InputContent {
+    pub fn text(self) -> String {
+        match self {
+            Self::String(string) => string,
+            Self::Array(parts) => parts
+                .into_iter()
+                .filter_map(|part| match part {
+                    InputContentPart::InputText { text }
+                    | InputContentPart::OutputText { text }
+                    | InputContentPart::Refusal { refusal: text } => Some(text),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputContentPart {
+    InputText {
+        text: String,
+    },
+    OutputText {
+        text: String,
+    },
+    Refusal {
+        refusal: String,
+    },
+    InputImage {
+        #[serde(default)]
+        image_url: Option<String>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tool {
+    Function {
+        name: String,
+        #[serde(default)]
+        description: Option<String>,
+        #[serde(default)]
+        parameters: IndexMap<String, serde_json::Value>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoiceOption {
+    Mode(ToolChoiceMode),
+    Named(NamedToolChoice),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NamedToolChoice {
+    Function { name: String },
+}
+
+impl From<ToolChoiceOption> for ChatCompletionToolChoiceOption {
+    #[inline]
+    fn from(option: ToolChoiceOption) -> Self {
+        match option {
+            ToolChoiceOption::Mode(mode) => Self::Mode(mode),
+            ToolChoiceOption::Named(NamedToolChoice::Function { name }) => {
+                Self::Named(ChatCompletionNamedToolChoice::Function {
+                    function: NamedFunction { name },
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response<'a> {
+    pub id: &'a str,
+    pub object: ObjectResponse,
+    pub created_at: i64,
+    pub status: ResponseStatus,
+    pub model: &'a str,
+    pub output: &'a [OutputItem],
+    pub parallel_tool_calls: bool,
+    pub error: Option<ResponseError<'a>>,
+    pub incomplete_details: (),
+    pub usage: Option<Usage>,
+}
+
+/// Why a response failed
+#[derive(Serialize, Clone, Copy)]
+pub struct ResponseError<'a> {
+    pub code: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseStatus {
+    InProgress,
+    Completed,
+    // Incomplete,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+    InProgress,
+    Completed,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputItem {
+    Reasoning {
+        id: String,
+        summary: Vec<SummaryPart>,
+    },
+    Message {
+        id: String,
+        status: ItemStatus,
+        role: super::openai::Assistant,
+        content: Vec<OutputContent>,
+    },
+    FunctionCall {
+        id: String,
+        call_id: ByteStr,
+        name: ByteStr,
+        arguments: String,
+        status: ItemStatus,
+    },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputContent {
+    OutputText { text: String, annotations: [(); 0] },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SummaryPart {
+    SummaryText { text: String },
+}
+
+#[derive(Serialize)]
+pub struct ResponseStreamEvent<'a> {
+    #[serde(flatten)]
+    pub event: StreamEvent<'a>,
+    pub sequence_number: u32,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum StreamEvent<'a> {
+    #[serde(rename = "response.created")]
+    Created { response: Response<'a> },
+    #[serde(rename = "response.in_progress")]
+    InProgress { response: Response<'a> },
+    #[serde(rename = "response.output_item.added")]
+    OutputItemAdded { output_index: u32, item: &'a OutputItem },
+    #[serde(rename = "response.output_item.done")]
+    OutputItemDone { output_index: u32, item: &'a OutputItem },
+    #[serde(rename = "response.content_part.added")]
+    ContentPartAdded {
+        item_id: &'a str,
+        output_index: u32,
+        content_index: u32,
+        part: OutputContent,
+    },
+    #[serde(rename = "response.content_part.done")]
+    ContentPartDone { item_id: &'a str, output_index: u32, content_index: u32, part: OutputContent },
+    #[serde(rename = "response.output_text.delta")]
+    OutputTextDelta {
+        item_id: &'a str,
+        output_index: u32,
+        content_index: u32,
+        delta: Cow<'a, str>,
+        logprobs: [(); 0],
+    },
+    #[serde(rename = "response.output_text.done")]
+    OutputTextDone {
+        item_id: &'a str,
+        output_index: u32,
+        content_index: u32,
+        text: &'a str,
+        logprobs: [(); 0],
+    },
+    #[serde(rename = "response.function_call_arguments.delta")]
+    FunctionCallArgumentsDelta { item_id: &'a str, output_index: u32, delta: Cow<'a, str> },
+    #[serde(rename = "response.function_call_arguments.done")]
+    FunctionCallArgumentsDone { item_id: &'a str, output_index: u32, arguments: &'a str },
+    #[serde(rename = "response.reasoning_summary_part.added")]
+    ReasoningSummaryPartAdded {
+        item_id: &'a str,
+        output_index: u32,
+        summary_index: u32,
+        part: SummaryPart,
+    },
+    #[serde(rename = "response.reasoning_summary_part.done")]
+    ReasoningSummaryPartDone {
+        item_id: &'a str,
+        output_index: u32,
+        summary_index: u32,
+        part: SummaryPart,
+    },
+    #[serde(rename = "response.reasoning_summary_text.delta")]
+    ReasoningSummaryTextDelta {
+        item_id: &'a str,
+        output_index: u32,
+        summary_index: u32,
+        delta: Cow<'a, str>,
+    },
+    #[serde(rename = "response.reasoning_summary_text.done")]
+    ReasoningSummaryTextDone {
+        item_id: &'a str,
+        output_index: u32,
+        summary_index: u32,
+        text: &'a str,
+    },
+    #[serde(rename = "response.completed")]
+    Completed { response: Response<'a> },
+    #[serde(rename = "response.failed")]
+    Failed { response: Response<'a> },
+    #[serde(rename = "error")]
+    Error { code: Option<Cow<'static, str>>, message: Cow<'static, str>, param: () },
+}
+
+impl StreamEvent<'_> {
+    #[inline]
+    pub const fn type_name(&self) -> &'static str {
+        match self {
+            Self::Created { .. } => "response.created",
+            Self::InProgress { .. } => "response.in_progress",
+            Self::OutputItemAdded { .. } => "response.output_item.added",
+            Self::OutputItemDone { .. } => "response.output_item.done",
+            Self::ContentPartAdded { .. } => "response.content_part.added",
+            Self::ContentPartDone { .. } => "response.content_part.done",
+            Self::OutputTextDelta { .. } => "response.output_text.delta",
+            Self::OutputTextDone { .. } => "response.output_text.done",
+            Self::FunctionCallArgumentsDelta { .. } => "response.function_call_arguments.delta",
+            Self::FunctionCallArgumentsDone { .. } => "response.function_call_arguments.done",
+            Self::ReasoningSummaryPartAdded { .. } => "response.reasoning_summary_part.added",
+            Self::ReasoningSummaryPartDone { .. } => "response.reasoning_summary_part.done",
+            Self::ReasoningSummaryTextDelta { .. } => "response.reasoning_summary_text.delta",
+            Self::ReasoningSummaryTextDone { .. } => "response.reasoning_summary_text.done",
+            Self::Completed { .. } => "response.completed",
+            Self::Failed { .. } => "response.failed",
+            Self::Error { .. } => "error",
+        }
+    }
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct InputTokensDetails {
+    pub cached_tokens: i32,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct OutputTokensDetails {
+    pub reasoning_tokens: i32,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Usage {
+    pub input_tokens: i32,
+    pub input_tokens_details: InputTokensDetails,
+    pub output_tokens: i32,
+    pub output_tokens_details: OutputTokensDetails,
+    pub total_tokens: i32,
+}
+
+impl From<openai::Usage> for Usage {
+    #[inline]
+    fn from(usage: openai::Usage) -> Self {
+        Self {
+            input_tokens: usage.prompt_tokens,
+            input_tokens_details: InputTokensDetails {
+                cached_tokens: usage.prompt_tokens_details.cached_tokens,
+            },
+            output_tokens: usage.completion_tokens,
+            output_tokens_details: OutputTokensDetails {
+                reasoning_tokens: usage.completion_tokens_details.reasoning_tokens,
+            },
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+const_string!(ObjectResponse = "response");
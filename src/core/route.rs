@@ -1,26 +1,33 @@
+mod budgets;
 mod config;
 mod health;
+mod keys;
 mod logs;
 mod page;
 mod proxies;
+mod revoked_keys;
 mod token;
 mod tokens;
 mod utils;
 
+pub use budgets::{handle_delete_budgets, handle_get_budgets, handle_set_budget};
 pub use config::{handle_get_config, handle_reload_config, handle_set_config};
 pub use health::{handle_health, init_endpoints};
+pub use keys::{handle_add_key, handle_delete_keys, handle_get_keys, handle_set_key};
 pub use logs::{handle_get_logs, handle_get_logs_tokens};
 pub use page::{handle_config_example, handle_env_example, handle_license, handle_readme};
 pub use proxies::{
     handle_add_proxy, handle_delete_proxies, handle_get_proxies, handle_set_general_proxy,
     handle_set_proxies,
 };
+pub use revoked_keys::{handle_get_revoked_keys, handle_revoke_keys};
 pub use token::{handle_build_key, handle_get_config_version, handle_get_token_profile};
 pub use tokens::{
-    handle_add_tokens, handle_delete_tokens, handle_get_tokens, handle_merge_tokens,
-    handle_refresh_tokens, handle_set_tokens, handle_set_tokens_alias, handle_set_tokens_proxy,
-    handle_set_tokens_status, handle_set_tokens_timezone, handle_update_tokens_config_version,
-    handle_update_tokens_profile,
+    handle_add_tokens, handle_delete_tokens, handle_get_suspended_tokens, handle_get_tokens,
+    handle_get_tokens_groups, handle_merge_tokens, handle_refresh_tokens, handle_set_tokens,
+    handle_set_tokens_alias, handle_set_tokens_groups, handle_set_tokens_limits,
+    handle_set_tokens_proxy, handle_set_tokens_status, handle_set_tokens_timezone,
+    handle_set_tokens_weight, handle_update_tokens_config_version, handle_update_tokens_profile,
 };
 pub use utils::{
     handle_gen_checksum, handle_gen_hash, handle_gen_uuid, handle_get_checksum_header,
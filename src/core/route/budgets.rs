@@ -0,0 +1,112 @@
+use crate::{
+    app::model::{
+        ApiKeys, Budgets, BudgetsDeleteRequest, BudgetsGetResponse, BudgetsSetRequest,
+        CommonResponse, Spender,
+    },
+    common::model::{ApiStatus, GenericError},
+    core::config::decode_dynamic_token,
+};
+use alloc::borrow::Cow;
+use axum::Json;
+use http::StatusCode;
+
+crate::define_typed_constants! {
+    &'static str => {
+        ERROR_SAVE_BUDGETS = "Failed to save budgets",
+        MESSAGE_SAVE_BUDGETS_FAILED = "Failed to save budget data",
+        ERROR_INVALID_SPENDER = "Invalid spender",
+        MESSAGE_INVALID_SPENDER = "Give a key, its 32 hex digit id, or an API key by name",
+        MESSAGE_BUDGET_SET = "Budget set",
+        MESSAGE_DELETED_PREFIX = "Deleted ",
+        MESSAGE_DELETED_SUFFIX = " budgets",
+    }
+}
+
+fn error(
+    status: StatusCode,
+    error: &'static str,
+    message: &'static str,
+) -> (StatusCode, Json<GenericError>) {
+    (
+        status,
+        Json(GenericError {
+            status: ApiStatus::Error,
+            code: None,
+            error: Some(Cow::Borrowed(error)),
+            message: Some(Cow::Borrowed(message)),
+        }),
+    )
+}
+
+async fn save() -> Result<(), (StatusCode, Json<GenericError>)> {
+    Budgets::save().await.map_err(|_| {
+        error(StatusCode::INTERNAL_SERVER_ERROR, ERROR_SAVE_BUDGETS, MESSAGE_SAVE_BUDGETS_FAILED)
+    })
+}
+
+/// Turns a key given as the key itself, or an API key given by name, into its id, `None` when
+/// it is none of those
+fn normalize(spender: Spender) -> Option<Spender> {
+    let is_id = |id: &str| id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit());
+    match spender {
+        Spender::ApiKey(id) => {
+            if let Some(key_id) = ApiKeys::id(&id).or_else(|| ApiKeys::id_by_name(&id)) {
+                return Some(Spender::ApiKey(key_id));
+            }
+            is_id(&id).then(|| Spender::ApiKey(id.to_ascii_lowercase()))
+        }
+        Spender::DynamicKey(id) => {
+            if let Some(key) = decode_dynamic_token(&id) {
+                return Some(Spender::DynamicKey(hex::encode(key.id()?)));
+            }
+            is_id(&id).then(|| Spender::DynamicKey(id.to_ascii_lowercase()))
+        }
+        Spender::Share => Some(Spender::Share),
+    }
+}
+
+pub async fn handle_get_budgets() -> Json<BudgetsGetResponse> {
+    Json(BudgetsGetResponse { budgets: Budgets::list() })
+}
+
+pub async fn handle_set_budget(
+    Json(request): Json<BudgetsSetRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    let Some(spender) = normalize(request.spender) else {
+        return Err(error(StatusCode::BAD_REQUEST, ERROR_INVALID_SPENDER, MESSAGE_INVALID_SPENDER));
+    };
+
+    Budgets::set(spender, request.limits);
+    save().await?;
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Borrowed(MESSAGE_BUDGET_SET),
+    }))
+}
+
+pub async fn handle_delete_budgets(
+    Json(request): Json<BudgetsDeleteRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    let deleted_count = request
+        .spenders
+        .into_iter()
+        .filter_map(normalize)
+        .filter(|spender| Budgets::remove(spender))
+        .count();
+    if deleted_count > 0 {
+        save().await?;
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                MESSAGE_DELETED_PREFIX,
+                itoa::Buffer::new().format(deleted_count),
+                MESSAGE_DELETED_SUFFIX,
+            ]
+            .concat(),
+        ),
+    }))
+}
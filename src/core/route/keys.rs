@@ -0,0 +1,113 @@
+use crate::{
+    app::model::{
+        AdminRole, ApiKey, ApiKeys, ApiKeysAddResponse, ApiKeysDeleteRequest, ApiKeysGetResponse,
+        ApiKeysSetRequest, CommonResponse, Permission,
+    },
+    common::model::{ApiStatus, GenericError},
+};
+use alloc::borrow::Cow;
+use axum::{Extension, Json};
+use http::StatusCode;
+
+crate::define_typed_constants! {
+    &'static str => {
+        ERROR_SAVE_API_KEYS = "Failed to save API keys",
+        MESSAGE_SAVE_API_KEYS_FAILED = "Failed to save API key data",
+        ERROR_INVALID_KEY_NAME = "Invalid key name",
+        MESSAGE_INVALID_KEY_NAME = "Key names must not be empty",
+        ERROR_KEY_NAME_TAKEN = "Key name taken",
+        MESSAGE_KEY_NAME_TAKEN = "Another key already has this name",
+        ERROR_KEY_NOT_FOUND = "Key not found",
+        MESSAGE_KEY_NOT_FOUND = "No key has this name",
+        MESSAGE_KEY_UPDATED = "Key updated",
+        MESSAGE_DELETED_PREFIX = "Deleted ",
+        MESSAGE_DELETED_SUFFIX = " keys",
+    }
+}
+
+fn error(
+    status: StatusCode,
+    error: &'static str,
+    message: &'static str,
+) -> (StatusCode, Json<GenericError>) {
+    (
+        status,
+        Json(GenericError {
+            status: ApiStatus::Error,
+            code: None,
+            error: Some(Cow::Borrowed(error)),
+            message: Some(Cow::Borrowed(message)),
+        }),
+    )
+}
+
+async fn save() -> Result<(), (StatusCode, Json<GenericError>)> {
+    ApiKeys::save().await.map_err(|_| {
+        error(StatusCode::INTERNAL_SERVER_ERROR, ERROR_SAVE_API_KEYS, MESSAGE_SAVE_API_KEYS_FAILED)
+    })
+}
+
+pub async fn handle_get_keys(Extension(role): Extension<AdminRole>) -> Json<ApiKeysGetResponse> {
+    let mut keys = ApiKeys::list();
+    if !role.grants(Permission::Manage) {
+        keys.iter_mut().for_each(ApiKey::redact);
+    }
+    Json(ApiKeysGetResponse { keys })
+}
+
+pub async fn handle_add_key(
+    Json(mut request): Json<ApiKeysSetRequest>,
+) -> Result<Json<ApiKeysAddResponse>, (StatusCode, Json<GenericError>)> {
+    if request.name.trim().is_empty() {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            ERROR_INVALID_KEY_NAME,
+            MESSAGE_INVALID_KEY_NAME,
+        ));
+    }
+    request.settings.normalize();
+
+    let Some(key) = ApiKeys::add(request.name, request.settings) else {
+        return Err(error(StatusCode::CONFLICT, ERROR_KEY_NAME_TAKEN, MESSAGE_KEY_NAME_TAKEN));
+    };
+    save().await?;
+
+    Ok(Json(ApiKeysAddResponse { key }))
+}
+
+pub async fn handle_set_key(
+    Json(mut request): Json<ApiKeysSetRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    request.settings.normalize();
+
+    if !ApiKeys::update(&request.name, request.settings) {
+        return Err(error(StatusCode::NOT_FOUND, ERROR_KEY_NOT_FOUND, MESSAGE_KEY_NOT_FOUND));
+    }
+    save().await?;
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Borrowed(MESSAGE_KEY_UPDATED),
+    }))
+}
+
+pub async fn handle_delete_keys(
+    Json(request): Json<ApiKeysDeleteRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    let deleted_count = request.names.iter().filter(|name| ApiKeys::remove(name)).count();
+    if deleted_count > 0 {
+        save().await?;
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                MESSAGE_DELETED_PREFIX,
+                itoa::Buffer::new().format(deleted_count),
+                MESSAGE_DELETED_SUFFIX,
+            ]
+            .concat(),
+        ),
+    }))
+}
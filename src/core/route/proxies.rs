@@ -1,13 +1,13 @@
 use crate::{
     app::model::{
-        CommonResponse, ProxiesDeleteRequest, ProxiesDeleteResponse, ProxyAddRequest,
-        ProxyInfoResponse, ProxyUpdateRequest, SetGeneralProxyRequest,
+        AdminRole, CommonResponse, Permission, ProxiesDeleteRequest, ProxiesDeleteResponse,
+        ProxyAddRequest, ProxyInfoResponse, ProxyUpdateRequest, SetGeneralProxyRequest,
         proxy_pool::{self, Proxies},
     },
     common::model::{ApiStatus, GenericError},
 };
 use alloc::{borrow::Cow, sync::Arc};
-use axum::{Json, http::StatusCode};
+use axum::{Extension, Json, http::StatusCode};
 use interned::Str;
 
 type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
@@ -33,9 +33,14 @@ fn format_save_proxy_config_error(
 }
 
 // Get all proxy configurations
-pub async fn handle_get_proxies() -> Json<ProxyInfoResponse> {
+pub async fn handle_get_proxies(Extension(role): Extension<AdminRole>) -> Json<ProxyInfoResponse> {
     // Get proxy configuration and release lock immediately
-    let proxies = proxy_pool::proxies().load_full();
+    let mut proxies = proxy_pool::proxies().load_full();
+    if !role.grants(Permission::Manage) {
+        proxies = Arc::new(
+            proxies.iter().map(|(name, proxy)| (name.clone(), proxy.redacted())).collect(),
+        );
+    }
 
     let proxies_count = proxies.len();
     let general_proxy = proxy_pool::general_name().load_full();
@@ -0,0 +1,84 @@
+use crate::{
+    app::model::{CommonResponse, RevokedKeys, RevokedKeysAddRequest, RevokedKeysGetResponse},
+    common::model::{ApiStatus, GenericError},
+    core::config::decode_dynamic_token,
+};
+use alloc::borrow::Cow;
+use axum::Json;
+use http::StatusCode;
+
+crate::define_typed_constants! {
+    &'static str => {
+        ERROR_SAVE_REVOKED_KEYS = "Failed to save revoked keys",
+        MESSAGE_SAVE_REVOKED_KEYS_FAILED = "Failed to save revoked key data",
+        ERROR_INVALID_KEY = "Invalid key",
+        MESSAGE_INVALID_KEY = "Give a dynamic key as the key or its 32 hex digit id",
+        MESSAGE_REVOKED_PREFIX = "Revoked ",
+        MESSAGE_REVOKED_SUFFIX = " keys",
+    }
+}
+
+fn error(
+    status: StatusCode,
+    error: &'static str,
+    message: &'static str,
+) -> (StatusCode, Json<GenericError>) {
+    (
+        status,
+        Json(GenericError {
+            status: ApiStatus::Error,
+            code: None,
+            error: Some(Cow::Borrowed(error)),
+            message: Some(Cow::Borrowed(message)),
+        }),
+    )
+}
+
+/// Id and label of a dynamic key given as the key itself or as its id
+fn parse(key: &str) -> Option<([u8; 16], Option<String>)> {
+    if let Some(key) = decode_dynamic_token(key) {
+        return Some((key.id()?, key.label));
+    }
+    let mut id = [0; 16];
+    hex::decode_to_slice(key, &mut id).ok()?;
+    Some((id, None))
+}
+
+pub async fn handle_get_revoked_keys() -> Json<RevokedKeysGetResponse> {
+    Json(RevokedKeysGetResponse { keys: RevokedKeys::list() })
+}
+
+pub async fn handle_revoke_keys(
+    Json(request): Json<RevokedKeysAddRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    let Some(keys) = request.keys.iter().map(|key| parse(key)).collect::<Option<Vec<_>>>() else {
+        return Err(error(StatusCode::BAD_REQUEST, ERROR_INVALID_KEY, MESSAGE_INVALID_KEY));
+    };
+
+    let revoked_count = keys
+        .into_iter()
+        .map(|(id, label)| RevokedKeys::revoke(id, label))
+        .filter(|&revoked| revoked)
+        .count();
+    if revoked_count > 0 {
+        RevokedKeys::save().await.map_err(|_| {
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ERROR_SAVE_REVOKED_KEYS,
+                MESSAGE_SAVE_REVOKED_KEYS_FAILED,
+            )
+        })?;
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                MESSAGE_REVOKED_PREFIX,
+                itoa::Buffer::new().format(revoked_count),
+                MESSAGE_REVOKED_SUFFIX,
+            ]
+            .concat(),
+        ),
+    }))
+}
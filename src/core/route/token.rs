@@ -5,7 +5,7 @@ use crate::{
         model::{
             AppConfig, BuildKeyRequest, BuildKeyResponse, ExtToken, GetConfigVersionRequest,
             GetConfigVersionResponse, Token, UnextTokenRef, UsageCheckModelType,
-            dynamic_key::get_hash, proxy_pool::get_client_or_general,
+            proxy_pool::get_client_or_general,
         },
     },
     common::{
@@ -19,6 +19,7 @@ use axum::{
     http::{HeaderMap, StatusCode, header::AUTHORIZATION},
 };
 use interned::ArcStr;
+use rand::Rng as _;
 
 // Constant definitions
 const ERROR_UNAUTHORIZED: &str = "Unauthorized";
@@ -26,6 +27,7 @@ const ERROR_INVALID_SESSION_TOKEN: &str =
     "Invalid parameter: session_token must be a session token, not a web token";
 const ERROR_INVALID_WEB_TOKEN: &str =
     "Invalid parameter: web_token must be a web token, not a session token";
+const ERROR_INVALID_TIME_WINDOW: &str = "Invalid parameter: not_after must be after not_before";
 
 // Verify authentication token
 fn verify_auth_token(headers: &HeaderMap) -> bool {
@@ -44,10 +46,16 @@ pub async fn handle_build_key(
         return (StatusCode::UNAUTHORIZED, Json(BuildKeyResponse::Error(ERROR_UNAUTHORIZED)));
     }
 
-    let token_key = request.token.key();
-    let secret = get_hash(&request.token);
+    if let (Some(not_before), Some(not_after)) = (request.not_before, request.not_after)
+        && not_after <= not_before
+    {
+        return (StatusCode::BAD_REQUEST, Json(BuildKeyResponse::Error(ERROR_INVALID_TIME_WINDOW)));
+    }
+
+    let raw = request.token;
+    let token_key = raw.key();
     let token_info = token_to_tokeninfo(
-        request.token,
+        raw,
         request.checksum,
         request.client_key,
         request.config_version,
@@ -58,9 +66,9 @@ pub async fn handle_build_key(
     );
 
     // Build proto message
-    let key_config = ConfiguredKey {
+    let mut key_config = ConfiguredKey {
         token_info: Some(token_info),
-        secret: Some(secret),
+        secret: None,
         disable_vision: request.disable_vision,
         enable_slow_pool: request.enable_slow_pool,
         include_web_references: request.include_web_references,
@@ -76,7 +84,16 @@ pub async fn handle_build_key(
         } else {
             None
         },
+        group: request.group.filter(|group| !group.is_empty()),
+        key_id: Some(rand::rng().random()),
+        not_before: request.not_before,
+        not_after: request.not_after,
+        label: request.label.filter(|label| !label.is_empty()),
+        models: request.models.filter(|models| !models.is_empty()),
+        routes: request.routes.filter(|routes| !routes.is_empty()),
+        max_rpm: request.max_rpm,
     };
+    key_config.secret = Some(key_config.hash(&raw));
 
     // Serialize
     let mut encoder = ::minicbor::Encoder::new(Vec::with_capacity(::minicbor::len(&key_config)));
@@ -2,17 +2,20 @@ use crate::{
     app::{
         constant::UNNAMED,
         model::{
-            AppState, Checksum, CommonResponse, ExtToken, GcppHost, Hash, RawToken, Token,
-            TokenError, TokenHealth, TokenInfo, TokenManager, TokenStatus, TokensAddRequest,
-            TokensAddResponse, TokensAliasSetRequest, TokensDeleteRequest, TokensDeleteResponse,
-            TokensGetResponse, TokensMergeRequest, TokensProxySetRequest, TokensStatusSetRequest,
-            TokensTimezoneSetRequest, TokensUpdateRequest,
+            AdminRole, AppState, Checksum, CommonResponse, ExtToken, GcppHost, Hash, Permission,
+            RawToken, SuspendedToken, Token, TokenError, TokenGroup, TokenHealth, TokenInfo,
+            TokenManager, TokenPolicy, TokenStatus, TokensAddRequest, TokensAddResponse,
+            TokensAliasSetRequest, TokensDeleteRequest, TokensDeleteResponse, TokensGetResponse,
+            TokensGroupsGetResponse, TokensGroupsSetRequest, TokensLimitsSetRequest,
+            TokensMergeRequest, TokensProxySetRequest, TokensStatusSetRequest,
+            TokensSuspendedResponse, TokensTimezoneSetRequest, TokensUpdateRequest,
+            TokensWeightSetRequest,
         },
     },
     common::model::{ApiStatus, GenericError},
 };
 use alloc::{borrow::Cow, sync::Arc};
-use axum::{Json, extract::State};
+use axum::{Extension, Json, extract::State};
 use core::str::FromStr as _;
 use http::StatusCode;
 use interned::ArcStr;
@@ -35,18 +38,58 @@ crate::define_typed_constants! {
         ERROR_SAVE_TOKEN_ALIASES = "Failed to save token aliases",
         ERROR_SAVE_TOKEN_PROXIES = "Failed to save token proxies",
         ERROR_SAVE_TOKEN_TIMEZONES = "Failed to save token timezones",
+        ERROR_SAVE_TOKEN_WEIGHTS = "Failed to save token weights",
+        ERROR_SAVE_TOKEN_LIMITS = "Failed to save token limits",
+        ERROR_SAVE_TOKEN_GROUPS = "Failed to save token groups",
+        ERROR_INVALID_GROUP = "Invalid group name",
+        MESSAGE_INVALID_GROUP = "Group names only hold ASCII letters, digits, '-', '_' and '.'",
         MESSAGE_SAVE_TOKEN_PROFILE_FAILED = "Failed to save token profile data",
         MESSAGE_SAVE_TOKEN_CONFIG_VERSION_FAILED = "Failed to save token config version data",
         MESSAGE_SAVE_TOKEN_STATUS_FAILED = "Failed to save token status data",
         MESSAGE_SAVE_TOKEN_PROXY_FAILED = "Failed to save token proxy data",
         MESSAGE_SAVE_TOKEN_TIMEZONE_FAILED = "Failed to save token timezone data",
+        MESSAGE_SAVE_TOKEN_WEIGHT_FAILED = "Failed to save token weight data",
+        MESSAGE_SAVE_TOKEN_LIMITS_FAILED = "Failed to save token limit data",
+        MESSAGE_SAVE_TOKEN_GROUPS_FAILED = "Failed to save token group data",
     }
 }
 
-pub async fn handle_get_tokens(State(state): State<Arc<AppState>>) -> Json<TokensGetResponse> {
+pub async fn handle_get_tokens(
+    State(state): State<Arc<AppState>>,
+    Extension(role): Extension<AdminRole>,
+) -> Json<TokensGetResponse> {
     let tokens = state.token_manager_read().await.list();
 
-    Json(TokensGetResponse { tokens })
+    Json(TokensGetResponse { tokens, redacted: !role.grants(Permission::Manage) })
+}
+
+pub async fn handle_get_suspended_tokens(
+    State(state): State<Arc<AppState>>,
+) -> Json<TokensSuspendedResponse> {
+    let token_manager = state.token_manager_read().await;
+    let tokens = token_manager
+        .tokens()
+        .iter()
+        .enumerate()
+        .filter_map(|(id, info)| {
+            let suspension = info.as_ref()?.status.suspension?;
+            if !suspension.is_active() {
+                return None;
+            }
+            // SAFETY: id_to_alias is maintained in sync with tokens
+            let alias = unsafe {
+                token_manager.id_to_alias().get_unchecked(id).as_ref().unwrap_unchecked().clone()
+            };
+            Some(SuspendedToken {
+                id,
+                alias,
+                reason: suspension.reason,
+                resumes_at: suspension.until,
+            })
+        })
+        .collect();
+
+    Json(TokensSuspendedResponse { tokens })
 }
 
 pub async fn handle_set_tokens(
@@ -118,11 +161,20 @@ pub async fn handle_add_tokens(
                             .and_then(|s| chrono_tz::Tz::from_str(&s).ok()),
                         gcpp_host: token_info.gcpp_host.and_then(|s| GcppHost::from_str(&s)),
                     },
-                    status: TokenStatus { enabled: request.enabled, health: TokenHealth::new() },
+                    status: TokenStatus {
+                        enabled: request.enabled,
+                        health: TokenHealth::new(),
+                        last_refresh: None,
+                        suspension: None,
+                        profile_updated_at: None,
+                    },
                     usage: None,
                     user: None,
                     stripe: None,
                     sessions: vec![],
+                    policy: TokenPolicy::default(),
+                    groups: Vec::new(),
+                    load: Arc::default(),
                 },
                 token_info
                     .alias
@@ -273,7 +325,7 @@ pub async fn handle_update_tokens_profile(
                     alias_updaters.set_len(len + 1);
                 }
             }
-            token_info.usage = usage;
+            token_info.set_usage(usage);
             token_info.user = user;
             token_info.stripe = stripe;
             if let Some(sessions) = sessions {
@@ -425,14 +477,18 @@ pub async fn handle_refresh_tokens(
     let mut updated_count = 0u32;
     let mut failed_count = 0u32;
 
+    let mut attempted = false;
+
     for alias in aliases {
-        if let Some(writer) = token_manager
-            .alias_map()
-            .get(alias.as_str())
-            .copied()
-            .map(|id| unsafe { token_manager.tokens_mut().into_token_writer(id) })
-            && crate::common::utils::get_new_token(writer, true).await
-        {
+        let Some(id) = token_manager.alias_map().get(alias.as_str()).copied() else {
+            failed_count += 1;
+            continue;
+        };
+        let writer = unsafe { token_manager.tokens_mut().into_token_writer(id) };
+        let refreshed = crate::common::utils::get_new_token(writer, true).await;
+        unsafe { token_manager.tokens_mut().get_unchecked_mut(id) }.record_refresh(refreshed);
+        attempted = true;
+        if refreshed.is_ok() {
             updated_count += 1;
         } else {
             failed_count += 1;
@@ -440,7 +496,7 @@ pub async fn handle_refresh_tokens(
     }
 
     // Save changes
-    if updated_count > 0 && token_manager.save().await.is_err() {
+    if attempted && token_manager.save().await.is_err() {
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(GenericError {
@@ -500,6 +556,10 @@ pub async fn handle_set_tokens_status(
             .map(|id| unsafe { token_manager.tokens_mut().get_unchecked_mut(id) })
         {
             info.status.enabled = request.enabled;
+            if request.enabled {
+                info.status.health.clear_backoff();
+                info.status.suspension = None;
+            }
             updated_count += 1;
         } else {
             failed_count += 1;
@@ -743,6 +803,247 @@ pub async fn handle_set_tokens_timezone(
     }))
 }
 
+pub async fn handle_set_tokens_weight(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TokensWeightSetRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    // Validate request
+    if request.aliases.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_NO_TOKENS_PROVIDED)),
+                message: Some(Cow::Borrowed(MESSAGE_NO_TOKENS_PROVIDED)),
+            }),
+        ));
+    }
+
+    // Get current token_manager
+    let mut token_manager = state.token_manager_write().await;
+
+    // Batch set tokens weight
+    let mut updated_count = 0u32;
+    let mut failed_count = 0u32;
+
+    for alias in request.aliases {
+        // Verify token exists in token_manager
+        if let Some(info) = token_manager
+            .alias_map()
+            .get(alias.as_str())
+            .copied()
+            .map(|id| unsafe { token_manager.tokens_mut().get_unchecked_mut(id) })
+        {
+            info.policy.weight = request.weight;
+            updated_count += 1;
+        } else {
+            failed_count += 1;
+        }
+    }
+
+    // Save changes
+    if updated_count > 0 && token_manager.save().await.is_err() {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_SAVE_TOKEN_WEIGHTS)),
+                message: Some(Cow::Borrowed(MESSAGE_SAVE_TOKEN_WEIGHT_FAILED)),
+            }),
+        ));
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                SET_SUCCESS,
+                itoa::Buffer::new().format(updated_count),
+                " token weights, ",
+                itoa::Buffer::new().format(failed_count),
+                SET_FAILURE_COUNT,
+            ]
+            .concat(),
+        ),
+    }))
+}
+
+pub async fn handle_get_tokens_groups(
+    State(state): State<Arc<AppState>>,
+) -> Json<TokensGroupsGetResponse> {
+    let token_manager = state.token_manager_read().await;
+    let mut groups: Vec<TokenGroup> = token_manager
+        .groups()
+        .map(|name| TokenGroup { name: name.to_owned(), aliases: Vec::new() })
+        .collect();
+    groups.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    for (id, info) in token_manager.tokens().iter().enumerate() {
+        let Some(info) = info else { continue };
+        for group in groups.iter_mut().filter(|group| info.in_group(&group.name)) {
+            // SAFETY: id_to_alias is maintained in sync with tokens
+            let alias = unsafe {
+                token_manager.id_to_alias().get_unchecked(id).as_ref().unwrap_unchecked()
+            };
+            group.aliases.push(alias.clone());
+        }
+    }
+
+    Json(TokensGroupsGetResponse { groups })
+}
+
+pub async fn handle_set_tokens_groups(
+    State(state): State<Arc<AppState>>,
+    Json(mut request): Json<TokensGroupsSetRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    // Validate request
+    if request.aliases.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_NO_TOKENS_PROVIDED)),
+                message: Some(Cow::Borrowed(MESSAGE_NO_TOKENS_PROVIDED)),
+            }),
+        ));
+    }
+    if !request.groups.iter().all(|group| is_valid_group(group)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_INVALID_GROUP)),
+                message: Some(Cow::Borrowed(MESSAGE_INVALID_GROUP)),
+            }),
+        ));
+    }
+    request.groups.sort_unstable();
+    request.groups.dedup();
+
+    // Get current token_manager
+    let mut token_manager = state.token_manager_write().await;
+
+    // Batch set tokens groups
+    let mut updated_count = 0u32;
+    let mut failed_count = 0u32;
+
+    for alias in request.aliases {
+        // Verify token exists in token_manager
+        if let Some(id) = token_manager.alias_map().get(alias.as_str()).copied()
+            && token_manager.set_groups(id, request.groups.clone()).is_ok()
+        {
+            updated_count += 1;
+        } else {
+            failed_count += 1;
+        }
+    }
+
+    // Save changes
+    if updated_count > 0 && token_manager.save().await.is_err() {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_SAVE_TOKEN_GROUPS)),
+                message: Some(Cow::Borrowed(MESSAGE_SAVE_TOKEN_GROUPS_FAILED)),
+            }),
+        ));
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                SET_SUCCESS,
+                itoa::Buffer::new().format(updated_count),
+                " token groups, ",
+                itoa::Buffer::new().format(failed_count),
+                SET_FAILURE_COUNT,
+            ]
+            .concat(),
+        ),
+    }))
+}
+
+/// Group names end up in `AUTH_TOKEN@group`, so they stay within what a header can carry
+#[inline]
+fn is_valid_group(group: &str) -> bool {
+    !group.is_empty()
+        && group.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+pub async fn handle_set_tokens_limits(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TokensLimitsSetRequest>,
+) -> Result<Json<CommonResponse>, (StatusCode, Json<GenericError>)> {
+    // Validate request
+    if request.aliases.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_NO_TOKENS_PROVIDED)),
+                message: Some(Cow::Borrowed(MESSAGE_NO_TOKENS_PROVIDED)),
+            }),
+        ));
+    }
+
+    // Get current token_manager
+    let mut token_manager = state.token_manager_write().await;
+
+    // Batch set tokens limits
+    let mut updated_count = 0u32;
+    let mut failed_count = 0u32;
+
+    for alias in request.aliases {
+        // Verify token exists in token_manager
+        if let Some(info) = token_manager
+            .alias_map()
+            .get(alias.as_str())
+            .copied()
+            .map(|id| unsafe { token_manager.tokens_mut().get_unchecked_mut(id) })
+        {
+            info.policy.max_concurrent = request.max_concurrent;
+            info.policy.max_rpm = request.max_rpm;
+            updated_count += 1;
+        } else {
+            failed_count += 1;
+        }
+    }
+
+    // Save changes
+    if updated_count > 0 && token_manager.save().await.is_err() {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GenericError {
+                status: ApiStatus::Error,
+                code: None,
+                error: Some(Cow::Borrowed(ERROR_SAVE_TOKEN_LIMITS)),
+                message: Some(Cow::Borrowed(MESSAGE_SAVE_TOKEN_LIMITS_FAILED)),
+            }),
+        ));
+    }
+
+    Ok(Json(CommonResponse {
+        status: ApiStatus::Success,
+        message: Cow::Owned(
+            [
+                SET_SUCCESS,
+                itoa::Buffer::new().format(updated_count),
+                " token limits, ",
+                itoa::Buffer::new().format(failed_count),
+                SET_FAILURE_COUNT,
+            ]
+            .concat(),
+        ),
+    }))
+}
+
 pub async fn handle_merge_tokens(
     State(state): State<Arc<AppState>>,
     Json(request): Json<TokensMergeRequest>,
@@ -1,6 +1,12 @@
 // mod backend;
-// mod context;
+mod choices;
+mod completion;
+pub mod context;
+mod failover;
 pub mod cpp;
+pub mod gemini;
+pub mod ollama;
+pub mod responses;
 
 use crate::{
     app::{
@@ -11,32 +17,35 @@ use crate::{
         },
         lazy::{AUTH_TOKEN, REAL_USAGE, chat_url, dry_chat_url},
         model::{
-            AppConfig, AppState, Chain, ChainUsage, DateTime, ErrorInfo, LogStatus, LogTokenInfo,
-            LogUpdate, QueueType, RequestLog, TimingInfo, TokenKey, UsageCheck, log_manager,
+            ApiKeys, AppConfig, AppState, Budgets, Chain, ChainUsage, DateTime, ErrorInfo,
+            ExtToken, HealthEvent, LogStatus, LogTokenInfo, LogUpdate, QueueType, RequestLog,
+            Spender, TimingInfo, TokenKey, UsageCheck, log_manager,
         },
     },
     common::{
         client::{AiServiceRequest, build_client_request},
         model::{ApiStatus, GenericError, error::ChatError, tri::Tri},
         utils::{
-            CollectBytes, TrimNewlines as _, get_available_models, get_token_profile,
-            get_token_usage, new_uuid_v4, tokeninfo_to_token,
+            CollectBytes, TrimNewlines as _, approx_token_count, get_available_models,
+            get_token_profile, get_token_usage, new_uuid_v4, tokeninfo_to_token,
         },
     },
     core::{
         aiserver::v1::EnvironmentInfo,
-        auth::{AuthError, TokenBundleResult, auth},
+        auth::{AuthError, TokenBundleResult, TokenPool, auth},
         config::{KeyConfig, parse_dynamic_token},
         constant::Models,
         error::{ErrorExt as _, StreamError},
         model::{
-            ExtModel, MessageId, RawModelsResponse, Role,
+            ExtModel, JsonOutput, MessageId, RawModelsResponse, Role, ToolChoice,
             anthropic::{self, AnthropicError},
             openai::{self, OpenAiError},
         },
+        session::{Owner, Parking, Registry},
         stream::{
             decoder::{StreamDecoder, StreamMessage, Thinking},
             droppable::DroppableStream,
+            limiter::{ContentLimiter, StopReason},
         },
     },
 };
@@ -49,10 +58,12 @@ use axum::{
     response::Response,
 };
 use bytes::Bytes;
+use context::{Session, Tendency, Upstream};
 use core::{
     convert::Infallible,
     sync::atomic::{AtomicU32, Ordering},
 };
+use failover::Failover;
 use futures_util::StreamExt as _;
 use http::{
     Extensions, StatusCode,
@@ -97,48 +108,83 @@ pub async fn handle_models(
     };
 
     // Get token information
-    let (ext_token, use_pri) = async {
+    // A token taken from a queue keeps its slot until the models are fetched
+    let (ext_token, use_pri, _in_flight) = async {
         // Admin Token
         if let Some(part) = auth_token.strip_prefix(&**AUTH_TOKEN) {
             let token_manager = state.token_manager.read().await;
 
-            let bundle = if part.is_empty() {
+            let (bundle, in_flight) = if part.is_empty() {
                 token_manager
-                    .select(QueueType::PrivilegedFree)
+                    .select(QueueType::PrivilegedFree, None)
+                    .ok_or(AuthError::NoAvailableTokens)?
+            } else if let Some(group) = part.strip_prefix('@') {
+                if !token_manager.has_group(group) {
+                    return Err(AuthError::GroupNotFound);
+                }
+                token_manager
+                    .select(QueueType::PrivilegedFree, Some(group))
                     .ok_or(AuthError::NoAvailableTokens)?
             } else if let Some(alias) = part.strip_prefix('-') {
                 if !token_manager.alias_map().contains_key(alias) {
                     return Err(AuthError::AliasNotFound);
                 }
-                token_manager
+                let bundle = token_manager
                     .get_by_alias(alias)
                     .map(|token_info| token_info.bundle.clone())
-                    .ok_or(AuthError::Unauthorized)?
+                    .ok_or(AuthError::Unauthorized)?;
+                return Ok((bundle, true, None));
             } else {
                 return Err(AuthError::Unauthorized);
             };
 
-            return Ok((bundle, true));
+            return Ok((bundle, true, Some(in_flight)));
         } else
         // Shared Token
         if AppConfig::is_share() && AppConfig::share_token_eq(auth_token) {
             let token_manager = state.token_manager.read().await;
-            let bundle =
-                token_manager.select(QueueType::NormalFree).ok_or(AuthError::NoAvailableTokens)?;
-            return Ok((bundle, true));
+            let (bundle, in_flight) = token_manager
+                .select(QueueType::NormalFree, None)
+                .ok_or(AuthError::NoAvailableTokens)?;
+            return Ok((bundle, true, Some(in_flight)));
+        } else
+        // API key
+        if let Some(checked) = ApiKeys::check(auth_token) {
+            checked.map_err(AuthError::from)?;
+            let token_manager = state.token_manager.read().await;
+            let (bundle, in_flight) = token_manager
+                .select(QueueType::NormalFree, None)
+                .ok_or(AuthError::NoAvailableTokens)?;
+            return Ok((bundle, true, Some(in_flight)));
         } else
         // Regular user Token
         if let Some(key) = TokenKey::from_string(auth_token) {
             if let Some(bundle) = log_manager::get_token(key).await {
-                return Ok((bundle, false));
+                return Ok((bundle, false, None));
             }
         } else
         // Dynamic key
         if AppConfig::is_dynamic_key_enabled() {
-            if let Some(parsed_config) = parse_dynamic_token(auth_token)
-                && let Some(ext_token) = parsed_config.into_tuple().and_then(tokeninfo_to_token) {
-                    return Ok((ext_token, false));
+            if let Some(parsed_config) = parse_dynamic_token(auth_token) {
+                if parsed_config.is_revoked() {
+                    return Err(AuthError::KeyRevoked);
+                }
+                if let Some(group) = parsed_config.verified_group() {
+                    let token_manager = state.token_manager.read().await;
+                    if !token_manager.has_group(group) {
+                        return Err(AuthError::GroupNotFound);
+                    }
+                    let (bundle, in_flight) = token_manager
+                        .select(QueueType::NormalFree, Some(group))
+                        .ok_or(AuthError::NoAvailableTokens)?;
+                    return Ok((bundle, true, Some(in_flight)));
                 }
+                if let Some(ext_token) =
+                    parsed_config.into_verified().and_then(tokeninfo_to_token)
+                {
+                    return Ok((ext_token, false, None));
+                }
+            }
         }
 
         Err(AuthError::Unauthorized)
@@ -202,36 +248,180 @@ enum LastContentType {
 atomic_enum!(StreamState = u8);
 atomic_enum!(LastContentType = u8);
 
+/// What a request that could not be sent says about its token, nothing unless the upstream
+/// answered with a rate limit or a rejection, a connection, proxy or timeout failure being no
+/// fault of the token
+fn send_health_event(e: &reqwest::Error) -> Option<HealthEvent> {
+    match e.status()?.as_u16() {
+        429 => Some(HealthEvent::Throttled),
+        401 | 403 => Some(HealthEvent::Unauthorized),
+        _ => None,
+    }
+}
+
+/// Sends the encoded chat request once more, for non-streaming retries
+async fn resend_chat_request(
+    state: &AppState,
+    ext_token: &ExtToken,
+    use_pri: bool,
+    data: Vec<u8>,
+    current_id: u64,
+) -> Result<reqwest::Response, ChatError> {
+    let req = build_client_request(AiServiceRequest {
+        ext_token,
+        fs_client_key: None,
+        url: chat_url(use_pri),
+        stream: true,
+        compressed: true,
+        trace_id: new_uuid_v4(),
+        use_pri,
+        cookie: None,
+        exact_length: Some(data.len()),
+    });
+    match req.body(data).send().await {
+        Ok(resp) => Ok(resp),
+        Err(e) => {
+            let e = e.without_url();
+            if let Some(event) = send_health_event(&e) {
+                state.record_token_health(ext_token.primary_token.key(), event).await;
+            }
+            let status_code = if e.is_timeout() {
+                StatusCode::GATEWAY_TIMEOUT
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            crate::debug!("request: {e:?}");
+            let e = e.to_string();
+            log_manager::update_log(
+                current_id,
+                LogUpdate::Failure(ErrorInfo::Simple(Str::new(&e))),
+            )
+            .await;
+            Err(ChatError::RequestFailed(status_code, Cow::Owned(e)))
+        }
+    }
+}
+
+/// `finish_reason` of a finished chat completion
+fn openai_finish_reason(decoder: &StreamDecoder) -> openai::FinishReason {
+    match decoder.stop_reason() {
+        Some(StopReason::StopSequence(_)) => openai::FinishReason::Stop,
+        Some(StopReason::MaxTokens) => openai::FinishReason::Length,
+        None if decoder.tool_processed() == 0 => openai::FinishReason::Stop,
+        None => openai::FinishReason::ToolCalls,
+    }
+}
+
+/// `stop_reason` and `stop_sequence` of a finished message
+fn anthropic_stop_reason(decoder: &StreamDecoder) -> (anthropic::StopReason, Option<String>) {
+    match decoder.stop_reason() {
+        Some(StopReason::StopSequence(seq)) => {
+            (anthropic::StopReason::StopSequence, Some(seq.clone()))
+        }
+        Some(StopReason::MaxTokens) => (anthropic::StopReason::MaxTokens, None),
+        None if decoder.tool_processed() == 0 => (anthropic::StopReason::EndTurn, None),
+        None => (anthropic::StopReason::ToolUse, None),
+    }
+}
+
+/// Whether the usage of a finished request is fetched, it is when reported or when the request
+/// spends from a budget
+#[inline]
+fn wants_usage(spender: Option<&Spender>) -> bool { *REAL_USAGE || spender.is_some() }
+
+/// Usage of a finished request, charged to the budget of `spender` as soon as it is known
+///
+/// Charged here rather than on `LogUpdate::Usage`, which never arrives for requests that are not
+/// logged, so that every request with a budget is charged
+async fn request_usage(
+    ext_token: ExtToken,
+    use_pri: bool,
+    request_time: DateTime,
+    model_id: &'static str,
+    spender: Option<&Spender>,
+) -> Option<ChainUsage> {
+    if !wants_usage(spender) {
+        return None;
+    }
+    let usage = get_token_usage(ext_token, use_pri, request_time, model_id).await?;
+    if let Some(spender) = spender {
+        Budgets::charge(spender, usage.cents);
+    }
+    Some(usage)
+}
+
+/// Token the session parked on `key` runs on, `None` once it is no longer in the pool
+async fn parked_on(state: &AppState, ext_token: &ExtToken, key: TokenKey) -> Option<ExtToken> {
+    if ext_token.primary_token.key() == key {
+        return Some(ext_token.clone());
+    }
+    let token_manager = state.token_manager_read().await;
+    let &id = token_manager.id_map().get(&key)?;
+    token_manager.tokens().get(id)?.as_ref().map(|info| info.bundle.clone())
+}
+
 // Chat handler function signature
 pub async fn handle_chat_completions(
     State(state): State<Arc<AppState>>,
     mut extensions: Extensions,
-    Json(request): Json<openai::ChatCompletionCreateParams>,
+    Json(mut request): Json<openai::ChatCompletionCreateParams>,
 ) -> Result<Response<Body>, (StatusCode, Json<OpenAiError>)> {
-    let (ext_token, use_pri) =
+    let (mut ext_token, use_pri) =
         __unwrap!(extensions.remove::<TokenBundleResult>()).map_err(|e| e.into_openai_tuple())?;
+    let mut token_key = ext_token.primary_token.key();
 
     // Verify model is supported and get model information
     let model = if let Some(model) = ExtModel::from_str(&request.model) {
-        model
+        model.with_thinking_level(
+            request.reasoning_effort.map(openai::ReasoningEffort::thinking_level),
+        )
     } else {
         return Err(ChatError::ModelNotSupported(request.model).into_openai_tuple());
     };
-    let (params, tools, is_stream, stream_options) = request.strip();
+    if !__unwrap!(extensions.get::<KeyConfig>()).allows_model(model.id) {
+        return Err(AuthError::ModelNotAllowed.into_openai_tuple());
+    }
+    let tool_choice = request.tool_choice.take().map_or(ToolChoice::Auto, Into::into);
+    let parallel_tool_calls = request.parallel_tool_calls.unwrap_or(true);
+    let json_output =
+        request.response_format.take().and_then(openai::ResponseFormat::into_json_output);
+    let limiter = ContentLimiter::new(
+        request.stop.take().map_or_else(Vec::new, openai::StopSequences::into_vec),
+        request.max_completion_tokens.or(request.max_tokens),
+    );
+    let n = request.n.unwrap_or(1);
+    let (mut params, mut tools, is_stream, stream_options) = request.strip();
 
     // Validate request
     if params.is_empty() {
         return Err(ChatError::EmptyMessages(StatusCode::BAD_REQUEST).into_openai_tuple());
     }
+    if n == 0 || n > openai::MAX_CHOICES {
+        return Err(ChatError::InvalidChoiceCount(n).into_openai_tuple());
+    }
+    let require_tool_call = tool_choice
+        .apply(&mut tools, openai::ChatCompletionTool::name)
+        .map_err(ChatError::into_openai_tuple)?;
+    if let Some(ref json_output) = json_output {
+        super::adapter::openai::append_instructions(
+            &mut params,
+            json_output.instructions(),
+            ext_token.now(),
+        );
+    }
 
     let current_config = __unwrap!(extensions.remove::<KeyConfig>());
 
     let environment_info = __unwrap!(extensions.remove::<EnvironmentInfo>());
 
+    let pool = extensions.remove::<TokenPool>();
+
     let current_id: u64;
     let mut usage_check = None;
 
     let request_time = __unwrap!(extensions.remove::<DateTime>());
+    let spender = extensions.remove::<Spender>();
+    let owner = __unwrap!(extensions.remove::<Owner>());
 
     // Update request log
     state.increment_total();
@@ -293,6 +483,8 @@ pub async fn handle_chat_completions(
                     stripe: None,
                 },
                 chain: Chain { delays: None, usage: None, think: None },
+                choices: Vec::new(),
+                attempts: Vec::new(),
                 timing: TimingInfo { total: 0.0 },
                 stream: is_stream,
                 status: LogStatus::Pending,
@@ -351,7 +543,7 @@ pub async fn handle_chat_completions(
                         alias_updater = Some((id, alias.clone()));
                     }
                     token_info.user = user;
-                    token_info.usage = usage;
+                    token_info.set_usage(usage);
                     token_info.stripe = stripe;
                 };
 
@@ -364,74 +556,163 @@ pub async fn handle_chat_completions(
         current_id = 0;
     }
 
-    // Convert Message to hex format
-    let msg_id = uuid::Uuid::new_v4();
-    let data = match super::adapter::openai::encode_create_params(
-        params,
-        tools,
-        ext_token.now(),
-        model,
-        msg_id,
-        environment_info,
-        current_config.disable_vision,
-        current_config.enable_slow_pool,
-    )
-    .await
-    {
-        Ok(data) => data,
-        Err(e) => {
-            log_manager::update_log(current_id, LogUpdate::Failure(e.to_log_error())).await;
-            state.decrement_active();
-            state.increment_error();
-            return Err(e.into_openai_tuple());
+    if n > 1 {
+        return choices::Fanout {
+            state,
+            ext_token,
+            use_pri,
+            pool,
+            n,
+            model,
+            params,
+            tools,
+            environment_info,
+            config: current_config,
+            current_id,
+            request_time,
+            is_stream,
+            include_usage: stream_options.include_usage,
+            limiter,
+            json_output,
+            require_tool_call,
+            parallel_tool_calls,
+            spender,
         }
-    };
-    let msg_id = MessageId::new(msg_id.as_bytes());
+        .handle(usage_check)
+        .await;
+    }
 
-    // Build Request client
-    let req = build_client_request(AiServiceRequest {
-        ext_token: &ext_token,
-        fs_client_key: None,
-        url: chat_url(use_pri),
-        stream: true,
-        compressed: true,
-        trace_id: new_uuid_v4(),
-        use_pri,
-        cookie: None,
-        exact_length: Some(data.len()),
-    });
-    // crate::debug!("request: {req:?}");
-    // Send Request
-    let response = req.body(data).send().await;
+    // Keep the params around for a corrective retry when the JSON output is rejected
+    let mut json_retry = (json_output.is_some() && !is_stream)
+        .then(|| (params.clone(), tools.clone(), environment_info.clone()));
+
+    // A tool result for a stream parked at its call is sent into that stream instead
+    let parked = if is_stream
+        && let Some((tool_call_id, name, content)) = context::openai_tool_result(&params)
+        && let Some(parked) = Registry::take(tool_call_id, model.id, owner)
+        && let Some(parked_token) = parked_on(&state, &ext_token, parked.token).await
+        && let Ok(data) = super::adapter::openai::encode_tool_result(
+            content.clone(),
+            tool_call_id.clone(),
+            name.clone(),
+        )
+        .await
+        && parked.session.send(data.into()).await.is_ok()
+    {
+        Some((parked, parked_token))
+    } else {
+        None
+    };
+    // Keep the stream open at tool calls so that their results can follow
+    let keep_open = is_stream && !tools.is_empty() && Registry::is_enabled();
 
-    // Handle Request result
-    let response = match response {
-        Ok(resp) => {
-            // Update Request log to success
-            log_manager::update_log(current_id, LogUpdate::Success).await;
-            resp
+    let msg_id = uuid::Uuid::new_v4();
+    let mut retry_data = None;
+    // Other pooled tokens to retry on when this one fails before the first byte
+    let mut failover = None;
+    let tendency = if let Some((parked, parked_token)) = parked {
+        // The turn is served by the token the stream runs on, not the one picked for it
+        if parked.token != token_key {
+            log_manager::update_log(current_id, LogUpdate::Token(parked_token.clone())).await;
+            token_key = parked.token;
         }
-        Err(e) => {
-            let e = e.without_url();
+        ext_token = parked_token;
+        log_manager::update_log(current_id, LogUpdate::Success).await;
+        Tendency::Continue(parked)
+    } else {
+        // Convert Message to hex format
+        let data = match super::adapter::openai::encode_create_params(
+            params,
+            tools,
+            ext_token.now(),
+            model,
+            msg_id,
+            environment_info,
+            current_config.disable_vision,
+            current_config.enable_slow_pool,
+        )
+        .await
+        {
+            Ok(data) => data,
+            Err(e) => {
+                log_manager::update_log(current_id, LogUpdate::Failure(e.to_log_error())).await;
+                state.decrement_active();
+                state.increment_error();
+                return Err(e.into_openai_tuple());
+            }
+        };
 
-            // Return different status codes based on Error type
-            let status_code = if e.is_timeout() {
-                StatusCode::GATEWAY_TIMEOUT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-            crate::debug!("request: {e:?}");
-            let e = e.to_string();
+        // Keep the request around for a retry when a required tool call does not arrive
+        retry_data = (require_tool_call && !is_stream).then(|| data.clone());
+        failover =
+            Failover::new(&state, pool, &ext_token, &data, use_pri, keep_open, current_id);
+        // Send Request
+        let response = if keep_open {
+            context::start(data, &ext_token, use_pri).await.map(|(resp, sink)| (resp, Some(sink)))
+        } else {
+            let req = build_client_request(AiServiceRequest {
+                ext_token: &ext_token,
+                fs_client_key: None,
+                url: chat_url(use_pri),
+                stream: true,
+                compressed: true,
+                trace_id: new_uuid_v4(),
+                use_pri,
+                cookie: None,
+                exact_length: Some(data.len()),
+            });
+            req.body(data).send().await.map(|resp| (resp, None))
+        };
 
-            // Update Request log to failed
-            let error = Str::new(&e);
-            log_manager::update_log(current_id, LogUpdate::Failure(ErrorInfo::Simple(error))).await;
-            state.decrement_active();
-            state.increment_error();
+        // Handle Request result
+        match response {
+            Ok(resp) => {
+                // Update Request log to success
+                log_manager::update_log(current_id, LogUpdate::Success).await;
+                Tendency::Start(resp)
+            }
+            Err(e) => {
+                let e = e.without_url();
+                if let Some(event) = send_health_event(&e) {
+                    state.record_token_health(token_key, event).await;
+                }
 
-            return Err(ChatError::RequestFailed(status_code, Cow::Owned(e)).into_openai_tuple());
+                // Return different status codes based on Error type
+                let status_code = if e.is_timeout() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                crate::debug!("request: {e:?}");
+                let e = e.to_string();
+                let error = Str::new(&e);
+
+                if let Some(ref mut failover) = failover
+                    && let Some((next, resp, sink)) =
+                        failover.retry(ErrorInfo::Simple(error.clone())).await
+                {
+                    ext_token = next;
+                    token_key = ext_token.primary_token.key();
+                    log_manager::update_log(current_id, LogUpdate::Success).await;
+                    Tendency::Start((resp, sink))
+                } else {
+                    // Update Request log to failed
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(error)),
+                    )
+                    .await;
+                    state.decrement_active();
+                    state.increment_error();
+
+                    return Err(
+                        ChatError::RequestFailed(status_code, Cow::Owned(e)).into_openai_tuple()
+                    );
+                }
+            }
         }
     };
+    let msg_id = MessageId::new(msg_id.as_bytes());
 
     // Release active Request count
     state.decrement_active();
@@ -450,7 +731,20 @@ pub async fn handle_chat_completions(
         });
         let index = Arc::new(AtomicU32::new(0));
         let start_time = std::time::Instant::now();
-        let decoder = Arc::new(Mutex::new(StreamDecoder::new()));
+        // Decoding starts over when the request fails over to another token
+        let restart_limiter = if failover.is_some() { limiter.clone() } else { None };
+        let (upstream, decoder) = match tendency {
+            Tendency::Start((response, sink)) => (
+                Upstream::new(Session::new(response, sink)),
+                Arc::new(Mutex::new(StreamDecoder::new().with_limiter(limiter))),
+            ),
+            Tendency::Continue(parked) => {
+                parked.decoder.lock().await.next_turn(limiter);
+                (Upstream::new(parked.session), parked.decoder)
+            }
+        };
+        // An open stream waits at each tool call, so the turn ends at the first one
+        let parallel_tool_calls = parallel_tool_calls && !upstream.is_open();
         let stream_state = Arc::new(Atomic::new(StreamState::NotStarted));
         let last_content_type = Arc::new(Atomic::new(LastContentType::None));
         let is_need = stream_options.include_usage;
@@ -466,6 +760,7 @@ pub async fn handle_chat_completions(
             current_id: u64,
             created: i64,
             is_need: bool,
+            parallel_tool_calls: bool,
         }
 
         #[inline]
@@ -511,14 +806,52 @@ pub async fn handle_chat_completions(
                             created: ctx.created,
                             model: ctx.model,
                             choices: Some(openai::chat_completion_chunk::Choice {
-                                index: (),
+                                index: 0,
                                 delta: Some(openai::chat_completion_chunk::choice::Delta {
                                     role: if is_start { Some(Role::Assistant) } else { None },
-                                    content: Some(Cow::Owned(if is_start {
-                                        text.trim_leading_newlines()
-                                    } else {
-                                        text
-                                    })),
+                                    content: Some(Cow::Owned(
+                                        if is_start || last_type == LastContentType::Thinking {
+                                            text.trim_leading_newlines()
+                                        } else {
+                                            text
+                                        },
+                                    )),
+                                    reasoning_content: None,
+                                    tool_calls: None,
+                                }),
+                                logprobs: (),
+                                finish_reason: None,
+                            }),
+                            usage: Tri::Null(ctx.is_need),
+                        };
+
+                        extend_from_slice(&mut response_data, &chunk);
+                    }
+                    StreamMessage::Thinking(Thinking::Text(text)) => {
+                        let is_start =
+                            ctx.stream_state.load(Ordering::Acquire) == StreamState::NotStarted;
+                        if is_start {
+                            ctx.stream_state
+                                .store(StreamState::ContentBlockActive, Ordering::Release);
+                        }
+
+                        let last_type = ctx.last_content_type.load(Ordering::Acquire);
+                        if last_type != LastContentType::Thinking {
+                            ctx.last_content_type
+                                .store(LastContentType::Thinking, Ordering::Release);
+                        }
+
+                        let chunk = openai::ChatCompletionChunk {
+                            id: ctx.response_id,
+                            object: openai::ObjectChatCompletionChunk,
+                            created: ctx.created,
+                            model: ctx.model,
+                            choices: Some(openai::chat_completion_chunk::Choice {
+                                index: 0,
+                                delta: Some(openai::chat_completion_chunk::choice::Delta {
+                                    role: if is_start { Some(Role::Assistant) } else { None },
+                                    content: None,
+                                    reasoning_content: Some(Cow::Owned(text)),
                                     tool_calls: None,
                                 }),
                                 logprobs: (),
@@ -536,10 +869,14 @@ pub async fn handle_chat_completions(
                             ctx.stream_state
                                 .store(StreamState::ContentBlockActive, Ordering::Release);
                         }
+                        let is_last = tool_call.is_last;
 
                         let last_type = ctx.last_content_type.load(Ordering::Acquire);
                         if last_type != LastContentType::InputJson {
-                            if last_type != LastContentType::None {
+                            if !matches!(
+                                last_type,
+                                LastContentType::None | LastContentType::Thinking
+                            ) {
                                 ctx.index.fetch_add(1, Ordering::AcqRel);
                             }
 
@@ -549,10 +886,11 @@ pub async fn handle_chat_completions(
                                 created: ctx.created,
                                 model: ctx.model,
                                 choices: Some(openai::chat_completion_chunk::Choice {
-                                    index: (),
+                                    index: 0,
                                     delta: Some(openai::chat_completion_chunk::choice::Delta {
                                         role: if is_start { Some(Role::Assistant) } else { None },
                                         content: None,
+                                        reasoning_content: None,
                                         tool_calls: Some(Box::new(openai::chat_completion_chunk::choice::delta::ToolCall {
                                             index: ctx.index.load(Ordering::Acquire),
                                             id: Some(tool_call.id),
@@ -579,10 +917,11 @@ pub async fn handle_chat_completions(
                             created: ctx.created,
                             model: ctx.model,
                             choices: Some(openai::chat_completion_chunk::Choice {
-                                index: (),
+                                index: 0,
                                 delta: Some(openai::chat_completion_chunk::choice::Delta {
                                     role: None,
                                     content: None,
+                                    reasoning_content: None,
                                     tool_calls: Some(Box::new(openai::chat_completion_chunk::choice::delta::ToolCall {
                                         index: ctx.index.load(Ordering::Acquire),
                                         id: None,
@@ -597,6 +936,17 @@ pub async fn handle_chat_completions(
                             usage: Tri::Null(ctx.is_need),
                         };
                         extend_from_slice(&mut response_data, &chunk);
+
+                        // Without parallel tool calls the stream ends after the first one
+                        if is_last && !ctx.parallel_tool_calls {
+                            let total_time = ctx.start_time.elapsed().as_secs_f64();
+
+                            log_manager::update_log(ctx.current_id, LogUpdate::Timing(total_time))
+                                .await;
+
+                            ctx.stream_state.store(StreamState::Completed, Ordering::Release);
+                            break;
+                        }
                     }
                     StreamMessage::StreamEnd => {
                         // Calculate total time and first chunk time
@@ -633,7 +983,7 @@ pub async fn handle_chat_completions(
         }
 
         // First Handle stream until get first result
-        let (mut stream, drop_handle) = DroppableStream::new(response.bytes_stream());
+        let (mut stream, drop_handle) = DroppableStream::new(upstream.clone());
         {
             let mut decoder = decoder.lock().await;
             while !decoder.is_first_result_ready() {
@@ -643,6 +993,20 @@ pub async fn handle_chat_completions(
                             decoder.decode(&chunk, convert_web_ref)
                         {
                             let canonical = error.canonical();
+                            if let Some(event) = canonical.health_event() {
+                                state.record_token_health(token_key, event).await;
+                            }
+                            if let Some(ref mut failover) = failover
+                                && let Some((next, response, sink)) =
+                                    failover.retry(canonical.to_error_info()).await
+                            {
+                                ext_token = next;
+                                token_key = ext_token.primary_token.key();
+                                upstream.replace(Session::new(response, sink));
+                                *decoder =
+                                    StreamDecoder::new().with_limiter(restart_limiter.clone());
+                                continue;
+                            }
                             // Update Request log to failed
                             log_manager::update_log(
                                 current_id,
@@ -685,14 +1049,18 @@ pub async fn handle_chat_completions(
                 }
             }
         }
+        state.record_token_health(token_key, HealthEvent::Success).await;
 
         let response_id_clone = response_id.clone();
         let decoder_clone = decoder.clone();
+        // The upstream may say nothing more after a first result that ends at a tool call
+        let kick = upstream.is_open().then_some(Ok(Bytes::new()));
 
         let created = DateTime::utc_now().timestamp();
 
         // Handle subsequent stream
-        let stream = stream
+        let stream = futures_util::stream::iter(kick)
+            .chain(stream)
             .then(move |chunk| {
                 let decoder = decoder_clone.clone();
                 let response_id = response_id_clone.clone();
@@ -700,6 +1068,7 @@ pub async fn handle_chat_completions(
                 let stream_state = stream_state.clone();
                 let last_content_type = last_content_type.clone();
                 let drop_handle = drop_handle.clone();
+                let upstream = upstream.clone();
 
                 async move {
                     let chunk = match chunk {
@@ -720,10 +1089,17 @@ pub async fn handle_chat_completions(
                         stream_state: &stream_state,
                         last_content_type: &last_content_type,
                         is_need,
+                        parallel_tool_calls,
                     };
 
+                    // An empty chunk only flushes the first result, see `kick`
+                    let decoded = if chunk.is_empty() {
+                        Ok(Vec::new())
+                    } else {
+                        decoder.lock().await.decode(&chunk, convert_web_ref)
+                    };
                     // UsedecoderHandlechunk
-                    let messages = match decoder.lock().await.decode(&chunk, convert_web_ref) {
+                    let messages = match decoded {
                         Ok(msgs) => msgs,
                         Err(e) => {
                             match e {
@@ -749,7 +1125,14 @@ pub async fn handle_chat_completions(
 
                     let mut first_response = None;
 
-                    if let Some(first_msg) = decoder.lock().await.take_first_result() {
+                    let first_msg = decoder.lock().await.take_first_result();
+                    // The tool call an open stream now waits at
+                    let tool_call_id = if upstream.is_open() {
+                        context::finished_tool_call(first_msg.iter().flatten().chain(&messages))
+                    } else {
+                        None
+                    };
+                    if let Some(first_msg) = first_msg {
                         first_response = Some(process_messages(first_msg, &ctx).await);
                     }
 
@@ -763,6 +1146,12 @@ pub async fn handle_chat_completions(
                     };
 
                     if ctx.stream_state.load(Ordering::Acquire) == StreamState::Completed {
+                        if let Some(tool_call_id) = tool_call_id
+                            && let Some(session) = upstream.take()
+                        {
+                            let parking = Parking { owner, token: token_key, model: model.id };
+                            Registry::park(tool_call_id, session, decoder.clone(), parking);
+                        }
                         drop_handle.drop_stream()
                     }
 
@@ -772,47 +1161,72 @@ pub async fn handle_chat_completions(
                 }
             })
             .chain(futures_util::stream::once(async move {
+                // Keeps the slot of the token the request failed over to until the stream ends
+                let _failover = failover;
                 // Update delays
                 let mut decoder_guard = decoder.lock().await;
                 let content_delays = decoder_guard.take_content_delays();
                 let thinking_content = decoder_guard.take_thinking_content();
+                let reasoning_tokens =
+                    thinking_content.as_deref().map_or(0, approx_token_count);
+                let json_error = json_output
+                    .as_ref()
+                    .filter(|_| decoder_guard.tool_processed() == 0)
+                    .and_then(|json_output| {
+                        json_output
+                            .extract(content_delays.as_ref().map_or("", |(text, _)| text.as_str()))
+                            .err()
+                    });
 
                 log_manager::update_log(current_id, LogUpdate::Delays(content_delays, thinking_content))
                     .await;
 
-                let usage = if *REAL_USAGE {
-                    let usage =
-                        get_token_usage(ext_token, use_pri, request_time, model.id)
-                            .await;
-                    if let Some(usage) = usage {
-                        log_manager::update_log(current_id, LogUpdate::Usage(usage))
-                            .await;
-                    }
-                    usage.map(ChainUsage::into_openai)
-                } else {
-                    None
-                };
+                let usage =
+                    request_usage(ext_token, use_pri, request_time, model.id, spender.as_ref())
+                        .await;
+                if let Some(usage) = usage {
+                    log_manager::update_log(current_id, LogUpdate::Usage(usage)).await;
+                }
+                let usage = usage
+                    .filter(|_| *REAL_USAGE)
+                    .map(|usage| usage.into_openai().with_reasoning_tokens(reasoning_tokens));
 
                 let mut response_data = Vec::with_capacity(128);
 
+                // The error goes ahead of the finish chunk, which clients take as the end
+                if require_tool_call && decoder_guard.tool_processed() == 0 {
+                    let error = ChatError::ToolCallMissing;
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                    )
+                    .await;
+                    extend_from_slice(&mut response_data, &error.into_openai_tuple().1.0);
+                } else if let Some(e) = json_error {
+                    let error = ChatError::InvalidJsonOutput(e);
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                    )
+                    .await;
+                    extend_from_slice(&mut response_data, &error.into_openai_tuple().1.0);
+                }
+
                 let response = openai::ChatCompletionChunk {
                     id: &response_id,
                     object: openai::ObjectChatCompletionChunk,
                     created,
                     model: model.id,
                     choices: Some(openai::chat_completion_chunk::Choice {
-                        index: (),
+                        index: 0,
                         delta: Some(openai::chat_completion_chunk::choice::Delta {
                             role: None,
                             content: None,
+                            reasoning_content: None,
                             tool_calls: None,
                         }),
                         logprobs: (),
-                        finish_reason: Some(if decoder_guard.tool_processed() == 0 {
-                            openai::FinishReason::Stop
-                        } else {
-                            openai::FinishReason::ToolCalls
-                        }),
+                        finish_reason: Some(openai_finish_reason(&decoder_guard)),
                     }),
                     usage: Tri::Null(is_need),
                 };
@@ -850,79 +1264,204 @@ pub async fn handle_chat_completions(
     } else {
         // Non-streaming Response
         let start_time = std::time::Instant::now();
-        let mut decoder = StreamDecoder::new().no_first_cache();
+        let mut decoder;
         let mut thinking_text = String::with_capacity(128);
         let mut full_text = String::with_capacity(128);
         let mut tool_calls = Vec::new();
-        let mut stream = response.bytes_stream();
+        let Tendency::Start((mut response, _)) = tendency else { __unreachable!() };
+        let mut retry_data = retry_data;
         // let mut prompt = Prompt::None;
 
-        // Handle chunks one by one
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| {
-                ChatError::RequestFailed(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Cow::Owned(format!("Failed to read response chunk: {e}")),
-                )
-                .into_openai_tuple()
-            })?;
-
-            // Immediately Handle current chunk
-            match decoder.decode(&chunk, convert_web_ref) {
-                Ok(messages) => {
-                    for message in messages {
-                        match message {
-                            StreamMessage::Content(text) => full_text.push_str(&text),
-                            StreamMessage::Thinking(Thinking::Text(text)) => {
-                                thinking_text.push_str(&text)
-                            }
-                            StreamMessage::ToolCall(tool_call) => {
-                                tool_calls.push(openai::ChatCompletionMessageToolCall::Function {
-                                    id: tool_call.id,
-                                    function: openai::chat_completion_message_tool_call::Function {
-                                        arguments: tool_call.input,
-                                        name: tool_call.name,
-                                    },
-                                })
+        'request: loop {
+            decoder = StreamDecoder::new().no_first_cache().with_limiter(limiter.clone());
+            let mut stream = response.bytes_stream();
+
+            // Handle chunks one by one
+            'decode: while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| {
+                    ChatError::RequestFailed(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Cow::Owned(format!("Failed to read response chunk: {e}")),
+                    )
+                    .into_openai_tuple()
+                })?;
+
+                // Immediately Handle current chunk
+                match decoder.decode(&chunk, convert_web_ref) {
+                    Ok(messages) => {
+                        for message in messages {
+                            match message {
+                                StreamMessage::Content(text) => full_text.push_str(&text),
+                                StreamMessage::Thinking(Thinking::Text(text)) => {
+                                    thinking_text.push_str(&text)
+                                }
+                                StreamMessage::ToolCall(tool_call) => {
+                                    // Argument deltas of one call share its id
+                                    if let Some(openai::ChatCompletionMessageToolCall::Function {
+                                        id,
+                                        function,
+                                    }) = tool_calls.last_mut()
+                                        && id[..] == tool_call.id[..]
+                                    {
+                                        function.arguments.push_str(&tool_call.input);
+                                    } else {
+                                        tool_calls.push(
+                                            openai::ChatCompletionMessageToolCall::Function {
+                                                id: tool_call.id,
+                                                function:
+                                                    openai::chat_completion_message_tool_call::Function {
+                                                        arguments: tool_call.input,
+                                                        name: tool_call.name,
+                                                    },
+                                            },
+                                        )
+                                    }
+                                    if tool_call.is_last && !parallel_tool_calls {
+                                        break 'decode;
+                                    }
+                                }
+                                StreamMessage::StreamEnd => break 'decode,
+                                // StreamMessage::Debug(debug_prompt) => {
+                                //     if prompt.is_none() {
+                                //         prompt = Prompt::new(debug_prompt);
+                                //     } else {
+                                //         __cold_path!();
+                                //         crate::debug!("UB!2 {debug_prompt:?}");
+                                //     }
+                                // }
+                                _ => {}
                             }
-                            // StreamMessage::Debug(debug_prompt) => {
-                            //     if prompt.is_none() {
-                            //         prompt = Prompt::new(debug_prompt);
-                            //     } else {
-                            //         __cold_path!();
-                            //         crate::debug!("UB!2 {debug_prompt:?}");
-                            //     }
-                            // }
-                            _ => {}
+                        }
+                    }
+                    Err(StreamError::Upstream(error)) => {
+                        let canonical = error.canonical();
+                        if let Some(event) = canonical.health_event() {
+                            state.record_token_health(token_key, event).await;
+                        }
+                        if !decoder.has_seen_content()
+                            && let Some(ref mut failover) = failover
+                            && let Some((next, resp, _)) =
+                                failover.retry(canonical.to_error_info()).await
+                        {
+                            ext_token = next;
+                            token_key = ext_token.primary_token.key();
+                            response = resp;
+                            continue 'request;
+                        }
+                        log_manager::update_log(
+                            current_id,
+                            LogUpdate::Failure(canonical.to_error_info()),
+                        )
+                        .await;
+                        state.increment_error();
+                        return Err((
+                            canonical.status_code(),
+                            Json(canonical.into_openai().wrapped()),
+                        ));
+                    }
+                    Err(StreamError::EmptyStream) => {
+                        let empty_stream_count = decoder.get_empty_stream_count();
+                        if empty_stream_count > 1 {
+                            eprintln!(
+                                "[Warning] Stream error: empty stream (continuous count: {})",
+                                decoder.get_empty_stream_count()
+                            );
                         }
                     }
                 }
-                Err(StreamError::Upstream(error)) => {
-                    let canonical = error.canonical();
+            }
+
+            let data = if require_tool_call && decoder.tool_processed() == 0 {
+                // The required tool call did not arrive, retry once before giving up
+                let Some(data) = retry_data.take() else {
+                    let error = ChatError::ToolCallMissing;
                     log_manager::update_log(
                         current_id,
-                        LogUpdate::Failure(canonical.to_error_info()),
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
                     )
                     .await;
                     state.increment_error();
-                    return Err((canonical.status_code(), Json(canonical.into_openai().wrapped())));
-                }
-                Err(StreamError::EmptyStream) => {
-                    let empty_stream_count = decoder.get_empty_stream_count();
-                    if empty_stream_count > 1 {
-                        eprintln!(
-                            "[Warning] Stream error: empty stream (continuous count: {})",
-                            decoder.get_empty_stream_count()
-                        );
+                    return Err(error.into_openai_tuple());
+                };
+                data
+            } else if let Some(ref json_output) = json_output
+                && tool_calls.is_empty()
+            {
+                match json_output.extract(&full_text) {
+                    Ok(json) => {
+                        full_text = json.to_owned();
+                        break;
+                    }
+                    Err(e) => {
+                        // Ask once for a corrected reply before giving up
+                        let Some((mut params, tools, environment_info)) = json_retry.take() else {
+                            let error = ChatError::InvalidJsonOutput(e);
+                            log_manager::update_log(
+                                current_id,
+                                LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                            )
+                            .await;
+                            state.increment_error();
+                            return Err(error.into_openai_tuple());
+                        };
+                        params.push(openai::ChatCompletionMessageParam::Assistant {
+                            content: openai::ChatCompletionContentText::String(core::mem::take(
+                                &mut full_text,
+                            )),
+                            tool_calls: None,
+                        });
+                        params.push(openai::ChatCompletionMessageParam::User {
+                            content: openai::ChatCompletionContent::String(JsonOutput::correction(
+                                &e,
+                            )),
+                        });
+                        match super::adapter::openai::encode_create_params(
+                            params,
+                            tools,
+                            ext_token.now(),
+                            model,
+                            uuid::Uuid::new_v4(),
+                            environment_info,
+                            current_config.disable_vision,
+                            current_config.enable_slow_pool,
+                        )
+                        .await
+                        {
+                            Ok(data) => data,
+                            Err(e) => {
+                                log_manager::update_log(
+                                    current_id,
+                                    LogUpdate::Failure(e.to_log_error()),
+                                )
+                                .await;
+                                state.increment_error();
+                                return Err(e.into_openai_tuple());
+                            }
+                        }
                     }
                 }
-            }
+            } else {
+                break;
+            };
+            thinking_text.clear();
+            full_text.clear();
+            tool_calls.clear();
+            response = match resend_chat_request(&state, &ext_token, use_pri, data, current_id)
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    state.increment_error();
+                    return Err(e.into_openai_tuple());
+                }
+            };
         }
+        state.record_token_health(token_key, HealthEvent::Success).await;
 
         full_text = full_text.trim_leading_newlines();
 
         // Check if Response is empty
-        if full_text.is_empty() {
+        if full_text.is_empty() && tool_calls.is_empty() {
             // Update Request log to failed
             log_manager::update_log(
                 current_id,
@@ -937,13 +1476,11 @@ pub async fn handle_chat_completions(
             .into_openai_tuple());
         }
 
-        let (chain_usage, openai_usage) = if *REAL_USAGE {
-            let usage = get_token_usage(ext_token, use_pri, request_time, model.id).await;
-            let openai = usage.map(ChainUsage::into_openai);
-            (usage, openai)
-        } else {
-            (None, None)
-        };
+        let chain_usage =
+            request_usage(ext_token, use_pri, request_time, model.id, spender.as_ref()).await;
+        let openai_usage = chain_usage.filter(|_| *REAL_USAGE).map(|usage| {
+            usage.into_openai().with_reasoning_tokens(approx_token_count(&thinking_text))
+        });
 
         let response_data = openai::ChatCompletion {
             id: &{
@@ -956,20 +1493,21 @@ pub async fn handle_chat_completions(
             object: openai::ObjectChatCompletion,
             created: DateTime::utc_now().timestamp(),
             model: Some(model.id),
-            choices: Some(openai::chat_completion::Choice {
+            choices: vec![openai::chat_completion::Choice {
                 index: 0,
-                finish_reason: if decoder.tool_processed() == 0 {
-                    openai::FinishReason::Stop
-                } else {
-                    openai::FinishReason::ToolCalls
-                },
+                finish_reason: openai_finish_reason(&decoder),
                 message: openai::ChatCompletionMessage {
                     role: openai::Assistant,
                     content: Some(full_text),
+                    reasoning_content: if thinking_text.is_empty() {
+                        None
+                    } else {
+                        Some(thinking_text)
+                    },
                     tool_calls,
                 },
                 logprobs: (),
-            }),
+            }],
             usage: openai_usage,
         };
 
@@ -1006,33 +1544,61 @@ pub async fn handle_chat_completions(
 pub async fn handle_messages(
     State(state): State<Arc<AppState>>,
     mut extensions: Extensions,
-    Json(request): Json<anthropic::MessageCreateParams>,
+    Json(mut request): Json<anthropic::MessageCreateParams>,
 ) -> Result<Response<Body>, (StatusCode, Json<AnthropicError>)> {
-    let (ext_token, use_pri) = __unwrap!(extensions.remove::<TokenBundleResult>())
+    let (mut ext_token, use_pri) = __unwrap!(extensions.remove::<TokenBundleResult>())
         .map_err(AuthError::into_anthropic_tuple)?;
+    let mut token_key = ext_token.primary_token.key();
 
     // Verify if model is supported and Get model info
     let model = if let Some(model) = ExtModel::from_str(request.model.as_str()) {
-        model
+        model.with_thinking_level(request.thinking.map(anthropic::ThinkingConfig::thinking_level))
     } else {
         return Err(ChatError::ModelNotSupported(request.model).into_anthropic_tuple());
     };
+    if !__unwrap!(extensions.get::<KeyConfig>()).allows_model(model.id) {
+        return Err(AuthError::ModelNotAllowed.into_anthropic_tuple());
+    }
     let is_stream = request.stream;
-    let (params, tools) = request.strip();
+    let (tool_choice, parallel_tool_calls) = match request.tool_choice.take() {
+        Some(tool_choice) => {
+            let parallel_tool_calls = !tool_choice.disable_parallel_tool_use();
+            (tool_choice.into(), parallel_tool_calls)
+        }
+        None => (ToolChoice::Auto, true),
+    };
+    let json_output = request.output_format.take().map(JsonOutput::from);
+    let limiter =
+        ContentLimiter::new(core::mem::take(&mut request.stop_sequences), Some(request.max_tokens));
+    let (mut params, mut tools) = request.strip();
 
     // Verify Request
     if params.0.is_empty() {
         return Err(ChatError::EmptyMessages(StatusCode::BAD_REQUEST).into_anthropic_tuple());
     }
+    let require_tool_call = tool_choice
+        .apply(&mut tools, |tool| tool.name.as_str())
+        .map_err(ChatError::into_anthropic_tuple)?;
+    if let Some(ref json_output) = json_output {
+        super::adapter::anthropic::append_instructions(
+            &mut params.1,
+            json_output.instructions(),
+            ext_token.now(),
+        );
+    }
 
     let current_config = __unwrap!(extensions.remove::<KeyConfig>());
 
     let environment_info = __unwrap!(extensions.remove::<EnvironmentInfo>());
 
+    let pool = extensions.remove::<TokenPool>();
+
     let current_id: u64;
     let mut usage_check = None;
 
     let request_time = __unwrap!(extensions.remove::<DateTime>());
+    let spender = extensions.remove::<Spender>();
+    let owner = __unwrap!(extensions.remove::<Owner>());
 
     // Update Request log
     state.increment_total();
@@ -1094,6 +1660,8 @@ pub async fn handle_messages(
                     stripe: None,
                 },
                 chain: Chain { delays: None, usage: None, think: None },
+                choices: Vec::new(),
+                attempts: Vec::new(),
                 timing: TimingInfo { total: 0.0 },
                 stream: is_stream,
                 status: LogStatus::Pending,
@@ -1152,7 +1720,7 @@ pub async fn handle_messages(
                         alias_updater = Some((id, alias.clone()));
                     }
                     token_info.user = user;
-                    token_info.usage = usage;
+                    token_info.set_usage(usage);
                     token_info.stripe = stripe;
                 };
 
@@ -1165,75 +1733,138 @@ pub async fn handle_messages(
         current_id = 0;
     }
 
-    // Convert Message to hex format
+    // Keep the params around for a corrective retry when the JSON output is rejected
+    let mut json_retry = (json_output.is_some() && !is_stream)
+        .then(|| (params.clone(), tools.clone(), environment_info.clone()));
+
     let stream = is_stream;
-    let msg_id = uuid::Uuid::new_v4();
-    let data = match super::adapter::anthropic::encode_create_params(
-        params,
-        tools,
-        ext_token.now(),
-        model,
-        msg_id,
-        environment_info,
-        current_config.disable_vision,
-        current_config.enable_slow_pool,
-    )
-    .await
+    // A tool result for a stream parked at its call is sent into that stream instead
+    let parked = if is_stream
+        && let Some((tool_call_id, name, content)) = context::anthropic_tool_result(&params.0)
+        && let Some(parked) = Registry::take(tool_call_id, model.id, owner)
+        && let Some(parked_token) = parked_on(&state, &ext_token, parked.token).await
+        && let Ok(data) = super::adapter::anthropic::encode_tool_result(
+            (content.0.cloned(), content.1),
+            tool_call_id.clone(),
+            name.clone(),
+        )
+        .await
+        && parked.session.send(data.into()).await.is_ok()
     {
-        Ok(data) => data,
-        Err(e) => {
-            log_manager::update_log(current_id, LogUpdate::Failure(e.to_log_error())).await;
-            state.decrement_active();
-            state.increment_error();
-            return Err(e.into_anthropic_tuple());
-        }
+        Some((parked, parked_token))
+    } else {
+        None
     };
-    let msg_id = MessageId::new(msg_id.as_bytes());
+    // Keep the stream open at tool calls so that their results can follow
+    let keep_open = is_stream && !tools.is_empty() && Registry::is_enabled();
 
-    // Build Request client
-    let req = build_client_request(AiServiceRequest {
-        ext_token: &ext_token,
-        fs_client_key: None,
-        url: chat_url(use_pri),
-        stream: true,
-        compressed: true,
-        trace_id: new_uuid_v4(),
-        use_pri,
-        cookie: None,
-        exact_length: Some(data.len()),
-    });
-    // crate::debug!("request: {req:?}");
-    // Send Request
-    let response = req.body(data).send().await;
-
-    // Handle Request result
-    let response = match response {
-        Ok(resp) => {
-            // Update Request log to success
-            log_manager::update_log(current_id, LogUpdate::Success).await;
-            resp
+    let msg_id = uuid::Uuid::new_v4();
+    let mut retry_data = None;
+    // Other pooled tokens to retry on when this one fails before the first byte
+    let mut failover = None;
+    let tendency = if let Some((parked, parked_token)) = parked {
+        // The turn is served by the token the stream runs on, not the one picked for it
+        if parked.token != token_key {
+            log_manager::update_log(current_id, LogUpdate::Token(parked_token.clone())).await;
+            token_key = parked.token;
         }
-        Err(e) => {
-            let e = e.without_url();
+        ext_token = parked_token;
+        log_manager::update_log(current_id, LogUpdate::Success).await;
+        Tendency::Continue(parked)
+    } else {
+        // Convert Message to hex format
+        let data = match super::adapter::anthropic::encode_create_params(
+            params,
+            tools,
+            ext_token.now(),
+            model,
+            msg_id,
+            environment_info,
+            current_config.disable_vision,
+            current_config.enable_slow_pool,
+        )
+        .await
+        {
+            Ok(data) => data,
+            Err(e) => {
+                log_manager::update_log(current_id, LogUpdate::Failure(e.to_log_error())).await;
+                state.decrement_active();
+                state.increment_error();
+                return Err(e.into_anthropic_tuple());
+            }
+        };
 
-            // Return different status codes based on Error type
-            let status_code = if e.is_timeout() {
-                StatusCode::GATEWAY_TIMEOUT
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-            crate::debug!("request: {e:?}");
-            let e = e.to_string();
+        // Keep the request around for a retry when a required tool call does not arrive
+        retry_data = (require_tool_call && !is_stream).then(|| data.clone());
+        failover =
+            Failover::new(&state, pool, &ext_token, &data, use_pri, keep_open, current_id);
+        // Send Request
+        let response = if keep_open {
+            context::start(data, &ext_token, use_pri).await.map(|(resp, sink)| (resp, Some(sink)))
+        } else {
+            let req = build_client_request(AiServiceRequest {
+                ext_token: &ext_token,
+                fs_client_key: None,
+                url: chat_url(use_pri),
+                stream: true,
+                compressed: true,
+                trace_id: new_uuid_v4(),
+                use_pri,
+                cookie: None,
+                exact_length: Some(data.len()),
+            });
+            req.body(data).send().await.map(|resp| (resp, None))
+        };
 
-            // Update Request log to failed
-            let error = Str::new(&e);
-            log_manager::update_log(current_id, LogUpdate::Failure(ErrorInfo::Simple(error))).await;
-            state.decrement_active();
-            state.increment_error();
+        // Handle Request result
+        match response {
+            Ok(resp) => {
+                // Update Request log to success
+                log_manager::update_log(current_id, LogUpdate::Success).await;
+                Tendency::Start(resp)
+            }
+            Err(e) => {
+                let e = e.without_url();
+                if let Some(event) = send_health_event(&e) {
+                    state.record_token_health(token_key, event).await;
+                }
 
-            return Err(ChatError::RequestFailed(status_code, Cow::Owned(e)).into_anthropic_tuple());
+                // Return different status codes based on Error type
+                let status_code = if e.is_timeout() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                crate::debug!("request: {e:?}");
+                let e = e.to_string();
+                let error = Str::new(&e);
+
+                if let Some(ref mut failover) = failover
+                    && let Some((next, resp, sink)) =
+                        failover.retry(ErrorInfo::Simple(error.clone())).await
+                {
+                    ext_token = next;
+                    token_key = ext_token.primary_token.key();
+                    log_manager::update_log(current_id, LogUpdate::Success).await;
+                    Tendency::Start((resp, sink))
+                } else {
+                    // Update Request log to failed
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(error)),
+                    )
+                    .await;
+                    state.decrement_active();
+                    state.increment_error();
+
+                    return Err(
+                        ChatError::RequestFailed(status_code, Cow::Owned(e)).into_anthropic_tuple()
+                    );
+                }
+            }
         }
     };
+    let msg_id = MessageId::new(msg_id.as_bytes());
 
     // Release active Request count
     state.decrement_active();
@@ -1252,7 +1883,20 @@ pub async fn handle_messages(
         });
         let index = Arc::new(AtomicU32::new(0));
         let start_time = std::time::Instant::now();
-        let decoder = Arc::new(Mutex::new(StreamDecoder::new()));
+        // Decoding starts over when the request fails over to another token
+        let restart_limiter = if failover.is_some() { limiter.clone() } else { None };
+        let (upstream, decoder) = match tendency {
+            Tendency::Start((response, sink)) => (
+                Upstream::new(Session::new(response, sink)),
+                Arc::new(Mutex::new(StreamDecoder::new().with_limiter(limiter))),
+            ),
+            Tendency::Continue(parked) => {
+                parked.decoder.lock().await.next_turn(limiter);
+                (Upstream::new(parked.session), parked.decoder)
+            }
+        };
+        // An open stream waits at each tool call, so the turn ends at the first one
+        let parallel_tool_calls = parallel_tool_calls && !upstream.is_open();
         let stream_state = Arc::new(Atomic::new(StreamState::NotStarted));
         let last_content_type = Arc::new(Atomic::new(LastContentType::None));
 
@@ -1265,6 +1909,7 @@ pub async fn handle_messages(
             stream_state: &'a Atomic<StreamState>,
             last_content_type: &'a Atomic<LastContentType>,
             current_id: u64,
+            parallel_tool_calls: bool,
         }
 
         #[inline]
@@ -1301,6 +1946,7 @@ pub async fn handle_messages(
                                     id: ctx.msg_id,
                                     model: ctx.model,
                                     stop_reason: None,
+                                    stop_sequence: None,
                                 },
                             };
                             extend_from_slice(&mut response_data, &event);
@@ -1375,6 +2021,7 @@ pub async fn handle_messages(
                                     id: ctx.msg_id,
                                     model: ctx.model,
                                     stop_reason: None,
+                                    stop_sequence: None,
                                 },
                             };
                             extend_from_slice(&mut response_data, &event);
@@ -1467,6 +2114,29 @@ pub async fn handle_messages(
                             },
                         };
                         extend_from_slice(&mut response_data, &event);
+
+                        // Each tool call gets its own block
+                        if tool_call.is_last {
+                            let event = anthropic::RawMessageStreamEvent::ContentBlockStop {
+                                index: ctx.index.fetch_add(1, Ordering::AcqRel),
+                            };
+                            extend_from_slice(&mut response_data, &event);
+                            ctx.last_content_type.store(LastContentType::None, Ordering::Release);
+
+                            // Without parallel tool use the stream ends after the first call
+                            if !ctx.parallel_tool_calls {
+                                let total_time = ctx.start_time.elapsed().as_secs_f64();
+
+                                log_manager::update_log(
+                                    ctx.current_id,
+                                    LogUpdate::Timing(total_time),
+                                )
+                                .await;
+
+                                ctx.stream_state.store(StreamState::Completed, Ordering::Release);
+                                break;
+                            }
+                        }
                     }
                     StreamMessage::StreamEnd => {
                         // Calculate total time and first chunk time
@@ -1512,7 +2182,7 @@ pub async fn handle_messages(
         }
 
         // First Handle stream until get first result
-        let (mut stream, drop_handle) = DroppableStream::new(response.bytes_stream());
+        let (mut stream, drop_handle) = DroppableStream::new(upstream.clone());
         {
             let mut decoder = decoder.lock().await;
             while !decoder.is_first_result_ready() {
@@ -1522,6 +2192,20 @@ pub async fn handle_messages(
                             decoder.decode(&chunk, convert_web_ref)
                         {
                             let canonical = error.canonical();
+                            if let Some(event) = canonical.health_event() {
+                                state.record_token_health(token_key, event).await;
+                            }
+                            if let Some(ref mut failover) = failover
+                                && let Some((next, response, sink)) =
+                                    failover.retry(canonical.to_error_info()).await
+                            {
+                                ext_token = next;
+                                token_key = ext_token.primary_token.key();
+                                upstream.replace(Session::new(response, sink));
+                                *decoder =
+                                    StreamDecoder::new().with_limiter(restart_limiter.clone());
+                                continue;
+                            }
                             // Update Request log to failed
                             log_manager::update_log(
                                 current_id,
@@ -1564,11 +2248,15 @@ pub async fn handle_messages(
                 }
             }
         }
+        state.record_token_health(token_key, HealthEvent::Success).await;
 
         let decoder_clone = decoder.clone();
+        // The upstream may say nothing more after a first result that ends at a tool call
+        let kick = upstream.is_open().then_some(Ok(Bytes::new()));
 
         // Handle subsequent stream
-        let stream = stream
+        let stream = futures_util::stream::iter(kick)
+            .chain(stream)
             .then(move |chunk| {
                 let decoder = decoder_clone.clone();
                 let msg_id = msg_id.clone();
@@ -1576,6 +2264,7 @@ pub async fn handle_messages(
                 let stream_state = stream_state.clone();
                 let last_content_type = last_content_type.clone();
                 let drop_handle = drop_handle.clone();
+                let upstream = upstream.clone();
 
                 async move {
                     let chunk = match chunk {
@@ -1594,10 +2283,17 @@ pub async fn handle_messages(
                         stream_state: &stream_state,
                         last_content_type: &last_content_type,
                         current_id,
+                        parallel_tool_calls,
                     };
 
+                    // An empty chunk only flushes the first result, see `kick`
+                    let decoded = if chunk.is_empty() {
+                        Ok(Vec::new())
+                    } else {
+                        decoder.lock().await.decode(&chunk, convert_web_ref)
+                    };
                     // UsedecoderHandlechunk
-                    let messages = match decoder.lock().await.decode(&chunk, convert_web_ref) {
+                    let messages = match decoded {
                         Ok(msgs) => msgs,
                         Err(e) => {
                             match e {
@@ -1624,7 +2320,14 @@ pub async fn handle_messages(
 
                     let mut first_response = None;
 
-                    if let Some(first_msg) = decoder.lock().await.take_first_result() {
+                    let first_msg = decoder.lock().await.take_first_result();
+                    // The tool call an open stream now waits at
+                    let tool_call_id = if upstream.is_open() {
+                        context::finished_tool_call(first_msg.iter().flatten().chain(&messages))
+                    } else {
+                        None
+                    };
+                    if let Some(first_msg) = first_msg {
                         first_response = Some(process_messages(first_msg, &ctx).await);
                     }
 
@@ -1638,6 +2341,12 @@ pub async fn handle_messages(
 
                     // Check if completed
                     if ctx.stream_state.load(Ordering::Acquire) == StreamState::Completed {
+                        if let Some(tool_call_id) = tool_call_id
+                            && let Some(session) = upstream.take()
+                        {
+                            let parking = Parking { owner, token: token_key, model: model.id };
+                            Registry::park(tool_call_id, session, decoder.clone(), parking);
+                        }
                         drop_handle.drop_stream()
                     }
 
@@ -1645,37 +2354,66 @@ pub async fn handle_messages(
                 }
             })
             .chain(futures_util::stream::once(async move {
+                // Keeps the slot of the token the request failed over to until the stream ends
+                let _failover = failover;
                 // Update delays
                 let mut decoder_guard = decoder.lock().await;
                 let content_delays = decoder_guard.take_content_delays();
                 let thinking_content = decoder_guard.take_thinking_content();
+                let json_error = json_output
+                    .as_ref()
+                    .filter(|_| decoder_guard.tool_processed() == 0)
+                    .and_then(|json_output| {
+                        json_output
+                            .extract(content_delays.as_ref().map_or("", |(text, _)| text.as_str()))
+                            .err()
+                    });
 
                 log_manager::update_log(current_id, LogUpdate::Delays(content_delays, thinking_content))
                     .await;
 
                 // Handle usage statistics
-                let usage = if *REAL_USAGE {
-                    let usage =
-                        get_token_usage(ext_token, use_pri, request_time, model.id).await;
-                    if let Some(usage) = usage {
-                        log_manager::update_log(current_id, LogUpdate::Usage(usage))
-                            .await;
-                    }
-                    usage.map(ChainUsage::into_anthropic_delta)
-                } else {
-                    None
-                };
+                let usage =
+                    request_usage(ext_token, use_pri, request_time, model.id, spender.as_ref())
+                        .await;
+                if let Some(usage) = usage {
+                    log_manager::update_log(current_id, LogUpdate::Usage(usage)).await;
+                }
+                let usage = usage.filter(|_| *REAL_USAGE).map(ChainUsage::into_anthropic_delta);
 
                 let mut response_data = Vec::with_capacity(128);
 
-                extend_from_slice(&mut response_data, &anthropic::RawMessageStreamEvent::MessageDelta {
-                    delta: anthropic::MessageDelta {
-                        stop_reason: if decoder_guard.tool_processed() == 0 {
-                            anthropic::StopReason::EndTurn
-                        } else {
-                            anthropic::StopReason::ToolUse
+                if require_tool_call && decoder_guard.tool_processed() == 0 {
+                    let error = ChatError::ToolCallMissing;
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                    )
+                    .await;
+                    extend_from_slice(&mut response_data, &anthropic::RawMessageStreamEvent::Error {
+                        error: anthropic::AnthropicErrorInner {
+                            r#type: error.error_type(),
+                            message: Cow::Owned(error.to_string()),
                         },
-                    },
+                    });
+                } else if let Some(e) = json_error {
+                    let error = ChatError::InvalidJsonOutput(e);
+                    log_manager::update_log(
+                        current_id,
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                    )
+                    .await;
+                    extend_from_slice(&mut response_data, &anthropic::RawMessageStreamEvent::Error {
+                        error: anthropic::AnthropicErrorInner {
+                            r#type: error.error_type(),
+                            message: Cow::Owned(error.to_string()),
+                        },
+                    });
+                }
+
+                let (stop_reason, stop_sequence) = anthropic_stop_reason(&decoder_guard);
+                extend_from_slice(&mut response_data, &anthropic::RawMessageStreamEvent::MessageDelta {
+                    delta: anthropic::MessageDelta { stop_reason, stop_sequence },
                     usage: usage.unwrap_or_default(),
                 });
                 response_data.extend_from_slice(b"event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n");
@@ -1698,142 +2436,250 @@ pub async fn handle_messages(
     } else {
         // Non-streaming Response
         let start_time = std::time::Instant::now();
-        let mut decoder = StreamDecoder::new().no_first_cache();
+        let mut decoder;
         let mut content = Vec::with_capacity(16);
-        let mut stream = response.bytes_stream();
+        let mut input_json = String::with_capacity(64);
+        let Tendency::Start((mut response, _)) = tendency else { __unreachable!() };
+        let mut retry_data = retry_data;
         // let mut prompt = Prompt::None;
 
-        // Handle chunks one by one
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| {
-                ChatError::RequestFailed(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Cow::Owned(format!("Failed to read response chunk: {e}")),
-                )
-                .into_anthropic_tuple()
-            })?;
-
-            // Immediately Handle current chunk
-            match decoder.decode(&chunk, convert_web_ref) {
-                Ok(messages) => {
-                    let mut input_json = String::with_capacity(64);
-                    for message in messages {
-                        match message {
-                            StreamMessage::Thinking(thinking) => match thinking {
-                                Thinking::Text(text) => {
-                                    if let Some(anthropic::ContentBlock::Thinking {
-                                        thinking,
-                                        ..
-                                    }) = content.last_mut()
-                                    {
-                                        thinking.reserve_exact(text.len() * 2);
-                                        thinking.push_str(&text);
-                                    } else {
-                                        content.push(anthropic::ContentBlock::Thinking {
-                                            thinking: text,
-                                            signature: None,
+        'request: loop {
+            decoder = StreamDecoder::new().no_first_cache().with_limiter(limiter.clone());
+            let mut stream = response.bytes_stream();
+
+            // Handle chunks one by one
+            'decode: while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| {
+                    ChatError::RequestFailed(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Cow::Owned(format!("Failed to read response chunk: {e}")),
+                    )
+                    .into_anthropic_tuple()
+                })?;
+
+                // Immediately Handle current chunk
+                match decoder.decode(&chunk, convert_web_ref) {
+                    Ok(messages) => {
+                        for message in messages {
+                            match message {
+                                StreamMessage::Thinking(thinking) => match thinking {
+                                    Thinking::Text(text) => {
+                                        if let Some(anthropic::ContentBlock::Thinking {
+                                            thinking,
+                                            ..
+                                        }) = content.last_mut()
+                                        {
+                                            thinking.reserve_exact(text.len() * 2);
+                                            thinking.push_str(&text);
+                                        } else {
+                                            content.push(anthropic::ContentBlock::Thinking {
+                                                thinking: text,
+                                                signature: None,
+                                            });
+                                        }
+                                    }
+                                    Thinking::Signature(signature) => {
+                                        if let Some(anthropic::ContentBlock::Thinking {
+                                            signature: signature_ref,
+                                            ..
+                                        }) = content.last_mut()
+                                        {
+                                            *signature_ref = Some(signature);
+                                        } else {
+                                            crate::debug!("up!3 {signature:?}");
+                                            content.push(anthropic::ContentBlock::Thinking {
+                                                thinking: String::new(),
+                                                signature: Some(signature),
+                                            });
+                                        }
+                                    }
+                                    Thinking::RedactedThinking(redacted_thinking) => {
+                                        content.push(anthropic::ContentBlock::RedactedThinking {
+                                            data: redacted_thinking,
                                         });
                                     }
-                                }
-                                Thinking::Signature(signature) => {
-                                    if let Some(anthropic::ContentBlock::Thinking {
-                                        signature: signature_ref,
-                                        ..
-                                    }) = content.last_mut()
+                                },
+                                StreamMessage::Content(atext) => {
+                                    if let Some(anthropic::ContentBlock::Text { text }) =
+                                        content.last_mut()
                                     {
-                                        *signature_ref = Some(signature);
+                                        text.reserve_exact(atext.len() * 2);
+                                        text.push_str(&atext);
                                     } else {
-                                        crate::debug!("up!3 {signature:?}");
-                                        content.push(anthropic::ContentBlock::Thinking {
-                                            thinking: String::new(),
-                                            signature: Some(signature),
-                                        });
+                                        let mut text = atext;
+                                        text.reserve_exact(text.len());
+                                        content.push(anthropic::ContentBlock::Text { text });
                                     }
                                 }
-                                Thinking::RedactedThinking(redacted_thinking) => {
-                                    content.push(anthropic::ContentBlock::RedactedThinking {
-                                        data: redacted_thinking,
-                                    });
-                                }
-                            },
-                            StreamMessage::Content(atext) => {
-                                if let Some(anthropic::ContentBlock::Text { text }) =
-                                    content.last_mut()
-                                {
-                                    text.reserve_exact(atext.len() * 2);
-                                    text.push_str(&atext);
-                                } else {
-                                    let mut text = atext;
-                                    text.reserve_exact(text.len());
-                                    content.push(anthropic::ContentBlock::Text { text });
-                                }
-                            }
-                            StreamMessage::ToolCall(tool_call) => {
-                                input_json.push_str(&tool_call.input);
-                                if tool_call.is_last
-                                    && let Ok(input) = serde_json::from_str(&input_json)
-                                {
-                                    content.push(anthropic::ContentBlock::ToolUse {
-                                        id: tool_call.id,
-                                        name: tool_call.name,
-                                        input,
-                                    });
-                                    input_json.clear();
+                                StreamMessage::ToolCall(tool_call) => {
+                                    input_json.push_str(&tool_call.input);
+                                    if tool_call.is_last {
+                                        if let Ok(input) = serde_json::from_str(&input_json) {
+                                            content.push(anthropic::ContentBlock::ToolUse {
+                                                id: tool_call.id,
+                                                name: tool_call.name,
+                                                input,
+                                            });
+                                        }
+                                        input_json.clear();
+                                        if !parallel_tool_calls {
+                                            break 'decode;
+                                        }
+                                    }
                                 }
+                                StreamMessage::StreamEnd => break 'decode,
+                                // StreamMessage::Debug(debug_prompt) => {
+                                //     if prompt.is_none() {
+                                //         prompt = Prompt::new(debug_prompt);
+                                //     } else {
+                                //         __cold_path!();
+                                //         crate::debug!("UB!2 {debug_prompt:?}");
+                                //     }
+                                // }
+                                _ => {}
                             }
-                            // StreamMessage::Debug(debug_prompt) => {
-                            //     if prompt.is_none() {
-                            //         prompt = Prompt::new(debug_prompt);
-                            //     } else {
-                            //         __cold_path!();
-                            //         crate::debug!("UB!2 {debug_prompt:?}");
-                            //     }
-                            // }
-                            _ => {}
+                        }
+                    }
+                    Err(StreamError::Upstream(error)) => {
+                        let canonical = error.canonical();
+                        if let Some(event) = canonical.health_event() {
+                            state.record_token_health(token_key, event).await;
+                        }
+                        if !decoder.has_seen_content()
+                            && let Some(ref mut failover) = failover
+                            && let Some((next, resp, _)) =
+                                failover.retry(canonical.to_error_info()).await
+                        {
+                            ext_token = next;
+                            token_key = ext_token.primary_token.key();
+                            response = resp;
+                            continue 'request;
+                        }
+                        log_manager::update_log(
+                            current_id,
+                            LogUpdate::Failure(canonical.to_error_info()),
+                        )
+                        .await;
+                        state.increment_error();
+                        return Err((
+                            canonical.status_code(),
+                            Json(canonical.into_anthropic().wrapped()),
+                        ));
+                    }
+                    Err(StreamError::EmptyStream) => {
+                        let empty_stream_count = decoder.get_empty_stream_count();
+                        if empty_stream_count > 1 {
+                            eprintln!(
+                                "[Warning] Stream error: empty stream (continuous count: {})",
+                                decoder.get_empty_stream_count()
+                            );
                         }
                     }
                 }
-                Err(StreamError::Upstream(error)) => {
-                    let canonical = error.canonical();
+            }
+
+            let data = if require_tool_call && decoder.tool_processed() == 0 {
+                // The required tool call did not arrive, retry once before giving up
+                let Some(data) = retry_data.take() else {
+                    let error = ChatError::ToolCallMissing;
                     log_manager::update_log(
                         current_id,
-                        LogUpdate::Failure(canonical.to_error_info()),
+                        LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
                     )
                     .await;
                     state.increment_error();
-                    return Err((
-                        canonical.status_code(),
-                        Json(canonical.into_anthropic().wrapped()),
-                    ));
-                }
-                Err(StreamError::EmptyStream) => {
-                    let empty_stream_count = decoder.get_empty_stream_count();
-                    if empty_stream_count > 1 {
-                        eprintln!(
-                            "[Warning] Stream error: empty stream (continuous count: {})",
-                            decoder.get_empty_stream_count()
-                        );
+                    return Err(error.into_anthropic_tuple());
+                };
+                data
+            } else if let Some(ref json_output) = json_output
+                && decoder.tool_processed() == 0
+            {
+                let text = content.iter_mut().rev().find_map(|block| match block {
+                    anthropic::ContentBlock::Text { text } => Some(text),
+                    _ => None,
+                });
+                match json_output.extract(text.as_ref().map_or("", |text| text.as_str())) {
+                    Ok(json) => {
+                        let json = json.to_owned();
+                        if let Some(text) = text {
+                            *text = json;
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        // Ask once for a corrected reply before giving up
+                        let Some(((mut messages, system), tools, environment_info)) =
+                            json_retry.take()
+                        else {
+                            let error = ChatError::InvalidJsonOutput(e);
+                            log_manager::update_log(
+                                current_id,
+                                LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+                            )
+                            .await;
+                            state.increment_error();
+                            return Err(error.into_anthropic_tuple());
+                        };
+                        messages.push(anthropic::MessageParam {
+                            role: Role::Assistant,
+                            content: anthropic::MessageContent::String(
+                                text.map(core::mem::take).unwrap_or_default(),
+                            ),
+                        });
+                        messages.push(anthropic::MessageParam {
+                            role: Role::User,
+                            content: anthropic::MessageContent::String(JsonOutput::correction(&e)),
+                        });
+                        match super::adapter::anthropic::encode_create_params(
+                            (messages, system),
+                            tools,
+                            ext_token.now(),
+                            model,
+                            uuid::Uuid::new_v4(),
+                            environment_info,
+                            current_config.disable_vision,
+                            current_config.enable_slow_pool,
+                        )
+                        .await
+                        {
+                            Ok(data) => data,
+                            Err(e) => {
+                                log_manager::update_log(
+                                    current_id,
+                                    LogUpdate::Failure(e.to_log_error()),
+                                )
+                                .await;
+                                state.increment_error();
+                                return Err(e.into_anthropic_tuple());
+                            }
+                        }
                     }
                 }
-            }
+            } else {
+                break;
+            };
+            content.clear();
+            input_json.clear();
+            response = match resend_chat_request(&state, &ext_token, use_pri, data, current_id)
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    state.increment_error();
+                    return Err(e.into_anthropic_tuple());
+                }
+            };
         }
+        state.record_token_health(token_key, HealthEvent::Success).await;
 
-        drop(stream);
-
-        let (chain_usage, anthropic_usage) = if *REAL_USAGE {
-            let usage = get_token_usage(ext_token, use_pri, request_time, model.id).await;
-            let anthropic = usage.map(ChainUsage::into_anthropic);
-            (usage, anthropic)
-        } else {
-            (None, None)
-        };
+        let chain_usage =
+            request_usage(ext_token, use_pri, request_time, model.id, spender.as_ref()).await;
+        let anthropic_usage = chain_usage.filter(|_| *REAL_USAGE).map(ChainUsage::into_anthropic);
 
+        let (stop_reason, stop_sequence) = anthropic_stop_reason(&decoder);
         let response_data = anthropic::Message {
-            stop_reason: Some(if decoder.tool_processed() == 0 {
-                anthropic::StopReason::EndTurn
-            } else {
-                anthropic::StopReason::ToolUse
-            }),
+            stop_reason: Some(stop_reason),
+            stop_sequence,
             content,
             usage: anthropic_usage.unwrap_or_default(),
             id: &{
@@ -1889,6 +2735,9 @@ pub async fn handle_messages_count_tokens(
     } else {
         return Err(ChatError::ModelNotSupported(request.model).into_anthropic_tuple());
     };
+    if !__unwrap!(extensions.get::<KeyConfig>()).allows_model(model.id) {
+        return Err(AuthError::ModelNotAllowed.into_anthropic_tuple());
+    }
     let (params, tools) = request.strip();
 
     // Verify Request
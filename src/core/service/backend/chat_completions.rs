@@ -183,7 +183,7 @@ impl ProtocolHandler for ChatCompletions {
                             alias_updater = Some((id, alias.clone()));
                         }
                         token_info.user = user;
-                        token_info.usage = usage;
+                        token_info.set_usage(usage);
                         token_info.stripe = stripe;
                     };
 
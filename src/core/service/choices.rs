@@ -0,0 +1,1034 @@
+//! `n > 1` for chat completions
+//!
+//! Every choice is an independent upstream request. The requests are sent concurrently,
+//! optionally on different tokens of the pool, and their outputs are merged into one
+//! response. The request keeps a single log entry with the summed usage and one chain per
+//! choice.
+
+use super::{openai_finish_reason, wants_usage};
+use crate::{
+    app::{
+        constant::{
+            CHATCMPL_PREFIX, ERR_RESPONSE_RECEIVED, ERR_STREAM_RESPONSE,
+            header::{CHUNKED, EVENT_STREAM, JSON, KEEP_ALIVE, NO_CACHE_REVALIDATE},
+        },
+        lazy::REAL_USAGE,
+        model::{
+            AppConfig, AppState, Budgets, Chain, ChainUsage, DateTime, ErrorInfo, ExtToken,
+            HealthEvent, InFlight, LogUpdate, Spender, TokenKey, log_manager,
+        },
+    },
+    common::{
+        model::{error::ChatError, tri::Tri},
+        utils::{TrimNewlines as _, approx_token_count, get_token_usages},
+    },
+    core::{
+        aiserver::v1::EnvironmentInfo,
+        auth::{AuthError, TokenPool},
+        config::KeyConfig,
+        error::{ErrorExt as _, StreamError},
+        model::{
+            ExtModel, JsonOutput, MessageId, Role,
+            openai::{self, OpenAiError},
+        },
+        stream::{
+            decoder::{StreamDecoder, StreamMessage, Thinking},
+            limiter::ContentLimiter,
+        },
+    },
+};
+use alloc::{borrow::Cow, sync::Arc};
+use axum::{Json, body::Body, response::Response};
+use byte_str::ByteStr;
+use bytes::Bytes;
+use core::convert::Infallible;
+use futures_util::{StreamExt as _, stream::BoxStream};
+use http::{
+    StatusCode,
+    header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+};
+use interned::Str;
+
+type Failure = (StatusCode, Json<OpenAiError>);
+
+/// A chat completion with `n > 1`, prepared by `handle_chat_completions`
+pub(super) struct Fanout {
+    pub state: Arc<AppState>,
+    pub ext_token: ExtToken,
+    pub use_pri: bool,
+    pub pool: Option<TokenPool>,
+    pub n: u32,
+    pub model: ExtModel,
+    pub params: Vec<openai::ChatCompletionMessageParam>,
+    pub tools: Vec<openai::ChatCompletionTool>,
+    pub environment_info: EnvironmentInfo,
+    pub config: KeyConfig,
+    pub current_id: u64,
+    pub request_time: DateTime,
+    pub is_stream: bool,
+    pub include_usage: bool,
+    pub limiter: Option<ContentLimiter>,
+    pub json_output: Option<JsonOutput>,
+    pub require_tool_call: bool,
+    pub parallel_tool_calls: bool,
+    pub spender: Option<Spender>,
+}
+
+impl Fanout {
+    pub async fn handle<F>(self, usage_check: Option<F>) -> Result<Response<Body>, Failure>
+    where F: Future<Output = ()> + Send + 'static {
+        let (tokens, in_flight) = match self.select_tokens().await {
+            Ok(selected) => selected,
+            Err(e) => {
+                self.state.decrement_active();
+                let error = ErrorInfo::Simple(Str::from_static(e.error_type()));
+                return Err(self.fail(error, e.into_openai_tuple()).await);
+            }
+        };
+        let msg_ids: Vec<uuid::Uuid> = tokens.iter().map(|_| uuid::Uuid::new_v4()).collect();
+
+        // Send all requests before reading any of them
+        let responses = futures_util::future::try_join_all(
+            tokens.iter().zip(&msg_ids).map(|(ext_token, &msg_id)| self.send(ext_token, msg_id)),
+        )
+        .await;
+        self.state.decrement_active();
+        let responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => {
+                self.state.increment_error();
+                return Err(e);
+            }
+        };
+        log_manager::update_log(self.current_id, LogUpdate::Success).await;
+
+        let response_id = {
+            let mut buf = [0; 22];
+            let mut s = String::with_capacity(31);
+            s.push_str(CHATCMPL_PREFIX);
+            s.push_str(MessageId::new(msg_ids[0].as_bytes()).to_str(&mut buf));
+            s
+        };
+
+        if self.is_stream {
+            self.stream(tokens, in_flight, responses, response_id, usage_check).await
+        } else {
+            self.collect(tokens, responses, response_id, usage_check).await
+        }
+    }
+
+    /// Token of each choice, extra choices of a pooled key take the next tokens of its queue
+    /// when spreading is enabled, along with the slots taken on those
+    ///
+    /// Choices that find no token with a free slot take another slot on the token of the
+    /// request, like all choices do without spreading, so that every choice counts against the
+    /// limits of its token. The request fails when that token is at one of them too
+    async fn select_tokens(&self) -> Result<(Vec<ExtToken>, Vec<InFlight>), AuthError> {
+        let n = self.n as usize;
+        let mut tokens = Vec::with_capacity(n);
+        let mut in_flight = Vec::with_capacity(n - 1);
+        tokens.push(self.ext_token.clone());
+        let token_manager = self.state.token_manager_read().await;
+        let key = self.ext_token.primary_token.key();
+        // Tokens outside the manager have no limits to count against
+        let own = token_manager.id_map().get(&key).and_then(|&id| token_manager.get_by_id(id));
+        let spread = self.pool.as_ref().filter(|_| AppConfig::is_choice_token_spread_enabled());
+        let mut spread_keys = vec![key];
+        while tokens.len() < n {
+            // Tokens that already have a choice are reused only once the pool runs out
+            if let Some(pool) = spread
+                && let Some((ext_token, slot)) = token_manager
+                    .select_except(pool.queue, pool.group(), &spread_keys)
+                    .or_else(|| token_manager.select(pool.queue, pool.group()))
+            {
+                in_flight.push(slot);
+                spread_keys.push(ext_token.primary_token.key());
+                tokens.push(ext_token);
+                continue;
+            }
+            if let Some(token) = own {
+                in_flight.push(
+                    token.load.try_acquire(&token.policy).ok_or(AuthError::NoAvailableTokens)?,
+                );
+            }
+            tokens.push(self.ext_token.clone());
+        }
+        Ok((tokens, in_flight))
+    }
+
+    async fn send(
+        &self,
+        ext_token: &ExtToken,
+        msg_id: uuid::Uuid,
+    ) -> Result<reqwest::Response, Failure> {
+        let data = match super::super::adapter::openai::encode_create_params(
+            self.params.clone(),
+            self.tools.clone(),
+            ext_token.now(),
+            self.model,
+            msg_id,
+            self.environment_info.clone(),
+            self.config.disable_vision,
+            self.config.enable_slow_pool,
+        )
+        .await
+        {
+            Ok(data) => data,
+            Err(e) => {
+                log_manager::update_log(self.current_id, LogUpdate::Failure(e.to_log_error()))
+                    .await;
+                return Err(e.into_openai_tuple());
+            }
+        };
+        super::resend_chat_request(&self.state, ext_token, self.use_pri, data, self.current_id)
+            .await
+            .map_err(|e| e.into_openai_tuple())
+    }
+
+    async fn fail(&self, error: ErrorInfo, failure: Failure) -> Failure {
+        log_manager::update_log(self.current_id, LogUpdate::Failure(error)).await;
+        self.state.increment_error();
+        failure
+    }
+
+    async fn collect<F>(
+        self,
+        tokens: Vec<ExtToken>,
+        responses: Vec<reqwest::Response>,
+        response_id: String,
+        usage_check: Option<F>,
+    ) -> Result<Response<Body>, Failure>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let start_time = std::time::Instant::now();
+        let convert_web_ref = self.config.include_web_references;
+
+        let outputs = futures_util::future::try_join_all(responses.into_iter().zip(&tokens).map(
+            |(response, ext_token)| {
+                collect_choice(
+                    &self.state,
+                    ext_token.primary_token.key(),
+                    response,
+                    StreamDecoder::new().no_first_cache().with_limiter(self.limiter.clone()),
+                    convert_web_ref,
+                    self.parallel_tool_calls,
+                )
+            },
+        ))
+        .await;
+        let outputs = match outputs {
+            Ok(outputs) => outputs,
+            Err((error, failure)) => return Err(self.fail(error, failure).await),
+        };
+
+        let mut choices = Vec::with_capacity(outputs.len());
+        let mut chains = Vec::with_capacity(outputs.len());
+        let mut reasoning_tokens = 0;
+        for (index, mut output) in (0..).zip(outputs) {
+            let mut text = output.text.trim_leading_newlines();
+            let error = if self.require_tool_call && output.decoder.tool_processed() == 0 {
+                Some(ChatError::ToolCallMissing)
+            } else if let Some(ref json_output) = self.json_output
+                && output.tool_calls.is_empty()
+            {
+                match json_output.extract(&text) {
+                    Ok(json) => {
+                        text = json.to_owned();
+                        None
+                    }
+                    Err(e) => Some(ChatError::InvalidJsonOutput(e)),
+                }
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                let info = ErrorInfo::Simple(Str::new(&error.to_string()));
+                return Err(self.fail(info, error.into_openai_tuple()).await);
+            }
+            if text.is_empty() && output.tool_calls.is_empty() {
+                let info = ErrorInfo::Simple(Str::from_static(ERR_RESPONSE_RECEIVED));
+                let failure = ChatError::RequestFailed(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Cow::Borrowed(ERR_RESPONSE_RECEIVED),
+                )
+                .into_openai_tuple();
+                return Err(self.fail(info, failure).await);
+            }
+
+            reasoning_tokens += approx_token_count(&output.thinking);
+            choices.push(openai::chat_completion::Choice {
+                index,
+                finish_reason: openai_finish_reason(&output.decoder),
+                message: openai::ChatCompletionMessage {
+                    role: openai::Assistant,
+                    content: Some(text),
+                    reasoning_content: if output.thinking.is_empty() {
+                        None
+                    } else {
+                        Some(output.thinking)
+                    },
+                    tool_calls: output.tool_calls,
+                },
+                logprobs: (),
+            });
+            chains.push(Chain {
+                delays: output.decoder.take_content_delays(),
+                usage: None,
+                think: output.decoder.take_thinking_content(),
+            });
+        }
+
+        let usage = sum_usage(
+            tokens,
+            self.use_pri,
+            self.request_time,
+            self.model.id,
+            self.spender.as_ref(),
+        )
+        .await;
+
+        let response_data = openai::ChatCompletion {
+            id: &response_id,
+            object: openai::ObjectChatCompletion,
+            created: DateTime::utc_now().timestamp(),
+            model: Some(self.model.id),
+            choices,
+            usage: usage
+                .filter(|_| *REAL_USAGE)
+                .map(|usage| usage.into_openai().with_reasoning_tokens(reasoning_tokens)),
+        };
+
+        let total_time = start_time.elapsed().as_secs_f64();
+        log_manager::update_log(
+            self.current_id,
+            LogUpdate::TimingChain(total_time, Chain { delays: None, usage, think: None }),
+        )
+        .await;
+        log_manager::update_log(self.current_id, LogUpdate::Choices(chains)).await;
+
+        if let Some(usage_check) = usage_check {
+            tokio::spawn(usage_check);
+        }
+
+        let data = __unwrap!(serde_json::to_vec(&response_data));
+        Ok(__unwrap!(
+            Response::builder()
+                .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                .header(CONNECTION, KEEP_ALIVE)
+                .header(CONTENT_TYPE, JSON)
+                .header(CONTENT_LENGTH, data.len())
+                .body(Body::from(data))
+        ))
+    }
+
+    async fn stream<F>(
+        self,
+        tokens: Vec<ExtToken>,
+        in_flight: Vec<InFlight>,
+        responses: Vec<reqwest::Response>,
+        response_id: String,
+        usage_check: Option<F>,
+    ) -> Result<Response<Body>, Failure>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let start_time = std::time::Instant::now();
+        let convert_web_ref = self.config.include_web_references;
+
+        // Wait for the first result of every choice, so that errors still get a status code
+        let mut choices = Vec::with_capacity(responses.len());
+        for ((index, response), ext_token) in (0..).zip(responses).zip(&tokens) {
+            let token_key = ext_token.primary_token.key();
+            let mut stream = response.bytes_stream().boxed();
+            let mut decoder = StreamDecoder::new().with_limiter(self.limiter.clone());
+            while !decoder.is_first_result_ready() {
+                match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Err(StreamError::Upstream(error)) =
+                            decoder.decode(&chunk, convert_web_ref)
+                        {
+                            let canonical = error.canonical();
+                            if let Some(event) = canonical.health_event() {
+                                self.state.record_token_health(token_key, event).await;
+                            }
+                            log_manager::update_log(
+                                self.current_id,
+                                LogUpdate::Failure2(
+                                    canonical.to_error_info(),
+                                    start_time.elapsed().as_secs_f64(),
+                                ),
+                            )
+                            .await;
+                            self.state.increment_error();
+                            return Err((
+                                canonical.status_code(),
+                                Json(canonical.into_openai().wrapped()),
+                            ));
+                        }
+                    }
+                    Some(Err(e)) => {
+                        return Err(ChatError::RequestFailed(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Cow::Owned(format!("Failed to read response chunk: {e}")),
+                        )
+                        .into_openai_tuple());
+                    }
+                    None => {
+                        let info = ErrorInfo::Simple(Str::from_static(ERR_STREAM_RESPONSE));
+                        let failure = ChatError::RequestFailed(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Cow::Borrowed(ERR_STREAM_RESPONSE),
+                        )
+                        .into_openai_tuple();
+                        return Err(self.fail(info, failure).await);
+                    }
+                }
+            }
+            self.state.record_token_health(token_key, HealthEvent::Success).await;
+            choices.push(ChoiceStream {
+                index,
+                stream,
+                decoder,
+                started: false,
+                last: LastContent::None,
+                tool_index: 0,
+                tool_id: None,
+            });
+        }
+
+        let ctx = Arc::new(StreamContext {
+            response_id,
+            model: self.model.id,
+            created: DateTime::utc_now().timestamp(),
+            include_usage: self.include_usage,
+            convert_web_ref,
+            parallel_tool_calls: self.parallel_tool_calls,
+            require_tool_call: self.require_tool_call,
+            json_output: self.json_output,
+        });
+        let finished = (0..choices.len()).map(|_| None).collect();
+        let merged = futures_util::stream::select_all(
+            choices.into_iter().map(|choice| choice.into_stream(ctx.clone()).boxed()),
+        );
+
+        let state = MergeState {
+            merged,
+            finished,
+            ctx,
+            tokens,
+            _in_flight: in_flight,
+            use_pri: self.use_pri,
+            request_time: self.request_time,
+            current_id: self.current_id,
+            spender: self.spender,
+            start_time,
+            usage_check,
+        };
+        let stream = futures_util::stream::unfold(Some(state), |state| async move {
+            let mut state = state?;
+            match state.merged.next().await {
+                Some(event) => {
+                    if let Some(finished) = event.finished {
+                        state.finished[event.index as usize] = Some(finished);
+                    }
+                    Some((Ok::<_, Infallible>(Bytes::from(event.data)), Some(state)))
+                }
+                None => Some((Ok(Bytes::from(state.finish().await)), None)),
+            }
+        });
+
+        Ok(__unwrap!(
+            Response::builder()
+                .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                .header(CONNECTION, KEEP_ALIVE)
+                .header(CONTENT_TYPE, EVENT_STREAM)
+                .header(TRANSFER_ENCODING, CHUNKED)
+                .body(Body::from_stream(stream))
+        ))
+    }
+}
+
+/// Output of one non-streaming choice
+struct ChoiceOutput {
+    decoder: StreamDecoder,
+    text: String,
+    thinking: String,
+    tool_calls: Vec<openai::ChatCompletionMessageToolCall>,
+}
+
+async fn collect_choice(
+    state: &AppState,
+    token_key: TokenKey,
+    response: reqwest::Response,
+    mut decoder: StreamDecoder,
+    convert_web_ref: bool,
+    parallel_tool_calls: bool,
+) -> Result<ChoiceOutput, (ErrorInfo, Failure)> {
+    let mut text = String::with_capacity(128);
+    let mut thinking = String::new();
+    let mut tool_calls = Vec::new();
+    let mut stream = response.bytes_stream();
+
+    'decode: while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let error = ChatError::RequestFailed(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Cow::Owned(format!("Failed to read response chunk: {e}")),
+                );
+                return Err((
+                    ErrorInfo::Simple(Str::new(&error.to_string())),
+                    error.into_openai_tuple(),
+                ));
+            }
+        };
+        match decoder.decode(&chunk, convert_web_ref) {
+            Ok(messages) => {
+                for message in messages {
+                    match message {
+                        StreamMessage::Content(s) => text.push_str(&s),
+                        StreamMessage::Thinking(Thinking::Text(s)) => thinking.push_str(&s),
+                        StreamMessage::ToolCall(tool_call) => {
+                            // Argument deltas of one call share its id
+                            if let Some(openai::ChatCompletionMessageToolCall::Function {
+                                id,
+                                function,
+                            }) = tool_calls.last_mut()
+                                && id[..] == tool_call.id[..]
+                            {
+                                function.arguments.push_str(&tool_call.input);
+                            } else {
+                                tool_calls.push(openai::ChatCompletionMessageToolCall::Function {
+                                    id: tool_call.id,
+                                    function: openai::chat_completion_message_tool_call::Function {
+                                        arguments: tool_call.input,
+                                        name: tool_call.name,
+                                    },
+                                })
+                            }
+                            if tool_call.is_last && !parallel_tool_calls {
+                                break 'decode;
+                            }
+                        }
+                        StreamMessage::StreamEnd => break 'decode,
+                        _ => {}
+                    }
+                }
+            }
+            Err(StreamError::Upstream(error)) => {
+                let canonical = error.canonical();
+                if let Some(event) = canonical.health_event() {
+                    state.record_token_health(token_key, event).await;
+                }
+                return Err((
+                    canonical.to_error_info(),
+                    (canonical.status_code(), Json(canonical.into_openai().wrapped())),
+                ));
+            }
+            Err(StreamError::EmptyStream) => {}
+        }
+    }
+
+    state.record_token_health(token_key, HealthEvent::Success).await;
+    Ok(ChoiceOutput { decoder, text, thinking, tool_calls })
+}
+
+/// Sums the usage of all choices, polled once per distinct token, and charges it to the budget
+/// of `spender`
+async fn sum_usage(
+    tokens: Vec<ExtToken>,
+    use_pri: bool,
+    request_time: DateTime,
+    model_id: &'static str,
+    spender: Option<&Spender>,
+) -> Option<ChainUsage> {
+    if !wants_usage(spender) {
+        return None;
+    }
+    let counts = requests_per_token(tokens, |ext_token| ext_token.primary_token.key());
+    let usage = futures_util::future::join_all(counts.into_iter().map(|(ext_token, count)| {
+        get_token_usages(ext_token, use_pri, request_time, model_id, count)
+    }))
+    .await
+    .into_iter()
+    .flatten()
+    .reduce(|a, b| a + b)?;
+    if let Some(spender) = spender {
+        Budgets::charge(spender, usage.cents);
+    }
+    Some(usage)
+}
+
+/// Distinct tokens by `key` in order of first use, along with how many choices ran on each
+fn requests_per_token<T, K: PartialEq>(tokens: Vec<T>, key: impl Fn(&T) -> K) -> Vec<(T, usize)> {
+    let mut counts: Vec<(T, usize)> = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let k = key(&token);
+        match counts.iter_mut().find(|(t, _)| key(t) == k) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((token, 1)),
+        }
+    }
+    counts
+}
+
+struct StreamContext {
+    response_id: String,
+    model: &'static str,
+    created: i64,
+    include_usage: bool,
+    convert_web_ref: bool,
+    parallel_tool_calls: bool,
+    require_tool_call: bool,
+    json_output: Option<JsonOutput>,
+}
+
+impl StreamContext {
+    fn push_chunk(
+        &self,
+        buf: &mut Vec<u8>,
+        index: u32,
+        delta: openai::chat_completion_chunk::choice::Delta,
+        finish_reason: Option<openai::FinishReason>,
+    ) {
+        push_event(
+            buf,
+            &openai::ChatCompletionChunk {
+                id: &self.response_id,
+                object: openai::ObjectChatCompletionChunk,
+                created: self.created,
+                model: self.model,
+                choices: Some(openai::chat_completion_chunk::Choice {
+                    index,
+                    delta: Some(delta),
+                    logprobs: (),
+                    finish_reason,
+                }),
+                usage: Tri::Null(self.include_usage),
+            },
+        );
+    }
+}
+
+fn push_event<T: serde::Serialize>(buf: &mut Vec<u8>, value: &T) {
+    buf.extend_from_slice(b"data: ");
+    __unwrap!(serde_json::to_writer(&mut *buf, value));
+    buf.extend_from_slice(b"\n\n");
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LastContent {
+    None,
+    Thinking,
+    Text,
+    ToolCall,
+}
+
+/// State of one streaming choice
+struct ChoiceStream {
+    index: u32,
+    stream: BoxStream<'static, reqwest::Result<Bytes>>,
+    decoder: StreamDecoder,
+    started: bool,
+    last: LastContent,
+    tool_index: u32,
+    tool_id: Option<ByteStr>,
+}
+
+/// Output of a streaming choice, `finished` is set on its last event
+struct ChoiceEvent {
+    index: u32,
+    data: Vec<u8>,
+    finished: Option<Finished>,
+}
+
+struct Finished {
+    chain: Chain,
+    error: Option<ChatError>,
+    /// `finish_reason` chunk of a failed choice, held back to go after the error
+    held: Vec<u8>,
+}
+
+/// Chains of the finished choices in order, along with the error of the first one that failed,
+/// which is the one reported, and the chunks held back by the failed ones
+fn merge_finished(finished: Vec<Option<Finished>>) -> (Vec<Chain>, Option<ChatError>, Vec<u8>) {
+    let mut chains = Vec::with_capacity(finished.len());
+    let mut error = None;
+    let mut held = Vec::new();
+    for finished in finished.into_iter().flatten() {
+        if error.is_none() {
+            error = finished.error;
+        }
+        chains.push(finished.chain);
+        held.extend_from_slice(&finished.held);
+    }
+    (chains, error, held)
+}
+
+impl ChoiceStream {
+    fn into_stream(
+        self,
+        ctx: Arc<StreamContext>,
+    ) -> impl futures_util::Stream<Item = ChoiceEvent> + Send {
+        futures_util::stream::unfold(Some(self), move |choice| {
+            let ctx = ctx.clone();
+            async move {
+                let mut choice = choice?;
+                let mut data = Vec::with_capacity(128);
+                loop {
+                    let ended = match choice.stream.next().await {
+                        Some(Ok(chunk)) => match choice.decoder.decode(&chunk, ctx.convert_web_ref)
+                        {
+                            Ok(messages) => {
+                                let first = choice.decoder.take_first_result();
+                                let ended = first
+                                    .is_some_and(|first| choice.process(first, &ctx, &mut data));
+                                ended || choice.process(messages, &ctx, &mut data)
+                            }
+                            Err(StreamError::EmptyStream) => false,
+                            Err(StreamError::Upstream(e)) => {
+                                let message = __unwrap!(serde_json::to_string(
+                                    &e.canonical().into_openai().wrapped()
+                                ));
+                                choice.process(
+                                    vec![StreamMessage::Content(message)],
+                                    &ctx,
+                                    &mut data,
+                                );
+                                true
+                            }
+                        },
+                        Some(Err(e)) => {
+                            crate::debug!("Find chunk error: {e:?}");
+                            false
+                        }
+                        None => {
+                            if let Some(first) = choice.decoder.take_first_result() {
+                                choice.process(first, &ctx, &mut data);
+                            }
+                            true
+                        }
+                    };
+                    if ended {
+                        let finished = choice.finish(&ctx, &mut data);
+                        let event =
+                            ChoiceEvent { index: choice.index, data, finished: Some(finished) };
+                        return Some((event, None));
+                    }
+                    if !data.is_empty() {
+                        let event = ChoiceEvent { index: choice.index, data, finished: None };
+                        return Some((event, Some(choice)));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Converts messages to chunks, returns whether the choice has ended
+    fn process(
+        &mut self,
+        messages: Vec<StreamMessage>,
+        ctx: &StreamContext,
+        buf: &mut Vec<u8>,
+    ) -> bool {
+        use openai::chat_completion_chunk::choice::{
+            Delta,
+            delta::{ToolCall, tool_call::Function},
+        };
+
+        for message in messages {
+            let role = if self.started { None } else { Some(Role::Assistant) };
+            match message {
+                StreamMessage::Content(text) => {
+                    let text = if role.is_some() || self.last == LastContent::Thinking {
+                        text.trim_leading_newlines()
+                    } else {
+                        text
+                    };
+                    self.started = true;
+                    self.last = LastContent::Text;
+                    let delta = Delta {
+                        content: Some(Cow::Owned(text)),
+                        reasoning_content: None,
+                        role,
+                        tool_calls: None,
+                    };
+                    ctx.push_chunk(buf, self.index, delta, None);
+                }
+                StreamMessage::Thinking(Thinking::Text(text)) => {
+                    self.started = true;
+                    self.last = LastContent::Thinking;
+                    let delta = Delta {
+                        content: None,
+                        reasoning_content: Some(Cow::Owned(text)),
+                        role,
+                        tool_calls: None,
+                    };
+                    ctx.push_chunk(buf, self.index, delta, None);
+                }
+                StreamMessage::ToolCall(tool_call) => {
+                    self.started = true;
+                    self.last = LastContent::ToolCall;
+                    if self.tool_id.as_deref() != Some(&*tool_call.id) {
+                        if self.tool_id.is_some() {
+                            self.tool_index += 1;
+                        }
+                        self.tool_id = Some(tool_call.id.clone());
+                        let delta = Delta {
+                            content: None,
+                            reasoning_content: None,
+                            role,
+                            tool_calls: Some(Box::new(ToolCall {
+                                index: self.tool_index,
+                                id: Some(tool_call.id),
+                                function: Some(Function::Start {
+                                    name: tool_call.name,
+                                    arguments: openai::EmptyString,
+                                }),
+                            })),
+                        };
+                        ctx.push_chunk(buf, self.index, delta, None);
+                    }
+                    let delta = Delta {
+                        content: None,
+                        reasoning_content: None,
+                        role: None,
+                        tool_calls: Some(Box::new(ToolCall {
+                            index: self.tool_index,
+                            id: None,
+                            function: Some(Function::Partial { arguments: tool_call.input }),
+                        })),
+                    };
+                    ctx.push_chunk(buf, self.index, delta, None);
+
+                    // Without parallel tool calls the choice ends after the first one
+                    if tool_call.is_last && !ctx.parallel_tool_calls {
+                        return true;
+                    }
+                }
+                StreamMessage::StreamEnd => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Writes the `finish_reason` chunk and takes the chain of the choice
+    ///
+    /// The chunk of a choice that failed is held back, clients take it as the end of the choice
+    /// and the error is only written once all choices have ended
+    fn finish(&mut self, ctx: &StreamContext, buf: &mut Vec<u8>) -> Finished {
+        let delays = self.decoder.take_content_delays();
+        let error = if ctx.require_tool_call && self.decoder.tool_processed() == 0 {
+            Some(ChatError::ToolCallMissing)
+        } else if let Some(ref json_output) = ctx.json_output
+            && self.decoder.tool_processed() == 0
+        {
+            json_output
+                .extract(delays.as_ref().map_or("", |(text, _)| text.as_str()))
+                .err()
+                .map(ChatError::InvalidJsonOutput)
+        } else {
+            None
+        };
+
+        let delta = openai::chat_completion_chunk::choice::Delta {
+            content: None,
+            reasoning_content: None,
+            role: None,
+            tool_calls: None,
+        };
+        let mut held = Vec::new();
+        let finish_buf = if error.is_some() { &mut held } else { buf };
+        ctx.push_chunk(finish_buf, self.index, delta, Some(openai_finish_reason(&self.decoder)));
+        Finished {
+            chain: Chain { delays, usage: None, think: self.decoder.take_thinking_content() },
+            error,
+            held,
+        }
+    }
+}
+
+/// Merged choices of a streaming response
+struct MergeState<F> {
+    merged: futures_util::stream::SelectAll<BoxStream<'static, ChoiceEvent>>,
+    finished: Vec<Option<Finished>>,
+    ctx: Arc<StreamContext>,
+    tokens: Vec<ExtToken>,
+    /// Slots of the extra tokens, taken until the merged stream ends
+    _in_flight: Vec<InFlight>,
+    use_pri: bool,
+    request_time: DateTime,
+    current_id: u64,
+    spender: Option<Spender>,
+    start_time: std::time::Instant,
+    usage_check: Option<F>,
+}
+
+impl<F> MergeState<F>
+where F: Future<Output = ()> + Send + 'static
+{
+    /// Runs once all choices have ended, writes the log and the closing events
+    async fn finish(self) -> Vec<u8> {
+        let total_time = self.start_time.elapsed().as_secs_f64();
+        log_manager::update_log(self.current_id, LogUpdate::Timing(total_time)).await;
+
+        let (chains, error, held) = merge_finished(self.finished);
+        let reasoning_tokens: i32 =
+            chains.iter().map(|chain| chain.think.as_deref().map_or(0, approx_token_count)).sum();
+        log_manager::update_log(self.current_id, LogUpdate::Choices(chains)).await;
+
+        let usage = sum_usage(
+            self.tokens,
+            self.use_pri,
+            self.request_time,
+            self.ctx.model,
+            self.spender.as_ref(),
+        )
+        .await;
+        if let Some(usage) = usage {
+            log_manager::update_log(self.current_id, LogUpdate::Usage(usage)).await;
+        }
+        let usage = usage
+            .filter(|_| *REAL_USAGE)
+            .map(|usage| usage.into_openai().with_reasoning_tokens(reasoning_tokens));
+
+        let mut buf = Vec::with_capacity(128);
+        if let Some(error) = error {
+            log_manager::update_log(
+                self.current_id,
+                LogUpdate::Failure(ErrorInfo::Simple(Str::new(&error.to_string()))),
+            )
+            .await;
+            push_event(&mut buf, &error.into_openai_tuple().1.0);
+        }
+        buf.extend_from_slice(&held);
+        if self.ctx.include_usage {
+            push_event(
+                &mut buf,
+                &openai::ChatCompletionChunk {
+                    id: &self.ctx.response_id,
+                    object: openai::ObjectChatCompletionChunk,
+                    created: self.ctx.created,
+                    model: self.ctx.model,
+                    choices: None,
+                    usage: Tri::Value(usage.unwrap_or_default()),
+                },
+            );
+        }
+        buf.extend_from_slice(b"data: [DONE]\n\n");
+
+        if let Some(usage_check) = self.usage_check {
+            tokio::spawn(usage_check);
+        }
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::stream::decoder::ToolCall;
+
+    fn context(require_tool_call: bool) -> StreamContext {
+        StreamContext {
+            response_id: "chatcmpl-1".to_owned(),
+            model: "default",
+            created: 0,
+            include_usage: false,
+            convert_web_ref: false,
+            parallel_tool_calls: true,
+            require_tool_call,
+            json_output: None,
+        }
+    }
+
+    fn choice(index: u32) -> ChoiceStream {
+        ChoiceStream {
+            index,
+            stream: futures_util::stream::empty().boxed(),
+            decoder: StreamDecoder::new(),
+            started: false,
+            last: LastContent::None,
+            tool_index: 0,
+            tool_id: None,
+        }
+    }
+
+    fn tool_call(id: &'static str) -> StreamMessage {
+        StreamMessage::ToolCall(ToolCall {
+            id: ByteStr::from_static(id),
+            name: ByteStr::from_static("f"),
+            input: "{}".to_owned(),
+            is_last: true,
+        })
+    }
+
+    fn events(buf: &[u8]) -> Vec<serde_json::Value> {
+        core::str::from_utf8(buf)
+            .unwrap()
+            .split_terminator("\n\n")
+            .map(|event| serde_json::from_str(event.strip_prefix("data: ").unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chunks_carry_the_index_of_their_choice() {
+        let ctx = context(false);
+        let mut choice = choice(2);
+        let mut buf = Vec::new();
+        let messages =
+            vec![StreamMessage::Content("\nHi".to_owned()), tool_call("a"), tool_call("b")];
+        assert!(!choice.process(messages, &ctx, &mut buf));
+        choice.finish(&ctx, &mut buf);
+
+        let events = events(&buf);
+        // Content, start and arguments of both calls, then the finish reason
+        assert_eq!(events.len(), 6);
+        let choices: Vec<_> = events.iter().map(|event| &event["choices"][0]).collect();
+        assert!(choices.iter().all(|choice| choice["index"] == 2));
+        assert_eq!(choices[0]["delta"]["role"], "assistant");
+        assert_eq!(choices[0]["delta"]["content"], "Hi");
+        assert_eq!(choices[1]["delta"]["tool_calls"][0]["index"], 0);
+        assert_eq!(choices[3]["delta"]["tool_calls"][0]["index"], 1);
+        assert_eq!(choices[3]["delta"]["tool_calls"][0]["id"], "b");
+        assert!(choices[5]["finish_reason"].is_string());
+    }
+
+    #[test]
+    fn failed_choice_keeps_the_chains_of_the_others() {
+        let ctx = context(true);
+        let mut buf = Vec::new();
+        let mut failed = choice(1);
+        failed.process(vec![StreamMessage::Content("Hi".to_owned())], &ctx, &mut buf);
+        let failed = failed.finish(&ctx, &mut buf);
+        assert!(matches!(failed.error, Some(ChatError::ToolCallMissing)));
+        // The finish reason of the failed choice waits for the error
+        assert_eq!(events(&buf).len(), 1);
+        assert!(events(&failed.held)[0]["choices"][0]["finish_reason"].is_string());
+
+        let chain = || Chain { delays: None, usage: None, think: None };
+        let finished = vec![
+            Some(Finished { chain: chain(), error: None, held: Vec::new() }),
+            Some(failed),
+            Some(Finished {
+                chain: chain(),
+                error: Some(ChatError::InvalidJsonOutput("late".to_owned())),
+                held: b"late".to_vec(),
+            }),
+        ];
+        let (chains, error, held) = merge_finished(finished);
+        assert_eq!(chains.len(), 3);
+        assert!(matches!(error, Some(ChatError::ToolCallMissing)));
+        assert!(held.ends_with(b"late"));
+    }
+
+    #[test]
+    fn usage_is_polled_once_per_token() {
+        let counts = requests_per_token(vec!["a", "b", "a", "c", "a"], |token| *token);
+        assert_eq!(counts, [("a", 3), ("b", 1), ("c", 1)]);
+    }
+}
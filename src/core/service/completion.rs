@@ -0,0 +1,213 @@
+//! Chat completions as the other protocols see them
+//!
+//! Their handlers convert the request into a chat completion, run it through
+//! `handle_chat_completions` and convert back what it answers, so everything around the upstream
+//! request is taken care of in one place.
+
+use super::handle_chat_completions;
+use crate::{
+    app::model::AppState,
+    common::model::error::ChatError,
+    core::{
+        error::ErrorExt as _,
+        model::openai::{self, OpenAiError},
+    },
+};
+use alloc::{borrow::Cow, sync::Arc};
+use axum::{Json, body::Body, extract::State};
+use byte_str::ByteStr;
+use futures_util::{StreamExt as _, stream::BoxStream};
+use http::{Extensions, StatusCode};
+use serde::Deserialize;
+
+/// `data: ` line of a server-sent event
+const DATA_PREFIX: &[u8] = b"data: ";
+/// Separator of server-sent events
+const EVENT_END: &[u8] = b"\n\n";
+
+pub enum Reply {
+    Whole(Completion),
+    Stream(BoxStream<'static, Event>),
+}
+
+#[derive(Deserialize)]
+pub struct Completion {
+    pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<openai::Usage>,
+}
+
+#[derive(Deserialize)]
+pub struct Choice {
+    pub message: Message,
+    pub finish_reason: openai::FinishReason,
+}
+
+#[derive(Deserialize)]
+pub struct Message {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<openai::ChatCompletionMessageToolCall>,
+}
+
+/// One event of a streamed chat completion, `[DONE]` is left out
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// Failure after the response started, such as a missing required tool call
+    Error(ErrorEvent),
+    Chunk(Chunk),
+}
+
+#[derive(Deserialize)]
+pub struct ErrorEvent {
+    #[serde(default)]
+    pub code: Option<String>,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct Chunk {
+    #[serde(default)]
+    pub choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    pub usage: Option<openai::Usage>,
+}
+
+#[derive(Deserialize)]
+pub struct ChunkChoice {
+    #[serde(default)]
+    pub delta: Option<Delta>,
+    #[serde(default)]
+    pub finish_reason: Option<openai::FinishReason>,
+}
+
+#[derive(Deserialize)]
+pub struct Delta {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+/// A tool call starts with its id and name, the arguments follow in pieces
+#[derive(Deserialize)]
+pub struct ToolCallDelta {
+    #[serde(default)]
+    pub id: Option<ByteStr>,
+    #[serde(default)]
+    pub function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+pub enum FunctionDelta {
+    Start { name: ByteStr },
+    Partial { arguments: String },
+}
+
+/// Runs `request` as a chat completion
+pub async fn complete(
+    state: Arc<AppState>,
+    extensions: Extensions,
+    request: openai::ChatCompletionCreateParams,
+) -> Result<Reply, (StatusCode, Json<OpenAiError>)> {
+    let is_stream = request.stream;
+    let body = handle_chat_completions(State(state), extensions, Json(request)).await?.into_body();
+
+    if is_stream {
+        return Ok(Reply::Stream(events(body).boxed()));
+    }
+    let data = __unwrap!(axum::body::to_bytes(body, usize::MAX).await);
+    serde_json::from_slice(&data).map(Reply::Whole).map_err(|e| {
+        ChatError::RequestFailed(StatusCode::INTERNAL_SERVER_ERROR, Cow::Owned(e.to_string()))
+            .into_openai_tuple()
+    })
+}
+
+fn events(body: Body) -> impl futures_core::Stream<Item = Event> + Send + 'static {
+    let mut reader = EventReader::default();
+    body.into_data_stream().flat_map(move |chunk| {
+        futures_util::stream::iter(match chunk {
+            Ok(chunk) => reader.feed(&chunk),
+            Err(e) => {
+                crate::debug!("Find chunk error: {e:?}");
+                Vec::new()
+            }
+        })
+    })
+}
+
+/// Splits the server-sent events of a streamed chat completion back into events
+#[derive(Default)]
+struct EventReader {
+    buf: Vec<u8>,
+}
+
+impl EventReader {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buf.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        let mut consumed = 0;
+        while let Some(len) =
+            self.buf[consumed..].windows(EVENT_END.len()).position(|window| window == EVENT_END)
+        {
+            let event = &self.buf[consumed..consumed + len];
+            consumed += len + EVENT_END.len();
+            if let Some(data) = event.strip_prefix(DATA_PREFIX)
+                && let Ok(event) = serde_json::from_slice(data)
+            {
+                events.push(event);
+            }
+        }
+        self.buf.drain(..consumed);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_split_across_chunks_are_read_whole() {
+        let mut reader = EventReader::default();
+        let stream = concat!(
+            "data: {\"id\":\"chatcmpl-1\",\"choices\":[{\"index\":0,\"delta\":{\"role\":",
+            "\"assistant\",\"content\":\"Hi\"},\"logprobs\":null,\"finish_reason\":null}],",
+            "\"usage\":null}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":",
+            "[{\"index\":0,\"id\":\"call_1\",\"function\":{\"Start\":{\"name\":\"f\",",
+            "\"arguments\":\"\"}}}]},\"logprobs\":null,\"finish_reason\":null}]}\n\n",
+            "data: {\"type\":\"error\",\"code\":\"tool_call_missing\",\"message\":\"m\",",
+            "\"param\":null}\n\n",
+            "data: {\"id\":\"chatcmpl-1\",\"choices\":[],\"usage\":{\"prompt_tokens\":3,",
+            "\"completion_tokens\":2,\"total_tokens\":5}}\n\n",
+            "data: [DONE]\n\n",
+        )
+        .as_bytes();
+
+        let (head, tail) = stream.split_at(30);
+        let mut events = reader.feed(head);
+        assert!(events.is_empty());
+        events.extend(reader.feed(tail));
+        assert!(reader.buf.is_empty());
+
+        let [Event::Chunk(text), Event::Chunk(tool_call), Event::Error(error), Event::Chunk(usage)] =
+            &events[..]
+        else {
+            panic!("unexpected events")
+        };
+        let delta = |chunk: &Chunk| chunk.choices[0].delta.as_ref().unwrap();
+        assert_eq!(delta(text).content.as_deref(), Some("Hi"));
+        let call = &delta(tool_call).tool_calls[0];
+        assert_eq!(call.id.as_deref(), Some("call_1"));
+        assert!(matches!(&call.function, Some(FunctionDelta::Start { name }) if name == "f"));
+        assert_eq!(error.code.as_deref(), Some("tool_call_missing"));
+        assert_eq!(usage.usage.as_ref().unwrap().total_tokens, 5);
+    }
+}
@@ -6,20 +6,35 @@ use crate::{
         client::{AiServiceRequest, build_client_request},
         utils::new_uuid_v4,
     },
+    core::{
+        model::{
+            Role,
+            anthropic::{ContentBlockParam, MessageContent, MessageParam, ToolResultContent},
+            openai::{
+                ChatCompletionContentText, ChatCompletionMessageParam,
+                ChatCompletionMessageToolCall, chat_completion_message_tool_call,
+            },
+        },
+        stream::decoder::{StreamMessage, ToolCall},
+    },
 };
-use session::{Session, SessionSink, SessionStream};
+use byte_str::ByteStr;
+pub use session::{Session, SessionSink, Upstream};
 
 pub enum Tendency<N, O> {
     Start(N),
     Continue(O),
 }
 
+/// Sends `data` as the first message of a request body that stays open for tool results
 pub async fn start(
     data: Vec<u8>,
     ext_token: &ExtToken,
     use_pri: bool,
-) -> Result<Session, reqwest::Error> {
+) -> Result<(reqwest::Response, SessionSink), reqwest::Error> {
     let (sink, body) = SessionSink::new();
+    // The channel holds one message, so this does not wait for the upstream
+    __unwrap!(sink.send(data.into()).await);
     let req = build_client_request(AiServiceRequest {
         ext_token,
         fs_client_key: None,
@@ -29,10 +44,67 @@ pub async fn start(
         trace_id: new_uuid_v4(),
         use_pri,
         cookie: None,
+        exact_length: None,
     });
     let res = req.body(body).send().await?;
-    __unwrap!(sink.send(data.into()).await);
-    let stream = SessionStream::new(res);
 
-    Ok(Session { stream, sink })
+    Ok((res, sink))
+}
+
+/// The single tool result closing a conversation, with the name of the call it answers
+pub fn openai_tool_result(
+    params: &[ChatCompletionMessageParam],
+) -> Option<(&ByteStr, &ByteStr, &ChatCompletionContentText)> {
+    if let [
+        ..,
+        ChatCompletionMessageParam::Assistant { tool_calls: Some(tool_call), .. },
+        ChatCompletionMessageParam::Tool { content, tool_call_id },
+    ] = params
+        && let ChatCompletionMessageToolCall::Function {
+            id,
+            function: chat_completion_message_tool_call::Function { name, .. },
+        } = &**tool_call
+        && id == tool_call_id
+    {
+        Some((tool_call_id, name, content))
+    } else {
+        None
+    }
+}
+
+/// The single `tool_result` block closing a conversation, with the name of the `tool_use` it answers
+pub fn anthropic_tool_result(
+    params: &[MessageParam],
+) -> Option<(&ByteStr, &ByteStr, (Option<&ToolResultContent>, bool))> {
+    let [
+        ..,
+        MessageParam { role: Role::Assistant, content: MessageContent::Array(assistant) },
+        MessageParam { role: Role::User, content: MessageContent::Array(user) },
+    ] = params
+    else {
+        return None;
+    };
+    let [ContentBlockParam::ToolResult { tool_use_id, content, is_error }] = &user[..] else {
+        return None;
+    };
+    let mut tool_uses = assistant.iter().filter_map(|block| match block {
+        ContentBlockParam::ToolUse { id, name, .. } => Some((id, name)),
+        _ => None,
+    });
+    match (tool_uses.next(), tool_uses.next()) {
+        (Some((id, name)), None) if id == tool_use_id => {
+            Some((tool_use_id, name, (content.as_ref(), *is_error)))
+        }
+        _ => None,
+    }
+}
+
+/// Id of the first tool call that finished among `messages`
+pub fn finished_tool_call<'a>(
+    messages: impl IntoIterator<Item = &'a StreamMessage>,
+) -> Option<ByteStr> {
+    messages.into_iter().find_map(|message| match message {
+        StreamMessage::ToolCall(ToolCall { id, is_last: true, .. }) => Some(id.clone()),
+        _ => None,
+    })
 }
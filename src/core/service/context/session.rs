@@ -1,3 +1,4 @@
+use alloc::sync::Arc;
 use bytes::Bytes;
 use core::{
     pin::Pin,
@@ -5,6 +6,7 @@ use core::{
     task::{Context, Poll},
 };
 use futures_core::Stream;
+use parking_lot::Mutex;
 use reqwest::{DataStream, Decoder};
 use tokio::sync::mpsc::{Sender, error::SendError};
 
@@ -46,5 +48,54 @@ impl SessionSink {
 
 pub struct Session {
     pub stream: SessionStream,
-    pub sink: SessionSink,
+    /// `None` when the request body was sent whole and is already closed
+    pub sink: Option<SessionSink>,
+}
+
+impl Session {
+    #[inline]
+    pub fn new(res: reqwest::Response, sink: Option<SessionSink>) -> Self {
+        Self { stream: SessionStream::new(res), sink }
+    }
+
+    /// Sends another request message, fails once the upstream has gone away
+    pub async fn send(&self, item: Bytes) -> Result<(), SendError<Bytes>> {
+        match self.sink {
+            Some(ref sink) => sink.send(item).await,
+            None => Err(SendError(item)),
+        }
+    }
+}
+
+/// Session read by a response body, which can take it back out to park it
+#[derive(Clone)]
+pub struct Upstream(Arc<Mutex<Option<Session>>>);
+
+impl Upstream {
+    #[inline]
+    pub fn new(session: Session) -> Self { Self(Arc::new(Mutex::new(Some(session)))) }
+
+    /// Whether the request body is still open for tool results
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.0.lock().as_ref().is_some_and(|session| session.sink.is_some())
+    }
+
+    /// Reads from `session` from now on, dropping the previous one
+    #[inline]
+    pub fn replace(&self, session: Session) { *self.0.lock() = Some(session) }
+
+    /// Takes the session out, the stream ends for every other holder
+    #[inline]
+    pub fn take(&self) -> Option<Session> { self.0.lock().take() }
+}
+
+impl Stream for Upstream {
+    type Item = Result<Bytes, reqwest::Error>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match *self.0.lock() {
+            Some(ref mut session) => Pin::new(&mut session.stream).poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
 }
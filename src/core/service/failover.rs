@@ -0,0 +1,129 @@
+//! Retries of a pooled request on other tokens when its token fails before the first byte
+
+use super::context::{self, SessionSink};
+use crate::{
+    app::{
+        lazy::chat_url,
+        model::{
+            AppConfig, AppState, ErrorInfo, ExtToken, InFlight, LogUpdate, QueueType, TokenKey,
+            log_manager,
+        },
+    },
+    common::{
+        client::{AiServiceRequest, build_client_request},
+        utils::new_uuid_v4,
+    },
+    core::auth::TokenPool,
+};
+use alloc::sync::Arc;
+use interned::Str;
+
+pub struct Failover {
+    state: Arc<AppState>,
+    queue: QueueType,
+    group: Option<Arc<str>>,
+    /// The encoded request, sent unchanged on every token
+    data: Vec<u8>,
+    use_pri: bool,
+    keep_open: bool,
+    current_id: u64,
+    tried: Vec<TokenKey>,
+    remaining: u8,
+    /// Slot on the token the request failed over to last, taken for as long as the failover is
+    /// kept
+    in_flight: Option<InFlight>,
+}
+
+impl Failover {
+    /// `None` unless the token was taken from a pool and failover is enabled
+    pub fn new(
+        state: &Arc<AppState>,
+        pool: Option<TokenPool>,
+        ext_token: &ExtToken,
+        data: &[u8],
+        use_pri: bool,
+        keep_open: bool,
+        current_id: u64,
+    ) -> Option<Self> {
+        let TokenPool { queue, group } = pool?;
+        let remaining = AppConfig::failover_attempts();
+        if remaining == 0 {
+            return None;
+        }
+        Some(Self {
+            state: state.clone(),
+            queue,
+            group,
+            data: data.to_vec(),
+            use_pri,
+            keep_open,
+            current_id,
+            tried: vec![ext_token.primary_token.key()],
+            remaining,
+            in_flight: None,
+        })
+    }
+
+    /// Sends the request on the next untried token of the pool after the current one failed
+    /// with `error`, until a send succeeds or the attempts run out
+    pub async fn retry(
+        &mut self,
+        mut error: ErrorInfo,
+    ) -> Option<(ExtToken, reqwest::Response, Option<SessionSink>)> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+            let ext_token = self.next_token().await?;
+            log_manager::update_log(self.current_id, LogUpdate::Failover(ext_token.clone(), error))
+                .await;
+
+            match self.send(&ext_token).await {
+                Ok((response, sink)) => return Some((ext_token, response, sink)),
+                Err(e) => {
+                    let e = e.without_url();
+                    crate::debug!("failover request: {e:?}");
+                    if let Some(event) = super::send_health_event(&e) {
+                        let key = ext_token.primary_token.key();
+                        self.state.record_token_health(key, event).await;
+                    }
+                    error = ErrorInfo::Simple(Str::new(&e.to_string()));
+                }
+            }
+        }
+        None
+    }
+
+    async fn next_token(&mut self) -> Option<ExtToken> {
+        let (ext_token, in_flight) = self.state.token_manager_read().await.select_except(
+            self.queue,
+            self.group.as_deref(),
+            &self.tried,
+        )?;
+        self.tried.push(ext_token.primary_token.key());
+        // The slot of a token that failed is given back here
+        self.in_flight = Some(in_flight);
+        Some(ext_token)
+    }
+
+    async fn send(
+        &self,
+        ext_token: &ExtToken,
+    ) -> Result<(reqwest::Response, Option<SessionSink>), reqwest::Error> {
+        if self.keep_open {
+            let (response, sink) =
+                context::start(self.data.clone(), ext_token, self.use_pri).await?;
+            return Ok((response, Some(sink)));
+        }
+        let req = build_client_request(AiServiceRequest {
+            ext_token,
+            fs_client_key: None,
+            url: chat_url(self.use_pri),
+            stream: true,
+            compressed: true,
+            trace_id: new_uuid_v4(),
+            use_pri: self.use_pri,
+            cookie: None,
+            exact_length: Some(self.data.len()),
+        });
+        Ok((req.body(self.data.clone()).send().await?, None))
+    }
+}
@@ -0,0 +1,337 @@
+use super::completion::{self, Chunk, Completion, Event, FunctionDelta, Reply, ToolCallDelta};
+use crate::{
+    app::{
+        constant::header::{CHUNKED, EVENT_STREAM, JSON, KEEP_ALIVE, NO_CACHE_REVALIDATE},
+        model::AppState,
+    },
+    common::model::error::ChatError,
+    core::{
+        error::ErrorExt as _,
+        model::{
+            MessageId,
+            gemini::{
+                Candidate, CandidateContent, FinishReason, GENERATE_CONTENT, GeminiError,
+                GenerateContentQuery, GenerateContentRequest, GenerateContentResponse, Model,
+                ResponseFunctionCall, ResponsePart, STREAM_GENERATE_CONTENT, UsageMetadata,
+            },
+            openai::{self, OpenAiError},
+        },
+    },
+};
+use alloc::{borrow::Cow, sync::Arc};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    response::Response,
+};
+use byte_str::ByteStr;
+use bytes::Bytes;
+use core::convert::Infallible;
+use futures_util::StreamExt as _;
+use http::{
+    Extensions, StatusCode,
+    header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+};
+
+/// `finishReason` of a finished candidate, Gemini has no dedicated stop sequence or tool reason
+#[inline]
+fn gemini_finish_reason(finish_reason: Option<openai::FinishReason>) -> FinishReason {
+    match finish_reason {
+        Some(openai::FinishReason::Length) => FinishReason::MaxTokens,
+        Some(openai::FinishReason::Stop | openai::FinishReason::ToolCalls) | None => {
+            FinishReason::Stop
+        }
+    }
+}
+
+#[inline]
+fn into_gemini_tuple(
+    (status, Json(error)): (StatusCode, Json<OpenAiError>),
+) -> (StatusCode, Json<GeminiError>) {
+    (status, Json(GeminiError { code: status, message: error.into_inner().message }))
+}
+
+/// Writes `GenerateContentResponse` chunks as server-sent events or as one JSON array
+struct ChunkWriter {
+    buf: Vec<u8>,
+    sse: bool,
+    started: bool,
+}
+
+impl ChunkWriter {
+    fn write<T: serde::Serialize>(&mut self, value: &T) {
+        if self.sse {
+            self.buf.extend_from_slice(b"data: ");
+        } else if self.started {
+            self.buf.extend_from_slice(b",\r\n");
+        } else {
+            self.buf.push(b'[');
+        }
+        self.started = true;
+        let mut ser = serde_json::Serializer::new(&mut self.buf);
+        __unwrap!(serde::Serialize::serialize(value, &mut ser));
+        if self.sse {
+            self.buf.extend_from_slice(b"\r\n\r\n");
+        }
+    }
+
+    /// Closes the JSON array
+    fn finish(&mut self) {
+        if !self.sse {
+            if !self.started {
+                self.buf.push(b'[');
+            }
+            self.buf.push(b']');
+        }
+    }
+
+    #[inline]
+    fn take(&mut self) -> Vec<u8> { core::mem::take(&mut self.buf) }
+}
+
+/// Folds the chat completion into the parts of the only candidate
+struct Output {
+    response_id: String,
+    model: String,
+    parts: Vec<ResponsePart>,
+    /// Function call whose arguments are still arriving
+    tool_call: Option<(ByteStr, ByteStr, String)>,
+    finish_reason: Option<openai::FinishReason>,
+    usage: Option<UsageMetadata>,
+}
+
+impl Output {
+    fn new(response_id: String, model: String) -> Self {
+        Self {
+            response_id,
+            model,
+            parts: Vec::with_capacity(2),
+            tool_call: None,
+            finish_reason: None,
+            usage: None,
+        }
+    }
+
+    fn push_text(&mut self, text: String, thought: bool) {
+        if let Some(ResponsePart::Text { text: last, thought: last_thought }) =
+            self.parts.last_mut()
+            && *last_thought == thought
+        {
+            last.push_str(&text);
+        } else {
+            self.parts.push(ResponsePart::Text { text, thought });
+        }
+    }
+
+    fn push_function_call(&mut self, id: ByteStr, name: ByteStr, arguments: &str) {
+        let args = serde_json::from_str(arguments).unwrap_or_default();
+        self.parts.push(ResponsePart::FunctionCall {
+            function_call: ResponseFunctionCall { id, name, args },
+        });
+    }
+
+    /// Function calls are emitted whole, the arguments are buffered until the next call starts
+    /// or the stream ends
+    fn push_tool_call(&mut self, tool_call: ToolCallDelta) {
+        match tool_call.function {
+            Some(FunctionDelta::Start { name }) => {
+                self.end_tool_call();
+                self.tool_call = Some((tool_call.id.unwrap_or_default(), name, String::new()));
+            }
+            Some(FunctionDelta::Partial { arguments }) => {
+                if let Some((_, _, buffered)) = &mut self.tool_call {
+                    buffered.push_str(&arguments);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn end_tool_call(&mut self) {
+        if let Some((id, name, arguments)) = self.tool_call.take() {
+            self.push_function_call(id, name, &arguments);
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: Chunk) {
+        for choice in chunk.choices {
+            if let Some(delta) = choice.delta {
+                if let Some(text) = delta.reasoning_content {
+                    self.push_text(text, true);
+                }
+                if let Some(text) = delta.content {
+                    self.push_text(text, false);
+                }
+                for tool_call in delta.tool_calls {
+                    self.push_tool_call(tool_call);
+                }
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+        }
+        if let Some(usage) = chunk.usage {
+            self.usage = Some(usage.into());
+        }
+    }
+
+    /// Adds the output of a non-streaming completion at once
+    fn push_completion(&mut self, completion: Completion) {
+        if let Some(choice) = completion.choices.into_iter().next() {
+            let message = choice.message;
+            if let Some(text) = message.reasoning_content {
+                self.push_text(text, true);
+            }
+            if let Some(text) = message.content.filter(|text| !text.is_empty()) {
+                self.push_text(text, false);
+            }
+            for openai::ChatCompletionMessageToolCall::Function { id, function } in
+                message.tool_calls
+            {
+                self.push_function_call(id, function.name, &function.arguments);
+            }
+            self.finish_reason = Some(choice.finish_reason);
+        }
+        self.usage = completion.usage.map(Into::into);
+    }
+
+    #[inline]
+    fn response<'a>(
+        &'a self,
+        parts: &'a [ResponsePart],
+        finish_reason: Option<FinishReason>,
+        usage_metadata: Option<UsageMetadata>,
+    ) -> GenerateContentResponse<'a> {
+        GenerateContentResponse {
+            candidates: [Candidate {
+                content: CandidateContent { role: Model, parts },
+                finish_reason,
+                index: 0,
+            }],
+            usage_metadata,
+            model_version: &self.model,
+            response_id: &self.response_id,
+        }
+    }
+
+    /// Writes the parts collected since the last flush as one chunk
+    fn flush(&mut self, writer: &mut ChunkWriter) {
+        if self.parts.is_empty() {
+            return;
+        }
+        let parts = core::mem::take(&mut self.parts);
+        writer.write(&self.response(&parts, None, None));
+    }
+
+    /// Writes the last chunk, carrying the finish reason and usage
+    fn finish(&mut self, writer: &mut ChunkWriter) {
+        self.end_tool_call();
+        let parts = core::mem::take(&mut self.parts);
+        let usage = self.usage.take().unwrap_or_default();
+        writer.write(&self.response(
+            &parts,
+            Some(gemini_finish_reason(self.finish_reason)),
+            Some(usage),
+        ));
+        writer.finish();
+    }
+}
+
+pub async fn handle_generate_content(
+    State(state): State<Arc<AppState>>,
+    Path(model_action): Path<String>,
+    Query(query): Query<GenerateContentQuery>,
+    extensions: Extensions,
+    Json(request): Json<GenerateContentRequest>,
+) -> Result<Response<Body>, (StatusCode, Json<GeminiError>)> {
+    // The path segment is `{model}:{action}`
+    let (model, is_stream) = match model_action.rsplit_once(':') {
+        Some((model, GENERATE_CONTENT)) => (model, false),
+        Some((model, STREAM_GENERATE_CONTENT)) => (model, true),
+        _ => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(GeminiError {
+                    code: StatusCode::NOT_FOUND,
+                    message: Cow::Owned(format!("Unknown method: {model_action}")),
+                }),
+            ));
+        }
+    };
+    if request.contents.is_empty() {
+        return Err(ChatError::EmptyMessages(StatusCode::BAD_REQUEST).into_gemini_tuple());
+    }
+    // Upstream only takes images, other files would be sent as bogus ones
+    if let Some(mime_type) = request.non_image_file() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(GeminiError {
+                code: StatusCode::BAD_REQUEST,
+                message: Cow::Owned(format!("Only image files are supported, got {mime_type:?}")),
+            }),
+        ));
+    }
+
+    let response_id = {
+        let mut buf = [0; 22];
+        MessageId::new(uuid::Uuid::new_v4().as_bytes()).to_str(&mut buf).to_owned()
+    };
+    let mut output = Output::new(response_id, model.to_owned());
+    let request = request.into_chat_completion(model.to_owned(), is_stream);
+
+    match completion::complete(state, extensions, request).await.map_err(into_gemini_tuple)? {
+        Reply::Stream(events) => {
+            let sse = query.is_sse();
+            let mut writer = ChunkWriter { buf: Vec::with_capacity(128), sse, started: false };
+            let stream = events.map(Some).chain(futures_util::stream::once(async { None })).map(
+                move |event| {
+                    match event {
+                        Some(Event::Chunk(chunk)) => {
+                            output.push_chunk(chunk);
+                            output.flush(&mut writer);
+                        }
+                        // Only failures of the model output arrive this late
+                        Some(Event::Error(error)) => {
+                            output.flush(&mut writer);
+                            writer.write(&GeminiError {
+                                code: StatusCode::BAD_GATEWAY,
+                                message: Cow::Owned(error.message),
+                            });
+                        }
+                        None => output.finish(&mut writer),
+                    }
+                    Ok::<_, Infallible>(Bytes::from(writer.take()))
+                },
+            );
+
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, if sse { EVENT_STREAM } else { JSON })
+                    .header(TRANSFER_ENCODING, CHUNKED)
+                    .body(Body::from_stream(stream))
+            ))
+        }
+        Reply::Whole(completion) => {
+            output.push_completion(completion);
+
+            let usage = output.usage.take().unwrap_or_default();
+            let response_data = output.response(
+                &output.parts,
+                Some(gemini_finish_reason(output.finish_reason)),
+                Some(usage),
+            );
+            let data = __unwrap!(serde_json::to_vec(&response_data));
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, JSON)
+                    .header(CONTENT_LENGTH, data.len())
+                    .body(Body::from(data))
+            ))
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use super::completion::{self, Chunk, Completion, Event, FunctionDelta, Reply, ToolCallDelta};
+use crate::{
+    app::{
+        constant::header::{CHUNKED, JSON, KEEP_ALIVE, NO_CACHE_REVALIDATE},
+        model::{AppState, DateTime},
+    },
+    common::model::error::ChatError,
+    core::{
+        auth::TokenBundleResult,
+        constant::Models,
+        error::ErrorExt as _,
+        model::{
+            ExtModel,
+            ollama::{
+                APPLICATION_NDJSON, ChatRequest, ChatResponse, DoneReason, GenerateRequest,
+                GenerateResponse, Metrics, OllamaError, ResponseMessage, ShowRequest, ShowResponse,
+                TagsResponse, ToolCall, ToolCallFunction, model_name,
+            },
+            openai::{self, OpenAiError},
+        },
+    },
+};
+use alloc::{borrow::Cow, sync::Arc};
+use axum::{Json, body::Body, extract::State, response::Response};
+use byte_str::ByteStr;
+use bytes::Bytes;
+use core::convert::Infallible;
+use futures_util::StreamExt as _;
+use http::{
+    Extensions, StatusCode,
+    header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+};
+
+const ASSISTANT: &str = "assistant";
+
+/// Which of the two generation endpoints is answered, they differ only in the response shape
+#[derive(Clone, Copy, PartialEq)]
+enum Endpoint {
+    Chat,
+    Generate,
+}
+
+#[inline]
+fn ollama_done_reason(finish_reason: Option<openai::FinishReason>) -> DoneReason {
+    match finish_reason {
+        Some(openai::FinishReason::Length) => DoneReason::Length,
+        Some(openai::FinishReason::Stop | openai::FinishReason::ToolCalls) | None => {
+            DoneReason::Stop
+        }
+    }
+}
+
+#[inline]
+fn into_ollama_tuple(
+    (status, Json(error)): (StatusCode, Json<OpenAiError>),
+) -> (StatusCode, Json<OllamaError>) {
+    (status, Json(OllamaError { message: error.into_inner().message }))
+}
+
+/// Folds the chat completion into the content of the next response object
+struct Output {
+    endpoint: Endpoint,
+    model: String,
+    content: String,
+    thinking: String,
+    tool_calls: Vec<ToolCall>,
+    /// Tool call whose arguments are still arriving
+    tool_call: Option<(ByteStr, ByteStr, String)>,
+    finish_reason: Option<openai::FinishReason>,
+    metrics: Option<Metrics>,
+}
+
+impl Output {
+    fn new(endpoint: Endpoint, model: String) -> Self {
+        Self {
+            endpoint,
+            model,
+            content: String::new(),
+            thinking: String::new(),
+            tool_calls: Vec::new(),
+            tool_call: None,
+            finish_reason: None,
+            metrics: None,
+        }
+    }
+
+    fn push_function_call(&mut self, id: ByteStr, name: ByteStr, arguments: &str) {
+        let arguments = serde_json::from_str(arguments).unwrap_or_default();
+        self.tool_calls
+            .push(ToolCall { id: Some(id), function: ToolCallFunction { name, arguments } });
+    }
+
+    /// Tool calls are emitted whole, the arguments are buffered until the next call starts or
+    /// the stream ends
+    fn push_tool_call(&mut self, tool_call: ToolCallDelta) {
+        match tool_call.function {
+            Some(FunctionDelta::Start { name }) => {
+                self.end_tool_call();
+                self.tool_call = Some((tool_call.id.unwrap_or_default(), name, String::new()));
+            }
+            Some(FunctionDelta::Partial { arguments }) => {
+                if let Some((_, _, buffered)) = &mut self.tool_call {
+                    buffered.push_str(&arguments);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn end_tool_call(&mut self) {
+        if let Some((id, name, arguments)) = self.tool_call.take() {
+            self.push_function_call(id, name, &arguments);
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: Chunk) {
+        for choice in chunk.choices {
+            if let Some(delta) = choice.delta {
+                if let Some(text) = delta.reasoning_content {
+                    self.thinking.push_str(&text);
+                }
+                if let Some(text) = delta.content {
+                    self.content.push_str(&text);
+                }
+                for tool_call in delta.tool_calls {
+                    self.push_tool_call(tool_call);
+                }
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+        }
+        if let Some(usage) = chunk.usage {
+            self.metrics = Some(usage.into());
+        }
+    }
+
+    /// Adds the output of a non-streaming completion at once
+    fn push_completion(&mut self, completion: Completion) {
+        if let Some(choice) = completion.choices.into_iter().next() {
+            let message = choice.message;
+            if let Some(text) = message.reasoning_content {
+                self.thinking = text;
+            }
+            if let Some(text) = message.content {
+                self.content = text;
+            }
+            for openai::ChatCompletionMessageToolCall::Function { id, function } in
+                message.tool_calls
+            {
+                self.push_function_call(id, function.name, &function.arguments);
+            }
+            self.finish_reason = Some(choice.finish_reason);
+        }
+        self.metrics = completion.usage.map(Into::into);
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.content.is_empty() && self.thinking.is_empty() && self.tool_calls.is_empty()
+    }
+
+    /// Writes what was collected as one response object, followed by a newline
+    fn write(&self, buf: &mut Vec<u8>, done_reason: Option<DoneReason>, metrics: Option<Metrics>) {
+        let created_at = DateTime::utc_now();
+        let thinking = (!self.thinking.is_empty()).then_some(self.thinking.as_str());
+        let done = done_reason.is_some();
+        __unwrap!(match self.endpoint {
+            Endpoint::Chat => serde_json::to_writer(
+                &mut *buf,
+                &ChatResponse {
+                    model: &self.model,
+                    created_at,
+                    message: ResponseMessage {
+                        role: ASSISTANT,
+                        content: &self.content,
+                        thinking,
+                        tool_calls: &self.tool_calls,
+                    },
+                    done,
+                    done_reason,
+                    metrics,
+                }
+            ),
+            Endpoint::Generate => serde_json::to_writer(
+                &mut *buf,
+                &GenerateResponse {
+                    model: &self.model,
+                    created_at,
+                    response: &self.content,
+                    thinking,
+                    done,
+                    done_reason,
+                    metrics,
+                }
+            ),
+        });
+        buf.push(b'\n');
+    }
+
+    /// Writes the content collected since the last flush as one line
+    fn flush(&mut self, buf: &mut Vec<u8>) {
+        if self.is_empty() {
+            return;
+        }
+        self.write(buf, None, None);
+        self.content.clear();
+        self.thinking.clear();
+        self.tool_calls.clear();
+    }
+
+    /// Writes the last response object, carrying the done reason and metrics
+    fn finish(&mut self, buf: &mut Vec<u8>, start_time: std::time::Instant) {
+        self.end_tool_call();
+        let metrics = with_durations(self.metrics.take(), start_time);
+        self.write(buf, Some(ollama_done_reason(self.finish_reason)), Some(metrics));
+    }
+}
+
+/// Durations of the final response, counted from the request
+#[inline]
+fn with_durations(metrics: Option<Metrics>, start_time: std::time::Instant) -> Metrics {
+    let elapsed = start_time.elapsed().as_nanos() as u64;
+    Metrics { total_duration: elapsed, eval_duration: elapsed, ..metrics.unwrap_or_default() }
+}
+
+pub async fn handle_ollama_tags() -> Response<Body> {
+    let data = __unwrap!(serde_json::to_vec(&TagsResponse(Models::get().models())));
+    __unwrap!(
+        Response::builder()
+            .header(CONTENT_TYPE, JSON)
+            .header(CONTENT_LENGTH, data.len())
+            .body(Body::from(data))
+    )
+}
+
+pub async fn handle_ollama_show(
+    Json(request): Json<ShowRequest>,
+) -> Result<Json<ShowResponse>, (StatusCode, Json<OllamaError>)> {
+    Models::find_id(model_name(&request.model)).map(|model| Json(ShowResponse(model))).ok_or_else(
+        || {
+            (
+                StatusCode::NOT_FOUND,
+                Json(OllamaError {
+                    message: Cow::Owned(format!("model '{}' not found", request.model)),
+                }),
+            )
+        },
+    )
+}
+
+pub async fn handle_ollama_chat(
+    State(state): State<Arc<AppState>>,
+    extensions: Extensions,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response<Body>, (StatusCode, Json<OllamaError>)> {
+    handle_ollama(state, extensions, request, Endpoint::Chat).await
+}
+
+pub async fn handle_ollama_generate(
+    State(state): State<Arc<AppState>>,
+    extensions: Extensions,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Response<Body>, (StatusCode, Json<OllamaError>)> {
+    // An empty prompt only asks for the model to be loaded
+    if request.prompt.is_empty() && request.images.is_empty() {
+        if let Some(Err(e)) = extensions.get::<TokenBundleResult>() {
+            return Err(e.into_ollama_tuple());
+        }
+        if ExtModel::from_str(model_name(&request.model)).is_none() {
+            return Err(ChatError::ModelNotSupported(request.model).into_ollama_tuple());
+        }
+        let mut buf = Vec::with_capacity(128);
+        Output::new(Endpoint::Generate, request.model).write(
+            &mut buf,
+            Some(DoneReason::Load),
+            None,
+        );
+        return Ok(__unwrap!(
+            Response::builder()
+                .header(CONTENT_TYPE, JSON)
+                .header(CONTENT_LENGTH, buf.len())
+                .body(Body::from(buf))
+        ));
+    }
+    handle_ollama(state, extensions, request.into_chat(), Endpoint::Generate).await
+}
+
+async fn handle_ollama(
+    state: Arc<AppState>,
+    extensions: Extensions,
+    request: ChatRequest,
+    endpoint: Endpoint,
+) -> Result<Response<Body>, (StatusCode, Json<OllamaError>)> {
+    if request.messages.is_empty() {
+        return Err(ChatError::EmptyMessages(StatusCode::BAD_REQUEST).into_ollama_tuple());
+    }
+    let start_time = std::time::Instant::now();
+    let mut output = Output::new(endpoint, request.model.clone());
+    let request = request.into_chat_completion();
+
+    match completion::complete(state, extensions, request).await.map_err(into_ollama_tuple)? {
+        Reply::Stream(events) => {
+            let stream = events.map(Some).chain(futures_util::stream::once(async { None })).map(
+                move |event| {
+                    let mut buf = Vec::with_capacity(128);
+                    match event {
+                        Some(Event::Chunk(chunk)) => {
+                            output.push_chunk(chunk);
+                            output.flush(&mut buf);
+                        }
+                        Some(Event::Error(error)) => {
+                            output.flush(&mut buf);
+                            __unwrap!(serde_json::to_writer(
+                                &mut buf,
+                                &OllamaError { message: Cow::Owned(error.message) }
+                            ));
+                            buf.push(b'\n');
+                        }
+                        None => output.finish(&mut buf, start_time),
+                    }
+                    Ok::<_, Infallible>(Bytes::from(buf))
+                },
+            );
+
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, APPLICATION_NDJSON)
+                    .header(TRANSFER_ENCODING, CHUNKED)
+                    .body(Body::from_stream(stream))
+            ))
+        }
+        Reply::Whole(completion) => {
+            output.push_completion(completion);
+
+            let mut data = Vec::with_capacity(256);
+            output.finish(&mut data, start_time);
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, JSON)
+                    .header(CONTENT_LENGTH, data.len())
+                    .body(Body::from(data))
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(buf: &[u8]) -> Vec<serde_json::Value> {
+        buf.split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chat_stream_sends_tool_calls_whole_at_the_end() {
+        let mut output = Output::new(Endpoint::Chat, "gpt-4o".to_owned());
+        let mut buf = Vec::new();
+        for chunk in [
+            r#"{"choices":[{"delta":{"role":"assistant","content":"Hel"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"lo"}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_1",
+                "function":{"name":"f","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"a\":"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]}}]}"#,
+            r#"{"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5,
+                "prompt_tokens_details":{"cached_tokens":1}}}"#,
+        ] {
+            output.push_chunk(serde_json::from_str(chunk).unwrap());
+            output.flush(&mut buf);
+        }
+        output.finish(&mut buf, std::time::Instant::now());
+
+        let [hel, lo, last] = &lines(&buf)[..] else { panic!("unexpected lines") };
+        assert_eq!(hel["message"]["content"], "Hel");
+        assert_eq!(hel["done"], false);
+        assert_eq!(lo["message"]["content"], "lo");
+        assert_eq!(
+            last["message"]["tool_calls"],
+            serde_json::json!([{"id": "call_1", "function": {"name": "f", "arguments": {"a": 1}}}])
+        );
+        assert_eq!(last["done"], true);
+        assert_eq!(last["done_reason"], "stop");
+        assert_eq!(last["prompt_eval_count"], 4);
+        assert_eq!(last["eval_count"], 2);
+    }
+
+    #[test]
+    fn generate_response_carries_the_whole_completion() {
+        let mut output = Output::new(Endpoint::Generate, "gpt-4o".to_owned());
+        output.push_completion(
+            serde_json::from_str(
+                r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"Hi",
+                    "reasoning_content":"hmm"},"finish_reason":"length"}],
+                    "usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5}}"#,
+            )
+            .unwrap(),
+        );
+        let mut buf = Vec::new();
+        output.finish(&mut buf, std::time::Instant::now());
+
+        let [response] = &lines(&buf)[..] else { panic!("unexpected lines") };
+        assert_eq!(response["response"], "Hi");
+        assert_eq!(response["thinking"], "hmm");
+        assert_eq!(response["done_reason"], "length");
+        assert_eq!(response["eval_count"], 2);
+        assert!(response.get("message").is_none());
+    }
+}
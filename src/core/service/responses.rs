@@ -0,0 +1,464 @@
+use super::completion::{
+    self, Chunk, Completion, ErrorEvent, Event, FunctionDelta, Reply, ToolCallDelta,
+};
+use crate::{
+    app::{
+        constant::{
+            RESP_PREFIX,
+            header::{CHUNKED, EVENT_STREAM, JSON, KEEP_ALIVE, NO_CACHE_REVALIDATE},
+        },
+        lazy::REAL_USAGE,
+        model::{AppState, DateTime},
+    },
+    core::model::{
+        MessageId,
+        openai::{self, OpenAiError},
+        responses::{
+            ItemStatus, ObjectResponse, OutputContent, OutputItem, Response as ResponseObject,
+            ResponseCreateParams, ResponseError, ResponseStatus, ResponseStreamEvent, StreamEvent,
+            SummaryPart, Usage,
+        },
+    },
+};
+use alloc::{borrow::Cow, sync::Arc};
+use axum::{Json, body::Body, extract::State, response::Response};
+use bytes::Bytes;
+use core::convert::Infallible;
+use futures_util::StreamExt as _;
+use http::{
+    Extensions, StatusCode,
+    header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+};
+
+crate::define_typed_constants! {
+    &'static str => {
+        /// Output message item ID prefix
+        MSG_ITEM_PREFIX = "msg_",
+        /// Function call item ID prefix
+        FC_ITEM_PREFIX = "fc_",
+        /// Reasoning item ID prefix
+        RS_ITEM_PREFIX = "rs_",
+        /// Code of a failed response whose error came without one
+        SERVER_ERROR = "server_error",
+    }
+}
+
+/// Writes typed SSE events, or nothing at all for non-streaming responses
+struct EventSink {
+    buf: Vec<u8>,
+    sequence_number: u32,
+    enabled: bool,
+}
+
+impl EventSink {
+    #[inline]
+    fn emit(&mut self, event: StreamEvent<'_>) {
+        if !self.enabled {
+            return;
+        }
+        self.buf.extend_from_slice(b"event: ");
+        self.buf.extend_from_slice(event.type_name().as_bytes());
+        self.buf.extend_from_slice(b"\ndata: ");
+        let value = ResponseStreamEvent { event, sequence_number: self.sequence_number };
+        self.sequence_number += 1;
+        let mut ser = serde_json::Serializer::new(&mut self.buf);
+        __unwrap!(serde::Serialize::serialize(&value, &mut ser));
+        self.buf.extend_from_slice(b"\n\n");
+    }
+
+    #[inline]
+    fn take(&mut self) -> Vec<u8> { core::mem::take(&mut self.buf) }
+}
+
+struct ResponseMeta {
+    id: String,
+    model: String,
+    created_at: i64,
+    parallel_tool_calls: bool,
+}
+
+impl ResponseMeta {
+    #[inline]
+    fn response<'a>(
+        &'a self,
+        output: &'a [OutputItem],
+        status: ResponseStatus,
+        usage: Option<Usage>,
+    ) -> ResponseObject<'a> {
+        ResponseObject {
+            id: &self.id,
+            object: ObjectResponse,
+            created_at: self.created_at,
+            status,
+            model: &self.model,
+            output,
+            parallel_tool_calls: self.parallel_tool_calls,
+            error: None,
+            incomplete_details: (),
+            usage,
+        }
+    }
+}
+
+/// Folds chat completion deltas into Responses output items
+struct Output {
+    meta: ResponseMeta,
+    items: Vec<OutputItem>,
+    sink: EventSink,
+    /// Whether the last item is still receiving deltas
+    open: bool,
+    started: bool,
+    usage: Option<Usage>,
+    /// First error of the stream, which then ends as failed
+    error: Option<ErrorEvent>,
+}
+
+impl Output {
+    fn new(meta: ResponseMeta, streaming: bool) -> Self {
+        Self {
+            meta,
+            items: Vec::with_capacity(2),
+            sink: EventSink {
+                buf: Vec::with_capacity(128),
+                sequence_number: 0,
+                enabled: streaming,
+            },
+            open: false,
+            started: false,
+            usage: None,
+            error: None,
+        }
+    }
+
+    #[inline]
+    fn next_item_id(&self, prefix: &str) -> String {
+        let mut buf = itoa::Buffer::new();
+        [prefix, &self.meta.id[RESP_PREFIX.len()..], "_", buf.format(self.items.len())].concat()
+    }
+
+    #[inline]
+    fn output_index(&self) -> u32 { self.items.len().saturating_sub(1) as u32 }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        self.sink.emit(StreamEvent::Created {
+            response: self.meta.response(&self.items, ResponseStatus::InProgress, None),
+        });
+        self.sink.emit(StreamEvent::InProgress {
+            response: self.meta.response(&self.items, ResponseStatus::InProgress, None),
+        });
+    }
+
+    fn open(&mut self, item: OutputItem) {
+        self.close();
+        self.items.push(item);
+        self.open = true;
+        let output_index = self.output_index();
+        self.sink.emit(StreamEvent::OutputItemAdded {
+            output_index,
+            item: __unwrap!(self.items.last()),
+        });
+    }
+
+    fn close(&mut self) {
+        if !self.open {
+            return;
+        }
+        self.open = false;
+        let output_index = self.output_index();
+
+        if let Some(OutputItem::Message { status, .. } | OutputItem::FunctionCall { status, .. }) =
+            self.items.last_mut()
+        {
+            *status = ItemStatus::Completed;
+        }
+
+        let item = __unwrap!(self.items.last());
+        match item {
+            OutputItem::Reasoning { id, summary } => {
+                if let Some(SummaryPart::SummaryText { text }) = summary.first() {
+                    self.sink.emit(StreamEvent::ReasoningSummaryTextDone {
+                        item_id: id,
+                        output_index,
+                        summary_index: 0,
+                        text,
+                    });
+                    self.sink.emit(StreamEvent::ReasoningSummaryPartDone {
+                        item_id: id,
+                        output_index,
+                        summary_index: 0,
+                        part: SummaryPart::SummaryText { text: text.clone() },
+                    });
+                }
+            }
+            OutputItem::Message { id, content, .. } => {
+                if let Some(OutputContent::OutputText { text, .. }) = content.first() {
+                    self.sink.emit(StreamEvent::OutputTextDone {
+                        item_id: id,
+                        output_index,
+                        content_index: 0,
+                        text,
+                        logprobs: [],
+                    });
+                    self.sink.emit(StreamEvent::ContentPartDone {
+                        item_id: id,
+                        output_index,
+                        content_index: 0,
+                        part: OutputContent::OutputText { text: text.clone(), annotations: [] },
+                    });
+                }
+            }
+            OutputItem::FunctionCall { id, arguments, .. } => {
+                self.sink.emit(StreamEvent::FunctionCallArgumentsDone {
+                    item_id: id,
+                    output_index,
+                    arguments,
+                });
+            }
+        }
+        self.sink.emit(StreamEvent::OutputItemDone { output_index, item });
+    }
+
+    fn push_reasoning(&mut self, text: String) {
+        self.start();
+        if !(self.open && matches!(self.items.last(), Some(OutputItem::Reasoning { .. }))) {
+            let id = self.next_item_id(RS_ITEM_PREFIX);
+            self.open(OutputItem::Reasoning { id, summary: Vec::with_capacity(1) });
+
+            let output_index = self.output_index();
+            let Some(OutputItem::Reasoning { id, summary }) = self.items.last_mut() else {
+                __unreachable!()
+            };
+            summary.push(SummaryPart::SummaryText { text: String::new() });
+            self.sink.emit(StreamEvent::ReasoningSummaryPartAdded {
+                item_id: id,
+                output_index,
+                summary_index: 0,
+                part: SummaryPart::SummaryText { text: String::new() },
+            });
+        }
+
+        let output_index = self.output_index();
+        let Some(OutputItem::Reasoning { id, summary }) = self.items.last_mut() else {
+            __unreachable!()
+        };
+        self.sink.emit(StreamEvent::ReasoningSummaryTextDelta {
+            item_id: id,
+            output_index,
+            summary_index: 0,
+            delta: Cow::Borrowed(&text),
+        });
+        if let Some(SummaryPart::SummaryText { text: summary_text }) = summary.first_mut() {
+            summary_text.push_str(&text);
+        }
+    }
+
+    fn push_text(&mut self, text: String) {
+        self.start();
+        if !(self.open && matches!(self.items.last(), Some(OutputItem::Message { .. }))) {
+            let id = self.next_item_id(MSG_ITEM_PREFIX);
+            self.open(OutputItem::Message {
+                id,
+                status: ItemStatus::InProgress,
+                role: openai::Assistant,
+                content: Vec::with_capacity(1),
+            });
+
+            let output_index = self.output_index();
+            let Some(OutputItem::Message { id, content, .. }) = self.items.last_mut() else {
+                __unreachable!()
+            };
+            content.push(OutputContent::OutputText { text: String::new(), annotations: [] });
+            self.sink.emit(StreamEvent::ContentPartAdded {
+                item_id: id,
+                output_index,
+                content_index: 0,
+                part: OutputContent::OutputText { text: String::new(), annotations: [] },
+            });
+        }
+
+        let output_index = self.output_index();
+        let Some(OutputItem::Message { id, content, .. }) = self.items.last_mut() else {
+            __unreachable!()
+        };
+        self.sink.emit(StreamEvent::OutputTextDelta {
+            item_id: id,
+            output_index,
+            content_index: 0,
+            delta: Cow::Borrowed(&text),
+            logprobs: [],
+        });
+        if let Some(OutputContent::OutputText { text: content_text, .. }) = content.first_mut() {
+            content_text.push_str(&text);
+        }
+    }
+
+    /// A call starts with its name, every delta after that carries more of the arguments
+    fn push_tool_call(&mut self, tool_call: ToolCallDelta) {
+        self.start();
+        let delta = match tool_call.function {
+            Some(FunctionDelta::Start { name }) => {
+                let item_id = self.next_item_id(FC_ITEM_PREFIX);
+                self.open(OutputItem::FunctionCall {
+                    id: item_id,
+                    call_id: tool_call.id.unwrap_or_default(),
+                    name,
+                    arguments: String::new(),
+                    status: ItemStatus::InProgress,
+                });
+                return;
+            }
+            Some(FunctionDelta::Partial { arguments }) if !arguments.is_empty() => arguments,
+            _ => return,
+        };
+
+        let output_index = self.output_index();
+        let Some(OutputItem::FunctionCall { id, arguments, .. }) = self.items.last_mut() else {
+            return;
+        };
+        self.sink.emit(StreamEvent::FunctionCallArgumentsDelta {
+            item_id: id,
+            output_index,
+            delta: Cow::Borrowed(&delta),
+        });
+        arguments.push_str(&delta);
+    }
+
+    fn push_chunk(&mut self, chunk: Chunk) {
+        for delta in chunk.choices.into_iter().filter_map(|choice| choice.delta) {
+            if let Some(text) = delta.reasoning_content {
+                self.push_reasoning(text);
+            }
+            if let Some(text) = delta.content {
+                self.push_text(text);
+            }
+            for tool_call in delta.tool_calls {
+                self.push_tool_call(tool_call);
+            }
+        }
+        if let Some(usage) = chunk.usage {
+            self.usage = Some(usage.into());
+        }
+    }
+
+    fn push_error(&mut self, error: ErrorEvent) {
+        self.sink.emit(StreamEvent::Error {
+            code: error.code.clone().map(Cow::Owned),
+            message: Cow::Owned(error.message.clone()),
+            param: (),
+        });
+        self.error.get_or_insert(error);
+    }
+
+    /// Ends the stream with `response.failed` after an error, `response.completed` otherwise
+    fn complete(&mut self) {
+        self.start();
+        self.close();
+        let event = match self.error {
+            Some(ref error) => {
+                let mut response =
+                    self.meta.response(&self.items, ResponseStatus::Failed, self.usage);
+                response.error = Some(ResponseError {
+                    code: error.code.as_deref().unwrap_or(SERVER_ERROR),
+                    message: &error.message,
+                });
+                StreamEvent::Failed { response }
+            }
+            None => StreamEvent::Completed {
+                response: self.meta.response(&self.items, ResponseStatus::Completed, self.usage),
+            },
+        };
+        self.sink.emit(event);
+    }
+
+    /// Adds the output of a non-streaming completion at once
+    fn push_completion(&mut self, completion: Completion) {
+        if let Some(choice) = completion.choices.into_iter().next() {
+            let message = choice.message;
+            if let Some(text) = message.reasoning_content {
+                self.push_reasoning(text);
+            }
+            if let Some(text) = message.content.filter(|text| !text.is_empty()) {
+                self.push_text(text);
+            }
+            for openai::ChatCompletionMessageToolCall::Function { id, function } in
+                message.tool_calls
+            {
+                let item_id = self.next_item_id(FC_ITEM_PREFIX);
+                self.open(OutputItem::FunctionCall {
+                    id: item_id,
+                    call_id: id,
+                    name: function.name,
+                    arguments: function.arguments,
+                    status: ItemStatus::InProgress,
+                });
+            }
+        }
+        self.close();
+        self.usage = completion.usage.map(Into::into);
+    }
+}
+
+pub async fn handle_responses(
+    State(state): State<Arc<AppState>>,
+    extensions: Extensions,
+    Json(request): Json<ResponseCreateParams>,
+) -> Result<Response<Body>, (StatusCode, Json<OpenAiError>)> {
+    let meta = ResponseMeta {
+        id: {
+            let mut buf = [0; 22];
+            let msg_id = MessageId::new(uuid::Uuid::new_v4().as_bytes());
+            [RESP_PREFIX, &*msg_id.to_str(&mut buf)].concat()
+        },
+        model: request.model.clone(),
+        created_at: DateTime::utc_now().timestamp(),
+        parallel_tool_calls: request.parallel_tool_calls.unwrap_or(true),
+    };
+    let mut request = request.into_chat_completion();
+    // Usage is only reported when it is the real one
+    request.stream_options.include_usage = *REAL_USAGE;
+
+    match completion::complete(state, extensions, request).await? {
+        Reply::Stream(events) => {
+            let mut output = Output::new(meta, true);
+            let stream = events.map(Some).chain(futures_util::stream::once(async { None })).map(
+                move |event| {
+                    match event {
+                        Some(Event::Chunk(chunk)) => output.push_chunk(chunk),
+                        Some(Event::Error(error)) => output.push_error(error),
+                        None => output.complete(),
+                    }
+                    Ok::<_, Infallible>(Bytes::from(output.sink.take()))
+                },
+            );
+
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, EVENT_STREAM)
+                    .header(TRANSFER_ENCODING, CHUNKED)
+                    .body(Body::from_stream(stream))
+            ))
+        }
+        Reply::Whole(completion) => {
+            let mut output = Output::new(meta, false);
+            output.push_completion(completion);
+
+            let response_data =
+                output.meta.response(&output.items, ResponseStatus::Completed, output.usage);
+            let data = __unwrap!(serde_json::to_vec(&response_data));
+            Ok(__unwrap!(
+                Response::builder()
+                    .header(CACHE_CONTROL, NO_CACHE_REVALIDATE)
+                    .header(CONNECTION, KEEP_ALIVE)
+                    .header(CONTENT_TYPE, JSON)
+                    .header(CONTENT_LENGTH, data.len())
+                    .body(Body::from(data))
+            ))
+        }
+    }
+}
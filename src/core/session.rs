@@ -1,42 +1,172 @@
-use super::model::anthropic::ToolResultBlockParam;
+//! Upstream streams parked at a tool call, waiting for the client to send its result
+
+use super::{service::context::Session, stream::decoder::StreamDecoder};
+use crate::app::{lazy::TOOL_SESSION_IDLE_TIMEOUT, model::TokenKey};
+use alloc::sync::Arc;
 use byte_str::ByteStr;
-use bytes::Bytes;
-use core::{
-    pin::Pin,
-    task::{Context, Poll},
-};
-use futures::Stream;
+use core::hash::Hasher as _;
 use manually_init::ManuallyInit;
-use parking_lot::RwLock;
-use reqwest::{DataStream, Decoder};
-use tokio::sync::mpsc;
+use parking_lot::Mutex;
+use std::{hash::DefaultHasher, time::Instant};
 
 type HashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
-type BoxError = Box<dyn std::error::Error + Send + Sync>;
-type SessionTx = mpsc::Sender<ToolResultBlockParam>;
 
-pub struct Session {
-    stream: DataStream<Decoder>,
-    sender: mpsc::Sender<Result<Bytes, BoxError>>,
-}
+/// Key a request was made with, only requests with the same key resume its sessions
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Owner(u64);
 
-impl Stream for Session {
-    type Item = Result<Bytes, reqwest::Error>;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.stream).poll_next(cx)
+impl Owner {
+    pub fn of(auth_token: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(auth_token.as_bytes());
+        Self(hasher.finish())
     }
 }
 
+pub struct Parked {
+    pub session: Session,
+    /// Keeps the bytes of the next turn that arrived along with the tool call
+    pub decoder: Arc<tokio::sync::Mutex<StreamDecoder>>,
+    /// Token the upstream stream runs on, which the turns resuming it are made with
+    pub token: TokenKey,
+    owner: Owner,
+    model: &'static str,
+    parked_at: Instant,
+}
+
+/// Whom a session is parked for and on which token
+#[derive(Clone, Copy)]
+pub struct Parking {
+    pub owner: Owner,
+    pub token: TokenKey,
+    pub model: &'static str,
+}
+
 pub struct Registry {
-    inner: RwLock<HashMap<ByteStr, SessionTx>>,
+    inner: Mutex<HashMap<ByteStr, Parked>>,
 }
 
 impl Registry {
-    pub fn init() {
-        REGISTRY.init(Registry {
-            inner: RwLock::new(HashMap::with_capacity_and_hasher(16, ahash::RandomState::new())),
-        })
+    fn new() -> Self {
+        Registry {
+            inner: Mutex::new(HashMap::with_capacity_and_hasher(16, ahash::RandomState::new())),
+        }
+    }
+
+    pub fn init() { REGISTRY.init(Registry::new()) }
+
+    /// Whether streams are kept open at tool calls at all
+    #[inline]
+    pub fn is_enabled() -> bool { TOOL_SESSION_IDLE_TIMEOUT.to_duration().is_some() }
+
+    /// Parks `session` until the result of `tool_call_id` arrives or it idles out
+    pub fn park(
+        tool_call_id: ByteStr,
+        session: Session,
+        decoder: Arc<tokio::sync::Mutex<StreamDecoder>>,
+        parking: Parking,
+    ) {
+        let Some(timeout) = TOOL_SESSION_IDLE_TIMEOUT.to_duration() else { return };
+        let parked_at = REGISTRY.insert(tool_call_id.clone(), session, decoder, parking);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            REGISTRY.expire(&tool_call_id, parked_at);
+        });
+    }
+
+    /// Takes the session parked at `tool_call_id` for `owner`, one opened for another model is
+    /// closed. Sessions of other owners stay parked
+    #[inline]
+    pub fn take(tool_call_id: &ByteStr, model: &'static str, owner: Owner) -> Option<Parked> {
+        REGISTRY.remove(tool_call_id, model, owner)
+    }
+
+    /// Parks `session` in place of any parked at `tool_call_id`, returns when it was parked
+    fn insert(
+        &self,
+        tool_call_id: ByteStr,
+        session: Session,
+        decoder: Arc<tokio::sync::Mutex<StreamDecoder>>,
+        Parking { owner, token, model }: Parking,
+    ) -> Instant {
+        let parked_at = Instant::now();
+        self.inner
+            .lock()
+            .insert(tool_call_id, Parked { session, decoder, token, owner, model, parked_at });
+        parked_at
+    }
+
+    /// Drops the session parked at `tool_call_id` at `parked_at`, one parked there since stays
+    fn expire(&self, tool_call_id: &ByteStr, parked_at: Instant) {
+        let mut inner = self.inner.lock();
+        // Dropping the session closes the request body and so the upstream stream
+        if inner.get(tool_call_id).is_some_and(|parked| parked.parked_at == parked_at) {
+            inner.remove(tool_call_id);
+        }
+    }
+
+    fn remove(&self, tool_call_id: &ByteStr, model: &'static str, owner: Owner) -> Option<Parked> {
+        let mut inner = self.inner.lock();
+        if inner.get(tool_call_id)?.owner != owner {
+            return None;
+        }
+        inner.remove(tool_call_id).filter(|parked| parked.model == model)
     }
 }
 
 static REGISTRY: ManuallyInit<Registry> = ManuallyInit::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::model::{Randomness, UserId};
+
+    fn park(registry: &Registry, model: &'static str) -> Instant {
+        let session = Session::new(http::Response::new(Vec::<u8>::new()).into(), None);
+        let decoder = Arc::new(tokio::sync::Mutex::new(StreamDecoder::new()));
+        let token = TokenKey { user_id: UserId::from_u128(1), randomness: Randomness::from_u64(0) };
+        let parking = Parking { owner: Owner::of("key-a"), token, model };
+        registry.insert(ByteStr::from_static("call_1"), session, decoder, parking)
+    }
+
+    #[test]
+    fn parked_session_is_resumed_once_by_its_model() {
+        let registry = Registry::new();
+        let id = ByteStr::from_static("call_1");
+        park(&registry, "gpt-5");
+        assert!(registry.remove(&id, "gpt-5", Owner::of("key-a")).is_some());
+        assert!(registry.remove(&id, "gpt-5", Owner::of("key-a")).is_none());
+
+        // A result sent to another model closes the session instead
+        park(&registry, "gpt-5");
+        assert!(registry.remove(&id, "claude-4-sonnet", Owner::of("key-a")).is_none());
+        assert!(registry.remove(&id, "gpt-5", Owner::of("key-a")).is_none());
+    }
+
+    #[test]
+    fn parked_session_is_resumed_only_by_its_owner() {
+        let registry = Registry::new();
+        let id = ByteStr::from_static("call_1");
+        park(&registry, "gpt-5");
+        assert!(registry.remove(&id, "gpt-5", Owner::of("key-b")).is_none());
+
+        // Left parked for the key that opened it
+        let parked = registry.remove(&id, "gpt-5", Owner::of("key-a")).unwrap();
+        assert!(parked.token.user_id == UserId::from_u128(1));
+    }
+
+    #[test]
+    fn only_the_latest_parking_idles_out() {
+        let registry = Registry::new();
+        let id = ByteStr::from_static("call_1");
+        let first = park(&registry, "gpt-5");
+        std::thread::sleep(core::time::Duration::from_millis(1));
+        let second = park(&registry, "gpt-5");
+
+        registry.expire(&id, first);
+        assert!(registry.inner.lock().contains_key(&id));
+        registry.expire(&id, second);
+        assert!(registry.remove(&id, "gpt-5", Owner::of("key-a")).is_none());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod decoder;
 pub mod droppable;
+pub mod limiter;
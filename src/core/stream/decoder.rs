@@ -4,6 +4,7 @@ pub mod types;
 mod utils;
 
 // use core::sync::atomic::{AtomicU32, Ordering};
+use super::limiter::{ContentLimiter, StopReason};
 use crate::core::{
     adapter::ToolId,
     aiserver::v1::{StreamUnifiedChatResponseWithTools, WebReference},
@@ -132,6 +133,7 @@ pub struct StreamDecoder {
     first_result: Option<Vec<StreamMessage>>,
     content_delays: Option<(String, Vec<(u32, f32)>)>,
     thinking_content: Option<String>,
+    limiter: Option<ContentLimiter>,
     // Counter and time (8 bytes + 8 bytes)
     context: Context,
     empty_stream_count: usize,
@@ -150,6 +152,7 @@ impl StreamDecoder {
             first_result: None,
             content_delays: None,
             thinking_content: None,
+            limiter: None,
             context: Context {
                 raw_args_len: 0,
                 processed: 0,
@@ -194,6 +197,10 @@ impl StreamDecoder {
     #[inline]
     pub fn tool_processed(&self) -> u32 { self.context.processed }
 
+    /// Whether any content was decoded yet, until then the request can be sent again elsewhere
+    #[inline]
+    pub fn has_seen_content(&self) -> bool { self.has_seen_content }
+
     #[inline]
     pub fn take_content_delays(&mut self) -> Option<(String, Vec<(u32, f32)>)> {
         core::mem::take(&mut self.content_delays)
@@ -204,6 +211,18 @@ impl StreamDecoder {
         core::mem::take(&mut self.thinking_content)
     }
 
+    /// Why the content was cut short by the limiter, if it was
+    #[inline]
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.limiter.as_ref().and_then(ContentLimiter::stop_reason)
+    }
+
+    #[inline]
+    pub fn with_limiter(mut self, limiter: Option<ContentLimiter>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
     #[inline]
     pub fn no_first_cache(mut self) -> Self {
         self.first_result_ready = true;
@@ -211,6 +230,21 @@ impl StreamDecoder {
         self
     }
 
+    /// Starts over for the turn that follows a tool result on the same stream,
+    /// the bytes already buffered belong to it
+    pub fn next_turn(&mut self, limiter: Option<ContentLimiter>) {
+        self.first_result = None;
+        self.content_delays = None;
+        self.thinking_content = None;
+        self.limiter = limiter;
+        self.context.processed = 0;
+        self.empty_stream_count = 0;
+        self.last_content_time = Instant::now();
+        self.first_result_ready = false;
+        self.first_result_taken = false;
+        self.has_seen_content = false;
+    }
+
     pub fn decode(
         &mut self,
         data: &[u8],
@@ -232,6 +266,12 @@ impl StreamDecoder {
 
         self.reset_empty_stream_count();
 
+        // Content after a stop is dropped until the upstream is closed
+        if self.limiter.as_ref().is_some_and(ContentLimiter::is_stopped) {
+            unsafe { self.buffer.advance_unchecked(self.buffer.len()) };
+            return Ok(Vec::new());
+        }
+
         let mut iter = (&self.buffer).into_iter();
         let count = iter.len();
 
@@ -273,6 +313,23 @@ impl StreamDecoder {
                 }
             };
 
+            // Apply stop sequences and the token budget to content
+            let mut end = false;
+            let result = match (result, &mut self.limiter) {
+                (Some(StreamMessage::Content(text)), Some(limiter)) => {
+                    let text = limiter.push(&text);
+                    end = limiter.is_stopped();
+                    (!text.is_empty()).then_some(StreamMessage::Content(text))
+                }
+                (Some(StreamMessage::StreamEnd), Some(limiter)) => {
+                    // Flush the tail held back for a possible stop sequence
+                    end = true;
+                    let text = limiter.finish();
+                    (!text.is_empty()).then_some(StreamMessage::Content(text))
+                }
+                (result, _) => result,
+            };
+
             if let Some(msg) = result {
                 if !self.has_seen_content && msg.any_content() {
                     self.has_seen_content = true;
@@ -292,6 +349,13 @@ impl StreamDecoder {
                 let msg = if convert_web_ref { msg.convert_web_ref_to_content() } else { msg };
                 messages.push(msg);
             }
+
+            if end {
+                messages.push(StreamMessage::StreamEnd);
+                if self.limiter.as_ref().is_some_and(ContentLimiter::is_stopped) {
+                    break;
+                }
+            }
         }
 
         unsafe { self.buffer.advance_unchecked(iter.offset()) };
@@ -306,8 +370,10 @@ impl StreamDecoder {
             }
         }
         if !self.first_result_ready {
-            self.first_result_ready =
-                self.first_result.is_some() && !self.first_result_taken && self.has_seen_content;
+            self.first_result_ready = self.first_result.is_some()
+                && !self.first_result_taken
+                && (self.has_seen_content
+                    || self.limiter.as_ref().is_some_and(ContentLimiter::is_stopped));
         }
         Ok(messages)
     }
@@ -656,4 +722,20 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn content_ends_the_window_for_failover() {
+        let stream_data = include_str!("../../../tests/data/stream_data.txt");
+        let bytes = hex::decode(stream_data).unwrap();
+        // Frames before offset 181 carry ids and empty text, the next one the first character
+        let (head, tail) = bytes.split_at(181);
+        let mut decoder = StreamDecoder::new();
+        decoder.decode(head, false).unwrap();
+        assert!(!decoder.has_seen_content());
+        decoder.decode(&tail[..12], false).unwrap();
+        assert!(decoder.has_seen_content());
+
+        decoder.next_turn(None);
+        assert!(!decoder.has_seen_content());
+    }
 }
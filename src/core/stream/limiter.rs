@@ -0,0 +1,141 @@
+/// Tokens are approximated as 4 bytes, see `approx_token_count`
+const BYTES_PER_TOKEN: usize = 4;
+
+/// Why the content was cut short
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// Matched one of the stop sequences
+    StopSequence(String),
+    /// Reached the token budget
+    MaxTokens,
+}
+
+/// Applies stop sequences and a token budget to the content of a response
+#[derive(Clone)]
+pub struct ContentLimiter {
+    stop_sequences: Vec<String>,
+    /// Remaining budget in bytes
+    remaining: Option<usize>,
+    /// Tail held back because it may be the start of a stop sequence
+    pending: String,
+    stop_reason: Option<StopReason>,
+}
+
+impl ContentLimiter {
+    /// Returns `None` when there is nothing to enforce
+    pub fn new(stop_sequences: Vec<String>, max_tokens: Option<u32>) -> Option<Self> {
+        let stop_sequences: Vec<String> =
+            stop_sequences.into_iter().filter(|seq| !seq.is_empty()).collect();
+        if stop_sequences.is_empty() && max_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            stop_sequences,
+            remaining: max_tokens.map(|n| (n as usize).saturating_mul(BYTES_PER_TOKEN)),
+            pending: String::new(),
+            stop_reason: None,
+        })
+    }
+
+    #[inline]
+    pub fn stop_reason(&self) -> Option<&StopReason> { self.stop_reason.as_ref() }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool { self.stop_reason.is_some() }
+
+    /// Feeds a content chunk, returns the part that can be emitted now
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stop_reason.is_some() {
+            return String::new();
+        }
+        let mut buf = core::mem::take(&mut self.pending);
+        buf.push_str(text);
+
+        // The earliest match wins
+        if let Some((pos, seq)) = self
+            .stop_sequences
+            .iter()
+            .filter_map(|seq| buf.find(seq.as_str()).map(|pos| (pos, seq)))
+            .min_by_key(|&(pos, _)| pos)
+        {
+            let seq = seq.clone();
+            buf.truncate(pos);
+            let text = self.take_budget(buf);
+            if self.stop_reason.is_none() {
+                self.stop_reason = Some(StopReason::StopSequence(seq));
+            }
+            return text;
+        }
+
+        let keep =
+            self.stop_sequences.iter().map(|seq| partial_match_len(&buf, seq)).max().unwrap_or(0);
+        self.pending = buf.split_off(buf.len() - keep);
+        self.take_budget(buf)
+    }
+
+    /// Flushes the held back tail at the end of the content
+    pub fn finish(&mut self) -> String {
+        let pending = core::mem::take(&mut self.pending);
+        self.take_budget(pending)
+    }
+
+    fn take_budget(&mut self, mut text: String) -> String {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if text.len() > *remaining {
+                text.truncate(text.floor_char_boundary(*remaining));
+                *remaining = 0;
+                self.pending.clear();
+                self.stop_reason = Some(StopReason::MaxTokens);
+            } else {
+                *remaining -= text.len();
+            }
+        }
+        text
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `seq`
+fn partial_match_len(text: &str, seq: &str) -> usize {
+    let max = (seq.len() - 1).min(text.len());
+    (1..=max)
+        .rev()
+        .find(|&n| {
+            let start = text.len() - n;
+            text.is_char_boundary(start) && seq.as_bytes().starts_with(&text.as_bytes()[start..])
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_sequence_across_chunks() {
+        let mut limiter = ContentLimiter::new(vec!["END".into()], None).unwrap();
+        assert_eq!(limiter.push("hello E"), "hello ");
+        assert_eq!(limiter.push("N"), "");
+        assert_eq!(limiter.push("D world"), "");
+        assert_eq!(limiter.stop_reason(), Some(&StopReason::StopSequence("END".into())));
+        assert_eq!(limiter.push("more"), "");
+    }
+
+    #[test]
+    fn test_partial_match_released() {
+        let mut limiter = ContentLimiter::new(vec!["END".into()], None).unwrap();
+        assert_eq!(limiter.push("the E"), "the ");
+        assert_eq!(limiter.push("xit"), "Exit");
+        assert_eq!(limiter.push("EN"), "");
+        assert_eq!(limiter.finish(), "EN");
+        assert!(!limiter.is_stopped());
+    }
+
+    #[test]
+    fn test_max_tokens() {
+        let mut limiter = ContentLimiter::new(vec![], Some(2)).unwrap();
+        assert_eq!(limiter.push("abcde"), "abcde");
+        assert_eq!(limiter.push("fghij"), "fgh");
+        assert_eq!(limiter.stop_reason(), Some(&StopReason::MaxTokens));
+        assert!(ContentLimiter::new(vec![String::new()], None).is_none());
+    }
+}
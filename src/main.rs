@@ -32,7 +32,10 @@ mod natural_args;
 use alloc::sync::Arc;
 use app::{
     constant::{EMPTY_STRING, ExeName, VERSION},
-    model::{AppConfig, AppState},
+    model::{
+        AppConfig, AppState, run_api_key_save, run_budget_save, run_profile_poll,
+        run_token_refresh,
+    },
 };
 use common::utils::parse_from_env;
 use natural_args::{DEFAULT_LISTEN_HOST, ENV_HOST, ENV_PORT};
@@ -164,6 +167,18 @@ async fn run() {
         }
     });
 
+    // Start background task to refresh tokens before they expire
+    tokio::spawn(run_token_refresh(state.clone()));
+
+    // Start background task to keep token profiles up to date
+    tokio::spawn(run_profile_poll(state.clone()));
+
+    // Start background task to save the budgets charged by requests
+    tokio::spawn(run_budget_save());
+
+    // Start background task to save the requests counted against the API key quotas
+    tokio::spawn(run_api_key_save());
+
     // Create a clone for signal handling
     let state_for_shutdown = state.clone();
 